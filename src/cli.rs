@@ -0,0 +1,317 @@
+use std::path::Path;
+
+use crate::geometry::color::{Color, Gamma};
+use crate::geometry::color_space::ColorSpace;
+use crate::geometry::tonemap::ToneMap;
+use crate::scenes::SCENES;
+use crate::render::{AdaptiveThreshold, Fog, parse_clamp_schedule, RenderSettings, RrHeuristic};
+use crate::world::aggregate::AggregateKind;
+use crate::world::environment::EnvironmentKind;
+use crate::world::sampler::SamplingMode;
+
+pub const USAGE: &str = "Usage: RayTracer [options]
+  --width N           image width in pixels
+  --height N          image height in pixels
+  --samples N         samples per pixel
+  --depth N           maximum bounces per path
+  --threads N         worker threads
+  --output PATH       where to save the image, other outputs are written next to it
+  --scene NAME        what to render, cornell (default), rtweekend, checkered, perlin, materials, bubbles, cloud, displaced, soft, transformed or tinted
+  --mesh PATH         add a grey .obj or .ply mesh to the scene, in scene coordinates
+  --bake-ao N         darken the --mesh by ambient occlusion baked from N rays per vertex
+  --camera PATH       read the view from the camera block of a scene file, the scene's own by default
+  --environment NAME  what rays that miss see, black, sky, equirect:PATH or cubemap:PX,NX,PY,NY,PZ,NZ, the scene's own by default
+  --accel NAME        acceleration structure, bvh (default), grid or list
+  --sampling NAME     stratified (default) or random placement of pixel samples
+  --color-space NAME  working space for light transport, srgb (default) or acescg
+  --tonemap NAME      tone curve, none (default, clips at 1), reinhard or aces
+  --exposure F        scale on the image before tone mapping, 1 by default
+  --gamma CURVE       encoding of the saved image, linear, srgb or a power, 2 by default
+  --roulette NAME     end dim paths at random, judged by max-channel or luminance
+  --roulette-after N  bounces every path makes before roulette may end it
+  --clamp NAME        limit indirect radiance per bounce to cut fireflies, falloff
+  --fog D[,R,G,B]     distance fog of density D, light grey unless a color is given
+  --burn-in           label the image with its sample count and render time
+  --convergence-map   also save a map of each pixel's remaining error, next to the image
+  --alpha             save RGBA, transparent where camera rays miss
+  --adaptive          spend each block's samples on its noisiest pixels
+  --deterministic     seed every pixel from its position so any thread count gives the same image
+  --deep N            also save up to N depth slices per pixel, next to the image
+  --layers N,N        render only objects on these render layers, all of them by default
+  --max-error F       stop pixels once their relative error is below F, checked every 16 samples
+  --preview           also save a quick quarter-resolution render first
+  --help              print this message";
+
+// What the command line asked for, on top of the settings main starts from
+pub struct Options {
+    pub settings: RenderSettings,
+    pub output: String,
+    pub aggregate: AggregateKind,
+    pub scene: &'static str,
+    pub mesh: Option<String>,
+    pub bake_ao: Option<u32>,
+    pub camera: Option<String>,
+    pub environment: Option<EnvironmentKind>,
+    pub help: bool,
+}
+
+impl Options {
+    // Takes the arguments after the program name. Flags left out keep their value in defaults.
+    pub fn parse(args: impl IntoIterator<Item = String>, defaults: RenderSettings) -> Result<Self, String> {
+        let mut options = Options { settings: defaults, output: String::from("render.png"), aggregate: AggregateKind::Bvh, scene: SCENES[0], mesh: Option::None, bake_ao: Option::None, camera: Option::None, environment: Option::None, help: false };
+        let mut args = args.into_iter();
+        while let Some(flag) = args.next() {
+            // Only taken once the flag is known, so a misspelt switch isn't reported as missing a value
+            let mut value = || args.next().ok_or(format!("{} needs a value", flag));
+            let settings = &mut options.settings;
+            match flag.as_str() {
+                "--help" | "-h" => options.help = true,
+                "--preview" => settings.progressive = true,
+                "--deterministic" => settings.deterministic = true,
+                "--adaptive" => settings.adaptive = true,
+                "--alpha" => settings.alpha = true,
+                "--convergence-map" => settings.convergence_map = true,
+                "--burn-in" => settings.burn_in = true,
+                "--width" => settings.image_width = Self::positive(&flag, &value()?)?,
+                "--height" => settings.image_height = Self::positive(&flag, &value()?)?,
+                "--samples" => settings.samples_per_pixel = Self::positive(&flag, &value()?)?,
+                "--depth" => settings.max_depth = Self::number(&flag, &value()?)?,
+                "--threads" => settings.n_threads = Self::positive(&flag, &value()?)?,
+                "--output" => options.output = value()?,
+                "--scene" => options.scene = Self::choice(&flag, &value()?, |name| SCENES.iter().copied().find(|&scene| scene == name), &Self::listing(&SCENES))?,
+                "--mesh" => options.mesh = Option::from(value()?),
+                "--bake-ao" => options.bake_ao = Option::from(Self::positive(&flag, &value()?)?),
+                "--camera" => options.camera = Option::from(value()?),
+                "--environment" => options.environment = Option::from(Self::choice(&flag, &value()?, EnvironmentKind::parse, "black, sky, equirect:PATH or cubemap: and six face paths")?),
+                "--accel" => options.aggregate = Self::choice(&flag, &value()?, AggregateKind::parse, "bvh, grid or list")?,
+                "--sampling" => settings.sampling = Self::choice(&flag, &value()?, SamplingMode::parse, "stratified or random")?,
+                "--color-space" => settings.color_space = Self::choice(&flag, &value()?, ColorSpace::parse, "srgb or acescg")?,
+                "--tonemap" => settings.tone_map = Self::choice(&flag, &value()?, ToneMap::parse, "none, reinhard or aces")?,
+                "--exposure" => settings.exposure = Self::positive_float(&flag, &value()?)?,
+                "--gamma" => settings.gamma = Self::choice(&flag, &value()?, Gamma::parse, "linear, srgb or a power above 0")?,
+                "--roulette" => settings.russian_roulette = Option::from(Self::choice(&flag, &value()?, RrHeuristic::parse, "max-channel or luminance")?),
+                "--roulette-after" => settings.rr_min_bounces = Self::number(&flag, &value()?)?,
+                "--clamp" => settings.clamp_schedule = Option::from(Self::choice(&flag, &value()?, parse_clamp_schedule, "falloff")?),
+                "--fog" => settings.fog = Option::from(Self::fog(&flag, &value()?)?),
+                "--deep" => settings.deep_slices = Self::number(&flag, &value()?)? as usize,
+                "--layers" => settings.render_layer_mask = Self::layer_mask(&flag, &value()?)?,
+                "--max-error" => settings.adaptive_threshold = Option::from(AdaptiveThreshold { max_error: Self::positive_float(&flag, &value()?)?, min_samples: 16 }),
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        return Ok(options);
+    }
+
+    // Path for another output of this render, e.g. ("_normals", "png") turns render.png into
+    // render_normals.png in the same directory
+    pub fn sibling(&self, suffix: &str, extension: &str) -> String {
+        let path = Path::new(&self.output);
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("render");
+        return path.with_file_name(format!("{}{}.{}", stem, suffix, extension)).to_string_lossy().into_owned();
+    }
+
+    // One of a fixed set of names, listed in the error when value is none of them
+    fn choice<T>(flag: &str, value: &str, parse: fn(&str) -> Option<T>, names: &str) -> Result<T, String> {
+        return parse(value).ok_or(format!("{} expects {}, got {}", flag, names, value));
+    }
+
+    // "a, b or c"
+    fn listing(names: &[&str]) -> String {
+        return match names.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} or {}", rest.join(", "), last),
+            _ => names.join(""),
+        };
+    }
+
+    fn number(flag: &str, value: &str) -> Result<u32, String> {
+        return value.parse().map_err(|_| format!("{} expects a whole number, got {}", flag, value));
+    }
+
+    fn float(flag: &str, value: &str) -> Result<f32, String> {
+        return match value.parse::<f32>() {
+            Ok(x) if x.is_finite() => Ok(x),
+            _ => Err(format!("{} expects a number, got {}", flag, value)),
+        };
+    }
+
+    fn positive_float(flag: &str, value: &str) -> Result<f32, String> {
+        let x = Self::float(flag, value)?;
+        if x <= 0.0 {
+            return Err(format!("{} must be above 0", flag));
+        }
+        return Ok(x);
+    }
+
+    fn fog(flag: &str, value: &str) -> Result<Fog, String> {
+        let parts = value.split(',').map(|part| Self::float(flag, part)).collect::<Result<Vec<f32>, String>>()?;
+        let color = match parts[1..] {
+            [] => Color { r: 0.8, g: 0.8, b: 0.8 },
+            [r, g, b] => Color { r, g, b },
+            _ => return Err(format!("{} expects a density and optionally R,G,B, got {}", flag, value)),
+        };
+        if parts[0] < 0.0 {
+            return Err(format!("{} density must not be negative", flag));
+        }
+        return Ok(Fog { color, density: parts[0] });
+    }
+
+    // A comma-separated list of layers as a mask with their bits set
+    fn layer_mask(flag: &str, value: &str) -> Result<u32, String> {
+        let mut mask = 0;
+        for part in value.split(',') {
+            match part.parse::<u32>() {
+                Ok(layer) if layer < 32 => mask |= 1 << layer,
+                _ => return Err(format!("{} expects layer numbers from 0 to 31, got {}", flag, part)),
+            }
+        }
+        return Ok(mask);
+    }
+
+    fn positive(flag: &str, value: &str) -> Result<u32, String> {
+        let n = Self::number(flag, value)?;
+        if n == 0 {
+            return Err(format!("{} must be at least 1", flag));
+        }
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::falloff_clamp;
+    use crate::render::tests::test_settings;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        return Options::parse(args.iter().map(|s| s.to_string()), test_settings(8, 8, 1));
+    }
+
+    #[test]
+    fn preview_is_off_unless_asked_for() {
+        assert!(!parse(&[]).unwrap().settings.progressive);
+        assert!(parse(&["--preview"]).unwrap().settings.progressive);
+        assert!(parse(&["--preview", "--width", "64"]).unwrap().settings.progressive);
+    }
+
+    #[test]
+    fn switches_turn_their_setting_on() {
+        assert!(!parse(&[]).unwrap().settings.burn_in);
+        assert!(parse(&["--burn-in"]).unwrap().settings.burn_in);
+        assert!(!parse(&[]).unwrap().settings.convergence_map);
+        assert!(parse(&["--convergence-map"]).unwrap().settings.convergence_map);
+        assert!(!parse(&[]).unwrap().settings.alpha);
+        assert!(parse(&["--alpha"]).unwrap().settings.alpha);
+        assert!(!parse(&[]).unwrap().settings.adaptive);
+        assert!(parse(&["--adaptive"]).unwrap().settings.adaptive);
+        // The test settings are deterministic already
+        let defaults = RenderSettings { deterministic: false, ..test_settings(8, 8, 1) };
+        assert!(!Options::parse(Vec::new(), defaults).unwrap().settings.deterministic);
+        assert!(Options::parse(vec!["--deterministic".to_string()], defaults).unwrap().settings.deterministic);
+    }
+
+    #[test]
+    fn flags_override_the_defaults() {
+        let options = parse(&["--width", "320", "--height", "200", "--samples", "64", "--depth", "12", "--threads", "3", "--output", "out/frame.png"]).unwrap();
+        let settings = options.settings;
+        assert_eq!((settings.image_width, settings.image_height), (320, 200));
+        assert_eq!((settings.samples_per_pixel, settings.max_depth, settings.n_threads), (64, 12, 3));
+        assert_eq!(options.output, "out/frame.png");
+        assert!(!options.help);
+
+        // Anything not given keeps its default
+        let options = parse(&["--depth", "0"]).unwrap();
+        assert_eq!((options.settings.image_width, options.settings.samples_per_pixel, options.settings.max_depth), (8, 1, 0));
+        assert_eq!(options.output, "render.png");
+        assert_eq!(options.aggregate, AggregateKind::Bvh);
+        assert_eq!(options.camera, Option::None);
+        assert_eq!(options.mesh, Option::None);
+        assert_eq!(options.bake_ao, Option::None);
+        assert_eq!(parse(&["--bake-ao", "64"]).unwrap().bake_ao, Option::from(64));
+        assert_eq!(parse(&["--mesh", "models/bunny.obj"]).unwrap().mesh.as_deref(), Option::from("models/bunny.obj"));
+        assert_eq!(options.scene, "cornell");
+        assert_eq!(options.environment, Option::None);
+        assert_eq!(parse(&["--environment", "equirect:hdri/park.png"]).unwrap().environment, Option::from(EnvironmentKind::Equirect("hdri/park.png".to_string())));
+        assert_eq!(parse(&["--scene", "perlin"]).unwrap().scene, "perlin");
+        assert_eq!(parse(&["--camera", "views/top.scene"]).unwrap().camera.as_deref(), Option::from("views/top.scene"));
+        assert_eq!(parse(&["--accel", "grid"]).unwrap().aggregate, AggregateKind::Grid);
+        assert!(parse(&[]).unwrap().settings.sampling == SamplingMode::Stratified);
+        assert!(parse(&["--sampling", "random"]).unwrap().settings.sampling == SamplingMode::Random);
+        assert!(parse(&[]).unwrap().settings.color_space == ColorSpace::Srgb);
+        assert!(parse(&["--color-space", "acescg"]).unwrap().settings.color_space == ColorSpace::AcesCg);
+        let settings = parse(&["--tonemap", "aces", "--exposure", "2.5"]).unwrap().settings;
+        assert!(settings.tone_map == ToneMap::AcesFilmic && settings.exposure == 2.5);
+        assert!(parse(&["--tonemap", "reinhard"]).unwrap().settings.tone_map == ToneMap::Reinhard);
+        assert!(parse(&[]).unwrap().settings.tone_map == ToneMap::None);
+        assert!(parse(&[]).unwrap().settings.gamma == Gamma::DEFAULT);
+        assert!(parse(&["--gamma", "srgb"]).unwrap().settings.gamma == Gamma::Srgb);
+        assert!(parse(&["--gamma", "2.2"]).unwrap().settings.gamma == Gamma::Power(2.2));
+        assert!(parse(&[]).unwrap().settings.russian_roulette.is_none());
+        let settings = parse(&["--roulette", "luminance", "--roulette-after", "5"]).unwrap().settings;
+        assert!(matches!(settings.russian_roulette, Some(RrHeuristic::Luminance)) && settings.rr_min_bounces == 5);
+        assert!(matches!(parse(&["--roulette", "max-channel"]).unwrap().settings.russian_roulette, Some(RrHeuristic::MaxChannel)));
+        assert!(parse(&[]).unwrap().settings.clamp_schedule.is_none());
+        let schedule = parse(&["--clamp", "falloff"]).unwrap().settings.clamp_schedule.unwrap();
+        assert_eq!((schedule(0), schedule(4)), (falloff_clamp(0), falloff_clamp(4)));
+        assert!(parse(&[]).unwrap().settings.fog.is_none());
+        let fog = parse(&["--fog", "0.25,0.1,0.2,0.3"]).unwrap().settings.fog.unwrap();
+        assert_eq!((fog.density, fog.color), (0.25, Color { r: 0.1, g: 0.2, b: 0.3 }));
+        assert_eq!(parse(&["--fog", "0.5"]).unwrap().settings.fog.unwrap().density, 0.5);
+        assert_eq!(parse(&[]).unwrap().settings.deep_slices, 0);
+        assert_eq!(parse(&["--deep", "4"]).unwrap().settings.deep_slices, 4);
+        assert_eq!(parse(&[]).unwrap().settings.render_layer_mask, u32::MAX);
+        assert_eq!(parse(&["--layers", "0,2"]).unwrap().settings.render_layer_mask, 0b101);
+        assert!(parse(&[]).unwrap().settings.adaptive_threshold.is_none());
+        let threshold = parse(&["--max-error", "0.05"]).unwrap().settings.adaptive_threshold.unwrap();
+        assert_eq!((threshold.max_error, threshold.min_samples), (0.05, 16));
+        assert!(parse(&["-h"]).unwrap().help);
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        for (args, message) in [
+            (&["--width", "0"][..], "--width must be at least 1"),
+            (&["--samples", "0"][..], "--samples must be at least 1"),
+            (&["--threads", "0"][..], "--threads must be at least 1"),
+            (&["--height", "tall"][..], "--height expects a whole number, got tall"),
+            (&["--width", "-5"][..], "--width expects a whole number, got -5"),
+            (&["--output"][..], "--output needs a value"),
+            (&["--camera"][..], "--camera needs a value"),
+            (&["--mesh"][..], "--mesh needs a value"),
+            (&["--bake-ao", "0"][..], "--bake-ao must be at least 1"),
+            (&["--environment", "sunset"][..], "--environment expects black, sky, equirect:PATH or cubemap: and six face paths, got sunset"),
+            (&["--scene", "teapot"][..], "--scene expects cornell, rtweekend, checkered, perlin, materials, bubbles, cloud, displaced, soft, transformed or tinted, got teapot"),
+            (&["--fast"][..], "unknown option --fast"),
+            (&["--width", "64", "--fast"][..], "unknown option --fast"),
+            (&["--fast", "yes"][..], "unknown option --fast"),
+            (&["--accel", "octree"][..], "--accel expects bvh, grid or list, got octree"),
+            (&["--sampling", "sobol"][..], "--sampling expects stratified or random, got sobol"),
+            (&["--color-space", "rec2020"][..], "--color-space expects srgb or acescg, got rec2020"),
+            (&["--tonemap", "filmic"][..], "--tonemap expects none, reinhard or aces, got filmic"),
+            (&["--exposure", "bright"][..], "--exposure expects a number, got bright"),
+            (&["--exposure", "inf"][..], "--exposure expects a number, got inf"),
+            (&["--exposure", "0"][..], "--exposure must be above 0"),
+            (&["--gamma", "-1"][..], "--gamma expects linear, srgb or a power above 0, got -1"),
+            (&["--roulette", "on"][..], "--roulette expects max-channel or luminance, got on"),
+            (&["--roulette-after", "two"][..], "--roulette-after expects a whole number, got two"),
+            (&["--clamp", "hard"][..], "--clamp expects falloff, got hard"),
+            (&["--fog", "thick"][..], "--fog expects a number, got thick"),
+            (&["--fog", "0.1,1,1"][..], "--fog expects a density and optionally R,G,B, got 0.1,1,1"),
+            (&["--fog", "-0.1"][..], "--fog density must not be negative"),
+            (&["--burn-in", "yes"][..], "unknown option yes"),
+            (&["--deep", "1.5"][..], "--deep expects a whole number, got 1.5"),
+            (&["--layers", "1,32"][..], "--layers expects layer numbers from 0 to 31, got 32"),
+            (&["--layers", ""][..], "--layers expects layer numbers from 0 to 31, got "),
+            (&["--max-error", "0"][..], "--max-error must be above 0"),
+        ] {
+            assert_eq!(parse(args).err().as_deref(), Option::from(message), "for {:?}", args);
+        }
+    }
+
+    #[test]
+    fn other_outputs_go_next_to_the_image() {
+        let options = parse(&["--output", "out/frame.png"]).unwrap();
+        assert_eq!(options.sibling("_normals", "png"), "out/frame_normals.png");
+        assert_eq!(options.sibling("_deep", "txt"), "out/frame_deep.txt");
+        assert_eq!(parse(&[]).unwrap().sibling("_preview", "png"), "render_preview.png");
+    }
+}
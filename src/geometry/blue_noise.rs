@@ -0,0 +1,150 @@
+use std::sync::OnceLock;
+
+// Side of the tileable dither mask
+pub const SIZE: usize = 64;
+const CELLS: usize = SIZE * SIZE;
+// Width of the gaussian the void-and-cluster energy is filtered with, 1.5 as in Ulichney's paper
+const SIGMA: f32 = 1.5;
+// Share of cells set in the starting pattern
+const INITIAL_DENSITY: f32 = 0.1;
+
+// Threshold in (0, 1) for pixel (x, y), tiling every SIZE pixels. Neighbouring thresholds are
+// far apart, so dithering with them leaves only fine, even grain instead of a visible pattern.
+pub fn threshold(x: u32, y: u32) -> f32 {
+    static RANKS: OnceLock<Vec<u16>> = OnceLock::new();
+    let ranks = RANKS.get_or_init(void_and_cluster);
+    let rank = ranks[(y as usize % SIZE) * SIZE + x as usize % SIZE];
+    return (rank as f32 + 0.5) / CELLS as f32;
+}
+
+// Ranks every cell of the mask by the void-and-cluster method (Ulichney 1993): starting from a
+// pattern whose set cells are spread evenly, cells are ranked by removing the most crowded set
+// cell one at a time, then by filling the emptiest unset cell one at a time. Takes a fraction
+// of a second, so it runs once on first use rather than being stored.
+fn void_and_cluster() -> Vec<u16> {
+    let kernel = gaussian_kernel();
+    let mut pattern = vec![false; CELLS];
+    let mut energy = vec![0.0; CELLS];
+    let toggle = |pattern: &mut [bool], energy: &mut [f32], cell: usize| {
+        let sign = if pattern[cell] { -1.0 } else { 1.0 };
+        pattern[cell] = !pattern[cell];
+        let (cx, cy) = (cell % SIZE, cell / SIZE);
+        for (other, e) in energy.iter_mut().enumerate() {
+            let dx = (other % SIZE + SIZE - cx) % SIZE;
+            let dy = (other / SIZE + SIZE - cy) % SIZE;
+            *e += sign * kernel[dy * SIZE + dx];
+        }
+    };
+
+    // A fixed random start, so the mask is the same on every run
+    let mut state: u32 = 0x2545_F491;
+    let n_initial = (INITIAL_DENSITY * CELLS as f32) as usize;
+    let mut placed = 0;
+    while placed < n_initial {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let cell = state as usize % CELLS;
+        if !pattern[cell] {
+            toggle(&mut pattern, &mut energy, cell);
+            placed += 1;
+        }
+    }
+
+    // Move the most crowded set cell into the emptiest gap until that stops changing anything
+    loop {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        let void = largest_void(&pattern, &energy);
+        if void == cluster {
+            toggle(&mut pattern, &mut energy, cluster);
+            break;
+        }
+        toggle(&mut pattern, &mut energy, void);
+    }
+
+    let mut ranks = vec![0u16; CELLS];
+    let (initial_pattern, initial_energy) = (pattern.clone(), energy.clone());
+    for rank in (0..n_initial).rev() {
+        let cluster = tightest_cluster(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, cluster);
+        ranks[cluster] = rank as u16;
+    }
+
+    // Filling the emptiest unset cell is the same as taking the most crowded unset cell in
+    // the inverted pattern, since the two energies add up to a constant, so one loop covers
+    // both the second and third phases of the paper
+    pattern = initial_pattern;
+    energy = initial_energy;
+    for rank in n_initial..CELLS {
+        let void = largest_void(&pattern, &energy);
+        toggle(&mut pattern, &mut energy, void);
+        ranks[void] = rank as u16;
+    }
+    return ranks;
+}
+
+// Gaussian falloff by wrapped offset, so the mask tiles without seams
+fn gaussian_kernel() -> Vec<f32> {
+    let mut kernel = vec![0.0; CELLS];
+    for dy in 0..SIZE {
+        for dx in 0..SIZE {
+            let wx = dx.min(SIZE - dx) as f32;
+            let wy = dy.min(SIZE - dy) as f32;
+            kernel[dy * SIZE + dx] = (-(wx * wx + wy * wy) / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+    return kernel;
+}
+
+fn tightest_cluster(pattern: &[bool], energy: &[f32]) -> usize {
+    return (0..CELLS).filter(|&cell| pattern[cell])
+        .fold(None, |best: Option<usize>, cell| match best {
+            Some(b) if energy[b] >= energy[cell] => Option::from(b),
+            _ => Option::from(cell),
+        })
+        .unwrap();
+}
+
+fn largest_void(pattern: &[bool], energy: &[f32]) -> usize {
+    return (0..CELLS).filter(|&cell| !pattern[cell])
+        .fold(None, |best: Option<usize>, cell| match best {
+            Some(b) if energy[b] <= energy[cell] => Option::from(b),
+            _ => Option::from(cell),
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_threshold_appears_once_per_tile() {
+        let mut seen = vec![false; CELLS];
+        for y in 0..SIZE as u32 {
+            for x in 0..SIZE as u32 {
+                let t = threshold(x, y);
+                assert!(t > 0.0 && t < 1.0);
+                let rank = (t * CELLS as f32) as usize;
+                assert!(!seen[rank]);
+                seen[rank] = true;
+            }
+        }
+        assert_eq!(threshold(3, 5), threshold(3 + SIZE as u32, 5 + 2 * SIZE as u32));
+    }
+
+    #[test]
+    fn small_windows_average_out() {
+        // Blue noise has almost no low-frequency energy, so every 4x4 window averages close
+        // to one half. A tile of white noise strays past 0.25 somewhere.
+        let mut worst: f32 = 0.0;
+        for y in 0..SIZE as u32 {
+            for x in 0..SIZE as u32 {
+                let mean: f32 = (0..16).map(|k| threshold(x + k % 4, y + k / 4)).sum::<f32>() / 16.0;
+                worst = worst.max((mean - 0.5).abs());
+            }
+        }
+        assert!(worst < 0.15, "worst 4x4 window mean is off by {}", worst);
+    }
+}
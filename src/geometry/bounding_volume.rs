@@ -28,18 +28,73 @@ impl AxisAlignedBoundingBox {
         return true;
     }
 
+    // Same slab test as hit, returning where the ray enters and leaves the box within [t_min, t_max]
+    pub fn hit_interval(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<(f32, f32)> {
+        let mut t_min = t_min;
+        let mut t_max = t_max;
+        for a in 0..3 {
+            let mut t0 = (self.minimum[a] - ray.origin[a]) / ray.direction[a];
+            let mut t1 = (self.maximum[a] - ray.origin[a]) / ray.direction[a];
+
+            if ray.direction[a] < 0.0 {
+                swap(&mut t0, &mut t1);
+            }
+
+            if t0 > t_min { t_min = t0; }
+            if t1 < t_max { t_max = t1; }
+            if t_max <= t_min { return Option::None; }
+        }
+        return Option::from((t_min, t_max));
+    }
+
+    pub fn contains(&self, point: Point) -> bool {
+        return (0..3).all(|a| point[a] >= self.minimum[a] && point[a] <= self.maximum[a]);
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let extent = self.maximum - self.minimum;
+        return 2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x);
+    }
+
     pub fn surrounding_box(box0: Self, box1: Self) -> Self {
         Self {
-            minimum: Point {
-                x: box0.minimum.x.min(box1.minimum.x),
-                y: box0.minimum.y.min(box1.minimum.y),
-                z: box0.minimum.z.min(box1.minimum.z),
-            },
-            maximum: Point {
-                x: box0.maximum.x.max(box1.maximum.x),
-                y: box0.maximum.y.max(box1.maximum.y),
-                z: box0.maximum.z.max(box1.maximum.z),
-            },
+            minimum: box0.minimum.min(box1.minimum),
+            maximum: box0.maximum.max(box1.maximum),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::ray::RayKind;
+    use crate::geometry::vector::Vector3;
+
+    fn unit_box() -> AxisAlignedBoundingBox {
+        return AxisAlignedBoundingBox { minimum: Point::ORIGIN, maximum: Point { x: 1.0, y: 1.0, z: 1.0 } };
+    }
+
+    #[test]
+    fn contains_includes_the_faces() {
+        let b = unit_box();
+        assert!(b.contains(Point { x: 0.5, y: 0.5, z: 0.5 }));
+        assert!(b.contains(Point { x: 0.0, y: 1.0, z: 0.3 }));
+        assert!(!b.contains(Point { x: 0.5, y: 1.01, z: 0.5 }));
+        assert!(!b.contains(Point { x: -0.01, y: 0.5, z: 0.5 }));
+    }
+
+    #[test]
+    fn rays_from_inside_hit_in_every_direction() {
+        let b = unit_box();
+        let origin = Point { x: 0.25, y: 0.5, z: 0.75 };
+        for k in 0..6 {
+            let mut direction = Vector3::ORIGIN;
+            direction[k / 2] = if k % 2 == 0 { 1.0 } else { -1.0 };
+            let ray = Ray { origin, direction, time: 0.0, kind: RayKind::Camera };
+            assert!(b.hit(ray, 0.001, f32::MAX));
+            let (t_enter, t_exit) = b.hit_interval(ray, 0.001, f32::MAX).unwrap();
+            assert_eq!(t_enter, 0.001);
+            assert!((ray.at_distance(t_exit)[k / 2] - if k % 2 == 0 { 1.0 } else { 0.0 }).abs() < 1e-6);
         }
     }
 }
@@ -1,30 +1,120 @@
+use std::iter::{Product, Sum};
 use std::ops::{Add, AddAssign, Mul, MulAssign};
 
 use image::Rgb;
+use rand::Rng;
 
-use crate::utils::random_f32;
+use crate::geometry::blue_noise;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color {
     pub r: f32,
     pub g: f32,
     pub b: f32,
 }
 
+// Curve from linear values to the encoded ones written to image files
+#[derive(Clone, Copy, PartialEq)]
+pub enum Gamma {
+    // Values written as they are, for data passes and linear workflows
+    Linear,
+    // v^(1/g), with 2 being the square root the renderer has always used
+    Power(f32),
+    // The piecewise sRGB curve, the inverse of Color::from_srgb8
+    Srgb,
+}
+
+impl Gamma {
+    pub const DEFAULT: Gamma = Gamma::Power(2.0);
+
+    // linear, srgb or a power such as 2.2
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "linear" => Option::from(Gamma::Linear),
+            "srgb" => Option::from(Gamma::Srgb),
+            _ => match name.parse::<f32>() {
+                Ok(g) if g.is_finite() && g > 0.0 => Option::from(Gamma::Power(g)),
+                _ => Option::None,
+            },
+        };
+    }
+
+    pub fn encode(self, v: f32) -> f32 {
+        match self {
+            Gamma::Linear => v,
+            // sqrt keeps the default bit-identical to before
+            Gamma::Power(g) if g == 2.0 => v.sqrt(),
+            Gamma::Power(g) => v.powf(1.0 / g),
+            Gamma::Srgb => if v <= 0.0031308 { 12.92 * v } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 },
+        }
+    }
+}
+
 impl Color {
     pub fn get_pixel(&self, spp: u32) -> image::Rgb<u8> {
-        let ir = (255.999 * (self.r / spp as f32).sqrt().clamp(0.0, 1.0)) as u8;
-        let ig = (255.999 * (self.g / spp as f32).sqrt().clamp(0.0, 1.0)) as u8;
-        let ib = (255.999 * (self.b / spp as f32).sqrt().clamp(0.0, 1.0)) as u8;
+        self.to_pixel(spp, Gamma::DEFAULT)
+    }
+
+    // Averages spp summed samples and encodes them with gamma
+    pub fn to_pixel(&self, spp: u32, gamma: Gamma) -> image::Rgb<u8> {
+        let c = self.map(|c| 255.999 * gamma.encode(c / spp as f32).clamp(0.0, 1.0));
+        return Rgb([c.r as u8, c.g as u8, c.b as u8]);
+    }
+
+    pub fn get_dithered_pixel(&self, spp: u32, x: u32, y: u32) -> image::Rgb<u8> {
+        self.to_dithered_pixel(spp, Gamma::DEFAULT, x, y)
+    }
 
-        return Rgb([ir, ig, ib]);
+    // Like to_pixel, but offsets each channel by a sub-LSB blue-noise threshold before truncating
+    // to break up banding
+    pub fn to_dithered_pixel(&self, spp: u32, gamma: Gamma, x: u32, y: u32) -> image::Rgb<u8> {
+        let threshold = blue_noise::threshold(x, y);
+        let c = self.map(|c| 255.0 * gamma.encode(c / spp as f32).clamp(0.0, 1.0) + threshold);
+        return Rgb([c.r as u8, c.g as u8, c.b as u8]);
+    }
+
+    pub fn get_pixel16(&self, spp: u32) -> image::Rgb<u16> {
+        self.to_pixel16(spp, Gamma::DEFAULT)
+    }
+
+    // 16-bit version of to_pixel, fine enough that smooth gradients don't band
+    pub fn to_pixel16(&self, spp: u32, gamma: Gamma) -> image::Rgb<u16> {
+        let c = self.map(|c| 65535.999 * gamma.encode(c / spp as f32).clamp(0.0, 1.0));
+        return Rgb([c.r as u16, c.g as u16, c.b as u16]);
+    }
+
+    // Linear color of an 8-bit sRGB-encoded pixel, as stored in PNG and JPEG files
+    pub fn from_srgb8(pixel: Rgb<u8>) -> Self {
+        let decode = |v: u8| {
+            let c = v as f32 / 255.0;
+            if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        Self { r: decode(pixel[0]), g: decode(pixel[1]), b: decode(pixel[2]) }
+    }
+
+    // Rec. 709 relative luminance
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        Self { r: f(self.r), g: f(self.g), b: f(self.b) }
+    }
+
+    // Takes the generator explicitly so seeded scenes are reproducible
+    pub fn random(rng: &mut impl Rng) -> Self {
+        Self {
+            r: rng.gen(),
+            g: rng.gen(),
+            b: rng.gen(),
+        }
     }
 
-    pub fn random() -> Self {
+    pub fn random_range(rng: &mut impl Rng, min: f32, max: f32) -> Self {
         Self {
-            r: random_f32(),
-            g: random_f32(),
-            b: random_f32(),
+            r: rng.gen_range(min..max),
+            g: rng.gen_range(min..max),
+            b: rng.gen_range(min..max),
         }
     }
 
@@ -84,3 +174,113 @@ impl Mul<Color> for f32 {
         Self::Output { r: self * _rhs.r, g: self * _rhs.g, b: self * _rhs.b }
     }
 }
+
+impl Sum for Color {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Color::BLACK, |acc, c| acc + c)
+    }
+}
+
+// Channel-wise, e.g. the throughput of a chain of attenuations
+impl Product for Color {
+    fn product<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Color::WHITE, |acc, c| acc * c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    // Row y of a ramp from 50.3 to 52.7 levels across 256 pixels, so it only steps twice
+    fn ramp(dithered: bool, y: u32) -> Vec<u8> {
+        return (0..256).map(|x| {
+            let v = (50.3 + 2.4 * x as f32 / 256.0) / 255.0;
+            let c = Color { r: v, g: v, b: v };
+            let pixel = if dithered { c.to_dithered_pixel(1, Gamma::Linear, x, y) } else { c.to_pixel(1, Gamma::Linear) };
+            pixel[0]
+        }).collect();
+    }
+
+    // Longest stretch of equal neighbouring values
+    fn longest_plateau(row: &[u8]) -> usize {
+        let (mut longest, mut run) = (1, 1);
+        for x in 1..row.len() {
+            run = if row[x] == row[x - 1] { run + 1 } else { 1 };
+            longest = longest.max(run);
+        }
+        return longest;
+    }
+
+    #[test]
+    fn colors_sum_and_multiply_like_the_operators() {
+        let colors = [
+            Color { r: 0.5, g: 1.0, b: 0.25 },
+            Color { r: 0.25, g: 0.5, b: 2.0 },
+            Color { r: 2.0, g: 0.25, b: 0.5 },
+        ];
+        assert_eq!(colors.iter().copied().sum::<Color>(), colors[0] + colors[1] + colors[2]);
+        assert_eq!(colors.iter().copied().product::<Color>(), colors[0] * colors[1] * colors[2]);
+        assert_eq!([].iter().copied().sum::<Color>(), Color::BLACK);
+        assert_eq!([].iter().copied().product::<Color>(), Color::WHITE);
+    }
+
+    #[test]
+    fn map_applies_to_every_channel() {
+        let c = Color { r: 0.25, g: 0.04, b: 0.81 };
+        assert_eq!(c.map(|c| c.sqrt()), Color { r: 0.5, g: 0.2, b: 0.9 });
+        assert_eq!(c.map(|c| 2.0 * c), 2.0 * c);
+    }
+
+    #[test]
+    fn equally_seeded_generators_give_the_same_colors() {
+        let (mut a, mut b) = (StdRng::seed_from_u64(11), StdRng::seed_from_u64(11));
+        for _ in 0..100 {
+            assert_eq!(Color::random(&mut a), Color::random(&mut b));
+            let c = Color::random_range(&mut a, 0.5, 1.0);
+            assert_eq!(c, Color::random_range(&mut b, 0.5, 1.0));
+            assert!([c.r, c.g, c.b].iter().all(|v| (0.5..1.0).contains(v)));
+        }
+        assert_ne!(Color::random(&mut a), Color::random(&mut StdRng::seed_from_u64(12)));
+    }
+
+    #[test]
+    fn dithering_breaks_up_plateaus() {
+        for y in 0..16 {
+            // Plain rounding holds each level for over a hundred pixels
+            assert!(longest_plateau(&ramp(false, y)) > 100);
+            // Dithered values only hold still right where the ramp crosses a whole level
+            let dithered = ramp(true, y);
+            assert!(longest_plateau(&dithered) < 48);
+            // and still follow the ramp when averaged over a few pixels
+            for (k, window) in dithered.chunks(32).enumerate() {
+                let mean = window.iter().map(|&v| v as f32).sum::<f32>() / 32.0;
+                let expected = 50.3 + 2.4 * (32.0 * k as f32 + 15.5) / 256.0;
+                assert!((mean - expected).abs() < 0.2, "window {} averages {}, expected {}", k, mean, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn mid_grey_encodes_by_the_chosen_gamma() {
+        // 18% grey summed over 4 samples
+        let grey = Color { r: 0.72, g: 0.72, b: 0.72 };
+        assert_eq!(grey.to_pixel(4, Gamma::Power(2.0)), Rgb([108, 108, 108]));
+        assert_eq!(grey.to_pixel(4, Gamma::Power(2.2)), Rgb([117, 117, 117]));
+        assert_eq!(grey.to_pixel(4, Gamma::Srgb), Rgb([118, 118, 118]));
+        assert_eq!(grey.to_pixel(4, Gamma::Linear), Rgb([46, 46, 46]));
+        // The old methods keep the square root
+        assert_eq!(grey.get_pixel(4), grey.to_pixel(4, Gamma::Power(2.0)));
+        assert_eq!(grey.get_pixel16(4), grey.to_pixel16(4, Gamma::DEFAULT));
+        assert!((grey.to_pixel16(4, Gamma::Power(2.2))[0] as i32 - 30058).abs() <= 1);
+
+        // sRGB encoding undoes from_srgb8 for every level
+        for level in 0..=255u8 {
+            let decoded = Color::from_srgb8(Rgb([level, level, level]));
+            assert_eq!((255.0 * Gamma::Srgb.encode(decoded.r)).round() as u8, level);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::geometry::color::Color;
+
+// Linear sRGB (D65) to ACEScg (AP1, D60), Bradford adapted
+const SRGB_TO_ACESCG: [[f32; 3]; 3] = [
+    [0.6131, 0.3395, 0.0474],
+    [0.0702, 0.9164, 0.0134],
+    [0.0206, 0.1096, 0.8698],
+];
+
+const ACESCG_TO_SRGB: [[f32; 3]; 3] = [
+    [1.7049, -0.6217, -0.0833],
+    [-0.1301, 1.1407, -0.0106],
+    [-0.0240, -0.1290, 1.1530],
+];
+
+// Stephen Hill's fit of the ACES RRT + sRGB ODT, applied between these two matrices
+const RRT_INPUT: [[f32; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+const ODT_OUTPUT: [[f32; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+fn transform(m: &[[f32; 3]; 3], c: Color) -> Color {
+    Color {
+        r: m[0][0] * c.r + m[0][1] * c.g + m[0][2] * c.b,
+        g: m[1][0] * c.r + m[1][1] * c.g + m[1][2] * c.b,
+        b: m[2][0] * c.r + m[2][1] * c.g + m[2][2] * c.b,
+    }
+}
+
+fn rrt_and_odt_fit(v: f32) -> f32 {
+    // The curve is flat long before this, and squaring much larger values overflows to NaN
+    let v = v.min(1e6);
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+    a / b
+}
+
+// Stephen Hill's fitted ACES tone curve on a linear sRGB color, output clamped to [0, 1]
+pub fn aces_fitted(color: Color) -> Color {
+    let rrt = transform(&RRT_INPUT, color).map(rrt_and_odt_fit);
+    return transform(&ODT_OUTPUT, rrt).map(|c| c.clamp(0.0, 1.0));
+}
+
+// Space light transport is computed in. Scene colors are authored in linear sRGB.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ColorSpace {
+    Srgb,
+    AcesCg,
+}
+
+impl ColorSpace {
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "srgb" => Option::from(ColorSpace::Srgb),
+            "acescg" => Option::from(ColorSpace::AcesCg),
+            _ => Option::None,
+        };
+    }
+
+    pub fn working_from_srgb(self, color: Color) -> Color {
+        return match self {
+            ColorSpace::Srgb => color,
+            ColorSpace::AcesCg => transform(&SRGB_TO_ACESCG, color),
+        };
+    }
+
+    pub fn to_srgb(self, color: Color) -> Color {
+        return match self {
+            ColorSpace::Srgb => color,
+            ColorSpace::AcesCg => transform(&ACESCG_TO_SRGB, color),
+        };
+    }
+
+    // Linear sRGB ready for gamma encoding. ACEScg output goes through the RRT/ODT.
+    pub fn to_display(self, color: Color) -> Color {
+        return match self {
+            ColorSpace::Srgb => color,
+            ColorSpace::AcesCg => aces_fitted(self.to_srgb(color)),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::RenderSettings;
+    use crate::render::tests::{render_test_scene, test_settings};
+
+    fn assert_close(a: Color, b: Color, tolerance: f32) {
+        assert!((a.r - b.r).abs() < tolerance && (a.g - b.g).abs() < tolerance && (a.b - b.b).abs() < tolerance,
+                "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn srgb_white_round_trips_through_acescg() {
+        let white = ColorSpace::AcesCg.working_from_srgb(Color::WHITE);
+        // Both spaces put white at equal channels, D65 and D60 being adapted onto each other
+        assert_close(white, Color::WHITE, 1e-3);
+        assert_close(ColorSpace::AcesCg.to_srgb(white), Color::WHITE, 1e-3);
+
+        let orange = Color { r: 0.9, g: 0.4, b: 0.1 };
+        assert_close(ColorSpace::AcesCg.to_srgb(ColorSpace::AcesCg.working_from_srgb(orange)), orange, 1e-3);
+        assert_eq!(ColorSpace::Srgb.to_display(orange), orange);
+    }
+
+    #[test]
+    fn grey_scene_renders_the_same_in_either_space() {
+        let settings = test_settings(16, 16, 4);
+        let srgb = render_test_scene(&settings);
+        let acescg = render_test_scene(&RenderSettings { color_space: ColorSpace::AcesCg, ..settings });
+        for (a, b) in srgb.pixels.iter().zip(acescg.pixels.iter()) {
+            assert_close(ColorSpace::AcesCg.to_srgb(*b), *a, 1e-2 * a.luminance().max(1.0));
+        }
+    }
+}
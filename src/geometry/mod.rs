@@ -1,6 +1,10 @@
 pub mod vector;
 pub mod color;
+pub mod blue_noise;
 pub mod ray;
 pub mod bounding_volume;
 pub mod onb;
 pub mod pdf;
+pub mod color_space;
+pub mod transform;
+pub mod tonemap;
@@ -18,4 +18,36 @@ impl ONB {
         let u = w.cross(v);
         return Self { u, v, w };
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axes_are_orthonormal_for_any_normal() {
+        let normals = [
+            Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            // Either side of where the helper axis switches
+            Vector3 { x: 0.9001, y: 0.4358, z: 0.0 },
+            Vector3 { x: 0.8999, y: 0.4362, z: 0.0 },
+            Vector3 { x: -0.3, y: 0.5, z: 0.81 },
+            // Tiny and not unit length
+            Vector3 { x: 1e-6, y: 2e-6, z: -1e-6 },
+        ];
+        for n in normals {
+            let onb = ONB::build_from_w(n);
+            for axis in [onb.u, onb.v, onb.w] {
+                assert!((axis.length() - 1.0).abs() < 1e-6, "{:?} gives an axis of length {}", n, axis.length());
+            }
+            assert!(onb.u.dot(onb.v).abs() < 1e-6 && onb.v.dot(onb.w).abs() < 1e-6 && onb.w.dot(onb.u).abs() < 1e-6, "{:?}", n);
+            assert!((onb.w - n.direction()).length() < 1e-6);
+            // local maps the local axes onto them and keeps lengths
+            assert_eq!(onb.local(Vector3 { x: 0.0, y: 0.0, z: 1.0 }), onb.w);
+            let local = Vector3 { x: 0.2, y: -0.7, z: 0.4 };
+            assert!((onb.local(local).length() - local.length()).abs() < 1e-6);
+        }
+    }
+}
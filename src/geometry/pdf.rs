@@ -6,8 +6,12 @@ use crate::objects::hittable::Hittable;
 use crate::utils::{PI, random_f32};
 
 pub fn random_cosine_direction() -> Vector3 {
-    let r1 = random_f32();
-    let r2 = random_f32();
+    return cosine_direction((random_f32(), random_f32()));
+}
+
+// Cosine-weighted direction about +z from a point in [0, 1)^2
+pub fn cosine_direction(sample: (f32, f32)) -> Vector3 {
+    let (r1, r2) = sample;
     let z = (1.0 - r2).sqrt();
 
     let phi = 2.0 * PI * r1;
@@ -20,6 +24,12 @@ pub fn random_cosine_direction() -> Vector3 {
 pub trait PDF {
     fn value(&self, direction: Vector3) -> f32;
     fn generate(&self) -> Vector3;
+    // Like generate, but driven by a point in [0, 1)^2 from one of the sampler's dimensions.
+    // This is the hook for stratified or guided sample streams, pdfs that can't map a point
+    // fall back to generate.
+    fn generate_from(&self, sample: (f32, f32)) -> Vector3 {
+        return self.generate();
+    }
 }
 
 pub struct CosinePDF {
@@ -43,37 +53,136 @@ impl PDF for CosinePDF {
     fn generate(&self) -> Vector3 {
         return self.uvw.local(random_cosine_direction());
     }
+
+    fn generate_from(&self, sample: (f32, f32)) -> Vector3 {
+        return self.uvw.local(cosine_direction(sample));
+    }
 }
 
 pub struct HittablePDF {
     pub o: Point,
     pub ptr: Arc<dyn Hittable>,
+    // Ray offset from o when finding where a direction meets the object
+    pub epsilon: f32,
 }
 
 impl PDF for HittablePDF {
     fn value(&self, direction: Vector3) -> f32 {
-        return self.ptr.pdf_value(self.o, direction);
+        return self.ptr.pdf_value(self.o, direction, self.epsilon);
     }
 
     fn generate(&self) -> Vector3 {
         return self.ptr.random(self.o);
     }
+
+    fn generate_from(&self, sample: (f32, f32)) -> Vector3 {
+        return self.ptr.random_from(self.o, sample);
+    }
 }
 
-pub struct MixturePDF {
-    pub ptr: [Arc<dyn PDF>; 2],
+pub struct MixturePDF<'a> {
+    pub ptr: [&'a dyn PDF; 2],
+    // Probability of sampling from the first pdf
+    pub weight: f32,
 }
 
-impl PDF for MixturePDF {
+impl MixturePDF<'_> {
+    // Picks one of the pdfs as generate does, the chosen one drawing from its own sample.
+    // Also returns which pdf it was, 0 or 1.
+    pub fn generate_each(&self, samples: [(f32, f32); 2]) -> (Vector3, usize) {
+        let k = self.pick();
+        return (self.ptr[k].generate_from(samples[k]), k);
+    }
+
+    // As generate, also returning which pdf drew the direction
+    pub fn generate_with_index(&self) -> (Vector3, usize) {
+        let k = self.pick();
+        return (self.ptr[k].generate(), k);
+    }
+
+    fn pick(&self) -> usize {
+        return if random_f32() < self.weight { 0 } else { 1 };
+    }
+}
+
+impl PDF for MixturePDF<'_> {
     fn value(&self, direction: Vector3) -> f32 {
-        return 0.5 * (self.ptr[0].value(direction) + self.ptr[1].value(direction));
+        return self.weight * self.ptr[0].value(direction) + (1.0 - self.weight) * self.ptr[1].value(direction);
     }
 
     fn generate(&self) -> Vector3 {
-        return if random_f32() < 0.5 {
-            self.ptr[0].generate()
-        } else {
-            self.ptr[1].generate()
+        return self.generate_with_index().0;
+    }
+
+    fn generate_from(&self, sample: (f32, f32)) -> Vector3 {
+        return self.generate_each([sample, sample]).0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::materials::light::DiffuseLight;
+    use crate::objects::rectangle::XZRect;
+    use crate::objects::sphere::Sphere;
+    use crate::utils::{random_f32, seed_thread_rng};
+
+    #[test]
+    fn generate_from_matches_generate_for_the_same_numbers() {
+        let pdf = CosinePDF::new(Vector3 { x: 0.3, y: 1.0, z: -0.2 }.direction());
+        seed_thread_rng(Option::from(9));
+        let generated = pdf.generate();
+        seed_thread_rng(Option::from(9));
+        let sample = (random_f32(), random_f32());
+        assert_eq!(pdf.generate_from(sample), generated);
+    }
+
+    #[test]
+    fn light_samples_map_onto_the_panel() {
+        let light = HittablePDF {
+            o: Point::ORIGIN,
+            ptr: Arc::new(XZRect { x: (-1.0, 3.0), z: (2.0, 4.0), k: 5.0, material: Arc::new(DiffuseLight::new(Color::WHITE)) }),
+            epsilon: 0.001,
+        };
+        assert_eq!(light.generate_from((0.0, 0.0)), Vector3 { x: -1.0, y: 5.0, z: 2.0 });
+        assert_eq!(light.generate_from((0.5, 0.5)), Vector3 { x: 1.0, y: 5.0, z: 3.0 });
+    }
+
+    #[test]
+    fn every_pdf_integrates_to_one_over_the_sphere() {
+        // Mean value over uniform directions times the 4 pi steradians they cover
+        let mut rng = StdRng::seed_from_u64(279);
+        let mut integrate = |pdf: &dyn PDF| {
+            let n = 200_000;
+            return 4.0 * PI * (0..n).map(|_| pdf.value(Vector3::random_unit_vector_with(&mut rng))).sum::<f32>() / n as f32;
         };
+        let material = Arc::new(DiffuseLight::new(Color::WHITE));
+        let cosine = CosinePDF::new(Vector3 { x: 0.3, y: 1.0, z: -0.2 }.direction());
+        let sphere = HittablePDF {
+            o: Point::ORIGIN,
+            ptr: Arc::new(Sphere { center: Point { x: 0.0, y: 0.0, z: -3.0 }, radius: 1.5, material: material.clone() }),
+            epsilon: 0.001,
+        };
+        let panel = HittablePDF {
+            o: Point::ORIGIN,
+            ptr: Arc::new(XZRect { x: (-1.0, 1.0), z: (-1.0, 1.0), k: 1.0, material }),
+            epsilon: 0.001,
+        };
+        let mixture = MixturePDF { ptr: [&cosine, &sphere], weight: 0.5 };
+        for (name, pdf) in [("cosine", &cosine as &dyn PDF), ("sphere", &sphere), ("panel", &panel), ("mixture", &mixture)] {
+            let total = integrate(pdf);
+            assert!((total - 1.0).abs() < 0.02, "the {} pdf integrates to {}", name, total);
+        }
+
+        // and each only generates directions it gives weight to
+        seed_thread_rng(Option::from(279));
+        for pdf in [&cosine as &dyn PDF, &sphere, &panel, &mixture] {
+            assert!((0..1000).all(|_| pdf.value(pdf.generate().direction()) > 0.0));
+        }
+        seed_thread_rng(Option::None);
     }
 }
@@ -1,14 +1,24 @@
 use super::vector::{Point, Vector3};
 
+// What a ray is used for, so objects can choose which kinds of rays see them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RayKind {
+    Camera,
+    Shadow,
+    // Any bounce off or through a surface, including refraction and volume scattering
+    Reflection,
+}
+
 #[derive(Clone, Copy)]
 pub struct Ray {
     pub origin: Point,
     pub direction: Vector3,
     pub time: f32,
+    pub kind: RayKind,
 }
 
 impl Ray {
     pub fn at_distance(&self, distance: f32) -> Point {
         self.origin + distance * self.direction
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,89 @@
+use crate::geometry::color::Color;
+use crate::geometry::color_space::aces_fitted;
+
+// Compresses unbounded linear radiance into [0, 1] before gamma encoding, so bright lights
+// roll off instead of clipping. Exposure scales the color first.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ToneMap {
+    // Clip at 1, the old behaviour
+    None,
+    Reinhard,
+    AcesFilmic,
+}
+
+impl ToneMap {
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "none" => Option::from(ToneMap::None),
+            "reinhard" => Option::from(ToneMap::Reinhard),
+            "aces" => Option::from(ToneMap::AcesFilmic),
+            _ => Option::None,
+        };
+    }
+
+    pub fn apply(self, color: Color, exposure: f32) -> Color {
+        let exposed = exposure * color;
+        return match self {
+            ToneMap::None => exposed,
+            ToneMap::Reinhard => reinhard(exposed),
+            ToneMap::AcesFilmic => aces_filmic(exposed),
+        };
+    }
+}
+
+// c / (1 + c) per channel, close to linear for dark values and never reaching 1
+pub fn reinhard(color: Color) -> Color {
+    color.map(|c| c.max(0.0) / (1.0 + c.max(0.0)))
+}
+
+// The RRT/ODT fit ACEScg output already goes through, applied to linear sRGB
+pub fn aces_filmic(color: Color) -> Color {
+    aces_fitted(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grey(c: f32) -> Color {
+        return Color { r: c, g: c, b: c };
+    }
+
+    fn in_unit_range(color: Color) -> bool {
+        return [color.r, color.g, color.b].iter().all(|c| (0.0..=1.0).contains(c));
+    }
+
+    #[test]
+    fn bright_input_stays_in_range() {
+        for value in [1.0, 10.0, 1e3, 1e6, f32::MAX] {
+            for color in [grey(value), Color { r: value, g: 0.0, b: 0.5 }] {
+                assert!(in_unit_range(reinhard(color)), "reinhard({}) = {:?}", value, reinhard(color));
+                assert!(in_unit_range(aces_filmic(color)), "aces_filmic({}) = {:?}", value, aces_filmic(color));
+            }
+        }
+        // Brighter still maps brighter, only ever closer to white
+        assert!(reinhard(grey(100.0)).r > reinhard(grey(10.0)).r && reinhard(grey(100.0)).r < 1.0);
+        assert!(aces_filmic(grey(4.0)).r > aces_filmic(grey(1.0)).r);
+    }
+
+    #[test]
+    fn dark_input_is_nearly_linear() {
+        for value in [0.001, 0.01, 0.05] {
+            let mapped = reinhard(grey(value)).r;
+            assert!((mapped - value).abs() < value * value + 1e-7, "reinhard({}) = {}", value, mapped);
+        }
+        // The filmic curve has a toe instead, darker than linear and still rising
+        let toe: Vec<f32> = [0.01, 0.05, 0.1, 0.2, 0.4].iter().map(|&value| aces_filmic(grey(value)).r).collect();
+        assert!(toe.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", toe);
+        assert!(toe.iter().zip([0.01, 0.05, 0.1, 0.2, 0.4]).all(|(&mapped, value)| mapped < value), "{:?}", toe);
+    }
+
+    #[test]
+    fn exposure_scales_before_mapping() {
+        let color = Color { r: 0.4, g: 2.0, b: 8.0 };
+        assert_eq!(ToneMap::None.apply(color, 1.0), color);
+        assert_eq!(ToneMap::None.apply(color, 0.5), 0.5 * color);
+        assert_eq!(ToneMap::Reinhard.apply(color, 2.0), reinhard(2.0 * color));
+        assert_eq!(ToneMap::AcesFilmic.apply(color, 0.25), aces_filmic(0.25 * color));
+    }
+}
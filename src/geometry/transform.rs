@@ -0,0 +1,190 @@
+use std::ops::Mul;
+
+use crate::geometry::vector::{Point, Vector3};
+use crate::utils::degrees_to_radians;
+
+// Affine transform as a row-major 4x4 matrix acting on column vectors
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mat4 {
+    pub m: [[f32; 4]; 4],
+}
+
+impl Mat4 {
+    pub const IDENTITY: Mat4 = Mat4 {
+        m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ],
+    };
+
+    pub fn translation(offset: Vector3) -> Self {
+        let mut t = Self::IDENTITY;
+        t.m[0][3] = offset.x;
+        t.m[1][3] = offset.y;
+        t.m[2][3] = offset.z;
+        t
+    }
+
+    pub fn scaling(factors: Vector3) -> Self {
+        let mut t = Self::IDENTITY;
+        t.m[0][0] = factors.x;
+        t.m[1][1] = factors.y;
+        t.m[2][2] = factors.z;
+        t
+    }
+
+    pub fn rotation_x(degrees: f32) -> Self {
+        Self::rotation_axis(Vector3 { x: 1.0, y: 0.0, z: 0.0 }, degrees)
+    }
+
+    // Same sense as RotateY
+    pub fn rotation_y(degrees: f32) -> Self {
+        Self::rotation_axis(Vector3 { x: 0.0, y: 1.0, z: 0.0 }, degrees)
+    }
+
+    pub fn rotation_z(degrees: f32) -> Self {
+        Self::rotation_axis(Vector3 { x: 0.0, y: 0.0, z: 1.0 }, degrees)
+    }
+
+    // Counter-clockwise about axis looking back down it (Rodrigues' formula), axis need not be unit length
+    pub fn rotation_axis(axis: Vector3, degrees: f32) -> Self {
+        let a = axis.direction();
+        let (sin, cos) = degrees_to_radians(degrees).sin_cos();
+        let k = 1.0 - cos;
+        Self {
+            m: [
+                [cos + a.x * a.x * k, a.x * a.y * k - a.z * sin, a.x * a.z * k + a.y * sin, 0.0],
+                [a.y * a.x * k + a.z * sin, cos + a.y * a.y * k, a.y * a.z * k - a.x * sin, 0.0],
+                [a.z * a.x * k - a.y * sin, a.z * a.y * k + a.x * sin, cos + a.z * a.z * k, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ],
+        }
+    }
+
+    // Chains transforms in the order they are applied, so compose(&[scale, rotate, move])
+    // scales first and moves last
+    pub fn compose(transforms: &[Mat4]) -> Self {
+        transforms.iter().fold(Self::IDENTITY, |acc, &t| t * acc)
+    }
+
+    pub fn transform_point(&self, p: Point) -> Point {
+        let m = &self.m;
+        Point {
+            x: m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3],
+            y: m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3],
+            z: m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3],
+        }
+    }
+
+    // Ignores the translation, for directions
+    pub fn transform_vector(&self, v: Vector3) -> Vector3 {
+        let m = &self.m;
+        Vector3 {
+            x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        }
+    }
+
+    pub fn transpose(&self) -> Self {
+        let mut t = Self::IDENTITY;
+        for i in 0..4 {
+            for j in 0..4 {
+                t.m[i][j] = self.m[j][i];
+            }
+        }
+        t
+    }
+
+    // Gauss-Jordan elimination with partial pivoting, None for a singular matrix such as a zero scale
+    pub fn inverse(&self) -> Option<Self> {
+        let mut a = self.m;
+        let mut inv = Self::IDENTITY.m;
+        for col in 0..4 {
+            let pivot = (col..4).max_by(|&i, &j| a[i][col].abs().partial_cmp(&a[j][col].abs()).unwrap())?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+            inv.swap(col, pivot);
+
+            let scale = 1.0 / a[col][col];
+            for j in 0..4 {
+                a[col][j] *= scale;
+                inv[col][j] *= scale;
+            }
+            for row in 0..4 {
+                if row == col {
+                    continue;
+                }
+                let factor = a[row][col];
+                for j in 0..4 {
+                    a[row][j] -= factor * a[col][j];
+                    inv[row][j] -= factor * inv[col][j];
+                }
+            }
+        }
+        Some(Self { m: inv })
+    }
+}
+
+impl Mul for Mat4 {
+    type Output = Self;
+
+    fn mul(self, _rhs: Self) -> Self::Output {
+        let mut t = Self { m: [[0.0; 4]; 4] };
+        for i in 0..4 {
+            for j in 0..4 {
+                t.m[i][j] = (0..4).map(|k| self.m[i][k] * _rhs.m[k][j]).sum();
+            }
+        }
+        t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(found: Vector3, expected: Vector3) {
+        assert!((found - expected).length() < 1e-5, "{:?} instead of {:?}", found, expected);
+    }
+
+    #[test]
+    fn builders_move_points_as_named() {
+        let p = Point { x: 1.0, y: 2.0, z: 3.0 };
+        assert_close(Mat4::translation(Vector3 { x: 1.0, y: -1.0, z: 0.5 }).transform_point(p), Point { x: 2.0, y: 1.0, z: 3.5 });
+        assert_close(Mat4::translation(Vector3 { x: 1.0, y: -1.0, z: 0.5 }).transform_vector(p), p);
+        assert_close(Mat4::scaling(Vector3 { x: 2.0, y: 3.0, z: -1.0 }).transform_point(p), Point { x: 2.0, y: 6.0, z: -3.0 });
+        // Quarter turns take each axis to the next one round
+        assert_close(Mat4::rotation_x(90.0).transform_vector(Vector3 { x: 0.0, y: 1.0, z: 0.0 }), Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert_close(Mat4::rotation_y(90.0).transform_vector(Vector3 { x: 0.0, y: 0.0, z: 1.0 }), Vector3 { x: 1.0, y: 0.0, z: 0.0 });
+        assert_close(Mat4::rotation_z(90.0).transform_vector(Vector3 { x: 1.0, y: 0.0, z: 0.0 }), Vector3 { x: 0.0, y: 1.0, z: 0.0 });
+        // An arbitrary axis leaves points along it alone
+        let axis = Vector3 { x: 1.0, y: 1.0, z: 1.0 };
+        assert_close(Mat4::rotation_axis(axis, 73.0).transform_point(2.0 * axis), 2.0 * axis);
+        assert_close(Mat4::rotation_axis(axis, 120.0).transform_vector(Vector3 { x: 1.0, y: 0.0, z: 0.0 }), Vector3 { x: 0.0, y: 1.0, z: 0.0 });
+    }
+
+    #[test]
+    fn compose_applies_in_order_and_inverse_undoes_it() {
+        let scale = Mat4::scaling(Vector3 { x: 2.0, y: 2.0, z: 2.0 });
+        let shift = Mat4::translation(Vector3 { x: 1.0, y: 0.0, z: 0.0 });
+        let p = Point { x: 1.0, y: 1.0, z: 1.0 };
+        assert_close(Mat4::compose(&[scale, shift]).transform_point(p), Point { x: 3.0, y: 2.0, z: 2.0 });
+        assert_close(Mat4::compose(&[shift, scale]).transform_point(p), Point { x: 4.0, y: 2.0, z: 2.0 });
+
+        let transform = Mat4::compose(&[Mat4::scaling(Vector3 { x: 3.0, y: 1.0, z: 0.5 }), Mat4::rotation_axis(Vector3 { x: 1.0, y: 2.0, z: -1.0 }, 40.0), shift]);
+        let inverse = transform.inverse().unwrap();
+        assert_close(inverse.transform_point(transform.transform_point(p)), p);
+        let product = transform * inverse;
+        for i in 0..4 {
+            for j in 0..4 {
+                assert!((product.m[i][j] - Mat4::IDENTITY.m[i][j]).abs() < 1e-5);
+            }
+        }
+        assert!(Mat4::scaling(Vector3 { x: 1.0, y: 1.0, z: 0.0 }).inverse().is_none());
+    }
+}
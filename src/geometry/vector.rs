@@ -1,9 +1,12 @@
+use std::iter::Sum;
 use std::ops::{Add, AddAssign, Div, Index, IndexMut, Mul, MulAssign, Neg, Sub};
 
-use rand::{Rng, thread_rng};
+use rand::Rng;
 use rand_distr::StandardNormal;
 
-#[derive(Clone, Copy)]
+use crate::utils::with_rng;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Vector3 {
     pub x: f32,
     pub y: f32,
@@ -21,11 +24,11 @@ impl Vector3 {
         (1.0 / self.length()) * self
     }
 
-    pub fn dot(&self, _rhs: Self) -> f32 {
+    pub fn dot(self, _rhs: Self) -> f32 {
         self.x * _rhs.x + self.y * _rhs.y + self.z * _rhs.z
     }
 
-    pub fn cross(&self, _rhs: Self) -> Self {
+    pub fn cross(self, _rhs: Self) -> Self {
         Self {
             x: self.y * _rhs.z - self.z * _rhs.y,
             y: self.z * _rhs.x - self.x * _rhs.z,
@@ -41,16 +44,50 @@ impl Vector3 {
         self.length_squared().sqrt()
     }
 
+    // Same direction scaled to len, the zero vector stays zero instead of turning into NaN
+    pub fn with_length(self, len: f32) -> Self {
+        let length = self.length();
+        if length == 0.0 {
+            return self;
+        }
+        (len / length) * self
+    }
+
+    pub fn clamp_length_max(self, max: f32) -> Self {
+        if self.length_squared() > max * max { self.with_length(max) } else { self }
+    }
+
+    pub fn min(self, other: Self) -> Self {
+        Self { x: self.x.min(other.x), y: self.y.min(other.y), z: self.z.min(other.z) }
+    }
+
+    pub fn max(self, other: Self) -> Self {
+        Self { x: self.x.max(other.x), y: self.y.max(other.y), z: self.z.max(other.z) }
+    }
+
+    // Per component, an axis where lo > hi ends up at hi rather than panicking like f32::clamp
+    pub fn clamp(self, lo: Self, hi: Self) -> Self {
+        self.max(lo).min(hi)
+    }
+
+    pub fn map(self, f: impl Fn(f32) -> f32) -> Self {
+        Self { x: f(self.x), y: f(self.y), z: f(self.z) }
+    }
+
     pub fn near_zero(&self) -> bool {
         const E: f32 = 1e-6;
         self.x.abs() < E && self.y.abs() < E && self.z.abs() < E
     }
 
     pub fn random_unit_vector() -> Self {
+        with_rng(|rng| Self::random_unit_vector_with(rng))
+    }
+
+    pub fn random_unit_vector_with(rng: &mut (impl Rng + ?Sized)) -> Self {
         Self {
-            x: thread_rng().sample(StandardNormal),
-            y: thread_rng().sample(StandardNormal),
-            z: thread_rng().sample(StandardNormal),
+            x: rng.sample(StandardNormal),
+            y: rng.sample(StandardNormal),
+            z: rng.sample(StandardNormal),
         }.direction()
     }
 }
@@ -138,7 +175,8 @@ impl Index<usize> for Vector3 {
         return match index {
             0 => &self.x,
             1 => &self.y,
-            2 | _ => &self.z,
+            2 => &self.z,
+            _ => panic!("Vector3 index {} out of range, expected 0, 1 or 2", index),
         };
     }
 }
@@ -148,9 +186,137 @@ impl IndexMut<usize> for Vector3 {
         return match index {
             0 => &mut self.x,
             1 => &mut self.y,
-            2 | _ => &mut self.z,
+            2 => &mut self.z,
+            _ => panic!("Vector3 index {} out of range, expected 0, 1 or 2", index),
         };
     }
 }
 
+impl Sum for Vector3 {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Vector3::ORIGIN, |acc, v| acc + v)
+    }
+}
+
 pub type Point = Vector3;
+
+pub fn lerp(a: Vector3, b: Vector3, t: f32) -> Vector3 {
+    (1.0 - t) * a + t * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_applies_to_every_component() {
+        let v = Vector3 { x: 4.0, y: 9.0, z: 16.0 };
+        assert_eq!(v.map(|c| c.sqrt()), Vector3 { x: 2.0, y: 3.0, z: 4.0 });
+        assert_eq!(v.map(|c| -c), -v);
+    }
+
+    #[test]
+    fn min_and_max_pick_each_component_separately() {
+        let a = Vector3 { x: 1.0, y: -2.0, z: 3.0 };
+        let b = Vector3 { x: -1.0, y: 5.0, z: 3.0 };
+        assert_eq!(a.min(b), Vector3 { x: -1.0, y: -2.0, z: 3.0 });
+        assert_eq!(a.max(b), Vector3 { x: 1.0, y: 5.0, z: 3.0 });
+    }
+
+    #[test]
+    fn clamp_bounds_each_component_and_an_inverted_axis_ends_at_hi() {
+        let lo = Vector3 { x: 0.0, y: 0.0, z: 2.0 };
+        let hi = Vector3 { x: 1.0, y: 1.0, z: -2.0 };
+        let v = Vector3 { x: -3.0, y: 0.5, z: 0.0 };
+        assert_eq!(v.clamp(lo, hi), Vector3 { x: 0.0, y: 0.5, z: -2.0 });
+        assert_eq!(Vector3 { x: 4.0, y: 2.0, z: 9.0 }.clamp(lo, hi), Vector3 { x: 1.0, y: 1.0, z: -2.0 });
+    }
+
+    #[test]
+    fn lerp_runs_from_a_to_b() {
+        let a = Vector3 { x: 1.0, y: 2.0, z: -4.0 };
+        let b = Vector3 { x: 3.0, y: -2.0, z: 0.0 };
+        assert_eq!(lerp(a, b, 0.0), a);
+        assert_eq!(lerp(a, b, 1.0), b);
+        assert_eq!(lerp(a, b, 0.25), Vector3 { x: 1.5, y: 1.0, z: -3.0 });
+    }
+
+    #[test]
+    fn vectors_sum_like_the_operator() {
+        let vectors = [Vector3 { x: 1.0, y: -2.0, z: 0.5 }, Vector3 { x: 0.25, y: 4.0, z: -1.0 }];
+        assert_eq!(vectors.iter().copied().sum::<Vector3>(), vectors[0] + vectors[1]);
+        assert_eq!([].iter().copied().sum::<Vector3>(), Vector3::ORIGIN);
+    }
+
+    #[test]
+    fn indices_read_and_write_x_y_and_z() {
+        let mut v = Vector3 { x: 1.0, y: 2.0, z: 3.0 };
+        assert_eq!([v[0], v[1], v[2]], [1.0, 2.0, 3.0]);
+        v[1] = -5.0;
+        v[2] += 1.0;
+        assert_eq!(v, Vector3 { x: 1.0, y: -5.0, z: 4.0 });
+    }
+
+    #[test]
+    #[should_panic(expected = "Vector3 index 3 out of range")]
+    fn reading_past_z_panics() {
+        let _ = Vector3::ORIGIN[3];
+    }
+
+    #[test]
+    #[should_panic(expected = "Vector3 index 7 out of range")]
+    fn writing_past_z_panics() {
+        let mut v = Vector3::ORIGIN;
+        v[7] = 1.0;
+    }
+
+    #[test]
+    fn vectors_stay_usable_after_dot_and_cross() {
+        let a = Vector3 { x: 1.0, y: 2.0, z: 3.0 };
+        let b = Vector3 { x: -2.0, y: 0.5, z: 4.0 };
+        assert_eq!(a.dot(b), 11.0);
+        let c = a.cross(b);
+        // Both are Copy, so they are still there to check the cross product against
+        assert_eq!(c, Vector3 { x: 6.5, y: -10.0, z: 4.5 });
+        assert_eq!(c.dot(a), 0.0);
+        assert_eq!(c.dot(b), 0.0);
+        assert_eq!(a + b, Vector3 { x: -1.0, y: 2.5, z: 7.0 });
+    }
+
+    #[test]
+    fn scalar_times_vector_scales_each_component() {
+        let vectors = [Vector3 { x: 1.0, y: 2.0, z: 3.0 }, Vector3 { x: -4.5, y: 0.0, z: 7.25 }, Vector3 { x: 0.1, y: -0.2, z: -0.3 }];
+        for v in vectors {
+            for s in [2.0, -3.0, 0.5, 0.0, -0.0] {
+                let expected = Vector3 { x: s * v.x, y: s * v.y, z: s * v.z };
+                assert_eq!(s * v, expected);
+            }
+        }
+        assert_eq!(2.0 * Vector3 { x: 1.0, y: 2.0, z: 3.0 }, Vector3 { x: 2.0, y: 4.0, z: 6.0 });
+    }
+
+    #[test]
+    fn direction_keeps_every_component_in_proportion() {
+        assert_eq!(Vector3 { x: 0.0, y: 3.0, z: -4.0 }.direction(), Vector3 { x: 0.0, y: 0.6, z: -0.8 });
+        let d = Vector3 { x: 1.0, y: 2.0, z: 2.0 }.direction();
+        assert!((d - Vector3 { x: 1.0 / 3.0, y: 2.0 / 3.0, z: 2.0 / 3.0 }).length() < 1e-6);
+    }
+
+    #[test]
+    fn long_vectors_are_clamped_to_the_max_length() {
+        let v = Vector3 { x: 3.0, y: 0.0, z: 4.0 };
+        let clamped = v.clamp_length_max(2.0);
+        assert!((clamped.length() - 2.0).abs() < 1e-6);
+        assert!((clamped.direction() - v.direction()).length() < 1e-6);
+        // Shorter vectors pass through untouched
+        assert_eq!(v.clamp_length_max(6.0), v);
+        assert_eq!(Vector3::ORIGIN.clamp_length_max(2.0), Vector3::ORIGIN);
+    }
+
+    #[test]
+    fn with_length_keeps_the_direction_and_zero_stays_zero() {
+        let v = Vector3 { x: -1.0, y: 2.0, z: 2.0 };
+        assert_eq!(v.with_length(6.0), Vector3 { x: -2.0, y: 4.0, z: 4.0 });
+        assert_eq!(Vector3::ORIGIN.with_length(3.0), Vector3::ORIGIN);
+    }
+}
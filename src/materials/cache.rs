@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::geometry::color::Color;
+use crate::materials::dielectric::Dielectric;
+use crate::materials::lambertian::Lambertian;
+use crate::materials::light::DiffuseLight;
+use crate::materials::material::Material;
+use crate::materials::metal::Metal;
+
+// Hands out one shared Arc per distinct material definition while a scene is built,
+// so objects with identical parameters don't each carry their own copy. Keys use the
+// exact float bits, so only bit-identical parameters are merged.
+#[derive(Default)]
+pub struct MaterialCache {
+    entries: HashMap<String, Arc<dyn Material>>,
+}
+
+fn color_key(color: Color) -> String {
+    return format!("{:08x}{:08x}{:08x}", color.r.to_bits(), color.g.to_bits(), color.b.to_bits());
+}
+
+impl MaterialCache {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    pub fn get_or_insert(&mut self, key: String, make: impl FnOnce() -> Arc<dyn Material>) -> Arc<dyn Material> {
+        return self.entries.entry(key).or_insert_with(make).clone();
+    }
+
+    pub fn lambertian(&mut self, color: Color) -> Arc<dyn Material> {
+        return self.get_or_insert(format!("lambertian {}", color_key(color)), || Arc::new(Lambertian::new(color)));
+    }
+
+    pub fn metal(&mut self, color: Color, fuzz: f32) -> Arc<dyn Material> {
+        return self.get_or_insert(format!("metal {} {:08x}", color_key(color), fuzz.to_bits()), || Arc::new(Metal { color, fuzz }));
+    }
+
+    pub fn dielectric(&mut self, refractive_index: f32) -> Arc<dyn Material> {
+        return self.get_or_insert(format!("dielectric {:08x}", refractive_index.to_bits()), || Arc::new(Dielectric { refractive_index }));
+    }
+
+    pub fn diffuse_light(&mut self, color: Color) -> Arc<dyn Material> {
+        return self.get_or_insert(format!("light {}", color_key(color)), || Arc::new(DiffuseLight::new(color)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::objects::sphere::Sphere;
+    use crate::scenes::final_scene_rtweekend_seeded;
+
+    #[test]
+    fn identical_materials_share_one_arc() {
+        let mut cache = MaterialCache::new();
+        let color = Color { r: 0.3, g: 0.6, b: 0.9 };
+        let spheres: Vec<Sphere> = (0..1000).map(|k| Sphere {
+            center: Point { x: k as f32, y: 0.0, z: 0.0 },
+            radius: 0.4,
+            material: cache.lambertian(color),
+        }).collect();
+        assert!(spheres.iter().all(|s| Arc::ptr_eq(&s.material, &spheres[0].material)));
+        // One reference per sphere and the cache's own
+        assert_eq!(Arc::strong_count(&spheres[0].material), 1001);
+    }
+
+    #[test]
+    fn different_definitions_stay_apart() {
+        let mut cache = MaterialCache::new();
+        let color = Color { r: 0.3, g: 0.6, b: 0.9 };
+        let nudged = Color { r: f32::from_bits(0.3f32.to_bits() + 1), ..color };
+        let lambertian = cache.lambertian(color);
+        assert!(!Arc::ptr_eq(&lambertian, &cache.lambertian(nudged)));
+        assert!(!Arc::ptr_eq(&lambertian, &cache.diffuse_light(color)));
+        assert!(!Arc::ptr_eq(&cache.metal(color, 0.1), &cache.metal(color, 0.2)));
+        assert!(Arc::ptr_eq(&cache.dielectric(1.5), &cache.dielectric(1.5)));
+    }
+
+    #[test]
+    fn the_cover_scene_shares_its_glass() {
+        let world = final_scene_rtweekend_seeded(7);
+        // The large glass sphere in the middle, three from the end
+        let glass = world[world.len() - 3].material().unwrap().clone();
+        let sharing = world.iter().filter(|obj| obj.material().is_some_and(|m| Arc::ptr_eq(m, &glass))).count();
+        assert!(sharing > 5, "only {} spheres share the glass", sharing);
+    }
+}
@@ -1,5 +1,5 @@
 use crate::geometry::color::Color;
-use crate::geometry::ray::Ray;
+use crate::geometry::ray::{Ray, RayKind};
 use crate::geometry::vector::{Point, Vector3};
 use crate::materials::material::{Material, reflect_ray, reflectance_schlick, refract, ScatterRecord};
 use crate::objects::hittable::HitRecord;
@@ -9,17 +9,25 @@ pub struct Dielectric {
     pub refractive_index: f32,
 }
 
+impl Dielectric {
+    // Ratio of refractive indices across the surface and the cosine of the incident angle
+    fn incidence(&self, in_ray: Ray, hit_rec: &HitRecord) -> (f32, f32) {
+        let ir = if hit_rec.front_face { 1.0 / self.refractive_index } else { self.refractive_index };
+        let cos_0 = (-in_ray.direction.dot(hit_rec.normal)).min(1.0);
+        return (ir, cos_0);
+    }
+
+    fn is_refracted(ir: f32, cos_0: f32) -> bool {
+        let sin_0 = (1.0 - cos_0.powi(2)).max(0.0).sqrt();
+        return ir * sin_0 <= 1.0;
+    }
+}
+
 impl Material for Dielectric {
     fn scatter(&self, in_ray: Ray, hit_rec: &HitRecord) -> Option<ScatterRecord> {
-        let mut ir = 1.0 / self.refractive_index;
-        if !hit_rec.front_face {
-            ir = self.refractive_index;
-        }
-
-        let cos_0 = -in_ray.direction.dot(hit_rec.normal).min(1.0);
-        let sin_0 = (1.0 - cos_0.powi(2)).sqrt();
+        let (ir, cos_0) = self.incidence(in_ray, hit_rec);
 
-        let is_refracted = ir * sin_0 <= 1.0;
+        let is_refracted = Self::is_refracted(ir, cos_0);
         let mut direction = Vector3::ORIGIN;
         if !is_refracted || reflectance_schlick(cos_0, ir) > random_f32() {
             direction = reflect_ray(in_ray.direction, hit_rec.normal);
@@ -28,9 +36,28 @@ impl Material for Dielectric {
         }
 
         return Option::from(ScatterRecord {
-            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction, time: in_ray.time }),
+            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction, time: in_ray.time, kind: RayKind::Reflection }),
             attenuation: Color::WHITE,
             pdf_ptr: None,
         });
     }
+
+    // The reflected and refracted rays weighted by Fresnel reflectance, or just the reflection
+    // past the critical angle
+    fn scatter_split(&self, in_ray: Ray, hit_rec: &HitRecord) -> Vec<(Ray, Color)> {
+        let (ir, cos_0) = self.incidence(in_ray, hit_rec);
+        let branch = |direction: Vector3| Ray { origin: hit_rec.intersection, direction, time: in_ray.time, kind: RayKind::Reflection };
+        let reflected = branch(reflect_ray(in_ray.direction, hit_rec.normal));
+        if !Self::is_refracted(ir, cos_0) {
+            return vec![(reflected, Color::WHITE)];
+        }
+
+        let reflectance = reflectance_schlick(cos_0, ir);
+        let refracted = branch(refract(in_ray.direction, hit_rec.normal, ir));
+        return vec![(reflected, reflectance * Color::WHITE), (refracted, (1.0 - reflectance) * Color::WHITE)];
+    }
+
+    fn is_specular(&self) -> bool {
+        return true;
+    }
 }
\ No newline at end of file
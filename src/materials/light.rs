@@ -10,22 +10,95 @@ use crate::textures::texture::Texture;
 
 pub struct DiffuseLight {
     pub emit: Arc<dyn Texture>,
+    // Emit from the back face too, e.g. for a panel hanging in open space
+    pub two_sided: bool,
 }
 
 impl DiffuseLight {
     pub fn new(color: Color) -> Self {
         return Self {
-            emit: Arc::new(SolidColor { color })
+            emit: Arc::new(SolidColor { color }),
+            two_sided: false,
+        };
+    }
+
+    pub fn new_two_sided(color: Color) -> Self {
+        return Self {
+            emit: Arc::new(SolidColor { color }),
+            two_sided: true,
         };
     }
 }
 
 impl Material for DiffuseLight {
     fn emitted(&self, _ray: Ray, hit_rec: &HitRecord, u: f32, v: f32, intersection: Point) -> Color {
-        return if hit_rec.front_face {
+        return if hit_rec.front_face || self.two_sided {
             self.emit.color(u, v, intersection)
         } else {
             Color::BLACK
         };
     }
+
+    fn is_emissive(&self) -> bool {
+        return true;
+    }
+
+    // Textured lights are taken at the middle of their uv range
+    fn radiance(&self) -> Color {
+        let face = self.emit.color(0.5, 0.5, Point::ORIGIN);
+        return if self.two_sided { 2.0 * face } else { face };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::ray::RayKind;
+    use crate::geometry::vector::Vector3;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::hittable::Hittable;
+    use crate::objects::rectangle::XZRect;
+    use crate::render::tests::{render_objects, test_settings};
+
+    fn hit_on(material: Arc<dyn Material>, front_face: bool, u: f32, v: f32, intersection: Point) -> (Ray, HitRecord) {
+        let ray = Ray { origin: Point { x: 0.0, y: 1.0, z: 0.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+        let hit_rec = HitRecord { intersection, normal: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, material, t: 1.0, u, v, front_face };
+        return (ray, hit_rec);
+    }
+
+    #[test]
+    fn lights_emit_their_color_everywhere_and_scatter_nothing() {
+        let color = Color { r: 3.0, g: 2.0, b: 1.0 };
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(color));
+        for (u, v, intersection) in [(0.0, 0.0, Point::ORIGIN), (0.9, 0.2, Point { x: 5.0, y: -2.0, z: 7.0 })] {
+            let (ray, hit_rec) = hit_on(light.clone(), true, u, v, intersection);
+            assert_eq!(light.emitted(ray, &hit_rec, u, v, intersection), color);
+            assert!(light.scatter(ray, &hit_rec).is_none());
+        }
+        assert!(light.is_emissive());
+    }
+
+    #[test]
+    fn only_two_sided_lights_emit_from_the_back() {
+        let color = Color { r: 1.0, g: 1.0, b: 1.0 };
+        for (light, back) in [(DiffuseLight::new(color), Color::BLACK), (DiffuseLight::new_two_sided(color), color)] {
+            let light: Arc<dyn Material> = Arc::new(light);
+            let (ray, hit_rec) = hit_on(light.clone(), false, 0.5, 0.5, Point::ORIGIN);
+            assert_eq!(light.emitted(ray, &hit_rec, 0.5, 0.5, Point::ORIGIN), back);
+        }
+    }
+
+    #[test]
+    fn a_panel_light_brightens_the_floor_below_it() {
+        let floor: Arc<dyn Hittable> = Arc::new(XZRect { x: (-10.0, 10.0), z: (-10.0, 10.0), k: -1.0, material: Arc::new(Lambertian::new(Color { r: 0.8, g: 0.8, b: 0.8 })) });
+        let framebuffer = render_objects(&test_settings(16, 16, 4), vec![floor]);
+        // The lower half of the view looks down at the floor under the light
+        let lit: Vec<f32> = (8 * 16..16 * 16).map(|k| framebuffer.pixels[k].luminance()).collect();
+        let mean = lit.iter().sum::<f32>() / lit.len() as f32;
+        assert!(lit.iter().all(|l| l.is_finite()));
+        assert!(mean > 0.1, "the floor averages {}", mean);
+        // A scene with nothing emitting stays black
+        let dark = render_objects(&test_settings(16, 16, 4), Vec::new());
+        assert!(dark.pixels[8 * 16..].iter().all(|&c| c == Color::BLACK));
+    }
 }
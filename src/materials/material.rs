@@ -22,6 +22,23 @@ pub trait Material: Send + Sync {
     fn emitted(&self, ray: Ray, hit_rec: &HitRecord, u: f32, v: f32, intersection: Point) -> Color {
         return Color::BLACK;
     }
+    // Scatters only along a single (delta) direction, so light sampling can't reach it
+    fn is_specular(&self) -> bool {
+        return false;
+    }
+    fn is_emissive(&self) -> bool {
+        return false;
+    }
+    // Every direction a specular material can send the ray, each with its weight, in place of
+    // the one scatter picks at random. Empty for materials that don't split.
+    fn scatter_split(&self, in_ray: Ray, hit_rec: &HitRecord) -> Vec<(Ray, Color)> {
+        return Vec::new();
+    }
+    // Typical radiance leaving the surface, added up over both faces for two-sided emitters,
+    // for estimating how much light an object gives off
+    fn radiance(&self) -> Color {
+        return Color::BLACK;
+    }
 }
 
 pub fn reflect_ray(in_direction: Vector3, normal: Vector3) -> Vector3 {
@@ -29,9 +46,9 @@ pub fn reflect_ray(in_direction: Vector3, normal: Vector3) -> Vector3 {
 }
 
 pub fn refract(in_direction: Vector3, normal: Vector3, ir: f32) -> Vector3 {
-    let cos_0 = -in_direction.dot(normal).min(1.0);
+    let cos_0 = (-in_direction.dot(normal)).min(1.0);
     let refract_perp = ir * (in_direction + cos_0 * normal);
-    let refract_para = -(1.0 - refract_perp.length_squared()).sqrt() * normal;
+    let refract_para = -(1.0 - refract_perp.length_squared()).max(0.0).sqrt() * normal;
     return (refract_perp + refract_para).direction();
 }
 
@@ -39,4 +56,117 @@ pub fn reflectance_schlick(cos_0: f32, ir: f32) -> f32 {
     let mut r0 = (1.0 - ir) / (1.0 + ir);
     r0 = r0.powi(2);
     return r0 + (1.0 - r0) * (1.0 - cos_0).powi(5);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::materials::dielectric::Dielectric;
+    use crate::materials::isotropic::Isotropic;
+    use crate::materials::lambertian::Lambertian;
+    use crate::materials::light::DiffuseLight;
+    use crate::materials::metal::Metal;
+    use crate::materials::thin_film::ThinFilm;
+    use crate::textures::solid::SolidColor;
+
+    fn assert_finite(v: Vector3) {
+        assert!(v.x.is_finite() && v.y.is_finite() && v.z.is_finite(), "{:?}", v);
+    }
+
+    // Random unit incident directions against random unit normals facing them
+    fn incident_pairs(seed: u64) -> Vec<(Vector3, Vector3)> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        return (0..10_000).map(|_| {
+            let direction = Vector3::random_unit_vector_with(&mut rng);
+            let normal = Vector3::random_unit_vector_with(&mut rng);
+            if direction.dot(normal) > 0.0 { (direction, -1.0 * normal) } else { (direction, normal) }
+        }).collect();
+    }
+
+    #[test]
+    fn reflection_keeps_the_angle_of_incidence() {
+        for (direction, normal) in incident_pairs(1) {
+            let reflected = reflect_ray(direction, normal);
+            assert_finite(reflected);
+            assert!((reflected.length() - 1.0).abs() < 1e-4);
+            assert!((reflected.dot(normal) + direction.dot(normal)).abs() < 1e-4);
+            // In the plane of incidence
+            assert!(direction.cross(normal).dot(reflected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn refraction_obeys_snells_law() {
+        let mut rng = StdRng::seed_from_u64(2);
+        for (direction, normal) in incident_pairs(3) {
+            let ior: f32 = rng.gen_range(1.0..2.5);
+            // Entering and leaving the denser side, ir being the ratio of the indices
+            for ir in [1.0 / ior, ior] {
+                let refracted = refract(direction, normal, ir);
+                assert_finite(refracted);
+                let sin_i = direction.cross(normal).length();
+                if ir * sin_i >= 1.0 {
+                    continue;
+                }
+                // sin θi / sin θt is the ratio of the indices, written so tiny angles don't divide by zero
+                let sin_t = refracted.cross(normal).length();
+                assert!((sin_t - ir * sin_i).abs() < 1e-3, "sin_t {} for sin_i {} and ir {}", sin_t, sin_i, ir);
+                assert!(refracted.dot(normal) < 0.0);
+                assert!(direction.cross(normal).dot(refracted).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn grazing_and_head_on_rays_stay_finite() {
+        let normal = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        for direction in [Vector3 { x: 1.0, y: 0.0, z: 0.0 }, Vector3 { x: 0.0, y: -1.0, z: 0.0 }, Vector3 { x: 1.0, y: -1e-7, z: 0.0 }.direction()] {
+            for ir in [0.5, 1.0, 1.5] {
+                assert_finite(refract(direction, normal, ir));
+            }
+            assert_finite(reflect_ray(direction, normal));
+        }
+        assert_eq!(refract(Vector3 { x: 0.0, y: -1.0, z: 0.0 }, normal, 1.5), Vector3 { x: 0.0, y: -1.0, z: 0.0 });
+    }
+
+    fn assert_close(a: Vector3, b: Vector3) {
+        assert!((a - b).length() < 1e-5, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn reflection_and_refraction_match_worked_examples() {
+        let normal = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        // Mirroring only flips the normal component, the other two are kept as they were
+        assert_close(reflect_ray(Vector3 { x: 1.0, y: -2.0, z: 3.0 }, normal), Vector3 { x: 1.0, y: 2.0, z: 3.0 }.direction());
+        assert_close(reflect_ray(Vector3 { x: 0.6, y: 0.0, z: -0.8 }, normal), Vector3 { x: 0.6, y: 0.0, z: -0.8 });
+
+        // 30 degrees from air into glass leaves at sin θt = 0.5 / 1.5
+        let incident = Vector3 { x: 0.5 * 0.6, y: -(0.75f32).sqrt(), z: 0.5 * 0.8 };
+        let sin_t = 0.5 / 1.5;
+        let expected = Vector3 { x: sin_t * 0.6, y: -(1.0 - sin_t * sin_t).sqrt(), z: sin_t * 0.8 };
+        assert_close(refract(incident, normal, 1.0 / 1.5), expected);
+        // Between equal media the ray carries straight on
+        assert_close(refract(incident, normal, 1.0), incident);
+    }
+
+    #[test]
+    fn materials_report_their_class() {
+        let white = Color::WHITE;
+        let classes: Vec<(Arc<dyn Material>, bool, bool)> = vec![
+            (Arc::new(Lambertian::new(white)), false, false),
+            (Arc::new(Isotropic { albedo: Arc::new(SolidColor { color: white }) }), false, false),
+            (Arc::new(Metal { color: white, fuzz: 0.0 }), true, false),
+            (Arc::new(Dielectric { refractive_index: 1.5 }), true, false),
+            (Arc::new(ThinFilm { thickness: 300.0, refractive_index: 1.33 }), true, false),
+            (Arc::new(DiffuseLight::new(white)), false, true),
+            (Arc::new(DiffuseLight::new_two_sided(white)), false, true),
+        ];
+        for (k, (material, specular, emissive)) in classes.iter().enumerate() {
+            assert_eq!(material.is_specular(), *specular, "material {}", k);
+            assert_eq!(material.is_emissive(), *emissive, "material {}", k);
+        }
+    }
+}
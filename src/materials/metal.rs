@@ -1,5 +1,5 @@
 use crate::geometry::color::Color;
-use crate::geometry::ray::Ray;
+use crate::geometry::ray::{Ray, RayKind};
 use crate::geometry::vector::Point;
 use crate::materials::material::{Material, reflect_ray, ScatterRecord};
 use crate::objects::hittable::HitRecord;
@@ -14,9 +14,13 @@ impl Material for Metal {
     fn scatter(&self, in_ray: Ray, hit_rec: &HitRecord) -> Option<ScatterRecord> {
         let reflected_direction = reflect_ray(in_ray.direction, hit_rec.normal) + self.fuzz * random_in_unit_sphere();
         return Option::from(ScatterRecord {
-            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction: reflected_direction, time: in_ray.time }),
+            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction: reflected_direction, time: in_ray.time, kind: RayKind::Reflection }),
             attenuation: self.color,
             pdf_ptr: None,
         });
     }
+
+    fn is_specular(&self) -> bool {
+        return true;
+    }
 }
\ No newline at end of file
@@ -4,3 +4,6 @@ pub mod metal;
 pub mod dielectric;
 pub mod light;
 pub mod isotropic;
+pub mod thin_film;
+pub mod cache;
+pub mod occlusion;
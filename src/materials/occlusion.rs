@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use crate::geometry::color::Color;
+use crate::geometry::ray::Ray;
+use crate::geometry::vector::Point;
+use crate::materials::material::{Material, ScatterRecord};
+use crate::objects::hittable::HitRecord;
+
+// Darkens another material by ambient occlusion baked at a triangle's vertices, blended across
+// the face by the barycentric (u, v) Triangle::hit leaves in the hit record. A cheap stand-in
+// for the light nearby geometry keeps out, on top of whatever the renderer finds itself.
+pub struct VertexOcclusion {
+    pub material: Arc<dyn Material>,
+    // For the triangle's vertices in order, 1 where nothing covers the hemisphere and 0 where
+    // it is closed off
+    pub occlusion: [f32; 3],
+}
+
+impl VertexOcclusion {
+    pub fn at(&self, u: f32, v: f32) -> f32 {
+        let [a, b, c] = self.occlusion;
+        return (1.0 - u - v) * a + u * b + v * c;
+    }
+}
+
+impl Material for VertexOcclusion {
+    fn scatter(&self, in_ray: Ray, hit_rec: &HitRecord) -> Option<ScatterRecord> {
+        let mut scatter_rec = self.material.scatter(in_ray, hit_rec)?;
+        scatter_rec.attenuation = self.at(hit_rec.u, hit_rec.v) * scatter_rec.attenuation;
+        return Option::from(scatter_rec);
+    }
+
+    fn scattering_pdf(&self, in_ray: Ray, hit_rec: &HitRecord, scattered_ray: Ray) -> f32 {
+        return self.material.scattering_pdf(in_ray, hit_rec, scattered_ray);
+    }
+
+    fn emitted(&self, ray: Ray, hit_rec: &HitRecord, u: f32, v: f32, intersection: Point) -> Color {
+        return self.material.emitted(ray, hit_rec, u, v, intersection);
+    }
+
+    fn is_specular(&self) -> bool {
+        return self.material.is_specular();
+    }
+
+    fn is_emissive(&self) -> bool {
+        return self.material.is_emissive();
+    }
+
+    fn scatter_split(&self, in_ray: Ray, hit_rec: &HitRecord) -> Vec<(Ray, Color)> {
+        let occlusion = self.at(hit_rec.u, hit_rec.v);
+        return self.material.scatter_split(in_ray, hit_rec).into_iter()
+            .map(|(ray, attenuation)| (ray, occlusion * attenuation))
+            .collect();
+    }
+
+    fn radiance(&self) -> Color {
+        return self.material.radiance();
+    }
+}
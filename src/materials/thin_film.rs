@@ -0,0 +1,114 @@
+use crate::geometry::color::Color;
+use crate::geometry::ray::{Ray, RayKind};
+use crate::materials::material::{Material, reflect_ray, ScatterRecord};
+use crate::objects::hittable::HitRecord;
+use crate::utils::{PI, random_f32};
+
+// Representative wavelength of each RGB channel, in nanometres
+const CHANNEL_WAVELENGTHS: [f32; 3] = [650.0, 532.0, 450.0];
+
+// A film thin enough to show interference colors, surrounded by air on both sides,
+// like a soap bubble. Light is either reflected or passes straight through, since
+// the offset from refracting through such a thin layer is negligible.
+pub struct ThinFilm {
+    pub thickness: f32, // nanometres
+    pub refractive_index: f32,
+}
+
+impl ThinFilm {
+    // Airy reflectance of the film for one wavelength, averaged over s and p polarisation
+    pub fn reflectance(&self, cos_i: f32, wavelength: f32) -> f32 {
+        let n = self.refractive_index;
+        let sin_t = (1.0 - cos_i.powi(2)).max(0.0).sqrt() / n;
+        let cos_t = (1.0 - sin_t.powi(2)).max(0.0).sqrt();
+
+        let r_s = (cos_i - n * cos_t) / (cos_i + n * cos_t);
+        let r_p = (n * cos_i - cos_t) / (n * cos_i + cos_t);
+
+        // Phase difference between the two surface reflections
+        let phase = 4.0 * PI * n * self.thickness * cos_t / wavelength;
+
+        // Both faces border air, so the back face amplitude is -r and the sum reduces to this
+        let airy = |r: f32| {
+            let r2 = r * r;
+            return 2.0 * r2 * (1.0 - phase.cos()) / (1.0 + r2 * r2 - 2.0 * r2 * phase.cos());
+        };
+        return 0.5 * (airy(r_s) + airy(r_p));
+    }
+
+    pub fn reflectance_color(&self, cos_i: f32) -> Color {
+        return Color {
+            r: self.reflectance(cos_i, CHANNEL_WAVELENGTHS[0]),
+            g: self.reflectance(cos_i, CHANNEL_WAVELENGTHS[1]),
+            b: self.reflectance(cos_i, CHANNEL_WAVELENGTHS[2]),
+        };
+    }
+}
+
+impl Material for ThinFilm {
+    fn scatter(&self, in_ray: Ray, hit_rec: &HitRecord) -> Option<ScatterRecord> {
+        let cos_i = (-in_ray.direction.direction().dot(hit_rec.normal)).clamp(0.0, 1.0);
+        let reflectance = self.reflectance_color(cos_i);
+
+        // Pick reflection by the average reflectance and weight by the per-channel ratio
+        let p = ((reflectance.r + reflectance.g + reflectance.b) / 3.0).clamp(0.001, 0.999);
+        let (direction, attenuation) = if random_f32() < p {
+            (reflect_ray(in_ray.direction, hit_rec.normal), (1.0 / p) * reflectance)
+        } else {
+            let transmittance = Color { r: 1.0 - reflectance.r, g: 1.0 - reflectance.g, b: 1.0 - reflectance.b };
+            (in_ray.direction, (1.0 / (1.0 - p)) * transmittance)
+        };
+
+        return Option::from(ScatterRecord {
+            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction, time: in_ray.time, kind: RayKind::Reflection }),
+            attenuation,
+            pdf_ptr: None,
+        });
+    }
+
+    fn is_specular(&self) -> bool {
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Wavelength in the visible range reflected most strongly at normal incidence
+    fn peak_wavelength(film: &ThinFilm) -> f32 {
+        return (380..=780).map(|nm| nm as f32)
+            .fold((0.0, -1.0), |best: (f32, f32), nm| {
+                let r = film.reflectance(1.0, nm);
+                if r > best.1 { (nm, r) } else { best }
+            })
+            .0;
+    }
+
+    #[test]
+    fn thicker_films_reflect_longer_wavelengths() {
+        // With the half-wave shift at the front face, reflections add up where
+        // 2 n d = (m + 1/2) wavelength. For these films only m = 0 falls in the visible range.
+        let mut last_peak = 0.0;
+        for thickness in [80.0, 100.0, 120.0, 140.0] {
+            let film = ThinFilm { thickness, refractive_index: 1.33 };
+            let predicted = 4.0 * film.refractive_index * thickness;
+            let peak = peak_wavelength(&film);
+            assert!((peak - predicted).abs() <= 1.0, "{} nm film peaks at {} nm, expected {}", thickness, peak, predicted);
+            assert!(peak > last_peak);
+            last_peak = peak;
+        }
+
+        // So the hue moves from green towards red
+        let green = ThinFilm { thickness: 100.0, refractive_index: 1.33 }.reflectance_color(1.0);
+        assert!(green.g > green.r && green.g > green.b, "{:?}", green);
+        let red = ThinFilm { thickness: 122.0, refractive_index: 1.33 }.reflectance_color(1.0);
+        assert!(red.r > red.g && red.r > red.b, "{:?}", red);
+    }
+
+    #[test]
+    fn vanishing_films_reflect_nothing() {
+        let film = ThinFilm { thickness: 0.0, refractive_index: 1.33 };
+        assert_eq!(film.reflectance_color(0.7), Color::BLACK);
+    }
+}
@@ -1,10 +1,12 @@
 use std::sync::Arc;
 
+use rand::RngCore;
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::ray::Ray;
-use crate::geometry::vector::Point;
+use crate::geometry::vector::{Point, Vector3};
 use crate::materials::material::Material;
-use crate::objects::hittable::{HitRecord, Hittable};
+use crate::objects::hittable::{FlipFace, HitRecord, Hittable};
 use crate::objects::rectangle::{XYRect, XZRect, YZRect};
 use crate::world::hittable_list::HittableList;
 
@@ -15,6 +17,8 @@ pub struct AxisAlignedBox {
 }
 
 impl AxisAlignedBox {
+    // The rectangles all face along their positive axis, so the faces on the minimum side are
+    // flipped to face out of the box like the others
     pub fn new(point_min: Point, point_max: Point, mat: Arc<dyn Material>) -> Self {
         let mut faces: Vec<Arc<dyn Hittable>> = Vec::new();
         faces.push(Arc::new(XYRect {
@@ -23,19 +27,22 @@ impl AxisAlignedBox {
             k: point_max.z,
             material: mat.clone(),
         }));
-        faces.push(Arc::new(XYRect {
-            x: (point_min.x, point_max.x),
-            y: (point_min.y, point_max.y),
-            k: point_min.z,
-            material: mat.clone(),
+        faces.push(Arc::new(FlipFace {
+            object: Arc::new(XYRect {
+                x: (point_min.x, point_max.x),
+                y: (point_min.y, point_max.y),
+                k: point_min.z,
+                material: mat.clone(),
+            }),
         }));
 
-
-        faces.push(Arc::new(XZRect {
-            x: (point_min.x, point_max.x),
-            z: (point_min.z, point_max.z),
-            k: point_min.y,
-            material: mat.clone(),
+        faces.push(Arc::new(FlipFace {
+            object: Arc::new(XZRect {
+                x: (point_min.x, point_max.x),
+                z: (point_min.z, point_max.z),
+                k: point_min.y,
+                material: mat.clone(),
+            }),
         }));
         faces.push(Arc::new(XZRect {
             x: (point_min.x, point_max.x),
@@ -50,11 +57,13 @@ impl AxisAlignedBox {
             k: point_max.x,
             material: mat.clone(),
         }));
-        faces.push(Arc::new(YZRect {
-            y: (point_min.y, point_max.y),
-            z: (point_min.z, point_max.z),
-            k: point_min.x,
-            material: mat.clone(),
+        faces.push(Arc::new(FlipFace {
+            object: Arc::new(YZRect {
+                y: (point_min.y, point_max.y),
+                z: (point_min.z, point_max.z),
+                k: point_min.x,
+                material: mat.clone(),
+            }),
         }));
 
         return Self {
@@ -70,10 +79,90 @@ impl Hittable for AxisAlignedBox {
         return self.faces.hit(ray, t_min, t_max);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.faces.objects.first().and_then(|face| face.material());
+    }
+
+    fn area(&self) -> f32 {
+        return self.faces.area();
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return self.faces.sample_surface(rng);
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         return Option::from(AxisAlignedBoundingBox {
             minimum: self.box_min,
             maximum: self.box_max,
         });
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::RayKind;
+    use crate::materials::lambertian::Lambertian;
+    use crate::utils::INF_F32;
+
+    fn unit_box() -> AxisAlignedBox {
+        let corner = Point { x: 1.0, y: 2.0, z: 3.0 };
+        return AxisAlignedBox::new(corner, corner + Vector3 { x: 1.0, y: 1.0, z: 1.0 }, Arc::new(Lambertian::new(Color::WHITE)));
+    }
+
+    fn ray(origin: Point, direction: Vector3) -> Ray {
+        return Ray { origin, direction, time: 0.0, kind: RayKind::Camera };
+    }
+
+    #[test]
+    fn rays_enter_the_nearest_face_or_miss() {
+        let unit_box = unit_box();
+        let hit_rec = unit_box.hit(ray(Point { x: 1.5, y: 2.5, z: 10.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 }), 0.001, INF_F32).unwrap();
+        assert_eq!(hit_rec.t, 6.0);
+        assert_eq!(hit_rec.intersection, Point { x: 1.5, y: 2.5, z: 4.0 });
+        assert!(hit_rec.front_face);
+        assert_eq!(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+
+        assert!(unit_box.hit(ray(Point { x: 3.5, y: 2.5, z: 10.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 }), 0.001, INF_F32).is_none());
+        assert!(unit_box.hit(ray(Point { x: 1.5, y: 2.5, z: 10.0 }, Vector3 { x: 0.0, y: 0.0, z: 1.0 }), 0.001, INF_F32).is_none());
+        let bound_box = unit_box.bounding_box(0.0, 1.0).unwrap();
+        assert_eq!((bound_box.minimum, bound_box.maximum), (unit_box.box_min, unit_box.box_max));
+    }
+
+    #[test]
+    fn every_face_is_entered_from_outside_and_left_from_inside() {
+        let unit_box = unit_box();
+        let center = Point { x: 1.5, y: 2.5, z: 3.5 };
+        for axis in 0..3 {
+            for sign in [1.0, -1.0] {
+                let mut outward = Vector3::ORIGIN;
+                outward[axis] = sign;
+                // A ray from the middle crosses the face with its outward normal along the ray
+                let leaving = unit_box.hit(ray(center, outward), 0.001, INF_F32).unwrap();
+                assert!(!leaving.front_face, "leaving along {:?}", outward);
+                assert_eq!(leaving.normal, -1.0 * outward);
+                let entering = unit_box.hit(ray(center + 2.0 * outward, -1.0 * outward), 0.001, INF_F32).unwrap();
+                assert!(entering.front_face, "entering against {:?}", outward);
+                assert_eq!(entering.normal, outward);
+                assert_eq!(entering.intersection, leaving.intersection);
+            }
+        }
+    }
+
+    #[test]
+    fn surface_samples_face_out_of_the_box() {
+        let unit_box = unit_box();
+        let center = Point { x: 1.5, y: 2.5, z: 3.5 };
+        let mut rng = StdRng::seed_from_u64(258);
+        for _ in 0..200 {
+            let (point, normal, pdf) = unit_box.sample_surface(&mut rng).unwrap();
+            assert!((pdf - 1.0 / 6.0).abs() < 1e-5);
+            assert!((point - center).dot(normal) > 0.49, "{:?} faces {:?}", point, normal);
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use std::cmp::Ordering;
 use std::sync::Arc;
 
+use rand::{Rng, RngCore};
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
-use crate::geometry::ray::Ray;
+use crate::geometry::ray::{Ray, RayKind};
 use crate::geometry::vector::{Point, Vector3};
 use crate::materials::material::Material;
+use crate::world::bvh_node::BVHNode;
 
 pub struct HitRecord {
     pub intersection: Point,
@@ -23,12 +26,51 @@ pub trait Hittable: Send + Sync {
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
         return Option::None;
     }
-    fn pdf_value(&self, o: Point, v: Vector3) -> f32 {
+    // Surface area, 0 where it isn't known
+    fn area(&self) -> f32 {
+        return 0.0;
+    }
+    // Material of the whole object, None for aggregates whose parts may differ
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::None;
+    }
+    // Offsets the ray from o by epsilon, the scene-scaled ray offset
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
         return 0.0;
     }
     fn random(&self, o: Vector3) -> Vector3 {
         return Vector3 { x: 1.0, y: 0.0, z: 0.0 };
     }
+    // Like random, driven by a point in [0, 1)^2 so stratified samples stay stratified.
+    // Objects that can't map one fall back to random.
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        return self.random(o);
+    }
+    // A point spread evenly over the surface by area, with its outward normal and the pdf of
+    // picking it per unit area. Lets lights of any shape be sampled from their surface.
+    // None for shapes without a sampler.
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return Option::None;
+    }
+    // Objects an aggregate is built over, empty for everything else
+    fn children(&self) -> Vec<&Arc<dyn Hittable>> {
+        return Vec::new();
+    }
+    // Lets BVH traversal step into nested nodes without going through hit
+    fn as_bvh_node(&self) -> Option<&BVHNode> {
+        return Option::None;
+    }
+    fn as_bvh_node_mut(&mut self) -> Option<&mut BVHNode> {
+        return Option::None;
+    }
+    // Render layer the object belongs to, see Layer
+    fn layer(&self) -> u32 {
+        return 0;
+    }
+    fn type_name(&self) -> &'static str {
+        let name = std::any::type_name::<Self>();
+        return name.rsplit("::").next().unwrap_or(name);
+    }
 }
 
 pub struct FlipFace {
@@ -46,9 +88,91 @@ impl Hittable for FlipFace {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
+    fn area(&self) -> f32 {
+        return self.object.area();
+    }
+
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
         return self.object.bounding_box(t0, t1);
     }
+
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
+        return self.object.pdf_value(o, v, epsilon);
+    }
+
+    fn random(&self, o: Vector3) -> Vector3 {
+        return self.object.random(o);
+    }
+
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        return self.object.random_from(o, sample);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let (point, normal, pdf) = self.object.sample_surface(rng)?;
+        return Option::from((point, -normal, pdf));
+    }
+
+    fn layer(&self) -> u32 {
+        return self.object.layer();
+    }
+}
+
+// Puts the wrapped object on a render layer other than the default 0, so passes can be rendered
+// for compositing with only some layers enabled
+pub struct Layer {
+    pub object: Arc<dyn Hittable>,
+    pub layer: u32,
+}
+
+impl Hittable for Layer {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        return self.object.hit(ray, t_min, t_max);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
+    fn area(&self) -> f32 {
+        return self.object.area();
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.object.bounding_box(t0, t1);
+    }
+
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
+        return self.object.pdf_value(o, v, epsilon);
+    }
+
+    fn random(&self, o: Vector3) -> Vector3 {
+        return self.object.random(o);
+    }
+
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        return self.object.random_from(o, sample);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return self.object.sample_surface(rng);
+    }
+
+    fn layer(&self) -> u32 {
+        return self.layer;
+    }
+}
+
+// Objects whose layer has its bit set in mask, layers past 31 are never selected
+pub fn on_layers(objects: &[Arc<dyn Hittable>], mask: u32) -> Vec<Arc<dyn Hittable>> {
+    return objects.iter()
+        .filter(|obj| 1u32.checked_shl(obj.layer()).map_or(false, |bit| mask & bit != 0))
+        .cloned()
+        .collect();
 }
 
 impl HitRecord {
@@ -60,30 +184,261 @@ impl HitRecord {
     }
 }
 
-fn box_compare(lhs: &Arc<dyn Hittable>, rhs: &Arc<dyn Hittable>, axis: usize) -> bool {
+// Orders by the box minimum on the axis, ties compare equal so a stable sort keeps them in input order
+fn box_compare(lhs: &Arc<dyn Hittable>, rhs: &Arc<dyn Hittable>, axis: usize) -> Ordering {
     let box_a = lhs.bounding_box(0.0, 0.0).unwrap();
     let box_b = rhs.bounding_box(0.0, 0.0).unwrap();
 
-    return box_a.minimum[axis] < box_b.minimum[axis];
+    return box_a.minimum[axis].partial_cmp(&box_b.minimum[axis]).unwrap_or(Ordering::Equal);
 }
 
 pub fn box_cmp_x(lhs: &Arc<dyn Hittable>, rhs: &Arc<dyn Hittable>) -> Ordering {
-    if box_compare(lhs, rhs, 0) {
-        return Ordering::Less;
-    }
-    return Ordering::Greater;
+    return box_compare(lhs, rhs, 0);
 }
 
 pub fn box_cmp_y(lhs: &Arc<dyn Hittable>, rhs: &Arc<dyn Hittable>) -> Ordering {
-    if box_compare(lhs, rhs, 1) {
-        return Ordering::Less;
-    }
-    return Ordering::Greater;
+    return box_compare(lhs, rhs, 1);
 }
 
 pub fn box_cmp_z(lhs: &Arc<dyn Hittable>, rhs: &Arc<dyn Hittable>) -> Ordering {
-    if box_compare(lhs, rhs, 2) {
-        return Ordering::Less;
+    return box_compare(lhs, rhs, 2);
+}
+
+// Which kinds of rays can see an object
+#[derive(Clone, Copy, Debug)]
+pub struct VisibilityFlags {
+    pub camera: bool,
+    pub shadow: bool,
+    pub reflection: bool,
+}
+
+impl VisibilityFlags {
+    pub const ALL: VisibilityFlags = VisibilityFlags { camera: true, shadow: true, reflection: true };
+
+    pub fn allows(&self, kind: RayKind) -> bool {
+        return match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadow,
+            RayKind::Reflection => self.reflection,
+        };
+    }
+}
+
+// Hides the wrapped object from the ray kinds its flags exclude, e.g. a shadow caster that
+// the camera looks straight through
+pub struct Visibility {
+    pub object: Arc<dyn Hittable>,
+    pub flags: VisibilityFlags,
+}
+
+impl Hittable for Visibility {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        if !self.flags.allows(ray.kind) {
+            return Option::None;
+        }
+        return self.object.hit(ray, t_min, t_max);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
+    fn area(&self) -> f32 {
+        return self.object.area();
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.object.bounding_box(t0, t1);
+    }
+
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
+        return self.object.pdf_value(o, v, epsilon);
+    }
+
+    fn random(&self, o: Vector3) -> Vector3 {
+        return self.object.random(o);
+    }
+
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        return self.object.random_from(o, sample);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return self.object.sample_surface(rng);
+    }
+
+    fn layer(&self) -> u32 {
+        return self.object.layer();
+    }
+}
+
+// Picks one of objects with probability proportional to its area and samples its surface, so
+// aggregates sample evenly over everything they hold. None when there is no area to pick
+// from or the picked object has no sampler.
+pub fn sample_by_area(objects: &[&Arc<dyn Hittable>], rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+    let areas: Vec<f32> = objects.iter().map(|obj| obj.area()).collect();
+    let total: f32 = areas.iter().sum();
+    if total <= 0.0 || !total.is_finite() {
+        return Option::None;
+    }
+    let mut target = rng.gen::<f32>() * total;
+    let mut k = 0;
+    while k + 1 < objects.len() && target >= areas[k] {
+        target -= areas[k];
+        k += 1;
+    }
+    let (point, normal, pdf) = objects[k].sample_surface(rng)?;
+    return Option::from((point, normal, pdf * areas[k] / total));
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::transform::Mat4;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::instances::{RotateY, Transformed, Translate};
+    use crate::objects::plane::GroundPlane;
+    use crate::objects::rectangle::XZRect;
+    use crate::output::framebuffer::Framebuffer;
+    use crate::objects::sphere::Sphere;
+    use crate::render::RenderSettings;
+    use crate::render::tests::{render_objects, test_camera, test_settings};
+    use crate::textures::solid::SolidColor;
+    use crate::utils::INF_F32;
+    use crate::world::hittable_list::HittableList;
+
+    fn floor() -> Arc<dyn Hittable> {
+        return Arc::new(XZRect { x: (-10.0, 10.0), z: (-10.0, 10.0), k: -1.0, material: Arc::new(Lambertian::new(Color { r: 0.8, g: 0.8, b: 0.8 })) });
+    }
+
+    fn ball() -> Arc<dyn Hittable> {
+        return Arc::new(Sphere { center: Point { x: 0.0, y: -0.5, z: 0.0 }, radius: 0.5, material: Arc::new(Lambertian::new(Color::WHITE)) });
+    }
+
+    #[test]
+    fn a_camera_hidden_object_still_casts_its_shadow() {
+        // Direct light only, so all the shading comes from shadow rays
+        let settings = RenderSettings { direct_only: true, ..test_settings(32, 32, 16) };
+        let hidden = Arc::new(Visibility { object: ball(), flags: VisibilityFlags { camera: false, ..VisibilityFlags::ALL } });
+        let bare = render_objects(&settings, vec![floor()]);
+        let visible = render_objects(&settings, vec![floor(), ball()]);
+        let ghost = render_objects(&settings, vec![floor(), hidden]);
+
+        // Pixels whose center sees the ball, and those that see the floor under it
+        let camera = test_camera(&settings);
+        let center_ray = |k: usize| camera.ray_through_pixel(k as u32 % 32, k as u32 / 32, 32, 32, (0.5, 0.5), Point::ORIGIN).unwrap();
+        let on_ball: Vec<usize> = (0..32 * 32).filter(|&k| ball().hit(center_ray(k), 0.001, INF_F32).is_some()).collect();
+        let under_ball: Vec<usize> = (0..32 * 32).filter(|&k| {
+            floor().hit(center_ray(k), 0.001, INF_F32).is_some_and(|hit_rec| {
+                let p = hit_rec.intersection;
+                p.x * p.x + p.z * p.z < 0.3 * 0.3
+            })
+        }).collect();
+        assert!(on_ball.len() > 20 && under_ball.len() > 5);
+
+        // The camera looks through the ball as if it were not there
+        for &k in on_ball.iter() {
+            assert_eq!(ghost.coverage[k], bare.coverage[k], "pixel {}", k);
+        }
+        assert!(on_ball.iter().any(|&k| visible.coverage[k] > bare.coverage[k]));
+
+        // but the floor beneath it is in shadow
+        let mean = |framebuffer: &Framebuffer, pixels: &[usize]| pixels.iter().map(|&k| framebuffer.pixels[k].luminance()).sum::<f32>() / pixels.len() as f32;
+        assert!(mean(&ghost, &under_ball) < 0.5 * mean(&bare, &under_ball), "{} shadowed, {} lit", mean(&ghost, &under_ball), mean(&bare, &under_ball));
+    }
+
+    #[test]
+    fn shadow_flags_hold_for_light_samples_mixed_into_the_bounce() {
+        // Default settings, one light sample drawn as the bounce direction
+        let settings = test_settings(32, 32, 16);
+        assert!(!settings.direct_only && settings.light_samples == 1);
+        let hidden = |flags: VisibilityFlags| -> Arc<dyn Hittable> {
+            return Arc::new(Visibility { object: ball(), flags: VisibilityFlags { camera: false, ..flags } });
+        };
+        let bare = render_objects(&settings, vec![floor()]);
+        let caster = render_objects(&settings, vec![floor(), hidden(VisibilityFlags::ALL)]);
+        let no_shadow = render_objects(&settings, vec![floor(), hidden(VisibilityFlags { shadow: false, ..VisibilityFlags::ALL })]);
+        let no_reflection = render_objects(&settings, vec![floor(), hidden(VisibilityFlags { reflection: false, ..VisibilityFlags::ALL })]);
+
+        let camera = test_camera(&settings);
+        let center_ray = |k: usize| camera.ray_through_pixel(k as u32 % 32, k as u32 / 32, 32, 32, (0.5, 0.5), Point::ORIGIN).unwrap();
+        let under_ball: Vec<usize> = (0..32 * 32).filter(|&k| {
+            floor().hit(center_ray(k), 0.001, INF_F32).is_some_and(|hit_rec| {
+                let p = hit_rec.intersection;
+                p.x * p.x + p.z * p.z < 0.3 * 0.3
+            })
+        }).collect();
+        let mean = |framebuffer: &Framebuffer| under_ball.iter().map(|&k| framebuffer.pixels[k].luminance()).sum::<f32>() / under_ball.len() as f32;
+
+        // Most of the light under the ball arrives along light samples, which pass a ball
+        // without shadows and are stopped by one that only hides from reflections
+        assert!(mean(&caster) < 0.5 * mean(&bare), "{} shadowed, {} lit", mean(&caster), mean(&bare));
+        assert!(mean(&no_shadow) > 0.7 * mean(&bare), "{} without shadows, {} lit", mean(&no_shadow), mean(&bare));
+        assert!(mean(&no_reflection) < 0.5 * mean(&bare), "{} without reflections, {} lit", mean(&no_reflection), mean(&bare));
+    }
+
+    #[test]
+    fn a_layer_zero_render_leaves_layer_one_out() {
+        let background: Arc<dyn Hittable> = Arc::new(Layer { object: ball(), layer: 1 });
+        let objects = vec![floor(), background.clone()];
+        assert_eq!(on_layers(&objects, 0b01).len(), 1);
+        assert!(Arc::ptr_eq(&on_layers(&objects, 0b10)[0], &background));
+        assert_eq!(on_layers(&objects, u32::MAX).len(), 2);
+        assert!(on_layers(&[Arc::new(Layer { object: ball(), layer: 40 }) as Arc<dyn Hittable>], u32::MAX).is_empty());
+
+        // Wrapping a tagged object keeps it on its layer
+        let offset = Point { x: 0.5, y: 0.0, z: 0.0 };
+        let wrapped: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(FlipFace { object: background.clone() }),
+            Arc::new(Visibility { object: background.clone(), flags: VisibilityFlags::ALL }),
+            Arc::new(Translate { object: background.clone(), offset }),
+            Arc::new(RotateY::new(background.clone(), 30.0)),
+            Arc::new(Transformed::new(background.clone(), Mat4::translation(offset)).unwrap()),
+        ];
+        for obj in wrapped.iter() {
+            assert_eq!(obj.layer(), 1, "{}", obj.type_name());
+        }
+        assert!(on_layers(&wrapped, 0b01).is_empty());
+
+        // and the layer-0 pass is exactly the render without it
+        let settings = test_settings(16, 16, 4);
+        let layer_zero = render_objects(&settings, on_layers(&objects, 0b01));
+        let bare = render_objects(&settings, vec![floor()]);
+        let everything = render_objects(&settings, objects);
+        assert_eq!(layer_zero.pixels, bare.pixels);
+        assert_eq!(layer_zero.coverage, bare.coverage);
+        assert!(everything.pixels != bare.pixels);
+    }
+
+    #[test]
+    fn shapes_without_a_sampler_give_no_surface_samples() {
+        let mut rng = StdRng::seed_from_u64(274);
+        let plane: Arc<dyn Hittable> = Arc::new(GroundPlane::new(-1.0, Arc::new(SolidColor { color: Color::WHITE })));
+        assert!(plane.sample_surface(&mut rng).is_none());
+        assert!(FlipFace { object: plane.clone() }.sample_surface(&mut rng).is_none());
+        assert!(Translate { object: plane.clone(), offset: Point { x: 1.0, y: 0.0, z: 0.0 } }.sample_surface(&mut rng).is_none());
+
+        // Nothing to pick from, and no area to pick by
+        let empty = HittableList::new();
+        assert!(sample_by_area(&[], &mut rng).is_none());
+        assert!(empty.sample_surface(&mut rng).is_none());
+        assert!(sample_by_area(&[&plane, &plane], &mut rng).is_none());
+
+        // An empty lights list gives a direction of pdf 0 rather than panicking
+        let o = Point::ORIGIN;
+        assert_eq!(empty.pdf_value(o, empty.random(o), 1e-4), 0.0);
+        assert_eq!(empty.pdf_value(o, empty.random_from(o, (0.99, 0.5)), 1e-4), 0.0);
+
+        // An object with no area is never picked, so a sampled one beside it still samples
+        let ball = ball();
+        for _ in 0..100 {
+            let (point, _, pdf) = sample_by_area(&[&plane, &ball], &mut rng).unwrap();
+            assert!((point - Point { x: 0.0, y: -0.5, z: 0.0 }).length() < 0.5 + 1e-5);
+            assert!((pdf - 1.0 / ball.area()).abs() < 1e-5);
+        }
     }
-    return Ordering::Greater;
 }
@@ -1,8 +1,12 @@
 use std::sync::Arc;
 
+use rand::RngCore;
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::ray::Ray;
-use crate::geometry::vector::Point;
+use crate::geometry::transform::Mat4;
+use crate::geometry::vector::{Point, Vector3};
+use crate::materials::material::Material;
 use crate::objects::hittable::{HitRecord, Hittable};
 use crate::utils::{degrees_to_radians, INF_F32};
 
@@ -17,6 +21,7 @@ impl Translate {
             origin: in_ray.origin - self.offset,
             direction: in_ray.direction,
             time: in_ray.time,
+            kind: in_ray.kind,
         };
     }
 }
@@ -35,6 +40,19 @@ impl Hittable for Translate {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
+    fn area(&self) -> f32 {
+        return self.object.area();
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let (point, normal, pdf) = self.object.sample_surface(rng)?;
+        return Option::from((point + self.offset, normal, pdf));
+    }
+
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
         let opt_bound_box = self.object.bounding_box(t0, t1);
         if opt_bound_box.is_none() {
@@ -46,6 +64,10 @@ impl Hittable for Translate {
             maximum: bound_box.maximum + self.offset,
         });
     }
+
+    fn layer(&self) -> u32 {
+        return self.object.layer();
+    }
 }
 
 pub struct RotateY {
@@ -88,10 +110,8 @@ impl RotateY {
                     let new_z = -sin_theta * x + cos_theta * z;
 
                     let tester = Point { x: new_x, y, z: new_z };
-                    for c in 0..3 {
-                        min[c] = min[c].min(tester[c]);
-                        max[c] = max[c].max(tester[c]);
-                    }
+                    min = min.min(tester);
+                    max = max.max(tester);
                 }
             }
         }
@@ -113,7 +133,7 @@ impl Hittable for RotateY {
         direction[0] = self.cos_theta * ray.direction[0] - self.sin_theta * ray.direction[2];
         direction[2] = self.sin_theta * ray.direction[0] + self.cos_theta * ray.direction[2];
 
-        let rotated_ray = Ray { origin, direction, time: ray.time };
+        let rotated_ray = Ray { origin, direction, time: ray.time, kind: ray.kind };
         let opt_hit_rec = self.object.hit(rotated_ray, t_min, t_max);
         if opt_hit_rec.is_none() {
             return Option::None;
@@ -131,12 +151,232 @@ impl Hittable for RotateY {
 
         hit_rec.intersection = intersection;
         hit_rec.normal = normal;
-        hit_rec.set_face_normal(rotated_ray);
+        // The normal is back in world space, so it is faced against the world ray
+        hit_rec.set_face_normal(ray);
+
+        return Option::from(hit_rec);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
+    fn area(&self) -> f32 {
+        return self.object.area();
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.bound_box.clone();
+    }
+
+    fn layer(&self) -> u32 {
+        return self.object.layer();
+    }
+}
+
+// Object placed by an arbitrary affine transform, e.g. a loaded mesh scaled and turned about a
+// tilted axis. Rays go to object space through the inverse without being renormalized, so hit
+// distances carry over, and normals come back through the inverse transpose.
+pub struct Transformed {
+    object: Arc<dyn Hittable>,
+    transform: Mat4,
+    inverse: Mat4,
+    normal_transform: Mat4,
+    bound_box: Option<AxisAlignedBoundingBox>,
+}
+
+impl Transformed {
+    // None when the transform has no inverse, e.g. a zero scale flattening the object
+    pub fn new(object: Arc<dyn Hittable>, transform: Mat4) -> Option<Self> {
+        let inverse = transform.inverse()?;
+        let bound_box = object.bounding_box(0.0, 1.0).map(|bound_box| {
+            let mut min = Point { x: INF_F32, y: INF_F32, z: INF_F32 };
+            let mut max = Point { x: -INF_F32, y: -INF_F32, z: -INF_F32 };
+            for i in 0..8 {
+                let corner = Point {
+                    x: if i & 1 == 0 { bound_box.minimum.x } else { bound_box.maximum.x },
+                    y: if i & 2 == 0 { bound_box.minimum.y } else { bound_box.maximum.y },
+                    z: if i & 4 == 0 { bound_box.minimum.z } else { bound_box.maximum.z },
+                };
+                let tester = transform.transform_point(corner);
+                min = min.min(tester);
+                max = max.max(tester);
+            }
+            return AxisAlignedBoundingBox { minimum: min, maximum: max };
+        });
+
+        return Option::from(Self {
+            object,
+            transform,
+            inverse,
+            normal_transform: inverse.transpose(),
+            bound_box,
+        });
+    }
+}
+
+impl Hittable for Transformed {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let local_ray = Ray {
+            origin: self.inverse.transform_point(ray.origin),
+            direction: self.inverse.transform_vector(ray.direction),
+            time: ray.time,
+            kind: ray.kind,
+        };
+        let opt_hit_rec = self.object.hit(local_ray, t_min, t_max);
+        if opt_hit_rec.is_none() {
+            return Option::None;
+        }
 
+        // The inverse transpose keeps the normal facing against the ray, so front_face carries over
+        let mut hit_rec = opt_hit_rec.unwrap();
+        hit_rec.intersection = self.transform.transform_point(hit_rec.intersection);
+        hit_rec.normal = self.normal_transform.transform_vector(hit_rec.normal).direction();
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return self.object.material();
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         return self.bound_box.clone();
     }
+
+    fn layer(&self) -> u32 {
+        return self.object.layer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::RayKind;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::boxes::AxisAlignedBox;
+    use crate::objects::sphere::Sphere;
+
+    fn ray(origin: Point, direction: Vector3) -> Ray {
+        return Ray { origin, direction, time: 0.0, kind: RayKind::Camera };
+    }
+
+    fn assert_close(found: Vector3, expected: Vector3) {
+        assert!((found - expected).length() < 1e-4, "{:?} instead of {:?}", found, expected);
+    }
+
+    #[test]
+    fn translated_spheres_are_hit_where_they_moved_to() {
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color::WHITE)) });
+        let offset = Point { x: 2.0, y: 1.0, z: -3.0 };
+        let moved = Translate { object: sphere, offset };
+        let down_z = Vector3 { x: 0.0, y: 0.0, z: -1.0 };
+
+        let hit_rec = moved.hit(ray(offset + Point { x: 0.0, y: 0.0, z: 5.0 }, down_z), 0.001, INF_F32).unwrap();
+        assert_close(hit_rec.intersection, offset + Point { x: 0.0, y: 0.0, z: 1.0 });
+        assert_close(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert!(hit_rec.front_face);
+        // Where the sphere used to be there is nothing left
+        assert!(moved.hit(ray(Point { x: 0.0, y: 0.0, z: 5.0 }, down_z), 0.001, INF_F32).is_none());
+
+        let bound_box = moved.bounding_box(0.0, 1.0).unwrap();
+        assert_close(bound_box.minimum, Point { x: 1.0, y: 0.0, z: -4.0 });
+        assert_close(bound_box.maximum, Point { x: 3.0, y: 2.0, z: -2.0 });
+    }
+
+    #[test]
+    fn rotated_boxes_turn_about_y() {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let cuboid: Arc<dyn Hittable> = Arc::new(AxisAlignedBox::new(Point::ORIGIN, Point { x: 2.0, y: 1.0, z: 1.0 }, material));
+        let down_z = Vector3 { x: 0.0, y: 0.0, z: -1.0 };
+
+        // A quarter turn takes +x to -z, so the box now spans x in [0, 1] and z in [-2, 0]
+        let turned = RotateY::new(cuboid.clone(), 90.0);
+        let hit_rec = turned.hit(ray(Point { x: 0.5, y: 0.5, z: 5.0 }, down_z), 0.001, INF_F32).unwrap();
+        assert_close(hit_rec.intersection, Point { x: 0.5, y: 0.5, z: 0.0 });
+        assert_close(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert!(hit_rec.front_face);
+        assert!(turned.hit(ray(Point { x: 1.5, y: 0.5, z: 5.0 }, down_z), 0.001, INF_F32).is_none());
+        assert!(cuboid.hit(ray(Point { x: 1.5, y: 0.5, z: 5.0 }, down_z), 0.001, INF_F32).is_some());
+        let bound_box = turned.bounding_box(0.0, 1.0).unwrap();
+        assert_close(bound_box.minimum, Point { x: 0.0, y: 0.0, z: -2.0 });
+        assert_close(bound_box.maximum, Point { x: 1.0, y: 1.0, z: 0.0 });
+
+        // At any other angle the box still holds every hit
+        let tilted = RotateY::new(cuboid, 30.0);
+        let bound_box = tilted.bounding_box(0.0, 1.0).unwrap();
+        let mut rng = StdRng::seed_from_u64(265);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let target = Point { x: rng.gen_range(-1.0..3.0), y: rng.gen_range(-1.0..2.0), z: rng.gen_range(-3.0..2.0) };
+            let origin = target + 6.0 * Vector3::random_unit_vector_with(&mut rng);
+            if let Some(hit_rec) = tilted.hit(ray(origin, target - origin), 0.001, INF_F32) {
+                hits += 1;
+                for a in 0..3 {
+                    let p = hit_rec.intersection[a];
+                    assert!(p >= bound_box.minimum[a] - 1e-4 && p <= bound_box.maximum[a] + 1e-4);
+                }
+            }
+        }
+        assert!(hits > 50, "only {} rays hit the tilted box", hits);
+    }
+
+    #[test]
+    fn transformed_matches_rotate_y_and_translate() {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let cuboid: Arc<dyn Hittable> = Arc::new(AxisAlignedBox::new(Point::ORIGIN, Point { x: 2.0, y: 1.0, z: 1.0 }, material));
+        let offset = Point { x: 1.0, y: -0.5, z: 2.0 };
+        let instanced = Translate { object: Arc::new(RotateY::new(cuboid.clone(), 35.0)), offset };
+        let transform = Mat4::compose(&[Mat4::rotation_y(35.0), Mat4::translation(offset)]);
+        let transformed = Transformed::new(cuboid, transform).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(266);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let target = offset + Point { x: rng.gen_range(-2.0..2.0), y: rng.gen_range(-1.0..2.0), z: rng.gen_range(-2.0..2.0) };
+            let origin = target + 6.0 * Vector3::random_unit_vector_with(&mut rng);
+            let r = ray(origin, target - origin);
+            match (instanced.hit(r, 0.001, INF_F32), transformed.hit(r, 0.001, INF_F32)) {
+                (Some(expected), Some(found)) => {
+                    hits += 1;
+                    assert!((found.t - expected.t).abs() < 1e-4);
+                    assert_close(found.intersection, expected.intersection);
+                    assert_close(found.normal, expected.normal);
+                    assert_eq!(found.front_face, expected.front_face);
+                }
+                (None, None) => {}
+                (expected, found) => panic!("hit {} by the instances, {} by the transform", expected.is_some(), found.is_some()),
+            }
+        }
+        assert!(hits > 100, "only {} rays hit the box", hits);
+        let (expected, found) = (instanced.bounding_box(0.0, 1.0).unwrap(), transformed.bounding_box(0.0, 1.0).unwrap());
+        assert_close(found.minimum, expected.minimum);
+        assert_close(found.maximum, expected.maximum);
+    }
+
+    #[test]
+    fn stretched_normals_stay_perpendicular_to_the_surface() {
+        // A unit sphere stretched to an ellipsoid with semi-axes 3, 1 and 0.5, whose surface
+        // normal at p is along (x / 9, y, z / 0.25)
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color::WHITE)) });
+        let ellipsoid = Transformed::new(sphere.clone(), Mat4::scaling(Vector3 { x: 3.0, y: 1.0, z: 0.5 })).unwrap();
+        let mut rng = StdRng::seed_from_u64(2662);
+        for _ in 0..200 {
+            let origin = 8.0 * Vector3::random_unit_vector_with(&mut rng);
+            let target = Point { x: rng.gen_range(-1.0..1.0), y: rng.gen_range(-0.5..0.5), z: rng.gen_range(-0.2..0.2) };
+            let hit_rec = ellipsoid.hit(ray(origin, target - origin), 0.001, INF_F32).unwrap();
+            let p = hit_rec.intersection;
+            assert!((p.x * p.x / 9.0 + p.y * p.y + p.z * p.z / 0.25 - 1.0).abs() < 1e-3, "{:?} is off the surface", p);
+            let outward = Vector3 { x: p.x / 9.0, y: p.y, z: p.z / 0.25 }.direction();
+            // Hit from outside, so the normal points out
+            assert!(hit_rec.front_face);
+            assert_close(hit_rec.normal, outward);
+        }
+
+        // Flattening the sphere to nothing has no inverse to send rays back through
+        assert!(Transformed::new(sphere, Mat4::scaling(Vector3 { x: 1.0, y: 0.0, z: 1.0 })).is_none());
+    }
 }
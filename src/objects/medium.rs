@@ -1,13 +1,14 @@
 use crate::objects::hittable::{Hittable, HitRecord};
 use std::sync::Arc;
-use crate::materials::material::Material;
+use crate::materials::material::{Material, ScatterRecord};
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
-use crate::geometry::ray::Ray;
-use crate::utils::{INF_F32, random_f32};
-use crate::geometry::vector::Vector3;
+use crate::geometry::ray::{Ray, RayKind};
+use crate::utils::{INF_F32, random_f32, random_int};
+use crate::geometry::vector::{Point, Vector3};
 use crate::geometry::color::Color;
 use crate::textures::solid::SolidColor;
 use crate::materials::isotropic::Isotropic;
+use crate::textures::perlin::Perlin;
 
 pub struct ConstMedium {
     pub boundary: Arc<dyn Hittable>,
@@ -66,7 +67,431 @@ impl Hittable for ConstMedium {
         });
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.phase_function);
+    }
+
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
         return self.boundary.bounding_box(t0, t1);
     }
 }
+
+// A fuzzy ball of scattering particles whose density falls off as a gaussian of
+// width sigma around the center, cut off at radius. Unlike ConstMedium the
+// density varies, so free flights are sampled by delta tracking against the peak
+// density along the ray.
+pub struct SoftSphere {
+    pub center: Point,
+    pub radius: f32,
+    pub sigma: f32,
+    pub density: f32,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl SoftSphere {
+    pub fn new(center: Point, radius: f32, sigma: f32, density: f32, color: Color) -> Self {
+        return Self {
+            center,
+            radius,
+            sigma,
+            density,
+            phase_function: Arc::new(Isotropic { albedo: Arc::new(SolidColor { color }) }),
+        };
+    }
+
+    fn density_at_distance_sq(&self, dist_sq: f32) -> f32 {
+        if dist_sq > self.radius.powi(2) {
+            return 0.0;
+        }
+        return self.density * (-dist_sq / (2.0 * self.sigma.powi(2))).exp();
+    }
+}
+
+impl Hittable for SoftSphere {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let oc = ray.origin - self.center;
+        let a = ray.direction.length_squared();
+        let b = oc.dot(ray.direction);
+        let closest_sq = (oc - (b / a) * ray.direction).length_squared();
+        if closest_sq > self.radius.powi(2) {
+            return Option::None;
+        }
+
+        // Chord through the cutoff sphere, clipped to the ray interval
+        let half_chord = ((self.radius.powi(2) - closest_sq) / a).sqrt();
+        let t_start = (-b / a - half_chord).max(t_min).max(0.0);
+        let t_end = (-b / a + half_chord).min(t_max);
+        if t_start >= t_end {
+            return Option::None;
+        }
+
+        // Nothing along the ray is denser than its closest approach to the center
+        let majorant = self.density_at_distance_sq(closest_sq);
+        if majorant <= 0.0 {
+            return Option::None;
+        }
+        let speed = a.sqrt();
+        let mut t = t_start;
+        loop {
+            t -= random_f32().ln() / (majorant * speed);
+            if t >= t_end {
+                return Option::None;
+            }
+            let dist_sq = (ray.at_distance(t) - self.center).length_squared();
+            if random_f32() * majorant < self.density_at_distance_sq(dist_sq) {
+                break;
+            }
+        }
+
+        return Option::from(HitRecord {
+            intersection: ray.at_distance(t),
+            normal: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            material: self.phase_function.clone(),
+            t,
+            u: 0.0,
+            v: 0.0,
+            front_face: true
+        });
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.phase_function);
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        let p = Point { x: self.radius, y: self.radius, z: self.radius };
+        return Option::from(AxisAlignedBoundingBox {
+            minimum: self.center - p,
+            maximum: self.center + p,
+        });
+    }
+}
+
+// A box of cloud-like scattering particles whose density follows Perlin turbulence, so it
+// thins out and thickens patchily instead of being uniform like ConstMedium. The density has
+// no cheap bound, so rays march through the box in fixed steps summing up optical depth.
+pub struct NoiseVolume {
+    pub bounds: AxisAlignedBoundingBox,
+    pub noise: Perlin,
+    // Frequency of the noise, as for NoiseTexture
+    pub scale: f32,
+    // Density where the turbulence is 1
+    pub density_scale: f32,
+    pub phase_function: Arc<dyn Material>,
+}
+
+impl NoiseVolume {
+    // Steps per crossing of the box, more resolves finer wisps but costs a noise lookup each
+    const MARCH_STEPS: u32 = 64;
+    const TURBULENCE_OCTAVES: u32 = 5;
+
+    pub fn new(bounds: AxisAlignedBoundingBox, noise: Perlin, scale: f32, density_scale: f32, color: Color) -> Self {
+        return Self {
+            bounds,
+            noise,
+            scale,
+            density_scale,
+            phase_function: Arc::new(Isotropic { albedo: Arc::new(SolidColor { color }) }),
+        };
+    }
+
+    pub fn density(&self, point: Point) -> f32 {
+        return self.density_scale * self.noise.turb(self.scale * point, Self::TURBULENCE_OCTAVES);
+    }
+
+    // Share of light along the ray between t_min and t_max absorbed or scattered away by the volume
+    pub fn opacity(&self, ray: Ray, t_min: f32, t_max: f32) -> f32 {
+        let (optical_depth, _) = self.march(ray, t_min, t_max, 0.5, INF_F32);
+        return 1.0 - (-optical_depth).exp();
+    }
+
+    // Sums density over the part of the ray inside the box, sampling each step at offset
+    // through it, and stops at the first step where the sum passes target. Returns the sum and
+    // where it stopped, if it did.
+    fn march(&self, ray: Ray, t_min: f32, t_max: f32, offset: f32, target: f32) -> (f32, Option<f32>) {
+        let (t_start, t_end) = match self.bounds.hit_interval(ray, t_min, t_max) {
+            Some(interval) => interval,
+            None => return (0.0, Option::None),
+        };
+        let dt = (t_end - t_start) / Self::MARCH_STEPS as f32;
+        let step_length = dt * ray.direction.length();
+        let mut optical_depth = 0.0;
+        for k in 0..Self::MARCH_STEPS {
+            let t = t_start + (k as f32 + offset) * dt;
+            optical_depth += self.density(ray.at_distance(t)) * step_length;
+            if optical_depth >= target {
+                return (optical_depth, Option::from(t));
+            }
+        }
+        return (optical_depth, Option::None);
+    }
+}
+
+impl Hittable for NoiseVolume {
+    // Scatters where the optical depth passes an exponentially distributed target, the marching
+    // counterpart of the free flight ConstMedium samples. A random offset within the steps
+    // keeps the fixed step length from showing up as bands.
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let target = -random_f32().ln();
+        let t = self.march(ray, t_min.max(0.0), t_max, random_f32(), target).1?;
+        return Option::from(HitRecord {
+            intersection: ray.at_distance(t),
+            normal: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            material: self.phase_function.clone(),
+            t,
+            u: 0.0,
+            v: 0.0,
+            front_face: true
+        });
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.phase_function);
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return Option::from(self.bounds.clone());
+    }
+}
+
+// How a ChromaticMedium draws the distance a ray goes before scattering
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelSampling {
+    // One distance for all three channels, from their mean extinction. Channels far from the
+    // mean then carry weights that swing exponentially with the distance, which shows up as
+    // speckles tinted toward whichever channel got lucky.
+    Shared,
+    // A channel picked at random for each flight draws the distance, and every channel is
+    // weighted by how likely any of the three was to draw it (the balance heuristic), so no
+    // weight grows past three times what that channel's own sampling would give.
+    Hero,
+}
+
+// A uniform medium, like ConstMedium, whose extinction differs by channel. Inside a Dielectric
+// it makes strongly tinted glass. The channels fall off at different rates, so a flight ends
+// with a weight per channel either way: scattering, or leaving the far side of the boundary,
+// where the ray carries on unchanged to whatever wraps the medium. The boundary should hold
+// nothing else and sit just inside any surface around it, so the two never meet at one t.
+pub struct ChromaticMedium {
+    pub boundary: Arc<dyn Hittable>,
+    // Extinction of each channel per unit length
+    pub extinction: Color,
+    // Share of the extinction that scatters rather than absorbs
+    pub albedo: Color,
+    pub sampling: ChannelSampling,
+}
+
+impl Hittable for ChromaticMedium {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let entry = self.boundary.hit(ray, -INF_F32, INF_F32)?;
+        let exit = self.boundary.hit(ray, entry.t + 0.0001, INF_F32)?;
+        let t_entry = entry.t.max(t_min).max(0.0);
+        if t_entry >= exit.t.min(t_max) {
+            return Option::None;
+        }
+
+        let speed = ray.direction.length();
+        let dist_inside_boundary = (exit.t - t_entry) * speed;
+        let sigma = self.extinction;
+        let mean_sigma = (sigma.r + sigma.g + sigma.b) / 3.0;
+        let sampled_sigma = match self.sampling {
+            ChannelSampling::Shared => mean_sigma,
+            ChannelSampling::Hero => [sigma.r, sigma.g, sigma.b][random_int(0, 2) as usize],
+        };
+        let distance = (-random_f32().ln() / sampled_sigma).min(dist_inside_boundary);
+        let scatters = distance < dist_inside_boundary;
+        let t = if scatters { t_entry + distance / speed } else { exit.t };
+        if t > t_max {
+            return Option::None;
+        }
+
+        // For an extinction, the density of stopping at distance or the chance of going past it
+        let stop = |s: f32| if scatters { s * (-s * distance).exp() } else { (-s * distance).exp() };
+        let per_channel = sigma.map(stop);
+        let chance = match self.sampling {
+            ChannelSampling::Shared => stop(mean_sigma),
+            ChannelSampling::Hero => (per_channel.r + per_channel.g + per_channel.b) / 3.0,
+        };
+        let weight = if chance <= 0.0 {
+            Color::BLACK
+        } else if scatters {
+            (1.0 / chance) * (self.albedo * per_channel)
+        } else {
+            (1.0 / chance) * per_channel
+        };
+        return Option::from(HitRecord {
+            intersection: ray.at_distance(t),
+            normal: Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            material: Arc::new(Flight { weight, scatters }),
+            t,
+            u: 0.0,
+            v: 0.0,
+            front_face: true
+        });
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.boundary.bounding_box(t0, t1);
+    }
+}
+
+// The end of one flight through a ChromaticMedium: an isotropic bounce, or the ray going on
+// as it was, with the flight's weight
+struct Flight {
+    weight: Color,
+    scatters: bool,
+}
+
+impl Material for Flight {
+    fn scatter(&self, in_ray: Ray, hit_rec: &HitRecord) -> Option<ScatterRecord> {
+        let (direction, kind) = if self.scatters { (Vector3::random_unit_vector(), RayKind::Reflection) } else { (in_ray.direction, in_ray.kind) };
+        return Option::from(ScatterRecord {
+            specular_ray: Option::from(Ray { origin: hit_rec.intersection, direction, time: in_ray.time, kind }),
+            attenuation: self.weight,
+            pdf_ptr: None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::dielectric::Dielectric;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::sphere::Sphere;
+    use crate::output::framebuffer::Framebuffer;
+    use crate::render::render;
+    use crate::render::tests::{test_camera, test_settings};
+    use crate::utils::seed_thread_rng;
+    use crate::world::environment::SolidEnvironment;
+    use crate::world::hittable_list::HittableList;
+
+    // Chance a ray along z, passing offset from the center, scatters somewhere in the sphere
+    fn scatter_rate(sphere: &SoftSphere, offset: f32) -> f32 {
+        let ray = Ray { origin: Point { x: offset, y: 0.0, z: -5.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, time: 0.0, kind: RayKind::Camera };
+        let trials = 4000;
+        return (0..trials).filter(|_| sphere.hit(ray, 0.001, INF_F32).is_some()).count() as f32 / trials as f32;
+    }
+
+    // 1 - exp(-optical depth), summing the density along that chord
+    fn expected_rate(sphere: &SoftSphere, offset: f32) -> f32 {
+        let steps = 1000;
+        let ds = 2.0 * sphere.radius / steps as f32;
+        let depth: f32 = (0..steps).map(|k| {
+            let s = -sphere.radius + (k as f32 + 0.5) * ds;
+            sphere.density_at_distance_sq(offset * offset + s * s) * ds
+        }).sum();
+        return 1.0 - (-depth).exp();
+    }
+
+    #[test]
+    fn rays_through_the_center_scatter_more_than_grazing_ones() {
+        seed_thread_rng(Option::from(236));
+        let sphere = SoftSphere::new(Point::ORIGIN, 1.0, 0.5, 2.0, Color::WHITE);
+        let (center, grazing) = (scatter_rate(&sphere, 0.0), scatter_rate(&sphere, 0.8));
+        assert!(center > grazing + 0.3, "center {} grazing {}", center, grazing);
+        assert!((center - expected_rate(&sphere, 0.0)).abs() < 0.03, "center {} expected {}", center, expected_rate(&sphere, 0.0));
+        assert!((grazing - expected_rate(&sphere, 0.8)).abs() < 0.03, "grazing {} expected {}", grazing, expected_rate(&sphere, 0.8));
+        assert_eq!(scatter_rate(&sphere, 1.1), 0.0);
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn dense_media_scatter_most_rays_and_thin_ones_let_them_through() {
+        seed_thread_rng(Option::from(264));
+        let ball = |density: f32| ConstMedium::new(
+            Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color::WHITE)) }),
+            density,
+            Color::WHITE,
+        );
+        let ray = Ray { origin: Point { x: 0.0, y: 0.0, z: -5.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, time: 0.0, kind: RayKind::Camera };
+        let trials = 2000;
+        let scattered = |medium: &ConstMedium| (0..trials).filter(|_| medium.hit(ray, 0.001, INF_F32).is_some()).count() as f32 / trials as f32;
+
+        let dense = ball(10.0);
+        assert!(scattered(&dense) > 0.99, "{} of rays scattered in the dense medium", scattered(&dense));
+        let thin = ball(1e-3);
+        assert!(scattered(&thin) < 0.01, "{} of rays scattered in the thin medium", scattered(&thin));
+        // Anywhere in between, the chance follows the 2 units of chord as 1 - exp(-2 * density)
+        let half = ball(2.0f32.ln() / 2.0);
+        assert!((scattered(&half) - 0.5).abs() < 0.04, "{} of rays scattered, half expected", scattered(&half));
+
+        // Scatter points lie inside the boundary, and the phase function sends rays every way
+        let mut mean_direction = Vector3::ORIGIN;
+        for _ in 0..trials {
+            let hit_rec = dense.hit(ray, 0.001, INF_F32).unwrap();
+            assert!(hit_rec.intersection.length() <= 1.0 + 1e-4);
+            let scatter_rec = hit_rec.material.scatter(ray, &hit_rec).unwrap();
+            mean_direction += scatter_rec.specular_ray.unwrap().direction;
+        }
+        assert!(mean_direction.length() / (trials as f32) < 0.05, "scattered rays lean {:?}", mean_direction);
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn rays_through_dense_noise_are_more_opaque_than_through_thin_noise() {
+        seed_thread_rng(Option::from(275));
+        let bounds = AxisAlignedBoundingBox { minimum: Point { x: -5.0, y: -5.0, z: -5.0 }, maximum: Point { x: 5.0, y: 5.0, z: 5.0 } };
+        let volume = NoiseVolume::new(bounds, Perlin::seeded(275), 0.4, 0.3, Color::WHITE);
+        let ray_at = |x: f32, y: f32| Ray { origin: Point { x, y, z: -8.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, time: 0.0, kind: RayKind::Camera };
+
+        // The densest and thinnest of a grid of parallel rays through the same box
+        let rays: Vec<Ray> = (0..400).map(|k| ray_at(-4.75 + 0.5 * (k % 20) as f32, -4.75 + 0.5 * (k / 20) as f32)).collect();
+        let opacity = |ray: Ray| volume.opacity(ray, 0.001, INF_F32);
+        let by_opacity = |a: &&Ray, b: &&Ray| opacity(**a).partial_cmp(&opacity(**b)).unwrap();
+        let thick = *rays.iter().max_by(by_opacity).unwrap();
+        let thin = *rays.iter().min_by(by_opacity).unwrap();
+        assert!(opacity(thick) > opacity(thin) + 0.3, "thickest {} thinnest {}", opacity(thick), opacity(thin));
+        assert_eq!(opacity(ray_at(6.0, 0.0)), 0.0);
+        assert!(volume.opacity(thick, 0.001, 5.0) < opacity(thick));
+
+        for ray in [thick, thin] {
+            // 1 - exp(-optical depth), summing the density finely along the 10 units inside
+            let depth: f32 = (0..2000).map(|k| volume.density(ray.at_distance(3.0 + (k as f32 + 0.5) * 0.005)) * 0.005).sum();
+            assert!((opacity(ray) - (1.0 - (-depth).exp())).abs() < 0.02, "marched {} integrated {}", opacity(ray), 1.0 - (-depth).exp());
+            // and rays scatter as often as the opacity says they are stopped
+            let trials = 4000;
+            let scattered = (0..trials).filter(|_| volume.hit(ray, 0.001, INF_F32).is_some()).count() as f32 / trials as f32;
+            assert!((scattered - opacity(ray)).abs() < 0.03, "{} scattered, opacity {}", scattered, opacity(ray));
+        }
+        seed_thread_rng(Option::None);
+    }
+
+    // A unit ball of deep red glass, barely letting green and blue through, against white
+    fn render_tinted_glass(sampling: ChannelSampling, spp: u32) -> Framebuffer {
+        let mut settings = test_settings(8, 8, spp);
+        settings.max_depth = 32;
+        let mut world = HittableList::new();
+        world.add(Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Dielectric { refractive_index: 1.5 }) }));
+        world.add(Arc::new(ChromaticMedium {
+            boundary: Arc::new(Sphere { center: Point::ORIGIN, radius: 0.99, material: Arc::new(Lambertian::new(Color::WHITE)) }),
+            extinction: Color { r: 0.5, g: 4.0, b: 8.0 },
+            albedo: Color { r: 0.6, g: 0.6, b: 0.6 },
+            sampling,
+        }));
+        let background = Arc::new(SolidEnvironment { color: Color::WHITE });
+        return render(&settings, &test_camera(&settings), Arc::new(world), Arc::new(HittableList::new()), background);
+    }
+
+    #[test]
+    fn hero_channel_sampling_tints_tinted_glass_less_noisily() {
+        seed_thread_rng(Option::from(239));
+        let chromaticity = |c: Color| (1.0 / (c.r + c.g + c.b).max(1e-6)) * c;
+        let mean = |framebuffer: &Framebuffer| (1.0 / framebuffer.pixels.len() as f32) * framebuffer.pixels.iter().copied().sum::<Color>();
+        let reference = render_tinted_glass(ChannelSampling::Hero, 1024);
+        let converged = mean(&render_tinted_glass(ChannelSampling::Shared, 1024));
+        // Both converge on the same glass
+        for (a, b) in [(converged.r, mean(&reference).r), (converged.g, mean(&reference).g), (converged.b, mean(&reference).b)] {
+            assert!((a - b).abs() < 0.03 * b, "shared {:?} hero {:?}", converged, mean(&reference));
+        }
+
+        // but at a few samples the shared distances leave each pixel's hue further from it
+        let hue_error = |framebuffer: &Framebuffer| framebuffer.pixels.iter().zip(&reference.pixels)
+            .map(|(&c, &r)| { let d = chromaticity(c) + (-1.0) * chromaticity(r); d.r.abs() + d.g.abs() + d.b.abs() })
+            .sum::<f32>() / reference.pixels.len() as f32;
+        let shared = hue_error(&render_tinted_glass(ChannelSampling::Shared, 8));
+        let hero = hue_error(&render_tinted_glass(ChannelSampling::Hero, 8));
+        assert!(hero < 0.6 * shared, "hue error shared {} hero {}", shared, hero);
+        seed_thread_rng(Option::None);
+    }
+}
@@ -0,0 +1,1070 @@
+use std::collections::HashMap;
+use std::fs;
+use std::mem::size_of;
+use std::path::Path;
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
+use crate::geometry::color::Color;
+use crate::geometry::onb::ONB;
+use crate::geometry::pdf::random_cosine_direction;
+use crate::geometry::ray::{Ray, RayKind};
+use crate::geometry::vector::{Point, Vector3};
+use crate::materials::cache::MaterialCache;
+use crate::materials::lambertian::Lambertian;
+use crate::materials::material::Material;
+use crate::materials::occlusion::VertexOcclusion;
+use crate::objects::hittable::{HitRecord, Hittable};
+use crate::objects::triangle::{Triangle, Wireframe};
+use crate::textures::image::ImageTexture;
+use crate::utils::{INF_F32, with_rng};
+use crate::world::aggregate::Aggregate;
+use crate::world::bvh_node::BVHNode;
+
+// Loads the triangles of a Wavefront OBJ file into a BVH. Only v, vn, f, mtllib and usemtl
+// lines are read, polygons are split into fans around their first vertex and faces without a
+// normal on every vertex are shaded flat. Faces before any usemtl get material, later ones the
+// named material from the .mtl files next to the OBJ. Texture coordinates and groups are skipped.
+// Fails without building anything when the file, or the mesh estimated from its counts, is over
+// max_bytes.
+pub fn load_obj(path: &str, material: Arc<dyn Material>, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> {
+    return load_obj_with_wireframe(path, material, Option::None, max_bytes);
+}
+
+// As load_obj, drawing the triangle edges over the mesh when wireframe is set
+pub fn load_obj_with_wireframe(path: &str, material: Arc<dyn Material>, wireframe: Option<Arc<Wireframe>>, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> {
+    let triangles = read_obj_file(path, material, wireframe, max_bytes)?;
+    return Ok(BVHNode::create_tree(&mut hittables(triangles), 0.0, 0.0));
+}
+
+fn read_obj_file(path: &str, material: Arc<dyn Material>, wireframe: Option<Arc<Wireframe>>, max_bytes: usize) -> Result<Vec<Triangle>, String> {
+    let file_bytes = fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    if file_bytes > max_bytes as u64 {
+        return Err(format!("{}: the file is {} bytes, over the {} byte budget", path, file_bytes, max_bytes));
+    }
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let estimate = estimated_bytes(&source);
+    if estimate > max_bytes {
+        return Err(format!("{}: the mesh needs ~{} bytes, over the {} byte budget", path, estimate, max_bytes));
+    }
+
+    let directory = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut materials = HashMap::new();
+    for library in source.lines().filter_map(|line| line.trim().strip_prefix("mtllib ")) {
+        let mtl_path = directory.join(library.trim());
+        let mtl_source = fs::read_to_string(&mtl_path).map_err(|e| format!("{}: {}", mtl_path.display(), e))?;
+        let (library_materials, ignored) = parse_mtl(&mtl_source, directory).map_err(|e| format!("{}: {}", mtl_path.display(), e))?;
+        for field in ignored {
+            eprintln!("warning: {}: ignoring {}", mtl_path.display(), field);
+        }
+        materials.extend(library_materials);
+    }
+
+    let triangles = read_obj(&source, material, Option::from(&materials), wireframe).map_err(|e| format!("{}: {}", path, e))?;
+    if triangles.is_empty() {
+        return Err(format!("{}: no faces", path));
+    }
+    return Ok(triangles);
+}
+
+// Loads the mesh at path with the loader for its extension, .obj or .ply. With occlusion_rays,
+// ambient occlusion from that many rays per vertex is baked in first.
+pub fn load_mesh(path: &str, material: Arc<dyn Material>, occlusion_rays: Option<u32>, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    let mut triangles = match extension.as_deref() {
+        Some("obj") => read_obj_file(path, material, Option::None, max_bytes)?,
+        Some("ply") => read_ply_file(path, material, max_bytes)?,
+        _ => return Err(format!("{}: expects an .obj or .ply mesh", path)),
+    };
+    if let Some(rays) = occlusion_rays {
+        bake_vertex_occlusion(&mut triangles, rays);
+    }
+    return Ok(BVHNode::create_tree(&mut hittables(triangles), 0.0, 0.0));
+}
+
+// Darkens each triangle's material by the ambient occlusion at its vertices, from
+// vertex_occlusion with that many rays
+pub fn bake_vertex_occlusion(triangles: &mut [Triangle], rays: u32) {
+    let occlusion = vertex_occlusion(triangles, rays);
+    for (triangle, values) in triangles.iter_mut().zip(occlusion) {
+        triangle.material = Arc::new(VertexOcclusion { material: triangle.material.clone(), occlusion: values });
+    }
+}
+
+// Ambient occlusion at the vertices of each triangle, from rays cast over the hemisphere around
+// each vertex. Vertices at the same position share one value and a normal summed from the faces
+// around them by area, so the faces should be wound consistently. Only the mesh itself
+// occludes, out to a quarter of its bounding box diagonal.
+pub fn vertex_occlusion(triangles: &[Triangle], rays: u32) -> Vec<[f32; 3]> {
+    let mut occluders = hittables(triangles.to_vec());
+    if occluders.is_empty() || rays == 0 {
+        return vec![[1.0; 3]; triangles.len()];
+    }
+    let bvh = BVHNode::create_tree(&mut occluders, 0.0, 0.0);
+    let bound_box = bvh.bounding_box(0.0, 0.0).unwrap();
+    let diagonal = (bound_box.maximum - bound_box.minimum).length();
+
+    let key = |p: Point| [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+    let mut normals: HashMap<[u32; 3], Vector3> = HashMap::new();
+    for triangle in triangles.iter() {
+        let [a, b, c] = triangle.vertices;
+        for p in triangle.vertices.iter() {
+            *normals.entry(key(*p)).or_insert(Vector3::ORIGIN) += (b - a).cross(c - a);
+        }
+    }
+    let mut occlusion: HashMap<[u32; 3], f32> = HashMap::new();
+    return triangles.iter().map(|triangle| {
+        triangle.vertices.map(|p| *occlusion.entry(key(p))
+            .or_insert_with(|| open_fraction(bvh.as_ref(), p, normals[&key(p)], rays, 1e-4 * diagonal, 0.25 * diagonal)))
+    }).collect();
+}
+
+// Share of cosine-weighted rays from point about normal that get further than reach without
+// hitting anything. Where the faces around a vertex cancel out there is no side to cast from.
+fn open_fraction(occluders: &dyn Hittable, point: Point, normal: Vector3, rays: u32, offset: f32, reach: f32) -> f32 {
+    if normal.length_squared() == 0.0 {
+        return 1.0;
+    }
+    let uvw = ONB::build_from_w(normal);
+    let origin = point + offset * uvw.w;
+    let open = (0..rays).filter(|_| {
+        let ray = Ray { origin, direction: uvw.local(random_cosine_direction()), time: 0.0, kind: RayKind::Shadow };
+        occluders.hit(ray, offset, reach).is_none()
+    }).count();
+    return open as f32 / rays as f32;
+}
+
+// Materials by their newmtl name
+pub type MaterialLibrary = HashMap<String, Arc<dyn Material>>;
+
+// The materials of a .mtl file by name, and the unsupported fields it skipped. Each material
+// becomes the closest one the renderer has: an emitter when Ke is set, glass when d is below 1,
+// metal when Ks outshines Kd, with the Ns exponent turned into fuzz, and Lambertian otherwise,
+// textured by map_Kd when given. Texture paths are relative to directory. Triangles carry no
+// texture coordinates, so map_Kd is looked up by barycentric position.
+pub fn parse_mtl(source: &str, directory: &Path) -> Result<(MaterialLibrary, Vec<String>), String> {
+    struct Definition {
+        name: String,
+        kd: Color,
+        ks: Color,
+        ke: Color,
+        ns: f32,
+        d: f32,
+        ni: f32,
+        map_kd: Option<String>,
+    }
+    let new = |name: &str| Definition { name: name.to_string(), kd: Color { r: 0.8, g: 0.8, b: 0.8 }, ks: Color::BLACK, ke: Color::BLACK, ns: 0.0, d: 1.0, ni: 1.5, map_kd: Option::None };
+
+    let mut definitions: Vec<Definition> = Vec::new();
+    let mut ignored: Vec<String> = Vec::new();
+    for (n, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let error = |message: &str| format!("line {}: {}", n + 1, message);
+        let keyword = match tokens.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => continue,
+        };
+        if keyword == "newmtl" {
+            definitions.push(new(tokens.next().ok_or_else(|| error("newmtl without a name"))?));
+            continue;
+        }
+        let definition = definitions.last_mut().ok_or_else(|| error(&format!("{} before any newmtl", keyword)))?;
+        let color = || parse_vector(tokens.clone()).map(|v| Color { r: v.x, g: v.y, b: v.z }).ok_or_else(|| error(&format!("bad {} color", keyword)));
+        match keyword {
+            "Kd" => definition.kd = color()?,
+            "Ks" => definition.ks = color()?,
+            "Ke" => definition.ke = color()?,
+            "Ns" | "d" | "Ni" => {
+                let value: f32 = tokens.next().and_then(|t| t.parse().ok()).ok_or_else(|| error(&format!("bad {} value", keyword)))?;
+                match keyword {
+                    "Ns" => definition.ns = value,
+                    "d" => definition.d = value,
+                    _ => definition.ni = value,
+                }
+            }
+            // Options such as -s or -o before the file name are not supported, the last token is the file
+            "map_Kd" => definition.map_kd = Option::from(tokens.last().ok_or_else(|| error("map_Kd without a file"))?.to_string()),
+            _ => ignored.push(format!("{} in {} (line {})", keyword, definition.name, n + 1)),
+        }
+    }
+
+    let brightest = |c: Color| c.r.max(c.g).max(c.b);
+    let mut cache = MaterialCache::new();
+    let mut materials = MaterialLibrary::new();
+    for definition in definitions {
+        let material = if brightest(definition.ke) > 0.0 {
+            cache.diffuse_light(definition.ke)
+        } else if definition.d < 1.0 {
+            cache.dielectric(definition.ni)
+        } else if brightest(definition.ks) > brightest(definition.kd) {
+            // The usual Phong exponent to roughness conversion, sqrt(2 / (Ns + 2))
+            cache.metal(definition.ks, (2.0 / (definition.ns.max(0.0) + 2.0)).sqrt())
+        } else if let Some(file) = &definition.map_kd {
+            let texture_path = directory.join(file);
+            if !texture_path.is_file() {
+                return Err(format!("{}: map_Kd {} not found", definition.name, texture_path.display()));
+            }
+            Arc::new(Lambertian { albedo: Arc::new(ImageTexture::new(texture_path.to_string_lossy().into_owned())) }) as Arc<dyn Material>
+        } else {
+            cache.lambertian(definition.kd)
+        };
+        materials.insert(definition.name, material);
+    }
+    return Ok((materials, ignored));
+}
+
+// Memory parse_obj and the BVH over its triangles will take, from the vertex and face counts
+fn estimated_bytes(source: &str) -> usize {
+    let (mut vertices, mut triangles) = (0, 0);
+    for line in source.lines() {
+        let mut tokens = line.split_whitespace();
+        match tokens.next() {
+            Some("v") | Some("vn") => vertices += 1,
+            Some("f") => triangles += tokens.count().saturating_sub(2),
+            _ => {}
+        }
+    }
+    return mesh_bytes(vertices, triangles);
+}
+
+fn mesh_bytes(vertices: usize, triangles: usize) -> usize {
+    return vertices * size_of::<Vector3>()
+        + triangles * (size_of::<Triangle>() + size_of::<Arc<dyn Hittable>>())
+        + BVHNode::estimated_bytes(triangles);
+}
+
+pub fn parse_obj(source: &str, material: Arc<dyn Material>, wireframe: Option<Arc<Wireframe>>) -> Result<Vec<Arc<dyn Hittable>>, String> {
+    return read_obj(source, material, Option::None, wireframe).map(hittables);
+}
+
+// As parse_obj, switching to the named material at each usemtl line. mtllib lines are left to
+// the caller, which reads the libraries into materials.
+pub fn parse_obj_with_materials(source: &str, material: Arc<dyn Material>, materials: &MaterialLibrary, wireframe: Option<Arc<Wireframe>>) -> Result<Vec<Arc<dyn Hittable>>, String> {
+    return read_obj(source, material, Option::from(materials), wireframe).map(hittables);
+}
+
+// Without materials every face gets material and usemtl lines are skipped
+fn read_obj(source: &str, material: Arc<dyn Material>, materials: Option<&MaterialLibrary>, wireframe: Option<Arc<Wireframe>>) -> Result<Vec<Triangle>, String> {
+    let mut material = material;
+    let mut positions: Vec<Point> = Vec::new();
+    let mut normals: Vec<Vector3> = Vec::new();
+    let mut triangles: Vec<Triangle> = Vec::new();
+
+    for (n, line) in source.lines().enumerate() {
+        let mut tokens = line.split_whitespace();
+        let error = |message: &str| format!("line {}: {}", n + 1, message);
+        match tokens.next() {
+            Some("v") => positions.push(parse_vector(tokens).ok_or_else(|| error("bad vertex"))?),
+            Some("vn") => normals.push(parse_vector(tokens).ok_or_else(|| error("bad normal"))?),
+            Some("f") => {
+                let mut corners: Vec<(Point, Option<Vector3>)> = Vec::new();
+                for token in tokens {
+                    let mut indices = token.split('/');
+                    let position = indices.next()
+                        .and_then(|i| resolve_index(i, positions.len()))
+                        .ok_or_else(|| error("bad vertex index"))?;
+                    // The texture coordinate index is skipped
+                    let normal = match indices.nth(1) {
+                        Some(i) if !i.is_empty() => Option::from(normals[resolve_index(i, normals.len()).ok_or_else(|| error("bad normal index"))?]),
+                        _ => Option::None,
+                    };
+                    corners.push((positions[position], normal));
+                }
+                if corners.len() < 3 {
+                    return Err(error("face with fewer than 3 vertices"));
+                }
+                push_fan(&mut triangles, &corners, &material, &wireframe);
+            }
+            Some("usemtl") => if let Some(materials) = materials {
+                let name = tokens.next().ok_or_else(|| error("usemtl without a name"))?;
+                material = materials.get(name).ok_or_else(|| error(&format!("unknown material {}", name)))?.clone();
+            },
+            _ => {}
+        }
+    }
+    return Ok(triangles);
+}
+
+// Splits a polygon into triangles around its first corner, shading them smooth only when
+// every corner has a normal
+fn push_fan(triangles: &mut Vec<Triangle>, corners: &[(Point, Option<Vector3>)], material: &Arc<dyn Material>, wireframe: &Option<Arc<Wireframe>>) {
+    for k in 1..corners.len() - 1 {
+        let (a, b, c) = (corners[0], corners[k], corners[k + 1]);
+        let mut triangle = Triangle::new(a.0, b.0, c.0, material.clone());
+        if let Some(wireframe) = wireframe {
+            triangle = triangle.with_wireframe(wireframe.clone());
+        }
+        triangles.push(match (a.1, b.1, c.1) {
+            (Some(na), Some(nb), Some(nc)) => triangle.with_normals([na, nb, nc]),
+            _ => triangle,
+        });
+    }
+}
+
+fn hittables(triangles: Vec<Triangle>) -> Vec<Arc<dyn Hittable>> {
+    return triangles.into_iter().map(|triangle| Arc::new(triangle) as Arc<dyn Hittable>).collect();
+}
+
+fn parse_vector<'a>(mut tokens: impl Iterator<Item = &'a str>) -> Option<Vector3> {
+    let mut next = || tokens.next()?.parse::<f32>().ok();
+    return Option::from(Vector3 { x: next()?, y: next()?, z: next()? });
+}
+
+// OBJ indices start at 1, negative ones count back from the last element read so far
+fn resolve_index(token: &str, count: usize) -> Option<usize> {
+    let index: i64 = token.parse().ok()?;
+    let resolved = if index < 0 { count as i64 + index } else { index - 1 };
+    if resolved < 0 || resolved >= count as i64 {
+        return Option::None;
+    }
+    return Option::from(resolved as usize);
+}
+
+// Loads the triangles of a PLY file, ASCII or binary little-endian, into a BVH. Vertices need
+// x, y and z and are shaded smooth when they also have nx, ny and nz. Faces come from their
+// vertex_indices list and are split into fans like OBJ polygons. Other elements and properties
+// are read past and dropped. Fails like load_obj when over max_bytes.
+pub fn load_ply(path: &str, material: Arc<dyn Material>, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> {
+    let triangles = read_ply_file(path, material, max_bytes)?;
+    return Ok(BVHNode::create_tree(&mut hittables(triangles), 0.0, 0.0));
+}
+
+fn read_ply_file(path: &str, material: Arc<dyn Material>, max_bytes: usize) -> Result<Vec<Triangle>, String> {
+    let file_bytes = fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    if file_bytes > max_bytes as u64 {
+        return Err(format!("{}: the file is {} bytes, over the {} byte budget", path, file_bytes, max_bytes));
+    }
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mesh = read_ply(&bytes).map_err(|e| format!("{}: {}", path, e))?;
+    let n_triangles = mesh.faces.iter().map(|face| face.len().saturating_sub(2)).sum();
+    let estimate = mesh_bytes(mesh.positions.len(), n_triangles);
+    if estimate > max_bytes {
+        return Err(format!("{}: the mesh needs ~{} bytes, over the {} byte budget", path, estimate, max_bytes));
+    }
+
+    let triangles = ply_faces(&mesh, material, Option::None);
+    if triangles.is_empty() {
+        return Err(format!("{}: no faces", path));
+    }
+    return Ok(triangles);
+}
+
+pub struct PlyMesh {
+    pub positions: Vec<Point>,
+    pub normals: Option<Vec<Vector3>>,
+    // Vertex indices of each face, checked to be in range
+    pub faces: Vec<Vec<usize>>,
+}
+
+pub fn ply_triangles(mesh: &PlyMesh, material: Arc<dyn Material>, wireframe: Option<Arc<Wireframe>>) -> Vec<Arc<dyn Hittable>> {
+    return hittables(ply_faces(mesh, material, wireframe));
+}
+
+fn ply_faces(mesh: &PlyMesh, material: Arc<dyn Material>, wireframe: Option<Arc<Wireframe>>) -> Vec<Triangle> {
+    let mut triangles: Vec<Triangle> = Vec::new();
+    for face in mesh.faces.iter() {
+        let corners: Vec<(Point, Option<Vector3>)> = face.iter()
+            .map(|&i| (mesh.positions[i], mesh.normals.as_ref().map(|normals| normals[i])))
+            .collect();
+        push_fan(&mut triangles, &corners, &material, &wireframe);
+    }
+    return triangles;
+}
+
+#[derive(Clone, Copy)]
+enum PlyScalar {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyScalar {
+    fn parse(name: &str) -> Result<Self, String> {
+        return match name {
+            "char" | "int8" => Ok(PlyScalar::I8),
+            "uchar" | "uint8" => Ok(PlyScalar::U8),
+            "short" | "int16" => Ok(PlyScalar::I16),
+            "ushort" | "uint16" => Ok(PlyScalar::U16),
+            "int" | "int32" => Ok(PlyScalar::I32),
+            "uint" | "uint32" => Ok(PlyScalar::U32),
+            "float" | "float32" => Ok(PlyScalar::F32),
+            "double" | "float64" => Ok(PlyScalar::F64),
+            _ => Err(format!("unknown property type {}", name)),
+        };
+    }
+
+    fn size(self) -> usize {
+        return match self {
+            PlyScalar::I8 | PlyScalar::U8 => 1,
+            PlyScalar::I16 | PlyScalar::U16 => 2,
+            PlyScalar::I32 | PlyScalar::U32 | PlyScalar::F32 => 4,
+            PlyScalar::F64 => 8,
+        };
+    }
+}
+
+// A property holds one scalar, or a list of them after a count of the list's own type
+struct PlyProperty {
+    name: String,
+    list_count: Option<PlyScalar>,
+    value: PlyScalar,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+// Hands out the body's values in order, whitespace-separated text or packed little-endian
+enum PlyBody<'a> {
+    Ascii(std::str::SplitWhitespace<'a>),
+    Binary(&'a [u8]),
+}
+
+impl PlyBody<'_> {
+    fn next(&mut self, scalar: PlyScalar) -> Result<f64, String> {
+        let end = || "unexpected end of data".to_string();
+        return match self {
+            PlyBody::Ascii(tokens) => {
+                let token = tokens.next().ok_or_else(end)?;
+                token.parse::<f64>().map_err(|_| format!("bad value {}", token))
+            }
+            PlyBody::Binary(bytes) => {
+                if bytes.len() < scalar.size() {
+                    return Err(end());
+                }
+                let (value, rest) = bytes.split_at(scalar.size());
+                *bytes = rest;
+                Ok(match scalar {
+                    PlyScalar::I8 => value[0] as i8 as f64,
+                    PlyScalar::U8 => value[0] as f64,
+                    PlyScalar::I16 => i16::from_le_bytes([value[0], value[1]]) as f64,
+                    PlyScalar::U16 => u16::from_le_bytes([value[0], value[1]]) as f64,
+                    PlyScalar::I32 => i32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    PlyScalar::U32 => u32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    PlyScalar::F32 => f32::from_le_bytes([value[0], value[1], value[2], value[3]]) as f64,
+                    PlyScalar::F64 => f64::from_le_bytes([value[0], value[1], value[2], value[3], value[4], value[5], value[6], value[7]]),
+                })
+            }
+        };
+    }
+}
+
+pub fn read_ply(bytes: &[u8]) -> Result<PlyMesh, String> {
+    let (elements, binary, body_start) = read_ply_header(bytes)?;
+    let mut body = if binary {
+        PlyBody::Binary(&bytes[body_start..])
+    } else {
+        PlyBody::Ascii(std::str::from_utf8(&bytes[body_start..]).map_err(|_| "ASCII body is not text".to_string())?.split_whitespace())
+    };
+
+    let mut mesh = PlyMesh { positions: Vec::new(), normals: Option::None, faces: Vec::new() };
+    let mut normals: Vec<Vector3> = Vec::new();
+    let mut has_faces = false;
+    for element in elements.iter() {
+        let find = |name: &str| element.properties.iter().position(|p| p.name == name);
+        let scalar = |name: &str| -> Result<Option<usize>, String> {
+            match find(name) {
+                Some(i) if element.properties[i].list_count.is_some() => Err(format!("{} property {} is a list", element.name, name)),
+                found => Ok(found),
+            }
+        };
+        let (position, normal, indices) = match element.name.as_str() {
+            "vertex" => {
+                let position = match (scalar("x")?, scalar("y")?, scalar("z")?) {
+                    (Some(x), Some(y), Some(z)) => [x, y, z],
+                    _ => return Err("vertex element has no x, y and z".to_string()),
+                };
+                let normal = match (scalar("nx")?, scalar("ny")?, scalar("nz")?) {
+                    (Some(x), Some(y), Some(z)) => Option::from([x, y, z]),
+                    _ => Option::None,
+                };
+                (Option::from(position), normal, Option::None)
+            }
+            "face" => {
+                let indices = find("vertex_indices").or_else(|| find("vertex_index"));
+                match indices {
+                    Some(i) if element.properties[i].list_count.is_some() => {}
+                    _ => return Err("face element has no vertex_indices list".to_string()),
+                }
+                has_faces = true;
+                (Option::None, Option::None, indices)
+            }
+            _ => (Option::None, Option::None, Option::None),
+        };
+
+        for n in 0..element.count {
+            let mut values: Vec<f64> = Vec::with_capacity(element.properties.len());
+            let mut list: Vec<f64> = Vec::new();
+            for (i, property) in element.properties.iter().enumerate() {
+                match property.list_count {
+                    Some(count_type) => {
+                        let count = body.next(count_type)? as usize;
+                        let items = (0..count).map(|_| body.next(property.value)).collect::<Result<Vec<f64>, String>>()?;
+                        if indices == Option::from(i) {
+                            list = items;
+                        }
+                        values.push(0.0);
+                    }
+                    None => values.push(body.next(property.value)?),
+                }
+            }
+            let vector = |axes: [usize; 3]| Vector3 { x: values[axes[0]] as f32, y: values[axes[1]] as f32, z: values[axes[2]] as f32 };
+            if let Some(axes) = position {
+                mesh.positions.push(vector(axes));
+            }
+            if let Some(axes) = normal {
+                normals.push(vector(axes));
+            }
+            if indices.is_some() {
+                if list.len() < 3 {
+                    return Err(format!("face {} has fewer than 3 vertices", n));
+                }
+                mesh.faces.push(list.iter().map(|&i| i as usize).collect());
+            }
+        }
+    }
+
+    if !has_faces {
+        return Err("no face element".to_string());
+    }
+    let n_vertices = mesh.positions.len();
+    if let Some((n, _)) = mesh.faces.iter().enumerate().find(|(_, face)| face.iter().any(|&i| i >= n_vertices)) {
+        return Err(format!("face {} has a vertex index past the {} vertices", n, n_vertices));
+    }
+    if !normals.is_empty() {
+        mesh.normals = Option::from(normals);
+    }
+    return Ok(mesh);
+}
+
+// Elements in file order, whether the body is binary and where it starts
+fn read_ply_header(bytes: &[u8]) -> Result<(Vec<PlyElement>, bool, usize), String> {
+    let mut elements: Vec<PlyElement> = Vec::new();
+    let mut binary = Option::None;
+    let mut pos = 0;
+    let mut line_number = 0;
+    loop {
+        let end = bytes[pos..].iter().position(|&b| b == b'\n').ok_or_else(|| "header has no end_header".to_string())?;
+        let line = std::str::from_utf8(&bytes[pos..pos + end]).map_err(|_| "header is not text".to_string())?.trim_end_matches('\r');
+        pos += end + 1;
+        line_number += 1;
+        let error = |message: &str| format!("header line {}: {}", line_number, message);
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if line_number == 1 {
+            if tokens != ["ply"] {
+                return Err("not a PLY file".to_string());
+            }
+            continue;
+        }
+        match tokens.as_slice() {
+            ["format", "ascii", _] => binary = Option::from(false),
+            ["format", "binary_little_endian", _] => binary = Option::from(true),
+            ["format", format, ..] => return Err(error(&format!("unsupported format {}", format))),
+            ["comment", ..] | ["obj_info", ..] => {}
+            ["element", name, count] => {
+                let count = count.parse().map_err(|_| error("bad element count"))?;
+                elements.push(PlyElement { name: name.to_string(), count, properties: Vec::new() });
+            }
+            ["property", "list", count_type, value_type, name] => {
+                let list_count = Option::from(PlyScalar::parse(count_type).map_err(|e| error(&e))?);
+                let value = PlyScalar::parse(value_type).map_err(|e| error(&e))?;
+                let element = elements.last_mut().ok_or_else(|| error("property before any element"))?;
+                element.properties.push(PlyProperty { name: name.to_string(), list_count, value });
+            }
+            ["property", value_type, name] => {
+                let value = PlyScalar::parse(value_type).map_err(|e| error(&e))?;
+                let element = elements.last_mut().ok_or_else(|| error("property before any element"))?;
+                element.properties.push(PlyProperty { name: name.to_string(), list_count: Option::None, value });
+            }
+            ["end_header"] => break,
+            _ => return Err(error(&format!("unexpected {}", line))),
+        }
+    }
+    let binary = binary.ok_or_else(|| "header has no format".to_string())?;
+    return Ok((elements, binary, pos));
+}
+
+// Triangles that can be sampled as one area light, e.g. an emissive quad or a shaped panel.
+// Hits go through a BVH over the triangles. Samples pick a triangle from a cumulative area
+// table, so every point of the surface is equally likely and a pick costs a binary search
+// rather than the walk BVHNode::sample_surface makes.
+pub struct Mesh {
+    pub triangles: Vec<Arc<Triangle>>,
+    bvh: Arc<dyn Hittable>,
+    // Area of the triangles up to and including each one, the last entry is the total
+    cumulative_areas: Vec<f32>,
+}
+
+impl Mesh {
+    // None when the triangles have no area between them
+    pub fn new(triangles: Vec<Triangle>) -> Option<Self> {
+        let triangles: Vec<Arc<Triangle>> = triangles.into_iter().map(Arc::new).collect();
+        let mut total = 0.0;
+        let cumulative_areas: Vec<f32> = triangles.iter().map(|triangle| {
+            total += triangle.area();
+            total
+        }).collect();
+        if total <= 0.0 || !total.is_finite() {
+            return Option::None;
+        }
+        let mut objects: Vec<Arc<dyn Hittable>> = triangles.iter().map(|triangle| triangle.clone() as Arc<dyn Hittable>).collect();
+        let bvh = BVHNode::create_tree(&mut objects, 0.0, 0.0);
+        return Option::from(Self { triangles, bvh, cumulative_areas });
+    }
+
+    fn total_area(&self) -> f32 {
+        return *self.cumulative_areas.last().unwrap();
+    }
+
+    // The triangle a uniform pick in [0, 1) lands on and the barycentric weights of its b and
+    // c for a point in [0, 1)^2, square-rooted like Triangle::sample_surface
+    fn sample_at(&self, pick: f32, sample: (f32, f32)) -> (&Arc<Triangle>, f32, f32) {
+        let target = pick * self.total_area();
+        let k = self.cumulative_areas.partition_point(|&area| area <= target).min(self.triangles.len() - 1);
+        let r1 = sample.0.sqrt();
+        return (&self.triangles[k], r1 * (1.0 - sample.1), r1 * sample.1);
+    }
+
+    // A point spread evenly over the mesh with its face normal, its pdf per unit area and the
+    // radiance the triangle's material emits there, looked up at the point's barycentric u, v
+    pub fn sample_emission(&self, rng: &mut dyn RngCore) -> (Point, Vector3, f32, Color) {
+        let (triangle, u, v) = self.sample_at(rng.gen(), (rng.gen(), rng.gen()));
+        let [a, b, c] = triangle.vertices;
+        let point = (1.0 - u - v) * a + u * b + v * c;
+        let normal = (b - a).cross(c - a).direction();
+        // Seen from straight in front, so one-sided emitters light up
+        let ray = Ray { origin: point + normal, direction: -normal, time: 0.0, kind: RayKind::Shadow };
+        let hit_rec = HitRecord { intersection: point, normal, material: triangle.material.clone(), t: 1.0, u, v, front_face: true };
+        let radiance = triangle.material.emitted(ray, &hit_rec, u, v, point);
+        return (point, normal, 1.0 / self.total_area(), radiance);
+    }
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        return self.bvh.hit(ray, t_min, t_max);
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.bvh.bounding_box(t0, t1);
+    }
+
+    fn area(&self) -> f32 {
+        return self.total_area();
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let (point, normal, pdf, _) = self.sample_emission(rng);
+        return Option::from((point, normal, pdf));
+    }
+
+    // The area pdf turned into one per solid angle at the first triangle v reaches. The cosine
+    // is unsigned like on the rectangles, so the mesh can be sampled from either side.
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
+        let hit_rec = match self.hit(Ray { origin: o, direction: v, time: 0.0, kind: RayKind::Shadow }, epsilon, INF_F32) {
+            Some(hit_rec) => hit_rec,
+            None => return 0.0,
+        };
+        let dist_squared = hit_rec.t.powi(2) * v.length_squared();
+        let cosine = (v.dot(hit_rec.normal) / v.length()).abs();
+        return dist_squared / (cosine * self.total_area());
+    }
+
+    fn random(&self, o: Vector3) -> Vector3 {
+        let (point, _, _) = with_rng(|rng| self.sample_surface(rng)).unwrap();
+        return point - o;
+    }
+
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        let (triangle, u, v) = self.sample_at(with_rng(|rng| rng.gen()), sample);
+        let [a, b, c] = triangle.vertices;
+        return (1.0 - u - v) * a + u * b + v * c - o;
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn Hittable>> {
+        return vec![&self.bvh];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::{Ray, RayKind};
+    use crate::materials::light::DiffuseLight;
+    use crate::utils::{PI, seed_thread_rng};
+
+    const CUBE: &str = "\
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+v 0 0 1
+v 1 0 1
+v 1 1 1
+v 0 1 1
+f 1 2 3 4
+f 8 7 6 5
+f 1 5 6 2
+f 4 3 7 8
+f 1 4 8 5
+f 2 6 7 3
+";
+
+    fn grey() -> Arc<dyn Material> {
+        return Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 }));
+    }
+
+    // Writes source to a file of its own in the temp directory and returns the path
+    fn write_temp(name: &str, source: &str) -> String {
+        let path = env::temp_dir().join(format!("raytracer_{}_{}.obj", name, process::id()));
+        fs::write(&path, source).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn over_budget_files_fail_before_building() {
+        let path = write_temp("budget", CUBE);
+        let needed = estimated_bytes(CUBE);
+        assert!(needed > CUBE.len());
+
+        let err = load_obj(&path, grey(), needed - 1).err().unwrap();
+        assert!(err.contains("over the") && err.contains("byte budget"), "{}", err);
+        let err = load_obj(&path, grey(), 16).err().unwrap();
+        assert!(err.contains("the file is"), "{}", err);
+        assert!(load_obj(&path, grey(), needed).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn loads_the_cube_as_twelve_triangles_in_a_unit_box() {
+        let path = write_temp("cube", CUBE);
+        let mesh = load_obj(&path, grey(), 1 << 20).unwrap();
+        fs::remove_file(path).unwrap();
+        let bound_box = mesh.bounding_box(0.0, 0.0).unwrap();
+        assert!((bound_box.minimum - Point::ORIGIN).length() < 1e-3, "{:?}", bound_box.minimum);
+        assert!((bound_box.maximum - Point { x: 1.0, y: 1.0, z: 1.0 }).length() < 1e-3, "{:?}", bound_box.maximum);
+        assert_eq!(parse_obj(CUBE, grey(), Option::None).unwrap().len(), 12);
+
+        // Without normals in the file the faces are shaded flat
+        let ray = Ray { origin: Point { x: 0.3, y: 0.6, z: 5.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, time: 0.0, kind: RayKind::Camera };
+        let hit_rec = mesh.hit(ray, 0.001, INF_F32).unwrap();
+        assert_eq!(hit_rec.t, 4.0);
+        assert_eq!(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+    }
+
+    #[test]
+    fn meshes_load_by_their_extension() {
+        let path = write_temp("by_extension", CUBE);
+        let mesh = load_mesh(&path, grey(), Option::None, 1 << 20).unwrap();
+        fs::remove_file(path).unwrap();
+        let bound_box = mesh.bounding_box(0.0, 0.0).unwrap();
+        assert!((bound_box.maximum - Point { x: 1.0, y: 1.0, z: 1.0 }).length() < 1e-3, "{:?}", bound_box.maximum);
+        assert_eq!(load_mesh("models/teapot.stl", grey(), Option::None, 1 << 20).err().as_deref(), Option::from("models/teapot.stl: expects an .obj or .ply mesh"));
+        let cube = load_mesh(&cube_ply_path(), grey(), Option::None, 1 << 20).unwrap();
+        assert!((cube.bounding_box(0.0, 0.0).unwrap().minimum - Point { x: -1.0, y: 0.0, z: 2.0 }).length() < 1e-3);
+    }
+
+    // A unit box with an open bottom standing near the corner of a floor and two walls, all
+    // facing into the room
+    const BOX_IN_CORNER: &str = "\
+v 0 0 0
+v 4 0 0
+v 4 0 4
+v 0 0 4
+v 0 4 0
+v 4 4 0
+v 0 4 4
+f 1 4 3 2
+f 1 5 7 4
+f 1 2 6 5
+v 0.5 0 0.5
+v 1.5 0 0.5
+v 1.5 0 1.5
+v 0.5 0 1.5
+v 0.5 1 0.5
+v 1.5 1 0.5
+v 1.5 1 1.5
+v 0.5 1 1.5
+f 12 15 14 13
+f 8 11 15 12
+f 9 13 14 10
+f 8 12 13 9
+f 11 10 14 15
+";
+
+    #[test]
+    fn vertices_tucked_into_the_corner_are_more_occluded() {
+        seed_thread_rng(Option::from(227));
+        let triangles: Vec<Triangle> = read_obj(BOX_IN_CORNER, grey(), Option::None, Option::None).unwrap();
+        let occlusion = vertex_occlusion(&triangles, 256);
+        let at = |p: Point| {
+            let values: Vec<f32> = triangles.iter().zip(occlusion.iter())
+                .flat_map(|(triangle, values)| (0..3).filter(move |&k| triangle.vertices[k] == p).map(move |k| values[k]))
+                .collect();
+            assert!(!values.is_empty() && values.iter().all(|&v| v == values[0]), "{:?} at {:?}", values, p);
+            values[0]
+        };
+        let floor = |x: f32, z: f32| at(Point { x, y: 0.0, z });
+        let box_corner = |x: f32, y: f32, z: f32| at(Point { x, y, z });
+
+        // The room's own corner and the box's foot facing it, against the open far end and the
+        // box's top corner facing the room
+        assert!(floor(0.0, 0.0) < 0.5 * floor(4.0, 4.0), "corner {} against far end {}", floor(0.0, 0.0), floor(4.0, 4.0));
+        assert_eq!(floor(4.0, 4.0), 1.0);
+        assert!(box_corner(0.5, 0.0, 0.5) < box_corner(1.5, 1.0, 1.5), "box foot {} against top {}", box_corner(0.5, 0.0, 0.5), box_corner(1.5, 1.0, 1.5));
+        assert!(box_corner(0.5, 1.0, 0.5) < box_corner(1.5, 1.0, 1.5), "top towards the walls {} against away {}", box_corner(0.5, 1.0, 0.5), box_corner(1.5, 1.0, 1.5));
+
+        // Baking darkens the material by the blended values
+        let mut baked = triangles.clone();
+        bake_vertex_occlusion(&mut baked, 256);
+        let down = |x: f32, z: f32| {
+            let ray = Ray { origin: Point { x, y: 5.0, z }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+            let hit_rec = baked.iter().filter_map(|t| t.hit(ray, 0.001, INF_F32)).min_by(|a, b| a.t.partial_cmp(&b.t).unwrap()).unwrap();
+            hit_rec.material.scatter(ray, &hit_rec).unwrap().attenuation.r
+        };
+        assert!((down(3.99, 3.99) - 0.5).abs() < 0.01, "{}", down(3.99, 3.99));
+        assert!(down(0.01, 0.01) < 0.3, "{}", down(0.01, 0.01));
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn skips_texture_and_material_lines_and_keeps_vertex_normals() {
+        let source = "\
+mtllib cube.mtl
+o quad
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vt 1 1
+vn 0 0 1
+vn 1 0 0
+usemtl grey
+s off
+f 1/1/1 2/2/2 3/1/1 4/2/1
+";
+        let triangles = parse_obj(source, grey(), Option::None).unwrap();
+        assert_eq!(triangles.len(), 2);
+        // Towards vertex 2, whose normal points along x, the shading normal leans that way
+        let ray = Ray { origin: Point { x: 0.95, y: 0.02, z: 1.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, time: 0.0, kind: RayKind::Camera };
+        let hit_rec = triangles[0].hit(ray, 0.001, INF_F32).unwrap();
+        assert!(hit_rec.normal.x > 0.5, "{:?}", hit_rec.normal);
+    }
+
+    #[test]
+    fn usemtl_switches_between_the_materials_of_the_mtllib() {
+        let mtl = "\
+# two materials
+newmtl red
+Kd 0.8 0.1 0.1
+illum 2
+newmtl lamp
+Kd 0 0 0
+Ke 4 4 4
+";
+        let obj = format!("mtllib raytracer_quads_{}.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+f 1 2 3
+usemtl red
+f 1 3 4
+usemtl lamp
+f 2 3 4
+", process::id());
+        let mtl_path = env::temp_dir().join(format!("raytracer_quads_{}.mtl", process::id()));
+        fs::write(&mtl_path, mtl).unwrap();
+        let (materials, ignored) = parse_mtl(mtl, &env::temp_dir()).unwrap();
+        assert_eq!(ignored, vec!["illum in red (line 4)".to_string()]);
+
+        let default = grey();
+        let triangles = parse_obj_with_materials(&obj, default.clone(), &materials, Option::None).unwrap();
+        let material = |k: usize| triangles[k].material().unwrap().clone();
+        assert!(Arc::ptr_eq(&material(0), &default));
+        assert!(Arc::ptr_eq(&material(1), &materials["red"]));
+        assert!(Arc::ptr_eq(&material(2), &materials["lamp"]));
+        assert!(!Arc::ptr_eq(&material(1), &material(2)));
+
+        // The red face scatters red, the lamp face glows and absorbs
+        let ray = Ray { origin: Point { x: 0.2, y: 0.6, z: 1.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, time: 0.0, kind: RayKind::Camera };
+        let hit_rec = triangles[1].hit(ray, 0.001, INF_F32).unwrap();
+        let attenuation = hit_rec.material.scatter(ray, &hit_rec).unwrap().attenuation;
+        assert_eq!(attenuation, Color { r: 0.8, g: 0.1, b: 0.1 });
+        let ray = Ray { origin: Point { x: 0.7, y: 0.6, z: 1.0 }, ..ray };
+        let hit_rec = triangles[2].hit(ray, 0.001, INF_F32).unwrap();
+        assert!(hit_rec.material.scatter(ray, &hit_rec).is_none());
+        assert_eq!(hit_rec.material.emitted(ray, &hit_rec, 0.0, 0.0, hit_rec.intersection), Color { r: 4.0, g: 4.0, b: 4.0 });
+
+        // load_obj reads the library next to the file, and names missing from it are reported
+        let path = write_temp("quads", &obj);
+        assert!(load_obj(&path, grey(), 1 << 20).is_ok());
+        let err = parse_obj_with_materials("usemtl blue\n", grey(), &materials, Option::None).err().unwrap();
+        assert_eq!(err, "line 1: unknown material blue");
+        fs::remove_file(path).unwrap();
+        fs::remove_file(mtl_path).unwrap();
+    }
+
+    #[test]
+    fn mtl_fields_pick_metal_glass_or_lambertian() {
+        let mtl = "newmtl steel\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 998\nnewmtl glass\nd 0.2\nNi 1.33\nnewmtl chalk\n";
+        let (materials, ignored) = parse_mtl(mtl, Path::new("")).unwrap();
+        assert!(ignored.is_empty());
+        assert!(materials["steel"].is_specular());
+        assert!(materials["glass"].is_specular());
+        assert!(!materials["chalk"].is_specular());
+
+        let error = |source: &str| parse_mtl(source, Path::new("")).err().unwrap();
+        assert_eq!(error("Kd 1 1 1\n"), "line 1: Kd before any newmtl");
+        assert_eq!(error("newmtl a\nKd 1 x 1\n"), "line 2: bad Kd color");
+        assert!(error("newmtl a\nmap_Kd missing.png\n").contains("missing.png not found"));
+    }
+
+    // A quad in the z = 0 plane split unevenly, 1.5 on the right of its diagonal and 1 on the left
+    fn lit_quad() -> Mesh {
+        let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Color { r: 3.0, g: 2.0, b: 1.0 }));
+        let corner = |x: f32, y: f32| Point { x, y, z: 0.0 };
+        return Mesh::new(vec![
+            Triangle::new(corner(0.0, 0.0), corner(3.0, 0.0), corner(2.0, 1.0), light.clone()),
+            Triangle::new(corner(0.0, 0.0), corner(2.0, 1.0), corner(0.0, 1.0), light),
+        ]).unwrap();
+    }
+
+    #[test]
+    fn mesh_samples_spread_evenly_over_the_area() {
+        let quad = lit_quad();
+        assert_eq!(quad.area(), 2.5);
+        let mut rng = StdRng::seed_from_u64(11);
+        let n = 50000;
+        // Half-unit cells over the part from x = 0 to 2, each a tenth of the quad
+        let mut cells = [0u32; 8];
+        for _ in 0..n {
+            let (point, normal, pdf, radiance) = quad.sample_emission(&mut rng);
+            assert_eq!(pdf, 1.0 / 2.5);
+            assert_eq!(normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+            assert_eq!(radiance, Color { r: 3.0, g: 2.0, b: 1.0 });
+            assert!(quad.hit(Ray { origin: point + Vector3 { x: 0.0, y: 0.0, z: 1.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, time: 0.0, kind: RayKind::Camera }, 0.001, INF_F32).is_some());
+            if point.x < 2.0 {
+                cells[(point.x / 0.5) as usize * 2 + (point.y / 0.5).min(1.0) as usize] += 1;
+            }
+        }
+        for count in cells {
+            assert!((count as f32 / n as f32 - 0.1).abs() < 0.005, "{:?}", cells);
+        }
+    }
+
+    #[test]
+    fn mesh_pdf_integrates_to_one_over_the_sphere_of_directions() {
+        let quad = lit_quad();
+        let o = Point { x: 1.0, y: 0.5, z: 2.0 };
+        let mut rng = StdRng::seed_from_u64(12);
+        let n = 200000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let z: f32 = 1.0 - 2.0 * rng.gen::<f32>();
+            let phi = 2.0 * PI * rng.gen::<f32>();
+            let r = (1.0 - z * z).max(0.0).sqrt();
+            sum += quad.pdf_value(o, Vector3 { x: r * phi.cos(), y: r * phi.sin(), z }, 0.001) as f64;
+        }
+        let integral = 4.0 * PI as f64 * sum / n as f64;
+        assert!((integral - 1.0).abs() < 0.03, "{}", integral);
+
+        // Directions it hands out reach the quad, at a density matching the sampled points
+        let v = quad.random(o);
+        assert!(quad.pdf_value(o, v, 0.001) > 0.0);
+        assert_eq!(quad.pdf_value(o, Vector3 { x: 0.0, y: 0.0, z: 1.0 }, 0.001), 0.0);
+        assert!(Mesh::new(vec![Triangle::new(o, o, o, grey())]).is_none());
+    }
+
+    fn cube_ply_path() -> String {
+        return format!("{}/src/objects/testdata/cube.ply", env!("CARGO_MANIFEST_DIR"));
+    }
+
+    #[test]
+    fn loads_a_binary_ply_cube() {
+        // Six quads over 8 float vertices, from (-1, 0, 2) to (1, 2, 4)
+        let mesh = read_ply(&fs::read(cube_ply_path()).unwrap()).unwrap();
+        assert_eq!(mesh.positions.len(), 8);
+        assert_eq!(mesh.faces.len(), 6);
+        assert!(mesh.faces.iter().all(|face| face.len() == 4));
+        assert!(mesh.normals.is_none());
+        assert_eq!(ply_triangles(&mesh, grey(), Option::None).len(), 12);
+
+        let cube = load_ply(&cube_ply_path(), grey(), 1 << 20).unwrap();
+        let b = cube.bounding_box(0.0, 0.0).unwrap();
+        assert!((b.minimum - Point { x: -1.0, y: 0.0, z: 2.0 }).length() < 1e-3, "{:?}", b.minimum);
+        assert!((b.maximum - Point { x: 1.0, y: 2.0, z: 4.0 }).length() < 1e-3, "{:?}", b.maximum);
+    }
+
+    #[test]
+    fn reads_ascii_with_normals_and_skips_other_elements() {
+        let source = "\
+ply
+format ascii 1.0
+element vertex 4
+property float x
+property float y
+property float z
+property float nx
+property float ny
+property float nz
+property uchar red
+element face 1
+property list uchar uint vertex_indices
+element edge 1
+property int vertex1
+property int vertex2
+end_header
+0 0 0 0 0 1 255
+1 0 0 0 0 1 255
+1 1 0 0 0 1 255
+0 1 0 0 0 1 255
+4 0 1 2 3
+0 2
+";
+        let mesh = read_ply(source.as_bytes()).unwrap();
+        assert_eq!(mesh.positions[2], Point { x: 1.0, y: 1.0, z: 0.0 });
+        assert_eq!(mesh.normals.as_ref().unwrap()[3], Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+        assert_eq!(mesh.faces, vec![vec![0, 1, 2, 3]]);
+        assert_eq!(ply_triangles(&mesh, grey(), Option::None).len(), 2);
+    }
+
+    #[test]
+    fn unsupported_layouts_are_reported() {
+        let header = |format: &str, vertex: &str, face: &str| format!("ply\nformat {} 1.0\nelement vertex 3\n{}element face 1\n{}end_header\n0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n", format, vertex, face);
+        let xyz = "property float x\nproperty float y\nproperty float z\n";
+        let list = "property list uchar int vertex_indices\n";
+        assert!(read_ply(header("ascii", xyz, list).as_bytes()).is_ok());
+
+        let error = |source: String| read_ply(source.as_bytes()).err().unwrap();
+        assert!(error(header("binary_big_endian", xyz, list)).contains("unsupported format binary_big_endian"));
+        assert!(error(header("ascii", "property float x\nproperty float y\n", list)).contains("no x, y and z"));
+        assert!(error(header("ascii", xyz, "property int vertex_indices\n")).contains("no vertex_indices list"));
+        assert!(error(header("ascii", xyz, "property list uchar int vertex_indices\nproperty list uchar int128 other\n")).contains("unknown property type int128"));
+        assert!(error(header("ascii", xyz, list).replace("3 0 1 2", "3 0 1 7")).contains("vertex index past the 3 vertices"));
+        assert!(error(header("ascii", xyz, list).replace("3 0 1 2", "2 0 1")).contains("fewer than 3 vertices"));
+        assert!(error("solid cube\n".to_string()).contains("not a PLY file"));
+
+        // A binary body cut short
+        let bytes = fs::read(cube_ply_path()).unwrap();
+        assert!(read_ply(&bytes[..bytes.len() - 3]).err().unwrap().contains("unexpected end of data"));
+    }
+}
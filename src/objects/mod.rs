@@ -3,4 +3,7 @@ pub mod sphere;
 pub mod rectangle;
 pub mod boxes;
 pub mod instances;
-pub mod medium;
\ No newline at end of file
+pub mod medium;
+pub mod plane;
+pub mod triangle;
+pub mod mesh;
\ No newline at end of file
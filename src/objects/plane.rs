@@ -0,0 +1,107 @@
+use std::sync::Arc;
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
+use crate::geometry::ray::Ray;
+use crate::geometry::vector::{Point, Vector3};
+use crate::materials::lambertian::Lambertian;
+use crate::materials::material::Material;
+use crate::objects::hittable::{HitRecord, Hittable};
+use crate::textures::texture::Texture;
+use crate::utils::INF_F32;
+
+// Infinite horizontal floor at height y, a flat replacement for the usual huge ground sphere.
+// The texture coordinates are the world x and z of the hit.
+pub struct GroundPlane {
+    pub y: f32,
+    pub material: Arc<dyn Material>,
+}
+
+impl GroundPlane {
+    const NORMAL: Vector3 = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+
+    pub fn new(y: f32, texture: Arc<dyn Texture>) -> Self {
+        return Self { y, material: Arc::new(Lambertian { albedo: texture }) };
+    }
+}
+
+impl Hittable for GroundPlane {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        if ray.direction.y == 0.0 {
+            return Option::None;
+        }
+        let t = (self.y - ray.origin.y) / ray.direction.y;
+        if t < t_min || t > t_max {
+            return Option::None;
+        }
+
+        // Pinning y keeps solid textures from flickering between cells lying on the plane
+        let mut intersection = ray.at_distance(t);
+        intersection.y = self.y;
+        let mut hit_rec = HitRecord {
+            intersection,
+            normal: Self::NORMAL,
+            material: self.material.clone(),
+            t,
+            u: intersection.x,
+            v: intersection.z,
+            front_face: false,
+        };
+        hit_rec.set_face_normal(ray);
+        return Option::from(hit_rec);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    // Unbounded across the floor, the infinite diagonal also leaves the scene epsilon at its default
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return Option::from(AxisAlignedBoundingBox {
+            minimum: Point { x: -INF_F32, y: self.y - 0.0001, z: -INF_F32 },
+            maximum: Point { x: INF_F32, y: self.y + 0.0001, z: INF_F32 },
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::RayKind;
+    use crate::scenes::with_ground;
+    use crate::textures::solid::SolidColor;
+
+    fn straight_down(x: f32, z: f32) -> Ray {
+        return Ray { origin: Point { x, y: 5.0, z }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+    }
+
+    #[test]
+    fn a_ray_from_above_hits_the_floor_below_it() {
+        let floor = GroundPlane::new(-1.0, Arc::new(SolidColor { color: Color::WHITE }));
+        let hit_rec = floor.hit(straight_down(0.3, -0.7), 0.001, INF_F32).unwrap();
+        assert_eq!(hit_rec.t, 6.0);
+        assert_eq!(hit_rec.intersection, Point { x: 0.3, y: -1.0, z: -0.7 });
+        assert_eq!((hit_rec.u, hit_rec.v), (0.3, -0.7));
+        assert_eq!(hit_rec.normal, GroundPlane::NORMAL);
+        assert!(hit_rec.front_face);
+        // Level rays and rays going away never reach it
+        assert!(floor.hit(Ray { direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, ..straight_down(0.0, 0.0) }, 0.001, INF_F32).is_none());
+        assert!(floor.hit(Ray { direction: Vector3 { x: 0.0, y: 1.0, z: 0.0 }, ..straight_down(0.0, 0.0) }, 0.001, INF_F32).is_none());
+    }
+
+    #[test]
+    fn the_scene_floor_checker_alternates_across_cell_boundaries() {
+        let world = with_ground(Vec::new(), 0.0);
+        let albedo = |x: f32, z: f32| {
+            let hit_rec = world[0].hit(straight_down(x, z), 0.001, INF_F32).unwrap();
+            hit_rec.material.scatter(straight_down(x, z), &hit_rec).unwrap().attenuation
+        };
+        let origin_cell = albedo(0.5, 0.5);
+        for (x, z) in [(0.99, 0.5), (0.5, 0.01), (1.01, 1.01), (-1.01, -1.01), (2.5, 0.5)] {
+            assert_eq!(albedo(x, z), origin_cell, "at ({}, {})", x, z);
+        }
+        for (x, z) in [(1.01, 0.5), (0.5, -0.01), (-0.01, 0.5), (1.5, 0.01), (0.5, 1.5)] {
+            assert_ne!(albedo(x, z), origin_cell, "at ({}, {})", x, z);
+        }
+    }
+}
@@ -1,11 +1,13 @@
 use std::sync::Arc;
 
+use rand::{Rng, RngCore};
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
-use crate::geometry::ray::Ray;
+use crate::geometry::ray::{Ray, RayKind};
 use crate::geometry::vector::{Point, Vector3};
 use crate::materials::material::Material;
 use crate::objects::hittable::{HitRecord, Hittable};
-use crate::utils::{INF_F32, random_f32_range};
+use crate::utils::{INF_F32, random_f32};
 
 pub struct XYRect {
     pub x: (f32, f32),
@@ -47,6 +49,23 @@ impl Hittable for XYRect {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        return (self.x.1 - self.x.0) * (self.y.1 - self.y.0);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let point = Point {
+            x: self.x.0 + rng.gen::<f32>() * (self.x.1 - self.x.0),
+            y: self.y.0 + rng.gen::<f32>() * (self.y.1 - self.y.0),
+            z: self.k,
+        };
+        return Option::from((point, Self::NORMAL, 1.0 / self.area()));
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         return Option::from(AxisAlignedBoundingBox {
             minimum: Point {
@@ -61,6 +80,34 @@ impl Hittable for XYRect {
             },
         });
     }
+
+    // The cosine is taken unsigned so the panel can be sampled from either side
+    fn pdf_value(&self, origin: Point, v: Vector3, epsilon: f32) -> f32 {
+        let opt_hit_rec = self.hit(Ray { origin, direction: v, time: 0.0, kind: RayKind::Shadow }, epsilon, INF_F32);
+        if opt_hit_rec.is_none() {
+            return 0.0;
+        }
+
+        let hit_rec = opt_hit_rec.unwrap();
+        let area = (self.x.1 - self.x.0) * (self.y.1 - self.y.0);
+        let dist_squared = hit_rec.t.powi(2) * v.length_squared();
+        let cosine = (v.dot(hit_rec.normal) / v.length()).abs();
+
+        return dist_squared / (cosine * area);
+    }
+
+    fn random(&self, origin: Vector3) -> Vector3 {
+        return self.random_from(origin, (random_f32(), random_f32()));
+    }
+
+    fn random_from(&self, origin: Vector3, sample: (f32, f32)) -> Vector3 {
+        let point = Point {
+            x: sample.0 * (self.x.1 - self.x.0) + self.x.0,
+            y: sample.1 * (self.y.1 - self.y.0) + self.y.0,
+            z: self.k,
+        };
+        return point - origin;
+    }
 }
 
 pub struct XZRect {
@@ -103,6 +150,23 @@ impl Hittable for XZRect {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        return (self.x.1 - self.x.0) * (self.z.1 - self.z.0);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let point = Point {
+            x: self.x.0 + rng.gen::<f32>() * (self.x.1 - self.x.0),
+            y: self.k,
+            z: self.z.0 + rng.gen::<f32>() * (self.z.1 - self.z.0),
+        };
+        return Option::from((point, Self::NORMAL, 1.0 / self.area()));
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         return Option::from(AxisAlignedBoundingBox {
             minimum: Point {
@@ -118,8 +182,9 @@ impl Hittable for XZRect {
         });
     }
 
-    fn pdf_value(&self, origin: Point, v: Vector3) -> f32 {
-        let opt_hit_rec = self.hit(Ray { origin, direction: v, time: 0.0 }, 0.001, INF_F32);
+    // The cosine is taken unsigned so the panel can be sampled from either side
+    fn pdf_value(&self, origin: Point, v: Vector3, epsilon: f32) -> f32 {
+        let opt_hit_rec = self.hit(Ray { origin, direction: v, time: 0.0, kind: RayKind::Shadow }, epsilon, INF_F32);
         if opt_hit_rec.is_none() {
             return 0.0;
         }
@@ -133,12 +198,16 @@ impl Hittable for XZRect {
     }
 
     fn random(&self, origin: Vector3) -> Vector3 {
-        let random_point = Point {
-            x: random_f32_range(self.x.0, self.x.1),
+        return self.random_from(origin, (random_f32(), random_f32()));
+    }
+
+    fn random_from(&self, origin: Vector3, sample: (f32, f32)) -> Vector3 {
+        let point = Point {
+            x: sample.0 * (self.x.1 - self.x.0) + self.x.0,
             y: self.k,
-            z: random_f32_range(self.z.0, self.z.1),
+            z: sample.1 * (self.z.1 - self.z.0) + self.z.0,
         };
-        return random_point - origin;
+        return point - origin;
     }
 }
 
@@ -182,6 +251,23 @@ impl Hittable for YZRect {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        return (self.y.1 - self.y.0) * (self.z.1 - self.z.0);
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let point = Point {
+            x: self.k,
+            y: self.y.0 + rng.gen::<f32>() * (self.y.1 - self.y.0),
+            z: self.z.0 + rng.gen::<f32>() * (self.z.1 - self.z.0),
+        };
+        return Option::from((point, Self::NORMAL, 1.0 / self.area()));
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         return Option::from(AxisAlignedBoundingBox {
             minimum: Point {
@@ -196,4 +282,143 @@ impl Hittable for YZRect {
             },
         });
     }
+
+    // The cosine is taken unsigned so the panel can be sampled from either side
+    fn pdf_value(&self, origin: Point, v: Vector3, epsilon: f32) -> f32 {
+        let opt_hit_rec = self.hit(Ray { origin, direction: v, time: 0.0, kind: RayKind::Shadow }, epsilon, INF_F32);
+        if opt_hit_rec.is_none() {
+            return 0.0;
+        }
+
+        let hit_rec = opt_hit_rec.unwrap();
+        let area = (self.y.1 - self.y.0) * (self.z.1 - self.z.0);
+        let dist_squared = hit_rec.t.powi(2) * v.length_squared();
+        let cosine = (v.dot(hit_rec.normal) / v.length()).abs();
+
+        return dist_squared / (cosine * area);
+    }
+
+    fn random(&self, origin: Vector3) -> Vector3 {
+        return self.random_from(origin, (random_f32(), random_f32()));
+    }
+
+    fn random_from(&self, origin: Vector3, sample: (f32, f32)) -> Vector3 {
+        let point = Point {
+            x: self.k,
+            y: sample.0 * (self.y.1 - self.y.0) + self.y.0,
+            z: sample.1 * (self.z.1 - self.z.0) + self.z.0,
+        };
+        return point - origin;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::materials::light::DiffuseLight;
+    use crate::objects::hittable::FlipFace;
+
+    // Direct light from the panel onto a receiver at origin facing it, estimated from a
+    // 16x16 grid of light samples the way next event estimation does
+    fn nee_estimate(panel: &dyn Hittable, origin: Point, panel_normal: Vector3) -> Color {
+        let mut total = Color::BLACK;
+        for k in 0..256 {
+            let sample = (((k % 16) as f32 + 0.5) / 16.0, ((k / 16) as f32 + 0.5) / 16.0);
+            let v = panel.random_from(origin, sample);
+            let pdf = panel.pdf_value(origin, v, 0.001);
+            assert!(pdf > 0.0 && pdf.is_finite(), "pdf {} from {:?}", pdf, origin);
+
+            let ray = Ray { origin, direction: v, time: 0.0, kind: RayKind::Shadow };
+            let hit_rec = panel.hit(ray, 0.001, INF_F32).unwrap();
+            let emitted = hit_rec.material.emitted(ray, &hit_rec, hit_rec.u, hit_rec.v, hit_rec.intersection);
+            total += (v.direction().dot(panel_normal).abs() / pdf) * emitted;
+        }
+        return (1.0 / 256.0) * total;
+    }
+
+    fn panels(two_sided: bool) -> Vec<(Arc<dyn Hittable>, Vector3)> {
+        let color = Color { r: 4.0, g: 4.0, b: 4.0 };
+        let light = || -> Arc<dyn Material> {
+            Arc::new(if two_sided { DiffuseLight::new_two_sided(color) } else { DiffuseLight::new(color) })
+        };
+        return vec![
+            (Arc::new(XYRect { x: (-1.0, 1.0), y: (-0.5, 0.5), k: 0.0, material: light() }), XYRect::NORMAL),
+            (Arc::new(XZRect { x: (-1.0, 1.0), z: (-0.5, 0.5), k: 0.0, material: light() }), XZRect::NORMAL),
+            (Arc::new(YZRect { y: (-1.0, 1.0), z: (-0.5, 0.5), k: 0.0, material: light() }), YZRect::NORMAL),
+            (Arc::new(FlipFace { object: Arc::new(XZRect { x: (-1.0, 1.0), z: (-0.5, 0.5), k: 0.0, material: light() }) }), XZRect::NORMAL),
+        ];
+    }
+
+    #[test]
+    fn straight_rays_hit_inside_the_bounds_and_miss_outside() {
+        let material: Arc<dyn Material> = Arc::new(DiffuseLight::new(Color::WHITE));
+        let rects: Vec<(Arc<dyn Hittable>, usize)> = vec![
+            (Arc::new(XYRect { x: (-1.0, 1.0), y: (-0.5, 0.5), k: 2.0, material: material.clone() }), 2),
+            (Arc::new(XZRect { x: (-1.0, 1.0), z: (-0.5, 0.5), k: 2.0, material: material.clone() }), 1),
+            (Arc::new(YZRect { y: (-1.0, 1.0), z: (-0.5, 0.5), k: 2.0, material }), 0),
+        ];
+        for (rect, axis) in rects {
+            // The two in-plane axes in the order the rect names them
+            let (a, b) = match axis { 2 => (0, 1), 1 => (0, 2), _ => (1, 2) };
+            let mut origin = Point::ORIGIN;
+            origin[a] = 0.5;
+            origin[b] = 0.25;
+            let mut direction = Vector3::ORIGIN;
+            direction[axis] = 1.0;
+            let ray = Ray { origin, direction, time: 0.0, kind: RayKind::Camera };
+
+            let hit_rec = rect.hit(ray, 0.001, INF_F32).unwrap();
+            assert_eq!(hit_rec.t, 2.0);
+            assert_eq!(hit_rec.intersection[axis], 2.0);
+            assert_eq!((hit_rec.u, hit_rec.v), (0.75, 0.75));
+            // Met from behind, so the normal is turned back towards the ray
+            assert!(!hit_rec.front_face);
+            assert_eq!(hit_rec.normal, -1.0 * direction);
+            let reverse = Ray { origin: origin + 4.0 * direction, direction: -1.0 * direction, ..ray };
+            let front = rect.hit(reverse, 0.001, INF_F32).unwrap();
+            assert!(front.front_face && front.normal == direction && front.t == 2.0);
+
+            for outside in [1.5, -1.5] {
+                let mut off = ray;
+                off.origin[a] = outside;
+                assert!(rect.hit(off, 0.001, INF_F32).is_none());
+            }
+            let mut off = ray;
+            off.origin[b] = 0.75;
+            assert!(rect.hit(off, 0.001, INF_F32).is_none());
+            assert!(rect.hit(ray, 0.001, 1.5).is_none());
+
+            // The flat axis is padded, so the box has a volume for the BVH
+            let bound_box = rect.bounding_box(0.0, 1.0).unwrap();
+            assert!(bound_box.minimum[axis] < 2.0 && bound_box.maximum[axis] > 2.0);
+        }
+    }
+
+    // Reflects point through the panel's plane
+    fn mirror(point: Point, normal: Vector3) -> Point {
+        return point - 2.0 * point.dot(normal) * normal;
+    }
+
+    #[test]
+    fn two_sided_panels_light_both_sides_equally() {
+        let above = Point { x: 1.1, y: 1.2, z: 1.0 };
+        for (panel, normal) in panels(true) {
+            let front = nee_estimate(panel.as_ref(), above, normal);
+            let back = nee_estimate(panel.as_ref(), mirror(above, normal), normal);
+            assert!(front.r > 0.1, "{:?}", front);
+            assert!((front.r - back.r).abs() < 1e-4 * front.r, "{:?} above, {:?} below", front, back);
+        }
+    }
+
+    #[test]
+    fn one_sided_panels_only_light_their_front() {
+        let above = Point { x: 1.1, y: 1.2, z: 1.0 };
+        for (panel, normal) in panels(false) {
+            let (front, back) = (nee_estimate(panel.as_ref(), above, normal), nee_estimate(panel.as_ref(), mirror(above, normal), normal));
+            // FlipFace turns the panel over, so it lights the other side
+            let lit = if front.r > 0.0 { front } else { back };
+            assert!(lit.r > 0.1 && (front.r == 0.0) != (back.r == 0.0), "{:?} above, {:?} below", front, back);
+        }
+    }
 }
@@ -1,12 +1,15 @@
 use std::sync::Arc;
 
+use rand::RngCore;
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::onb::ONB;
-use crate::geometry::ray::Ray;
+use crate::geometry::ray::{Ray, RayKind};
 use crate::geometry::vector::{Point, Vector3};
 use crate::materials::material::Material;
 use crate::objects::hittable::{HitRecord, Hittable};
-use crate::utils::{INF_F32, PI, random_to_sphere};
+use crate::textures::texture::Texture;
+use crate::utils::{INF_F32, PI, random_to_sphere, random_to_sphere_from};
 
 pub struct Sphere {
     pub center: Point,
@@ -23,8 +26,10 @@ impl Sphere {
         return normal;
     }
 
+    // u runs once around from -x through +z, v from the bottom pole to the top. Normals that
+    // round to just past unit length at the poles are clamped so acos stays defined.
     fn get_sphere_uv(point: Point) -> (f32, f32) {
-        let theta = (-(point.y)).acos();
+        let theta = (-(point.y)).clamp(-1.0, 1.0).acos();
         let phi = (-(point.z)).atan2(point.x) + PI;
 
         let u = phi / (2.0 * PI);
@@ -33,24 +38,39 @@ impl Sphere {
     }
 }
 
+// Nearest root of |origin + t * direction - center| = radius within [t_min, t_max].
+// The discriminant is taken from the ray's closest approach and the roots from
+// q = -(b + sign(b) * sqrt(disc)), which stays accurate when the sphere is small and far away.
+fn hit_sphere(center: Point, radius: f32, ray: Ray, t_min: f32, t_max: f32) -> Option<f32> {
+    let oc = ray.origin - center;
+    let a = ray.direction.length_squared();
+    let b = oc.dot(ray.direction);
+    let c = oc.length_squared() - radius.powi(2);
+
+    let closest = oc - (b / a) * ray.direction;
+    let discriminant = a * (radius.powi(2) - closest.length_squared());
+    if discriminant < 0.0 {
+        return Option::None;
+    }
+
+    let q = -(b + b.signum() * discriminant.sqrt());
+    let (mut t0, mut t1) = (q / a, c / q);
+    if t0 > t1 {
+        std::mem::swap(&mut t0, &mut t1);
+    }
+
+    if t0 >= t_min && t0 <= t_max {
+        return Option::from(t0);
+    }
+    if t1 >= t_min && t1 <= t_max {
+        return Option::from(t1);
+    }
+    return Option::None;
+}
+
 impl Hittable for Sphere {
     fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = ray.origin - self.center;
-        let b = oc.dot(ray.direction);
-        let c = oc.length_squared() - self.radius.powi(2);
-        let mut discriminant = b.powi(2) - c;
-        if discriminant < 0.0 {
-            return Option::None;
-        }
-        discriminant = discriminant.sqrt();
-
-        let mut t = -b - discriminant;
-        if t < t_min || t > t_max {
-            t = -b + discriminant;
-            if t < t_min || t > t_max {
-                return Option::None;
-            }
-        }
+        let t = hit_sphere(self.center, self.radius, ray, t_min, t_max)?;
 
         let intersection = ray.at_distance(t);
         let normal = self.normal(intersection);
@@ -62,6 +82,19 @@ impl Hittable for Sphere {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        return 4.0 * PI * self.radius * self.radius;
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let point = self.center + self.radius.abs() * Vector3::random_unit_vector_with(rng);
+        return Option::from((point, self.normal(point), 1.0 / self.area()));
+    }
+
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
         let p = Point { x: self.radius, y: self.radius, z: self.radius };
         return Option::from(AxisAlignedBoundingBox {
@@ -70,8 +103,8 @@ impl Hittable for Sphere {
         });
     }
 
-    fn pdf_value(&self, o: Point, v: Vector3) -> f32 {
-        let opt_hit_rec = self.hit(Ray { origin: o, direction: v, time: 0.0 }, 0.001, INF_F32);
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
+        let opt_hit_rec = self.hit(Ray { origin: o, direction: v, time: 0.0, kind: RayKind::Shadow }, epsilon, INF_F32);
         if opt_hit_rec.is_none() {
             return 0.0;
         }
@@ -86,6 +119,12 @@ impl Hittable for Sphere {
         let uvw = ONB::build_from_w(direction);
         return uvw.local(random_to_sphere(self.radius, dist_sq));
     }
+
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        let direction = self.center - o;
+        let uvw = ONB::build_from_w(direction);
+        return uvw.local(random_to_sphere_from(self.radius, direction.length_squared(), sample));
+    }
 }
 
 pub struct MovingSphere {
@@ -113,23 +152,7 @@ impl MovingSphere {
 
 impl Hittable for MovingSphere {
     fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = ray.origin - self.center(ray.time);
-        let b = oc.dot(ray.direction);
-        let c = oc.length_squared() - self.radius.powi(2);
-
-        let mut discriminant = b.powi(2) - c;
-        if discriminant < 0.0 {
-            return Option::None;
-        }
-        discriminant = discriminant.sqrt();
-
-        let mut t = -b - discriminant;
-        if t < t_min || t > t_max {
-            t = -b + discriminant;
-            if t < t_min || t > t_max {
-                return Option::None;
-            }
-        }
+        let t = hit_sphere(self.center(ray.time), self.radius, ray, t_min, t_max)?;
 
         let intersection = ray.at_distance(t);
         let normal = self.normal(intersection, ray.time);
@@ -141,6 +164,14 @@ impl Hittable for MovingSphere {
         return Option::from(hit_rec);
     }
 
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        return 4.0 * PI * self.radius * self.radius;
+    }
+
     fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
         let p = Point { x: self.radius, y: self.radius, z: self.radius };
         let box0 = AxisAlignedBoundingBox {
@@ -154,3 +185,298 @@ impl Hittable for MovingSphere {
         Option::from(AxisAlignedBoundingBox::surrounding_box(box0, box1))
     }
 }
+
+// A sphere pushed outwards along its normal by up to `amplitude`, following the
+// luminance of a height texture sampled at the sphere's uv and the matching point on
+// the unit sphere, so the pattern doesn't change with the sphere's size or position.
+// It is intersected by sphere tracing inside the bounding sphere of radius + amplitude.
+pub struct DisplacedSphere {
+    pub center: Point,
+    pub radius: f32,
+    pub amplitude: f32,
+    pub height: Arc<dyn Texture>,
+    pub material: Arc<dyn Material>,
+}
+
+impl DisplacedSphere {
+    const MAX_STEPS: u32 = 256;
+    // The height field is not a true distance, so steps are shortened to stay safe on slopes
+    const STEP_SCALE: f32 = 0.5;
+
+    pub fn displacement(&self, direction: Vector3) -> f32 {
+        let (u, v) = Sphere::get_sphere_uv(direction);
+        return self.amplitude * self.height.color(u, v, direction).luminance().clamp(0.0, 1.0);
+    }
+
+    // Signed distance estimate, negative inside the displaced surface
+    fn distance(&self, point: Point) -> f32 {
+        let offset = point - self.center;
+        let dist = offset.length();
+        if dist == 0.0 {
+            return -self.radius;
+        }
+        return dist - (self.radius + self.displacement(offset / dist));
+    }
+
+    fn normal(&self, point: Point) -> Vector3 {
+        let h = 1e-3 * self.radius;
+        let dx = Vector3 { x: h, y: 0.0, z: 0.0 };
+        let dy = Vector3 { x: 0.0, y: h, z: 0.0 };
+        let dz = Vector3 { x: 0.0, y: 0.0, z: h };
+        return Vector3 {
+            x: self.distance(point + dx) - self.distance(point - dx),
+            y: self.distance(point + dy) - self.distance(point - dy),
+            z: self.distance(point + dz) - self.distance(point - dz),
+        }.direction();
+    }
+}
+
+impl Hittable for DisplacedSphere {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let outer = self.radius + self.amplitude;
+        let inside_bound = (ray.origin - self.center).length() < outer;
+        let t_start = if inside_bound { t_min } else { hit_sphere(self.center, outer, ray, t_min, t_max)? };
+        let t_end = hit_sphere(self.center, outer, ray, t_start + 1e-4 * outer, t_max).unwrap_or(t_max);
+
+        // March until the distance changes sign, so rays starting inside find the far side
+        let speed = ray.direction.length();
+        let min_step = 1e-4 * self.radius;
+        let outside = self.distance(ray.at_distance(t_start)) > 0.0;
+        let mut prev_t = t_start;
+        let mut t = t_start;
+        let mut crossed = false;
+        for _ in 0..Self::MAX_STEPS {
+            let d = self.distance(ray.at_distance(t));
+            if (d > 0.0) != outside {
+                crossed = true;
+                break;
+            }
+            prev_t = t;
+            t += (Self::STEP_SCALE * d.abs()).max(min_step) / speed;
+            if t > t_end {
+                break;
+            }
+        }
+        if !crossed {
+            return Option::None;
+        }
+
+        // Bisect the crossing and keep the end on the ray's starting side so that
+        // rays leaving the hit point don't immediately hit the surface again
+        let (mut near, mut far) = (prev_t, t);
+        for _ in 0..20 {
+            let mid = 0.5 * (near + far);
+            if (self.distance(ray.at_distance(mid)) > 0.0) == outside { near = mid; } else { far = mid; }
+        }
+        let t = near;
+        if t < t_min || t > t_max {
+            return Option::None;
+        }
+
+        let intersection = ray.at_distance(t);
+        let normal = self.normal(intersection);
+        let material = self.material.clone();
+        let (u, v) = Sphere::get_sphere_uv((intersection - self.center).direction());
+
+        let mut hit_rec = HitRecord { intersection, normal, material, t, u, v, front_face: false };
+        hit_rec.set_face_normal(ray);
+        return Option::from(hit_rec);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        let r = self.radius + self.amplitude;
+        let p = Point { x: r, y: r, z: r };
+        return Option::from(AxisAlignedBoundingBox {
+            minimum: self.center - p,
+            maximum: self.center + p,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::materials::dielectric::Dielectric;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::instances::Translate;
+    use crate::textures::perlin::{NoiseMode, NoiseTexture, Perlin};
+    use crate::utils::seed_thread_rng;
+    use crate::world::hittable_list::HittableList;
+
+    fn unit_sphere() -> Sphere {
+        return Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color::WHITE)) };
+    }
+
+    fn ray(origin: Point, direction: Vector3) -> Ray {
+        return Ray { origin, direction, time: 0.0, kind: RayKind::Camera };
+    }
+
+    // Textbook quadratic, for comparison
+    fn hit_naive(center: Point, radius: f32, ray: Ray) -> Option<f32> {
+        let oc = ray.origin - center;
+        let a = ray.direction.length_squared();
+        let half_b = oc.dot(ray.direction);
+        let c = oc.length_squared() - radius * radius;
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return Option::None;
+        }
+        return Option::from((-half_b - discriminant.sqrt()) / a);
+    }
+
+    #[test]
+    fn distant_hits_are_accurate() {
+        let sphere = unit_sphere();
+        let distance = 1e5;
+        for offset in [0.0f32, 0.5, 0.9] {
+            let r = ray(Point { x: offset, y: 0.0, z: distance }, Vector3 { x: 0.0, y: 0.0, z: -1.0 });
+            let expected = distance as f64 - (1.0 - (offset as f64).powi(2)).sqrt();
+            let t = sphere.hit(r, 0.001, INF_F32).unwrap().t;
+            // f32 spacing near 1e5 is about 0.008, so this is as close as the type allows
+            assert!((t as f64 - expected).abs() < 0.02, "stable t {} for offset {}, expected {}", t, offset, expected);
+            let naive = hit_naive(sphere.center, sphere.radius, r).map_or(f64::INFINITY, |t| t as f64);
+            assert!((naive - expected).abs() > 0.1, "naive t {} was accurate for offset {}", naive, offset);
+        }
+    }
+
+    #[test]
+    fn nearby_hits_match_the_quadratic() {
+        let sphere = unit_sphere();
+        let r = ray(Point { x: 0.3, y: 0.2, z: 5.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 });
+        let t = sphere.hit(r, 0.001, INF_F32).unwrap().t;
+        assert!((t - hit_naive(sphere.center, sphere.radius, r).unwrap()).abs() < 1e-5);
+        assert!(sphere.hit(ray(Point { x: 1.1, y: 0.0, z: 5.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 }), 0.001, INF_F32).is_none());
+    }
+
+    #[test]
+    fn axis_points_map_to_known_uvs() {
+        let expected = [
+            (Vector3 { x: 1.0, y: 0.0, z: 0.0 }, (0.5, 0.5)),
+            (Vector3 { x: -1.0, y: 0.0, z: 0.0 }, (0.0, 0.5)),
+            (Vector3 { x: 0.0, y: 1.0, z: 0.0 }, (0.5, 1.0)),
+            (Vector3 { x: 0.0, y: -1.0, z: 0.0 }, (0.5, 0.0)),
+            (Vector3 { x: 0.0, y: 0.0, z: 1.0 }, (0.25, 0.5)),
+            (Vector3 { x: 0.0, y: 0.0, z: -1.0 }, (0.75, 0.5)),
+        ];
+        let sphere = Sphere { center: Point { x: 1.0, y: 2.0, z: -3.0 }, radius: 2.0, material: Arc::new(Lambertian::new(Color::WHITE)) };
+        for (axis, (u, v)) in expected {
+            let (found_u, found_v) = Sphere::get_sphere_uv(axis);
+            assert!((found_u - u).abs() < 1e-6 && (found_v - v).abs() < 1e-6, "{:?} maps to ({}, {})", axis, found_u, found_v);
+
+            // Hit records carry the same coordinates, from straight outside each point
+            let r = ray(sphere.center + 5.0 * axis, -1.0 * axis);
+            let hit_rec = sphere.hit(r, 0.001, INF_F32).unwrap();
+            assert!((hit_rec.u - u).abs() < 1e-5 && (hit_rec.v - v).abs() < 1e-5, "hit at {:?} has ({}, {})", axis, hit_rec.u, hit_rec.v);
+        }
+    }
+
+    #[test]
+    fn seam_and_poles_stay_in_range() {
+        // Either side of the seam at -x lands at opposite ends of u
+        let (start, _) = Sphere::get_sphere_uv(Vector3 { x: -1.0, y: 0.0, z: 1e-4 }.direction());
+        let (end, _) = Sphere::get_sphere_uv(Vector3 { x: -1.0, y: 0.0, z: -1e-4 }.direction());
+        assert!(start < 0.001 && end > 0.999, "u {} and {} across the seam", start, end);
+
+        // Normals a rounding error past unit length at the poles
+        for y in [1.000001f32, -1.000001] {
+            let (u, v) = Sphere::get_sphere_uv(Vector3 { x: 0.0, y, z: 0.0 });
+            assert!(u.is_finite() && v.is_finite());
+            assert!((0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn hit_records_carry_the_sphere_material() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color { r: 0.3, g: 0.6, b: 0.9 }));
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: material.clone() });
+        assert!(Arc::ptr_eq(sphere.material().unwrap(), &material));
+        let r = ray(Point { x: 0.0, y: 0.0, z: 5.0 }, Vector3 { x: 0.0, y: 0.0, z: -1.0 });
+        assert!(Arc::ptr_eq(&sphere.hit(r, 0.001, INF_F32).unwrap().material, &material));
+
+        // Wrappers pass it through and aggregates have none of their own
+        let moved = Translate { object: sphere.clone(), offset: Point { x: 0.0, y: 2.0, z: 0.0 } };
+        assert!(Arc::ptr_eq(moved.material().unwrap(), &material));
+        assert!(HittableList { objects: vec![sphere] }.material().is_none());
+    }
+
+    #[test]
+    fn displaced_hits_move_by_the_height_texture() {
+        let sphere = DisplacedSphere {
+            center: Point { x: 1.0, y: -2.0, z: 0.5 },
+            radius: 1.0,
+            amplitude: 0.2,
+            height: Arc::new(NoiseTexture { noise: Perlin::seeded(9), scale: 3.0, mode: NoiseMode::Smooth }),
+            material: Arc::new(Lambertian::new(Color::WHITE)),
+        };
+        let mut rng = StdRng::seed_from_u64(10);
+        for _ in 0..100 {
+            // Straight at the center, so the base sphere would be hit at distance - radius
+            let outward = Vector3::random_unit_vector_with(&mut rng);
+            let distance = 5.0;
+            let r = ray(sphere.center + distance * outward, -1.0 * outward);
+            let t = sphere.hit(r, 0.001, INF_F32).unwrap().t;
+            let displacement = sphere.displacement(outward);
+            assert!(displacement >= 0.0 && displacement <= sphere.amplitude);
+            let base_t = distance - sphere.radius;
+            assert!((base_t - t - displacement).abs() < 2e-3, "moved by {}, height {}", base_t - t, displacement);
+        }
+    }
+
+    #[test]
+    fn rays_inside_glass_exit_through_a_back_face() {
+        seed_thread_rng(Option::from(237));
+        let sphere = Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Dielectric { refractive_index: 1.5 }) };
+        let direction = Vector3 { x: 0.2, y: 0.1, z: 1.0 }.direction();
+        let r = ray(Point { x: 0.3, y: 0.0, z: 0.0 }, direction);
+        let hit_rec = sphere.hit(r, 0.001, INF_F32).unwrap();
+
+        // The far root, since the near one is behind the origin
+        assert!(hit_rec.t > 0.0);
+        assert!((hit_rec.intersection.length() - 1.0).abs() < 1e-5);
+        assert!(!hit_rec.front_face);
+        let outward = hit_rec.intersection;
+        assert!((hit_rec.normal + outward).length() < 1e-5, "normal {:?} should point inwards", hit_rec.normal);
+
+        // Glass to air, so sin θt = 1.5 sin θi for the rays that get out
+        let sin_i = direction.cross(outward).length();
+        let mut refracted = 0;
+        for _ in 0..200 {
+            let scattered = hit_rec.material.scatter(r, &hit_rec).unwrap().specular_ray.unwrap().direction;
+            if scattered.dot(outward) > 0.0 {
+                refracted += 1;
+                assert!((scattered.cross(outward).length() - 1.5 * sin_i).abs() < 1e-4);
+            }
+        }
+        assert!(refracted > 100, "only {} of 200 rays left the glass", refracted);
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn surface_samples_lie_on_the_sphere_with_a_uniform_pdf() {
+        let center = Point { x: 1.0, y: -2.0, z: 0.5 };
+        let sphere = Sphere { center, radius: 2.0, material: Arc::new(Lambertian::new(Color::WHITE)) };
+        let mut rng = StdRng::seed_from_u64(274);
+        let n = 20_000;
+        let (mut mean, mut upper) = (Vector3::ORIGIN, 0);
+        for _ in 0..n {
+            let (point, normal, pdf) = sphere.sample_surface(&mut rng).unwrap();
+            let offset = point - center;
+            assert!((offset.length() - 2.0).abs() < 1e-5);
+            assert!((normal - 0.5 * offset).length() < 1e-5);
+            assert!((pdf - 1.0 / (16.0 * PI)).abs() < 1e-7);
+            mean += offset;
+            upper += (offset.y > 0.0) as usize;
+        }
+        // Spread evenly, so the samples balance out around the centre and split between the halves
+        assert!(((1.0 / n as f32) * mean).length() < 0.05, "samples average {:?} off centre", mean);
+        assert!((upper as f32 / n as f32 - 0.5).abs() < 0.02);
+    }
+}
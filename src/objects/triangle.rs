@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use rand::{Rng, RngCore};
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
+use crate::geometry::color::Color;
+use crate::geometry::ray::{Ray, RayKind};
+use crate::geometry::vector::{Point, Vector3};
+use crate::materials::light::DiffuseLight;
+use crate::materials::material::Material;
+use crate::objects::hittable::{HitRecord, Hittable};
+
+#[derive(Clone)]
+pub struct Triangle {
+    pub vertices: [Point; 3],
+    // Per-vertex shading normals, the flat face normal is used without them
+    pub normals: Option<[Vector3; 3]>,
+    pub material: Arc<dyn Material>,
+    pub wireframe: Option<Arc<Wireframe>>,
+}
+
+// Edge lines drawn over a triangle's shading for technical illustration. Camera rays landing
+// within half the line width of an edge see the line material instead, the neighbouring
+// triangle drawing the other half. Bounced rays see the plain surface.
+pub struct Wireframe {
+    pub material: Arc<dyn Material>,
+    // In pixels
+    pub width: f32,
+    // Angle one pixel covers, from Camera::pixel_angle, so lines keep their width on screen
+    pub pixel_angle: f32,
+}
+
+impl Wireframe {
+    // Unlit lines of a flat color, Color::BLACK for plain dark edges
+    pub fn new(color: Color, width: f32, pixel_angle: f32) -> Self {
+        return Self { material: Arc::new(DiffuseLight::new_two_sided(color)), width, pixel_angle };
+    }
+}
+
+impl Triangle {
+    // Rays closer than this to parallel with the plane count as misses
+    const PARALLEL_EPSILON: f32 = 1e-8;
+    // Keeps the box from collapsing to zero thickness on axis-aligned triangles
+    const BOX_PADDING: f32 = 0.0001;
+
+    pub fn new(a: Point, b: Point, c: Point, material: Arc<dyn Material>) -> Self {
+        return Self { vertices: [a, b, c], normals: Option::None, material, wireframe: Option::None };
+    }
+
+    pub fn with_normals(mut self, normals: [Vector3; 3]) -> Self {
+        self.normals = Option::from(normals);
+        return self;
+    }
+
+    pub fn with_wireframe(mut self, wireframe: Arc<Wireframe>) -> Self {
+        self.wireframe = Option::from(wireframe);
+        return self;
+    }
+
+    // Whether a hit at barycentric (u, v) and distance along the ray lies on a wireframe line.
+    // Each barycentric weight times the altitude onto the opposite edge is the distance to that edge.
+    fn on_edge(&self, wireframe: &Wireframe, u: f32, v: f32, distance: f32) -> bool {
+        let [a, b, c] = self.vertices;
+        let double_area = (b - a).cross(c - a).length();
+        let edge_distance = ((1.0 - u - v) * double_area / (c - b).length())
+            .min(u * double_area / (c - a).length())
+            .min(v * double_area / (b - a).length());
+        return edge_distance < 0.5 * wireframe.width * wireframe.pixel_angle * distance;
+    }
+}
+
+impl Hittable for Triangle {
+    // Möller–Trumbore: solves origin + t * direction = a + u * (b - a) + v * (c - a) directly,
+    // with u and v the barycentric weights of b and c
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let [a, b, c] = self.vertices;
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = ray.direction.cross(edge2);
+        let det = edge1.dot(p);
+        // Also catches zero-area triangles, whose edges are parallel so det is zero for every ray
+        if det.abs() < Self::PARALLEL_EPSILON {
+            return Option::None;
+        }
+
+        let inv_det = 1.0 / det;
+        let s = ray.origin - a;
+        let u = s.dot(p) * inv_det;
+        if u < 0.0 || u > 1.0 {
+            return Option::None;
+        }
+        let q = s.cross(edge1);
+        let v = ray.direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return Option::None;
+        }
+        let t = edge2.dot(q) * inv_det;
+        if t < t_min || t > t_max {
+            return Option::None;
+        }
+
+        let normal = match self.normals {
+            Some([n0, n1, n2]) => ((1.0 - u - v) * n0 + u * n1 + v * n2).direction(),
+            None => edge1.cross(edge2).direction(),
+        };
+        let material = match &self.wireframe {
+            Some(wireframe) if ray.kind == RayKind::Camera && self.on_edge(wireframe, u, v, t * ray.direction.length()) => wireframe.material.clone(),
+            _ => self.material.clone(),
+        };
+        let mut hit_rec = HitRecord {
+            intersection: ray.at_distance(t),
+            normal,
+            material,
+            t,
+            u,
+            v,
+            front_face: false,
+        };
+        hit_rec.set_face_normal(ray);
+        return Option::from(hit_rec);
+    }
+
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        return Option::from(&self.material);
+    }
+
+    fn area(&self) -> f32 {
+        let [a, b, c] = self.vertices;
+        return 0.5 * (b - a).cross(c - a).length();
+    }
+
+    // The square root keeps the barycentric weights from bunching up at vertex a
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        let [a, b, c] = self.vertices;
+        let r1 = rng.gen::<f32>().sqrt();
+        let r2 = rng.gen::<f32>();
+        let point = (1.0 - r1) * a + r1 * (1.0 - r2) * b + r1 * r2 * c;
+        return Option::from((point, (b - a).cross(c - a).direction(), 1.0 / self.area()));
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        let [a, b, c] = self.vertices;
+        let padding = Vector3 { x: Self::BOX_PADDING, y: Self::BOX_PADDING, z: Self::BOX_PADDING };
+        return Option::from(AxisAlignedBoundingBox {
+            minimum: a.min(b).min(c) - padding,
+            maximum: a.max(b).max(c) + padding,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::lambertian::Lambertian;
+    use crate::render::tests::{render_objects, test_camera, test_settings};
+    use crate::utils::INF_F32;
+
+    // Right triangle in the z = -2 plane, facing +z towards the origin
+    fn triangle() -> Triangle {
+        return Triangle::new(
+            Point { x: 0.0, y: 0.0, z: -2.0 },
+            Point { x: 1.0, y: 0.0, z: -2.0 },
+            Point { x: 0.0, y: 1.0, z: -2.0 },
+            Arc::new(Lambertian::new(Color::WHITE)),
+        );
+    }
+
+    fn ray_to(target: Point) -> Ray {
+        return Ray { origin: Point::ORIGIN, direction: target, time: 0.0, kind: RayKind::Camera };
+    }
+
+    #[test]
+    fn the_centroid_hits_with_equal_barycentric_weights() {
+        let triangle = triangle();
+        let [a, b, c] = triangle.vertices;
+        let centroid = (1.0 / 3.0) * (a + b + c);
+        let hit_rec = triangle.hit(ray_to(centroid), 0.001, INF_F32).unwrap();
+        assert!((hit_rec.t - 1.0).abs() < 1e-6);
+        assert!((hit_rec.u - 1.0 / 3.0).abs() < 1e-6 && (hit_rec.v - 1.0 / 3.0).abs() < 1e-6);
+        assert!((hit_rec.intersection - centroid).length() < 1e-6);
+        assert!(hit_rec.front_face);
+        assert_eq!(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: 1.0 });
+
+        // From behind the normal is turned back towards the ray
+        let behind = Ray { origin: Point { x: 0.0, y: 0.0, z: -4.0 }, direction: centroid - Point { x: 0.0, y: 0.0, z: -4.0 }, ..ray_to(centroid) };
+        let hit_rec = triangle.hit(behind, 0.001, INF_F32).unwrap();
+        assert!(!hit_rec.front_face);
+        assert_eq!(hit_rec.normal, Vector3 { x: 0.0, y: 0.0, z: -1.0 });
+    }
+
+    #[test]
+    fn rays_grazing_an_edge_hit_and_just_outside_miss() {
+        let triangle = triangle();
+        // The middle of the hypotenuse from b to c, and a little beyond it
+        let on_edge = triangle.hit(ray_to(Point { x: 0.5, y: 0.5, z: -2.0 }), 0.001, INF_F32).unwrap();
+        assert!((on_edge.u + on_edge.v - 1.0).abs() < 1e-5);
+        assert!(triangle.hit(ray_to(Point { x: 0.51, y: 0.51, z: -2.0 }), 0.001, INF_F32).is_none());
+        assert!(triangle.hit(ray_to(Point { x: -0.01, y: 0.5, z: -2.0 }), 0.001, INF_F32).is_none());
+        assert!(triangle.hit(ray_to(Point { x: 0.5, y: -0.01, z: -2.0 }), 0.001, INF_F32).is_none());
+    }
+
+    #[test]
+    fn rays_that_never_reach_the_plane_miss() {
+        let triangle = triangle();
+        // Parallel to the plane, pointing away from it, and stopping short of it
+        assert!(triangle.hit(ray_to(Point { x: 1.0, y: 1.0, z: 0.0 }), 0.001, INF_F32).is_none());
+        assert!(triangle.hit(ray_to(Point { x: 0.2, y: 0.2, z: 2.0 }), 0.001, INF_F32).is_none());
+        assert!(triangle.hit(ray_to(Point { x: 0.2, y: 0.2, z: -2.0 }), 0.001, 0.5).is_none());
+    }
+
+    #[test]
+    fn degenerate_triangles_never_hit() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Color::WHITE));
+        let a = Point { x: 0.0, y: 0.0, z: -2.0 };
+        let line = Triangle::new(a, Point { x: 1.0, y: 1.0, z: -2.0 }, Point { x: 2.0, y: 2.0, z: -2.0 }, material.clone());
+        let point = Triangle::new(a, a, a, material);
+        for target in [a, Point { x: 1.0, y: 1.0, z: -2.0 }, Point { x: 0.5, y: 0.7, z: -2.0 }] {
+            assert!(line.hit(ray_to(target), 0.001, INF_F32).is_none());
+            assert!(point.hit(ray_to(target), 0.001, INF_F32).is_none());
+        }
+    }
+
+    #[test]
+    fn vertex_normals_are_interpolated_and_the_box_is_tight() {
+        let up = Vector3 { x: 0.0, y: 0.0, z: 1.0 };
+        let tilted = Vector3 { x: 1.0, y: 0.0, z: 1.0 }.direction();
+        let triangle = triangle().with_normals([up, tilted, up]);
+        let at_b = triangle.hit(ray_to(Point { x: 0.98, y: 0.01, z: -2.0 }), 0.001, INF_F32).unwrap();
+        let at_c = triangle.hit(ray_to(Point { x: 0.01, y: 0.98, z: -2.0 }), 0.001, INF_F32).unwrap();
+        assert!((at_b.normal - tilted).length() < 0.05, "{:?}", at_b.normal);
+        assert!((at_c.normal - up).length() < 0.05, "{:?}", at_c.normal);
+
+        let bound_box = triangle.bounding_box(0.0, 1.0).unwrap();
+        let pad = Triangle::BOX_PADDING;
+        assert_eq!(bound_box.minimum, Point { x: -pad, y: -pad, z: -2.0 - pad });
+        assert_eq!(bound_box.maximum, Point { x: 1.0 + pad, y: 1.0 + pad, z: -2.0 + pad });
+    }
+
+    #[test]
+    fn wireframes_darken_the_edge_pixels_and_leave_the_interior_shaded() {
+        let settings = test_settings(40, 40, 16);
+        let camera = test_camera(&settings);
+        // Leaning back towards the light above
+        let corners = [Point { x: -0.8, y: -0.6, z: 0.6 }, Point { x: 0.8, y: -0.6, z: 0.6 }, Point { x: 0.0, y: 0.8, z: -0.8 }];
+        let plain = || Triangle::new(corners[0], corners[1], corners[2], Arc::new(Lambertian::new(Color::WHITE)));
+        // 3.2 pixels wide, so the line reaches every pixel beside a miss, which is at most the
+        // diagonal of a pixel from the edge, and none of those two or more pixels in
+        let wireframe = Arc::new(Wireframe::new(Color::BLACK, 3.2, camera.pixel_angle(settings.image_height)));
+        let framed = plain().with_wireframe(wireframe.clone());
+
+        let center_ray = |i: i32, j: i32| camera.ray_through_pixel(i as u32, j as u32, 40, 40, (0.5, 0.5), Point::ORIGIN).unwrap();
+        let hits = |i: i32, j: i32| (0..40).contains(&i) && (0..40).contains(&j) && plain().hit(center_ray(i, j), 0.001, INF_F32).is_some();
+        let all_hit = |i: i32, j: i32, reach: i32| (-reach..=reach).all(|di| (-reach..=reach).all(|dj| hits(i + di, j + dj)));
+        let (mut edge, mut interior) = (Vec::new(), Vec::new());
+        for j in 0..40 {
+            for i in 0..40 {
+                if !hits(i, j) {
+                    continue;
+                }
+                let on_line = Arc::ptr_eq(&framed.hit(center_ray(i, j), 0.001, INF_F32).unwrap().material, &wireframe.material);
+                if !all_hit(i, j, 1) {
+                    assert!(on_line, "pixel ({}, {}) beside the edge misses the line", i, j);
+                    edge.push((j * 40 + i) as usize);
+                } else if all_hit(i, j, 2) {
+                    assert!(!on_line, "pixel ({}, {}) inside the triangle is on the line", i, j);
+                    interior.push((j * 40 + i) as usize);
+                }
+            }
+        }
+        assert!(edge.len() > 30 && interior.len() > 100, "{} edge and {} interior pixels", edge.len(), interior.len());
+
+        // Rendered, the edges go dark while the interior is exactly as without the wireframe
+        let bare = render_objects(&settings, vec![Arc::new(plain())]);
+        let drawn = render_objects(&settings, vec![Arc::new(framed)]);
+        let darker = edge.iter().filter(|&&k| drawn.pixels[k].luminance() < 0.8 * bare.pixels[k].luminance()).count();
+        assert!(darker * 10 > 9 * edge.len(), "only {} of {} edge pixels darkened", darker, edge.len());
+        assert!(interior.iter().all(|&k| drawn.pixels[k] == bare.pixels[k] && bare.pixels[k].luminance() > 0.0));
+    }
+}
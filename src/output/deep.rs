@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::geometry::color::Color;
+
+// One depth slice of a pixel. Alpha is the share of the pixel's camera samples whose first hit
+// landed at this depth and the color is premultiplied by it, so a pixel's slices plus whatever
+// the environment adds behind them sum to its flat color.
+#[derive(Clone, Copy)]
+pub struct DeepSample {
+    pub depth: f32,
+    pub color: Color,
+    pub alpha: f32,
+}
+
+// The nearest few depth slices of every pixel, nearest first, for holdouts and depth-based
+// compositing. Colors are in the working color space, like Framebuffer::pixels.
+pub struct DeepFramebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Vec<DeepSample>>,
+}
+
+impl DeepFramebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        return Self { width, height, pixels: vec![Vec::new(); (width * height) as usize] };
+    }
+
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    // Plain text: a "deep <width> <height>" header, then a line per pixel in row order holding
+    // the slice count followed by depth r g b alpha for each slice
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "deep {} {}", self.width, self.height)?;
+        for samples in self.pixels.iter() {
+            write!(out, "{}", samples.len())?;
+            for s in samples.iter() {
+                write!(out, " {} {} {} {} {}", s.depth, s.color.r, s.color.g, s.color.b, s.alpha)?;
+            }
+            writeln!(out)?;
+        }
+        return out.flush();
+    }
+}
+
+// Groups one pixel's camera samples into depth slices while it is being rendered
+#[derive(Clone, Default)]
+pub struct DeepAccumulator {
+    // Depth sum, color sum and sample count of each slice
+    slices: Vec<(f32, Color, u32)>,
+}
+
+impl DeepAccumulator {
+    // Samples within this fraction of a slice's mean depth join it
+    const DEPTH_TOLERANCE: f32 = 0.01;
+    // Past this many slices samples join the nearest one, so grazing surfaces stay bounded
+    const MAX_SLICES: usize = 16;
+
+    pub fn add(&mut self, depth: f32, color: Color) {
+        let mut nearest: Option<(usize, f32)> = Option::None;
+        for (k, &(depth_sum, _, count)) in self.slices.iter().enumerate() {
+            let gap = (depth_sum / count as f32 - depth).abs();
+            if nearest.map_or(true, |(_, best)| gap < best) {
+                nearest = Option::from((k, gap));
+            }
+        }
+
+        let joins = match nearest {
+            Some((k, gap)) if gap <= Self::DEPTH_TOLERANCE * depth || self.slices.len() >= Self::MAX_SLICES => Option::from(k),
+            _ => Option::None,
+        };
+        match joins {
+            Some(k) => {
+                let slice = &mut self.slices[k];
+                slice.0 += depth;
+                slice.1 += color;
+                slice.2 += 1;
+            }
+            None => self.slices.push((depth, color, 1)),
+        }
+    }
+
+    // Up to max_slices of the nearest slices, weighted by the pixel's total sample count
+    pub fn resolve(&self, total_samples: u32, max_slices: usize) -> Vec<DeepSample> {
+        if total_samples == 0 {
+            return Vec::new();
+        }
+        let scale = 1.0 / total_samples as f32;
+        let mut samples: Vec<DeepSample> = self.slices.iter().map(|&(depth_sum, color_sum, count)| DeepSample {
+            depth: depth_sum / count as f32,
+            color: scale * color_sum,
+            alpha: scale * count as f32,
+        }).collect();
+        samples.sort_by(|a, b| a.depth.partial_cmp(&b.depth).unwrap());
+        samples.truncate(max_slices);
+        return samples;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::hittable::Hittable;
+    use crate::objects::rectangle::XYRect;
+    use crate::render::RenderSettings;
+    use crate::render::tests::{render_objects, test_camera, test_settings};
+
+    // A screen of strips 0.04 wide every 0.08 facing the camera at depth z, so half of it is
+    // see-through. Vertical strips run along y, the others along x.
+    fn screen(z: f32, vertical: bool) -> Vec<Arc<dyn Hittable>> {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        return (-40..40).map(|k| {
+            let strip = (0.08 * k as f32, 0.08 * k as f32 + 0.04);
+            let (x, y) = if vertical { (strip, (-4.0, 4.0)) } else { ((-4.0, 4.0), strip) };
+            Arc::new(XYRect { x, y, k: z, material: material.clone() }) as Arc<dyn Hittable>
+        }).collect();
+    }
+
+    #[test]
+    fn overlapping_screens_each_get_a_slice_at_their_depth() {
+        let settings = RenderSettings { deep_slices: 4, max_depth: 2, ..test_settings(9, 9, 64) };
+        let mut objects = screen(1.0, true);
+        objects.extend(screen(-1.0, false));
+        let framebuffer = render_objects(&settings, objects);
+        let deep = framebuffer.deep.as_ref().unwrap();
+
+        // Through the front screen's gaps half the samples reach the back one, which stops half
+        // of those again, and the rest see the black sky and leave no slice
+        let camera = test_camera(&settings);
+        let mut front_alpha = 0.0;
+        let mut back_alpha = 0.0;
+        // Near the middle, where the depth barely changes across a pixel
+        for (x, y) in [(4, 4), (3, 4), (4, 5)] {
+            let samples = &deep.pixels[deep.index(x, y)];
+            assert_eq!(samples.len(), 2, "pixel ({}, {}) has {} slices", x, y, samples.len());
+            let direction = camera.ray_through_pixel(x, y, 9, 9, (0.5, 0.5), Point::ORIGIN).unwrap().direction.direction();
+            let (front, back) = (samples[0], samples[1]);
+            assert!((front.depth - 2.0 / -direction.z).abs() < 0.02, "front at {}", front.depth);
+            assert!((back.depth - 4.0 / -direction.z).abs() < 0.04, "back at {}", back.depth);
+            front_alpha += front.alpha / 3.0;
+            back_alpha += back.alpha / 3.0;
+        }
+        assert!((front_alpha - 0.5).abs() < 0.15, "front alpha {}", front_alpha);
+        assert!((back_alpha - 0.25).abs() < 0.15, "back alpha {}", back_alpha);
+    }
+
+    #[test]
+    fn close_depths_share_a_slice() {
+        let mut acc = DeepAccumulator::default();
+        let grey = Color { r: 0.5, g: 0.5, b: 0.5 };
+        acc.add(2.0, grey);
+        acc.add(2.01, grey);
+        acc.add(4.0, Color::WHITE);
+        let samples = acc.resolve(4, 4);
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0].depth - 2.005).abs() < 1e-5 && samples[0].alpha == 0.5);
+        assert_eq!(samples[0].color, 0.25 * (grey + grey));
+        assert!(samples[1].depth == 4.0 && samples[1].alpha == 0.25);
+        // Only the nearest are kept
+        assert_eq!(acc.resolve(4, 1).len(), 1);
+        assert!(acc.resolve(0, 4).is_empty());
+    }
+}
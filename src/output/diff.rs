@@ -0,0 +1,76 @@
+use image::{ImageBuffer, Rgb, RgbImage};
+
+pub struct DiffReport {
+    // Largest per-channel difference of any pixel, in 8-bit steps
+    pub max_error: u8,
+    // Per-channel difference averaged over all pixels and channels
+    pub mean_error: f32,
+    // Absolute per-channel difference, black where the images agree
+    pub diff: RgbImage,
+}
+
+// Compares two renders of the same size, for checking a change against a reference image
+pub fn image_diff(a: &RgbImage, b: &RgbImage) -> DiffReport {
+    assert_eq!(a.dimensions(), b.dimensions(), "can only diff images of the same size");
+
+    let mut max_error = 0;
+    let mut total_error: u64 = 0;
+    let diff = ImageBuffer::from_fn(a.width(), a.height(), |x, y| {
+        let (pa, pb) = (a.get_pixel(x, y), b.get_pixel(x, y));
+        let mut d = [0u8; 3];
+        for c in 0..3 {
+            d[c] = (pa[c] as i16 - pb[c] as i16).unsigned_abs() as u8;
+            max_error = max_error.max(d[c]);
+            total_error += d[c] as u64;
+        }
+        Rgb(d)
+    });
+
+    let n_values = (a.width() * a.height() * 3).max(1) as f32;
+    return DiffReport { max_error, mean_error: total_error as f32 / n_values, diff };
+}
+
+// Panics with the error figures if any pixel differs by more than tolerance
+pub fn assert_images_close(a: &RgbImage, b: &RgbImage, tolerance: u8) {
+    let report = image_diff(a, b);
+    assert!(report.max_error <= tolerance, "images differ by up to {} (mean {:.3}), tolerance is {}",
+            report.max_error, report.mean_error, tolerance);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient() -> RgbImage {
+        return ImageBuffer::from_fn(8, 6, |x, y| Rgb([(x * 30) as u8, (y * 40) as u8, 200]));
+    }
+
+    #[test]
+    fn identical_images_have_no_error() {
+        let report = image_diff(&gradient(), &gradient());
+        assert_eq!(report.max_error, 0);
+        assert_eq!(report.mean_error, 0.0);
+        assert!(report.diff.pixels().all(|p| *p == Rgb([0, 0, 0])));
+        assert_images_close(&gradient(), &gradient(), 0);
+    }
+
+    #[test]
+    fn a_single_pixel_change_is_found_with_its_size() {
+        let mut changed = gradient();
+        changed.put_pixel(3, 2, Rgb([90 + 17, 80, 200 - 5]));
+        let report = image_diff(&gradient(), &changed);
+        assert_eq!(report.max_error, 17);
+        assert_eq!(report.mean_error, 22.0 / (8.0 * 6.0 * 3.0));
+        assert_eq!(*report.diff.get_pixel(3, 2), Rgb([17, 0, 5]));
+        assert_eq!(report.diff.pixels().filter(|p| **p != Rgb([0, 0, 0])).count(), 1);
+        assert_images_close(&gradient(), &changed, 17);
+    }
+
+    #[test]
+    #[should_panic(expected = "images differ by up to 17")]
+    fn changes_over_the_tolerance_fail() {
+        let mut changed = gradient();
+        changed.put_pixel(0, 0, Rgb([17, 0, 200]));
+        assert_images_close(&gradient(), &changed, 16);
+    }
+}
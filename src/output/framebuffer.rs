@@ -0,0 +1,284 @@
+use image::{EncodableLayout, ImageBuffer, ImageResult, Pixel, Rgb, Rgba, RgbImage};
+
+use crate::geometry::color::{Color, Gamma};
+use crate::geometry::color_space::ColorSpace;
+use crate::geometry::tonemap::ToneMap;
+use crate::output::deep::DeepFramebuffer;
+use crate::output::text::{draw_text, text_height};
+use crate::render::RenderStats;
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+    pub variance: Vec<f32>,
+    pub sample_counts: Vec<u32>,
+    // Fraction of each pixel's camera rays that hit geometry
+    pub coverage: Vec<f32>,
+    // Save with the coverage as an alpha channel
+    pub alpha: bool,
+    pub normals: Option<Vec<Color>>,
+    pub deep: Option<DeepFramebuffer>,
+    pub color_space: ColorSpace,
+    // Applied after the color space's display transform. ACEScg output is already tone mapped
+    // by its ODT, so this is meant for sRGB renders.
+    pub tone_map: ToneMap,
+    pub exposure: f32,
+    pub gamma: Gamma,
+    pub stats: RenderStats,
+    // Burned into the bottom-left corner when saving
+    pub label: Option<String>,
+}
+
+impl Framebuffer {
+    // Keeps near-black pixels from dominating the relative error
+    pub const MIN_LUMINANCE: f32 = 0.01;
+
+    pub fn new(width: u32, height: u32, with_normals: bool) -> Self {
+        let size = (width * height) as usize;
+        return Self {
+            width,
+            height,
+            pixels: vec![Color::BLACK; size],
+            variance: vec![0.0; size],
+            sample_counts: vec![0; size],
+            coverage: vec![0.0; size],
+            alpha: false,
+            normals: if with_normals { Option::from(vec![Color::BLACK; size]) } else { None },
+            deep: None,
+            color_space: ColorSpace::Srgb,
+            tone_map: ToneMap::None,
+            exposure: 1.0,
+            gamma: Gamma::DEFAULT,
+            stats: RenderStats::default(),
+            label: None,
+        };
+    }
+
+    pub fn index(&self, x: u32, y: u32) -> usize {
+        (y * self.width + x) as usize
+    }
+
+    // Linear display color of a pixel, ready for gamma encoding
+    fn display_color(&self, x: u32, y: u32) -> Color {
+        let color = self.color_space.to_display(self.pixels[self.index(x, y)]);
+        return self.tone_map.apply(color, self.exposure);
+    }
+
+    pub fn to_image(&self, dither: bool) -> RgbImage {
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let pixel = self.display_color(x, y);
+            if dither { pixel.to_dithered_pixel(1, self.gamma, x, y) } else { pixel.to_pixel(1, self.gamma) }
+        });
+    }
+
+    pub fn to_image16(&self) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            self.display_color(x, y).to_pixel16(1, self.gamma)
+        });
+    }
+
+    // Writes 8 or 16 bits per channel, dithering only applies to 8-bit output
+    pub fn save(&self, path: &str, bit_depth: u8, dither: bool) -> ImageResult<()> {
+        if bit_depth == 16 {
+            let image = self.to_image16();
+            if self.alpha {
+                let rgba = self.with_alpha(&image, |a| (a * u16::MAX as f32) as u16);
+                return self.save_labelled(rgba, Rgba([u16::MAX; 4]), path);
+            }
+            return self.save_labelled(image, Rgb([u16::MAX; 3]), path);
+        }
+        let image = self.to_image(dither);
+        if self.alpha {
+            let rgba = self.with_alpha(&image, |a| (a * u8::MAX as f32) as u8);
+            return self.save_labelled(rgba, Rgba([u8::MAX; 4]), path);
+        }
+        return self.save_labelled(image, Rgb([u8::MAX; 3]), path);
+    }
+
+    fn with_alpha<T: image::Primitive + 'static>(&self, image: &ImageBuffer<Rgb<T>, Vec<T>>, to_channel: impl Fn(f32) -> T) -> ImageBuffer<Rgba<T>, Vec<T>> {
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let p = image.get_pixel(x, y);
+            Rgba([p[0], p[1], p[2], to_channel(self.coverage[self.index(x, y)].clamp(0.0, 1.0))])
+        });
+    }
+
+    fn save_labelled<P>(&self, mut image: ImageBuffer<P, Vec<P::Subpixel>>, label_color: P, path: &str) -> ImageResult<()>
+        where P: Pixel + 'static, [P::Subpixel]: EncodableLayout {
+        if let Some(label) = &self.label {
+            let margin = 2;
+            let y = self.height.saturating_sub(text_height() + margin);
+            draw_text(&mut image, label, margin, y, label_color);
+        }
+        return image.save(path);
+    }
+
+    // Standard error of each pixel's mean luminance relative to that luminance, scaled so the
+    // worst pixel is white. Bright areas are where noise remains, or where adaptive sampling
+    // could not bring it down.
+    pub fn convergence_image(&self) -> RgbImage {
+        let errors: Vec<f32> = (0..self.pixels.len()).map(|idx| {
+            let count = self.sample_counts[idx];
+            if count == 0 {
+                return 0.0;
+            }
+            relative_error(self.variance[idx], count, self.pixels[idx].luminance())
+        }).collect();
+        let max_error = errors.iter().cloned().fold(0.0, f32::max);
+        let scale = if max_error > 0.0 { 255.999 / max_error } else { 0.0 };
+        return ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let value = (scale * errors[self.index(x, y)]) as u8;
+            Rgb([value; 3])
+        });
+    }
+
+    // Normals are stored as colors already mapped to [0, 1], so they are written without gamma
+    pub fn normals_image(&self) -> Option<RgbImage> {
+        let normals = self.normals.as_ref()?;
+        return Option::from(ImageBuffer::from_fn(self.width, self.height, |x, y| {
+            let normal = normals[self.index(x, y)].map(|c| 255.999 * c.clamp(0.0, 1.0));
+            Rgb([normal.r as u8, normal.g as u8, normal.b as u8])
+        }));
+    }
+}
+
+// Standard error of a pixel's mean luminance over that luminance, from the per-sample variance
+pub fn relative_error(variance: f32, sample_count: u32, luminance: f32) -> f32 {
+    let std_error = (variance / sample_count as f32).sqrt();
+    return std_error / luminance.max(Framebuffer::MIN_LUMINANCE);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::materials::lambertian::Lambertian;
+    use crate::materials::metal::Metal;
+    use crate::objects::hittable::Hittable;
+    use crate::objects::rectangle::XZRect;
+    use crate::objects::sphere::Sphere;
+    use crate::render::RenderSettings;
+    use crate::render::tests::{render_objects, render_world, test_camera, test_settings};
+    use crate::utils::INF_F32;
+    use crate::world::hittable_list::HittableList;
+
+    // A 256x1 framebuffer ramping over a narrow range, about 13 steps of an 8-bit channel
+    fn shallow_ramp() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(256, 1, false);
+        for (x, pixel) in framebuffer.pixels.iter_mut().enumerate() {
+            let value = 0.2 + 0.05 * x as f32 / 255.0;
+            *pixel = Color { r: value, g: value, b: value };
+        }
+        return framebuffer;
+    }
+
+    fn temp_path(name: &str) -> String {
+        return env::temp_dir().join(format!("raytracer_{}_{}.png", name, process::id())).to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn sixteen_bit_output_keeps_smooth_ramps_smooth() {
+        let framebuffer = shallow_ramp();
+        let (path16, path8) = (temp_path("ramp16"), temp_path("ramp8"));
+        framebuffer.save(&path16, 16, false).unwrap();
+        framebuffer.save(&path8, 8, false).unwrap();
+
+        let deep = image::open(&path16).unwrap();
+        assert!(matches!(deep, image::DynamicImage::ImageRgb16(_)));
+        let deep = deep.to_rgb16();
+        assert!((1..256).all(|x| deep.get_pixel(x, 0)[0] > deep.get_pixel(x - 1, 0)[0]));
+
+        let shallow = image::open(&path8).unwrap().to_rgb8();
+        let flat_steps = (1..256).filter(|&x| shallow.get_pixel(x, 0)[0] == shallow.get_pixel(x - 1, 0)[0]).count();
+        assert!(flat_steps > 200, "only {} of 255 8-bit steps repeat", flat_steps);
+    }
+
+    #[test]
+    fn the_label_is_burnt_into_the_bottom_left_corner() {
+        let mut framebuffer = Framebuffer::new(64, 32, false);
+        for pixel in framebuffer.pixels.iter_mut() {
+            *pixel = Color { r: 0.1, g: 0.1, b: 0.1 };
+        }
+        let (plain, labelled) = (temp_path("plain"), temp_path("labelled"));
+        framebuffer.save(&plain, 8, false).unwrap();
+        framebuffer.label = Option::from("100spp".to_string());
+        framebuffer.save(&labelled, 8, false).unwrap();
+
+        let (plain, labelled) = (image::open(&plain).unwrap().to_rgb8(), image::open(&labelled).unwrap().to_rgb8());
+        let changed: Vec<(u32, u32)> = (0..64 * 32).map(|k| (k % 64, k / 64))
+            .filter(|&(x, y)| plain.get_pixel(x, y) != labelled.get_pixel(x, y))
+            .collect();
+        assert!(changed.len() > 50, "only {} pixels changed", changed.len());
+        assert!(changed.iter().all(|&(x, y)| x < 48 && y >= 32 - text_height() - 2));
+    }
+
+    #[test]
+    fn glossy_reflections_show_up_brighter_than_flat_diffuse_areas() {
+        // A rough metal ball reflecting the light over a floor lit with plenty of light samples,
+        // which only the floor can use
+        let metal: Arc<dyn Hittable> = Arc::new(Sphere {
+            center: Point { x: 0.0, y: 0.0, z: 0.0 },
+            radius: 0.5,
+            material: Arc::new(Metal { color: Color::WHITE, fuzz: 0.6 }),
+        });
+        let floor: Arc<dyn Hittable> = Arc::new(XZRect { x: (-2.0, 2.0), z: (-2.0, 2.0), k: -1.0, material: Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 })) });
+        let settings = RenderSettings { max_depth: 2, light_samples: 8, ..test_settings(32, 32, 16) };
+        let framebuffer = render_objects(&settings, vec![metal.clone(), floor.clone()]);
+        let map = framebuffer.convergence_image();
+
+        let camera = test_camera(&settings);
+        let (mut glossy, mut diffuse) = (Vec::new(), Vec::new());
+        for k in 0..32 * 32 {
+            let ray = camera.ray_through_pixel(k % 32, k / 32, 32, 32, (0.5, 0.5), Point::ORIGIN).unwrap();
+            let value = map.get_pixel(k % 32, k / 32)[0] as f32;
+            if metal.hit(ray, 0.001, INF_F32).is_some() {
+                glossy.push(value);
+            } else if floor.hit(ray, 0.001, INF_F32).is_some() && (k % 32 < 8 || k % 32 >= 24) {
+                // Flat floor well to the sides of the ball's penumbra
+                diffuse.push(value);
+            }
+        }
+        let mean = |values: &[f32]| values.iter().sum::<f32>() / values.len() as f32;
+        assert!(glossy.len() > 20 && diffuse.len() > 20);
+        assert!(mean(&glossy) > 2.0 * mean(&diffuse), "glossy {} against diffuse {}", mean(&glossy), mean(&diffuse));
+    }
+
+    #[test]
+    fn alpha_is_zero_where_camera_rays_miss_and_one_on_the_sphere() {
+        let settings = RenderSettings { alpha: true, ..test_settings(24, 24, 4) };
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 0.6, material: Arc::new(Lambertian::new(Color::WHITE)) });
+        // Only the sphere in the world, the test light is just aimed at
+        let framebuffer = render_world(&settings, Arc::new(HittableList { objects: vec![sphere.clone()] }));
+        let path = temp_path("alpha");
+        framebuffer.save(&path, 8, false).unwrap();
+        let image = image::open(&path).unwrap();
+        assert!(image.color().has_alpha());
+        let image = image.to_rgba8();
+
+        // Pixels whose whole jitter square looks at the sphere, or past it
+        let camera = test_camera(&settings);
+        let corners_hit = |x: u32, y: u32| [(0.0, 0.0), (0.999, 0.0), (0.0, 0.999), (0.999, 0.999)].iter().filter(|&&jitter| {
+            let ray = camera.ray_through_pixel(x, y, 24, 24, jitter, Point::ORIGIN).unwrap();
+            sphere.hit(ray, 0.001, INF_F32).is_some()
+        }).count();
+        let (mut inside, mut outside) = (0, 0);
+        for (x, y, pixel) in image.enumerate_pixels() {
+            match corners_hit(x, y) {
+                4 => {
+                    assert_eq!(pixel[3], 255, "({}, {})", x, y);
+                    inside += 1;
+                }
+                0 => {
+                    assert_eq!(pixel[3], 0, "({}, {})", x, y);
+                    outside += 1;
+                }
+                _ => {}
+            }
+        }
+        assert!(inside > 50 && outside > 200, "{} pixels inside, {} outside", inside, outside);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod framebuffer;
+pub mod postprocess;
+pub mod diff;
+pub mod text;
+pub mod deep;
@@ -0,0 +1,196 @@
+use crate::geometry::color::Color;
+use crate::output::framebuffer::Framebuffer;
+
+// Replaces pixels whose sample variance is over the threshold with the median-luminance
+// pixel of their 3x3 neighbourhood. A lone firefly is never the median, while a bright
+// region covering most of the window keeps its value.
+pub fn remove_fireflies(framebuffer: &mut Framebuffer, variance_threshold: f32) {
+    let source = framebuffer.pixels.clone();
+    let width = framebuffer.width as i32;
+    let height = framebuffer.height as i32;
+
+    for y in 0..height {
+        for x in 0..width {
+            let idx = framebuffer.index(x as u32, y as u32);
+            if framebuffer.variance[idx] <= variance_threshold {
+                continue;
+            }
+
+            let mut window: Vec<Color> = Vec::with_capacity(9);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let nx = (x + dx).clamp(0, width - 1) as u32;
+                    let ny = (y + dy).clamp(0, height - 1) as u32;
+                    window.push(source[framebuffer.index(nx, ny)]);
+                }
+            }
+            window.sort_by(|a, b| a.luminance().partial_cmp(&b.luminance()).unwrap_or(std::cmp::Ordering::Equal));
+            framebuffer.pixels[idx] = window[window.len() / 2];
+        }
+    }
+}
+
+// Cheap anti-aliasing for low sample counts. A pixel is on an edge when its normal
+// turns by more than ~25 degrees towards a neighbour, or when there are no normals,
+// when its luminance differs from a neighbour by more than a quarter of the brighter
+// one. Edge pixels are blended with the neighbours across the edge, flat regions are kept.
+pub fn smooth_edges(framebuffer: &mut Framebuffer) {
+    const NORMAL_COS: f32 = 0.9;
+    const LUMINANCE_CONTRAST: f32 = 0.25;
+    const BLEND: f32 = 0.125;
+
+    let source = framebuffer.pixels.clone();
+    let normals = framebuffer.normals.clone();
+    let width = framebuffer.width as i32;
+    let height = framebuffer.height as i32;
+
+    for y in 0..height {
+        for x in 0..width {
+            let idx = framebuffer.index(x as u32, y as u32);
+            let mut sum = Color::BLACK;
+            let mut weight = 0.0;
+
+            for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter() {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width || ny >= height {
+                    continue;
+                }
+                let n_idx = framebuffer.index(nx as u32, ny as u32);
+
+                let is_edge = match &normals {
+                    // Stored normals are mapped to [0, 1], undo that before comparing
+                    Some(normals) => {
+                        let (a, b) = (normals[idx].map(|c| 2.0 * c - 1.0), normals[n_idx].map(|c| 2.0 * c - 1.0));
+                        let dot = a.r * b.r + a.g * b.g + a.b * b.b;
+                        let len = ((a.r * a.r + a.g * a.g + a.b * a.b) * (b.r * b.r + b.g * b.g + b.b * b.b)).sqrt();
+                        len > 0.0 && dot < NORMAL_COS * len
+                    }
+                    None => {
+                        let (la, lb) = (source[idx].luminance(), source[n_idx].luminance());
+                        (la - lb).abs() > LUMINANCE_CONTRAST * la.max(lb)
+                    }
+                };
+                if is_edge {
+                    sum += source[n_idx];
+                    weight += BLEND;
+                }
+            }
+
+            if weight > 0.0 {
+                framebuffer.pixels[idx] = (1.0 - weight) * source[idx] + (BLEND * sum);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::hittable::Hittable;
+    use crate::objects::rectangle::XYRect;
+    use crate::objects::sphere::Sphere;
+    use crate::render::tests::{render_objects, test_camera, test_settings};
+    use crate::utils::INF_F32;
+
+    const FLAT: Color = Color { r: 0.2, g: 0.2, b: 0.2 };
+    const BRIGHT: Color = Color { r: 50.0, g: 50.0, b: 50.0 };
+
+    // A flat 12x12 image with one firefly at (2, 2) and a 4x4 bright cluster at (6..10, 6..10)
+    fn flat_with_firefly_and_cluster(cluster_variance: f32) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(12, 12, false);
+        for pixel in framebuffer.pixels.iter_mut() {
+            *pixel = FLAT;
+        }
+        let firefly = framebuffer.index(2, 2);
+        framebuffer.pixels[firefly] = BRIGHT;
+        framebuffer.variance[firefly] = 100.0;
+        for y in 6..10 {
+            for x in 6..10 {
+                let idx = framebuffer.index(x, y);
+                framebuffer.pixels[idx] = BRIGHT;
+                framebuffer.variance[idx] = cluster_variance;
+            }
+        }
+        return framebuffer;
+    }
+
+    #[test]
+    fn removes_a_lone_firefly_and_keeps_a_cluster() {
+        let mut framebuffer = flat_with_firefly_and_cluster(0.0);
+        let before = framebuffer.pixels.clone();
+        remove_fireflies(&mut framebuffer, 1.0);
+
+        assert_eq!(framebuffer.pixels[framebuffer.index(2, 2)], FLAT);
+        let changed: Vec<usize> = (0..before.len()).filter(|&k| framebuffer.pixels[k] != before[k]).collect();
+        assert_eq!(changed, vec![framebuffer.index(2, 2)]);
+    }
+
+    #[test]
+    fn a_noisy_cluster_keeps_its_inside() {
+        // Even where the whole cluster is flagged, its pixels mostly see bright neighbours
+        let mut framebuffer = flat_with_firefly_and_cluster(100.0);
+        remove_fireflies(&mut framebuffer, 1.0);
+
+        assert_eq!(framebuffer.pixels[framebuffer.index(2, 2)], FLAT);
+        for (x, y) in [(7, 7), (8, 7), (7, 8), (8, 8), (7, 6), (6, 8)] {
+            assert_eq!(framebuffer.pixels[framebuffer.index(x, y)], BRIGHT, "({}, {})", x, y);
+        }
+    }
+
+    // A small sphere in front of a wall, at one sample per pixel so its outline is jagged
+    fn render_sphere_on_wall(edge_aa: bool) -> Framebuffer {
+        let mut settings = test_settings(32, 32, 1);
+        settings.edge_aa = edge_aa;
+        let grey = Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 }));
+        let objects: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(Sphere { center: Point::ORIGIN, radius: 0.4, material: grey.clone() }),
+            Arc::new(XYRect { x: (-4.0, 4.0), y: (-4.0, 4.0), k: -1.0, material: grey }),
+        ];
+        return render_objects(&settings, objects);
+    }
+
+    // Summed luminance steps between neighbouring pixels where one looks at the sphere and
+    // the other past it
+    fn step_contrast(framebuffer: &Framebuffer, on_sphere: &[bool]) -> f32 {
+        let mut total = 0.0;
+        for y in 0..32 {
+            for x in 0..32 {
+                let idx = framebuffer.index(x, y);
+                for (nx, ny) in [(x + 1, y), (x, y + 1)] {
+                    if nx < 32 && ny < 32 && on_sphere[idx] != on_sphere[framebuffer.index(nx, ny)] {
+                        total += (framebuffer.pixels[idx].luminance() - framebuffer.pixels[framebuffer.index(nx, ny)].luminance()).abs();
+                    }
+                }
+            }
+        }
+        return total;
+    }
+
+    #[test]
+    fn edge_smoothing_softens_the_outline_and_keeps_flat_regions() {
+        let (before, after) = (render_sphere_on_wall(false), render_sphere_on_wall(true));
+        assert!(after.normals.is_none());
+
+        let camera = test_camera(&test_settings(32, 32, 1));
+        let sphere = Sphere { center: Point::ORIGIN, radius: 0.4, material: Arc::new(Lambertian::new(Color::WHITE)) };
+        let on_sphere: Vec<bool> = (0..32 * 32).map(|k| {
+            let ray = camera.ray_through_pixel(k % 32, k / 32, 32, 32, (0.5, 0.5), Point::ORIGIN).unwrap();
+            sphere.hit(ray, 0.001, INF_F32).is_some()
+        }).collect();
+        let contrast = (step_contrast(&before, &on_sphere), step_contrast(&after, &on_sphere));
+        assert!(contrast.1 < 0.85 * contrast.0, "step contrast went from {} to {}", contrast.0, contrast.1);
+        for y in 0..32 {
+            for x in 0..32 {
+                // The sphere covers the middle of the image, well inside 6..26
+                if !(6..26).contains(&x) || !(6..26).contains(&y) {
+                    let idx = before.index(x, y);
+                    assert_eq!(before.pixels[idx], after.pixels[idx], "wall pixel ({}, {}) changed", x, y);
+                }
+            }
+        }
+    }
+}
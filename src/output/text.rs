@@ -0,0 +1,89 @@
+use image::{ImageBuffer, Pixel};
+
+// 3x5 glyphs, one row per byte with the leftmost pixel in bit 2
+fn glyph(c: char) -> [u8; 5] {
+    return match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        'm' => [0b000, 0b000, 0b111, 0b111, 0b101],
+        'p' => [0b000, 0b111, 0b101, 0b111, 0b100],
+        's' => [0b000, 0b011, 0b010, 0b001, 0b110],
+        'x' => [0b000, 0b101, 0b010, 0b010, 0b101],
+        // Anything else, including spaces, is left blank
+        _ => [0; 5],
+    };
+}
+
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+const SCALE: u32 = 2;
+
+// Burns text into the image with its top-left corner at (x, y), clipped to the image.
+// Only digits and the letters of "spp", "ms" and "x" have glyphs.
+pub fn draw_text<P: Pixel + 'static>(buf: &mut ImageBuffer<P, Vec<P::Subpixel>>, text: &str, x: u32, y: u32, color: P) {
+    for (n, c) in text.chars().enumerate() {
+        let origin_x = x + n as u32 * (GLYPH_WIDTH + 1) * SCALE;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                for sy in 0..SCALE {
+                    for sx in 0..SCALE {
+                        let px = origin_x + col * SCALE + sx;
+                        let py = y + row as u32 * SCALE + sy;
+                        if px < buf.width() && py < buf.height() {
+                            buf.put_pixel(px, py, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Height of a line of text in pixels, for placing it against the bottom of an image
+pub fn text_height() -> u32 {
+    return GLYPH_HEIGHT * SCALE;
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    const BACKGROUND: Rgb<u8> = Rgb([40, 60, 80]);
+    const INK: Rgb<u8> = Rgb([255, 255, 255]);
+
+    #[test]
+    fn drawn_text_changes_exactly_its_glyph_pixels() {
+        let mut image = RgbImage::from_pixel(64, 20, BACKGROUND);
+        draw_text(&mut image, "100spp", 4, 3, INK);
+
+        let changed: Vec<(u32, u32)> = image.enumerate_pixels().filter(|(_, _, p)| **p != BACKGROUND).map(|(x, y, _)| (x, y)).collect();
+        let lit_bits: u32 = "100spp".chars().map(|c| glyph(c).iter().map(|row| row.count_ones()).sum::<u32>()).sum();
+        assert_eq!(changed.len() as u32, lit_bits * SCALE * SCALE);
+        assert!(changed.iter().all(|&p| image.get_pixel(p.0, p.1) == &INK));
+        // Six glyphs with a blank column between each, starting at (4, 3)
+        let right = 4 + 6 * (GLYPH_WIDTH + 1) * SCALE - SCALE;
+        assert!(changed.iter().all(|&(x, y)| (4..right).contains(&x) && (3..3 + text_height()).contains(&y)));
+    }
+
+    #[test]
+    fn text_past_the_edge_is_clipped() {
+        let mut image = RgbImage::from_pixel(10, 6, BACKGROUND);
+        draw_text(&mut image, "100spp", 6, 2, INK);
+        assert!(image.pixels().any(|p| *p == INK));
+    }
+}
@@ -0,0 +1,1555 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::geometry::color::{Color, Gamma};
+use crate::geometry::color_space::ColorSpace;
+use crate::geometry::tonemap::ToneMap;
+use crate::geometry::pdf::{HittablePDF, MixturePDF, PDF};
+use crate::geometry::ray::{Ray, RayKind};
+use crate::geometry::vector::Vector3;
+use crate::objects::hittable::{HitRecord, Hittable};
+use crate::output::deep::{DeepAccumulator, DeepFramebuffer, DeepSample};
+use crate::output::framebuffer::{Framebuffer, relative_error};
+use crate::output::postprocess::{remove_fireflies, smooth_edges};
+use crate::utils::{INF_F32, random_f32, seed_thread_rng, square_to_unit_disk};
+use crate::world::camera::Camera;
+use crate::world::environment::{Environment, EnvironmentPDF};
+use crate::world::sampler::{BounceSamples, SampleDimension, Sampler, SamplingMode};
+
+// How russian roulette turns the path throughput into a survival probability. The max channel
+// keeps saturated paths alive longer, luminance ends them sooner in exchange for more noise.
+#[derive(Clone, Copy)]
+pub enum RrHeuristic {
+    MaxChannel,
+    Luminance,
+}
+
+impl RrHeuristic {
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "max-channel" => Option::from(RrHeuristic::MaxChannel),
+            "luminance" => Option::from(RrHeuristic::Luminance),
+            _ => Option::None,
+        };
+    }
+
+    fn survival(self, throughput: Color) -> f32 {
+        return match self {
+            RrHeuristic::MaxChannel => throughput.r.max(throughput.g).max(throughput.b),
+            RrHeuristic::Luminance => throughput.luminance(),
+        };
+    }
+}
+
+// Floor on roulette survival so dark paths still get a chance
+const RR_MIN_SURVIVAL: f32 = 0.05;
+
+// Analytic distance fog over camera ray hits, a cheap stand-in for a participating medium.
+// Each hit is blended towards color by 1 - exp(-density * distance), misses keep the environment.
+#[derive(Clone, Copy)]
+pub struct Fog {
+    pub color: Color,
+    pub density: f32,
+}
+
+impl Fog {
+    fn apply(&self, color: Color, fog_color: Color, distance: f32) -> Color {
+        let amount = 1.0 - (-self.density * distance).exp();
+        return (1.0 - amount) * color + amount * fog_color;
+    }
+}
+
+// Largest radiance a path may carry into bounce n (0 is light arriving at the first hit)
+pub type ClampSchedule = fn(u32) -> f32;
+
+// Leaves light reaching the first hit alone and tightens the clamp with each further bounce
+pub fn falloff_clamp(bounce: u32) -> f32 {
+    return if bounce == 0 { INF_F32 } else { 20.0 / bounce as f32 };
+}
+
+// Schedules by name, for the command line
+pub fn parse_clamp_schedule(name: &str) -> Option<ClampSchedule> {
+    return match name {
+        "falloff" => Option::from(falloff_clamp as ClampSchedule),
+        _ => Option::None,
+    };
+}
+
+// Stops sampling each pixel once it looks converged instead of always taking samples_per_pixel.
+// Pixels are sampled in batches of min_samples until the relative standard error of their mean
+// luminance drops below max_error, up to samples_per_pixel in total.
+#[derive(Clone, Copy)]
+pub struct AdaptiveThreshold {
+    pub max_error: f32,
+    pub min_samples: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct RenderSettings {
+    pub image_width: u32,
+    pub image_height: u32,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub n_threads: u32,
+    // Rows per work item, 0 splits the image into one block per thread
+    pub tile_rows: u32,
+    // Pick n_threads and tile_rows by timing a few options before rendering
+    pub auto_tune: bool,
+    pub progressive: bool,
+    pub normals_preview: bool,
+    pub dither: bool,
+    // 8 or 16 bits per channel in the saved PNG
+    pub bit_depth: u8,
+    pub direct_only: bool,
+    pub max_memory_bytes: usize,
+    // Bit n enables render layer n, objects on other layers are left out of the scene
+    pub render_layer_mask: u32,
+    pub firefly_threshold: Option<f32>,
+    // Label the saved image with the sample count and render time
+    pub burn_in: bool,
+    // Bounces, counted from the camera, at which specular materials trace all their branches,
+    // e.g. both the reflection and refraction off glass weighted by Fresnel. Each split level
+    // at most doubles the rays per sample, so keep it to 2 or 3. 0 turns splitting off.
+    pub split_depth: u32,
+    // Randomly end low-throughput paths, None traces every path to max_depth
+    pub russian_roulette: Option<RrHeuristic>,
+    // Bounces every path makes before roulette may end it. Early bounces carry most of the
+    // light, so ending paths there saves little and adds a lot of noise.
+    pub rr_min_bounces: u32,
+    // Per-bounce limit on indirect radiance, trading a little bias for fewer fireflies
+    pub clamp_schedule: Option<ClampSchedule>,
+    // Blend pixels across detected edges after rendering, using the normals when available
+    pub edge_aa: bool,
+    pub fog: Option<Fog>,
+    // Shadow rays per diffuse bounce, and per shading point in direct-only mode. 1 mixes the
+    // light sample into the bounce direction, more cast them separately and follow the material.
+    pub light_samples: u32,
+    // Share of bounce directions drawn towards the lights rather than from the material, 0.5 by
+    // default. The mixture pdf keeps any weight below 1 unbiased. At 1 only directions that can
+    // reach a light are followed, so indirect light and anything the lights list misses goes dark.
+    // Only used with a single light sample.
+    pub nee_weight: f32,
+    // How camera samples are spread over each pixel and the lens
+    pub sampling: SamplingMode,
+    // Spread each block's sample budget towards its noisiest pixels
+    pub adaptive: bool,
+    // Stop pixels early once converged, replacing the block budget of adaptive when set
+    pub adaptive_threshold: Option<AdaptiveThreshold>,
+    // Save RGBA with alpha from the fraction of camera rays that hit geometry, for compositing
+    pub alpha: bool,
+    // Keep up to this many depth slices per pixel in a deep buffer, 0 for none
+    pub deep_slices: usize,
+    // Also write a grayscale map of each pixel's remaining relative error
+    pub convergence_map: bool,
+    // Seeds each pixel's random sequence from its coordinates so the image does not depend
+    // on thread count or tile order. Sums are already taken in sample order and Rust never
+    // fuses multiply-adds on its own, so this makes non-adaptive renders repeatable bit for bit
+    // on a given build. Adaptive budgets still depend on how rows are grouped into tiles.
+    pub deterministic: bool,
+    pub color_space: ColorSpace,
+    pub tone_map: ToneMap,
+    // Linear scale on the image before tone mapping, 1 leaves it unchanged
+    pub exposure: f32,
+    // Encoding of the saved image, Gamma::DEFAULT is a plain square root
+    pub gamma: Gamma,
+}
+
+impl RenderSettings {
+    // Settings for the quick quarter-resolution pass of a progressive render. Both sides shrink
+    // by the same factor, so the preview keeps the aspect ratio, and never below one pixel.
+    pub fn preview(&self) -> Self {
+        let shortest = self.image_width.min(self.image_height).max(1) as f32;
+        let scale = (1.0 / shortest).clamp(0.25, 1.0);
+        Self {
+            image_width: ((scale * self.image_width as f32).round() as u32).max(1),
+            image_height: ((scale * self.image_height as f32).round() as u32).max(1),
+            ..*self
+        }
+    }
+}
+
+// Shared flag for aborting a render from another thread. Blocks check it between
+// pixels, so render returns soon after with whatever had been traced.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        return CancellationToken(Arc::new(AtomicBool::new(false)));
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.0.load(Ordering::Relaxed);
+    }
+}
+
+struct Tracer {
+    world: Arc<dyn Hittable>,
+    lights: Arc<dyn Hittable>,
+    // Whether lights holds anything to aim at. Without lights, light samples go to the
+    // environment alone, or are not drawn at all when it isn't sampled either.
+    has_lights: bool,
+    environment: Arc<dyn Environment>,
+    settings: RenderSettings,
+    epsilon: f32,
+    cancel: CancellationToken,
+}
+
+// Ray offsets are this fraction of the scene's bounding-box diagonal, which keeps
+// them at the old fixed 0.01 for the Cornell box while scaling to tiny or huge scenes
+const EPSILON_SCALE: f32 = 1e-5;
+const DEFAULT_EPSILON: f32 = 0.01;
+
+fn scene_epsilon(world: &dyn Hittable) -> f32 {
+    return match world.bounding_box(0.0, 1.0) {
+        Some(b) => {
+            let diagonal = (b.maximum - b.minimum).length();
+            if diagonal.is_finite() && diagonal > 0.0 { diagonal * EPSILON_SCALE } else { DEFAULT_EPSILON }
+        }
+        None => DEFAULT_EPSILON,
+    };
+}
+
+impl Tracer {
+    // Scene colors are linear sRGB, transport happens in the configured working space
+    fn working(&self, color: Color) -> Color {
+        self.settings.color_space.working_from_srgb(color)
+    }
+
+    // Throughput is the product of the weights along the path so far, for russian roulette
+    fn ray_color(&self, ray: Ray, depth: u32, throughput: Color) -> Color {
+        if depth == 0 {
+            return Color::BLACK;
+        }
+
+        let opt_hit_rec = self.world.hit(ray, self.epsilon, INF_F32);
+        if opt_hit_rec.is_none() {
+            return self.working(self.environment.color(ray.direction));
+        }
+        return self.shade(ray, opt_hit_rec.unwrap(), depth, throughput, Option::None);
+    }
+
+    // Like ray_color, with the light the ray reaches directly, from an emitter or the environment,
+    // scaled by emission_weight. The emission shade adds at the hit is taken back out afterwards.
+    fn ray_color_weighted(&self, ray: Ray, depth: u32, throughput: Color, emission_weight: f32) -> Color {
+        if depth == 0 {
+            return Color::BLACK;
+        }
+
+        let opt_hit_rec = self.world.hit(ray, self.epsilon, INF_F32);
+        if opt_hit_rec.is_none() {
+            return emission_weight * self.working(self.environment.color(ray.direction));
+        }
+        let hit_rec = opt_hit_rec.unwrap();
+        let emitted = self.working(hit_rec.material.emitted(ray, &hit_rec, hit_rec.u, hit_rec.v, hit_rec.intersection));
+        return self.shade(ray, hit_rec, depth, throughput, Option::None) + (emission_weight - 1.0) * emitted;
+    }
+
+    // Traces a camera ray, also returning the normal and distance at its first hit, or None on a
+    // miss, for the normals preview, the alpha channel and deep output. The first bounce off a
+    // diffuse surface is drawn from samples.
+    fn trace_primary(&self, ray: Ray, depth: u32, camera: &Camera, samples: BounceSamples) -> (Color, Option<(Vector3, f32)>) {
+        if depth == 0 {
+            return (Color::BLACK, None);
+        }
+
+        let (t_min, t_max) = camera.clip_range(self.epsilon, INF_F32);
+        let opt_hit_rec = self.world.hit(ray, t_min, t_max);
+        if opt_hit_rec.is_none() {
+            return (self.working(self.environment.color(ray.direction)), None);
+        }
+        let hit_rec = opt_hit_rec.unwrap();
+        let normal = hit_rec.normal;
+        let distance = hit_rec.t;
+        let mut color = if self.settings.direct_only { self.direct_light(ray, hit_rec) } else { self.shade(ray, hit_rec, depth, Color::WHITE, Option::from(samples)) };
+        if let Some(fog) = &self.settings.fog {
+            color = fog.apply(color, self.working(fog.color), distance);
+        }
+        return (color, Option::from((normal, distance)));
+    }
+
+    // Samples, when given, place the bounce direction in their sampler dimensions
+    fn shade(&self, ray: Ray, hit_rec: HitRecord, depth: u32, throughput: Color, samples: Option<BounceSamples>) -> Color {
+        let emitted = self.working(hit_rec.material.emitted(ray, &hit_rec, hit_rec.u, hit_rec.v, hit_rec.intersection));
+
+        let opt_scatter_rec = hit_rec.material.scatter(ray, &hit_rec);
+        if opt_scatter_rec.is_none() {
+            return emitted;
+        }
+        let scatter_rec = opt_scatter_rec.unwrap();
+
+        if scatter_rec.specular_ray.is_some() {
+            // Near the camera, follow every branch a material offers instead of picking one
+            if self.settings.max_depth - depth < self.settings.split_depth {
+                let branches = hit_rec.material.scatter_split(ray, &hit_rec);
+                if !branches.is_empty() {
+                    return branches.into_iter()
+                        .map(|(branch, attenuation)| self.follow_specular(branch, self.working(attenuation), depth, throughput))
+                        .sum();
+                }
+            }
+            return self.follow_specular(scatter_rec.specular_ray.unwrap(), self.working(scatter_rec.attenuation), depth, throughput);
+        }
+        let light_pdf = HittablePDF {
+            o: hit_rec.intersection,
+            ptr: self.lights.clone(),
+            epsilon: self.epsilon,
+        };
+        let env_pdf = EnvironmentPDF { environment: self.environment.as_ref() };
+        let lights_and_env = MixturePDF { ptr: [&light_pdf, &env_pdf], weight: if self.has_lights { 0.5 } else { 0.0 } };
+        let nee_pdf: &dyn PDF = if self.environment.is_sampled() { &lights_and_env } else { &light_pdf };
+        let scatter_pdf = scatter_rec.pdf_ptr.unwrap();
+        let mix_pdf = MixturePDF {
+            ptr: [nee_pdf, scatter_pdf.as_ref()],
+            weight: if self.has_lights || self.environment.is_sampled() { self.settings.nee_weight.clamp(0.0, 1.0) } else { 0.0 },
+        };
+        let attenuation = self.working(scatter_rec.attenuation);
+
+        // With several light samples, they go to explicit shadow rays and the bounce is drawn from
+        // the material alone. Light reached both ways is weighted by the balance heuristic across
+        // all light_samples + 1 samples, so it isn't counted twice.
+        let n_lights = self.settings.light_samples;
+        if n_lights > 1 && depth > 1 {
+            let direct = self.sample_lights(ray, &hit_rec, attenuation, &mix_pdf, depth, samples.map(|s| s.light));
+            let bsdf_sample = samples.map_or_else(|| (random_f32(), random_f32()), |s| s.bsdf);
+            let scattered = Ray {
+                origin: hit_rec.intersection,
+                direction: scatter_pdf.generate_from(bsdf_sample).direction(),
+                time: ray.time,
+                kind: RayKind::Reflection,
+            };
+            let bsdf_pdf = scatter_pdf.value(scattered.direction);
+            if bsdf_pdf <= 0.0 {
+                return emitted + direct;
+            }
+            let weight = (hit_rec.material.scattering_pdf(ray, &hit_rec, scattered) / bsdf_pdf) * attenuation;
+            let survival = match self.roulette(throughput * weight, depth) {
+                Some(survival) => survival,
+                None => return emitted + direct,
+            };
+            let emission_weight = bsdf_pdf / (n_lights as f32 * nee_pdf.value(scattered.direction) + bsdf_pdf);
+            let incoming = self.clamp_incoming(self.ray_color_weighted(scattered, depth - 1, throughput * weight, emission_weight), depth);
+            return emitted + direct + survival * weight * incoming;
+        }
+
+        let (direction, lobe) = match samples {
+            Some(samples) => mix_pdf.generate_each([samples.light, samples.bsdf]),
+            None => mix_pdf.generate_with_index(),
+        };
+        // Directions drawn towards the lights are shadow rays, so objects hidden from those
+        // let the light through whichever way the bounce is sampled
+        let scattered = Ray {
+            origin: hit_rec.intersection,
+            direction: direction.direction(),
+            time: ray.time,
+            kind: if lobe == 0 { RayKind::Shadow } else { RayKind::Reflection },
+        };
+        let pdf_val = mix_pdf.value(scattered.direction);
+
+        let weight = (hit_rec.material.scattering_pdf(ray, &hit_rec, scattered) / pdf_val) * attenuation;
+        let survival = match self.roulette(throughput * weight, depth) {
+            Some(survival) => survival,
+            None => return emitted,
+        };
+        let incoming = self.clamp_incoming(self.ray_color(scattered, depth - 1, throughput * weight), depth);
+        return emitted + survival * weight * incoming;
+    }
+
+    // light_samples shadow rays drawn from the first of pdfs, the lights and environment, each
+    // weighted against the chance the second, the material, would have found the same light.
+    // The first ray is placed by first when given.
+    fn sample_lights(&self, ray: Ray, hit_rec: &HitRecord, attenuation: Color, pdfs: &MixturePDF, depth: u32, first: Option<(f32, f32)>) -> Color {
+        let [nee_pdf, scatter_pdf] = pdfs.ptr;
+        let n_lights = self.settings.light_samples as f32;
+        let mut direct = Color::BLACK;
+        for k in 0..self.settings.light_samples {
+            let direction = match first {
+                Some(sample) if k == 0 => nee_pdf.generate_from(sample),
+                _ => nee_pdf.generate(),
+            };
+            let shadow_ray = Ray {
+                origin: hit_rec.intersection,
+                direction: direction.direction(),
+                time: ray.time,
+                kind: RayKind::Shadow,
+            };
+            let light_pdf = nee_pdf.value(shadow_ray.direction);
+            if light_pdf <= 0.0 {
+                continue;
+            }
+            let scattering = hit_rec.material.scattering_pdf(ray, hit_rec, shadow_ray) * attenuation;
+            let light = self.clamp_incoming(self.working(self.light_along(shadow_ray)), depth);
+            direct += (1.0 / (n_lights * light_pdf + scatter_pdf.value(shadow_ray.direction))) * scattering * light;
+        }
+        return direct;
+    }
+
+    fn follow_specular(&self, ray: Ray, weight: Color, depth: u32, throughput: Color) -> Color {
+        let survival = match self.roulette(throughput * weight, depth) {
+            Some(survival) => survival,
+            None => return Color::BLACK,
+        };
+        let incoming = self.clamp_incoming(self.ray_color(ray, depth - 1, throughput * weight), depth);
+        return survival * weight * incoming;
+    }
+
+    // Russian roulette once the path is past its first few bounces: None ends it, otherwise the
+    // returned factor scales the surviving radiance up to make up for the paths that were ended
+    fn roulette(&self, throughput: Color, depth: u32) -> Option<f32> {
+        let heuristic = match self.settings.russian_roulette {
+            Some(heuristic) => heuristic,
+            None => return Option::from(1.0),
+        };
+        if self.settings.max_depth - depth < self.settings.rr_min_bounces {
+            return Option::from(1.0);
+        }
+        let survival = heuristic.survival(throughput).clamp(RR_MIN_SURVIVAL, 1.0);
+        if random_f32() >= survival {
+            return Option::None;
+        }
+        return Option::from(1.0 / survival);
+    }
+
+    // Scales radiance gathered at the current depth down to the schedule's limit, keeping its hue
+    fn clamp_incoming(&self, incoming: Color, depth: u32) -> Color {
+        let schedule = match self.settings.clamp_schedule {
+            Some(schedule) => schedule,
+            None => return incoming,
+        };
+        let limit = schedule(self.settings.max_depth - depth);
+        let peak = incoming.r.max(incoming.g).max(incoming.b);
+        if peak > limit {
+            return (limit / peak) * incoming;
+        }
+        return incoming;
+    }
+
+    // Emission plus light_samples light samples at the hit, with no further bounces. Specular
+    // surfaces only show their own emission since a light sample can never match them.
+    fn direct_light(&self, ray: Ray, hit_rec: HitRecord) -> Color {
+        let emitted = self.working(hit_rec.material.emitted(ray, &hit_rec, hit_rec.u, hit_rec.v, hit_rec.intersection));
+        if hit_rec.material.is_specular() {
+            return emitted;
+        }
+
+        let opt_scatter_rec = hit_rec.material.scatter(ray, &hit_rec);
+        if opt_scatter_rec.is_none() {
+            return emitted;
+        }
+        let scatter_rec = opt_scatter_rec.unwrap();
+        if scatter_rec.specular_ray.is_some() {
+            return emitted;
+        }
+
+        let light_pdf = HittablePDF {
+            o: hit_rec.intersection,
+            ptr: self.lights.clone(),
+            epsilon: self.epsilon,
+        };
+        let env_pdf = EnvironmentPDF { environment: self.environment.as_ref() };
+        let lights_and_env = MixturePDF { ptr: [&light_pdf, &env_pdf], weight: if self.has_lights { 0.5 } else { 0.0 } };
+        let nee_pdf: &dyn PDF = if self.environment.is_sampled() { &lights_and_env } else { &light_pdf };
+        let attenuation = self.working(scatter_rec.attenuation);
+        let n_samples = self.settings.light_samples.max(1);
+        let mut direct = Color::BLACK;
+        for _ in 0..n_samples {
+            let shadow_ray = Ray {
+                origin: hit_rec.intersection,
+                direction: nee_pdf.generate().direction(),
+                time: ray.time,
+                kind: RayKind::Shadow,
+            };
+            let pdf_val = nee_pdf.value(shadow_ray.direction);
+            if pdf_val <= 0.0 {
+                continue;
+            }
+
+            let light = self.light_along(shadow_ray);
+            direct += (hit_rec.material.scattering_pdf(ray, &hit_rec, shadow_ray) / pdf_val) * attenuation * self.working(light);
+        }
+
+        return emitted + (1.0 / n_samples as f32) * direct;
+    }
+
+    // Light arriving back along a shadow ray. The ray starts epsilon off the shading point so it
+    // can't shadow itself, and when it is aimed at one of the lights, anything up to epsilon short
+    // of that light is an occluder. The emission is then read from the world just around that
+    // distance, since the lights list may only hold stand-ins for the emitting surfaces. A ray
+    // aimed at no light picks up whatever emitter it hits first, or the environment if it escapes.
+    fn light_along(&self, shadow_ray: Ray) -> Color {
+        let emission = |light_rec: HitRecord| light_rec.material.emitted(shadow_ray, &light_rec, light_rec.u, light_rec.v, light_rec.intersection);
+        let t_light = match self.lights.hit(shadow_ray, self.epsilon, INF_F32) {
+            Some(light_rec) => light_rec.t,
+            None => {
+                return match self.world.hit(shadow_ray, self.epsilon, INF_F32) {
+                    Some(light_rec) => emission(light_rec),
+                    None => self.environment.color(shadow_ray.direction),
+                };
+            }
+        };
+        let t_shadow = t_light - self.epsilon;
+        if t_shadow > self.epsilon && self.world.hit(shadow_ray, self.epsilon, t_shadow).is_some() {
+            return Color::BLACK;
+        }
+        return match self.world.hit(shadow_ray, t_shadow.max(self.epsilon), t_light + self.epsilon) {
+            Some(light_rec) => emission(light_rec),
+            None => Color::BLACK,
+        };
+    }
+}
+
+struct ImageBlockInfo {
+    start_row: u32,
+    end_row: u32,
+    image_height: u32,
+    image_width: u32,
+    spp: u32,
+    max_depth: u32,
+    sampling: SamplingMode,
+    adaptive: bool,
+    adaptive_threshold: Option<AdaptiveThreshold>,
+    deterministic: bool,
+    normals: bool,
+    pixels: Vec<Color>,
+    variances: Vec<f32>,
+    sample_counts: Vec<u32>,
+    coverage: Vec<f32>,
+    normal_pixels: Vec<Color>,
+    // Depth slices kept per pixel, 0 when deep output is off
+    deep_slices: usize,
+    deep_pixels: Vec<Vec<DeepSample>>,
+}
+
+impl ImageBlockInfo {
+    fn new(settings: &RenderSettings, start_row: u32, end_row: u32, spp: u32) -> Self {
+        return ImageBlockInfo {
+            start_row,
+            end_row,
+            image_height: settings.image_height,
+            image_width: settings.image_width,
+            spp,
+            max_depth: settings.max_depth,
+            sampling: settings.sampling,
+            adaptive: settings.adaptive,
+            adaptive_threshold: settings.adaptive_threshold,
+            deterministic: settings.deterministic,
+            normals: settings.normals_preview || settings.edge_aa,
+            pixels: Vec::with_capacity(((end_row - start_row) * settings.image_width) as usize),
+            variances: Vec::with_capacity(((end_row - start_row) * settings.image_width) as usize),
+            sample_counts: Vec::with_capacity(((end_row - start_row) * settings.image_width) as usize),
+            coverage: Vec::with_capacity(((end_row - start_row) * settings.image_width) as usize),
+            normal_pixels: Vec::new(),
+            deep_slices: settings.deep_slices,
+            deep_pixels: Vec::new(),
+        };
+    }
+}
+
+// Running sums for one pixel, so more samples can be added to it later
+#[derive(Clone, Copy)]
+struct PixelAccumulator {
+    color: Color,
+    normal: Color,
+    luminance_sum: f32,
+    luminance_sq_sum: f32,
+    // Samples whose camera ray hit something
+    hits: u32,
+    count: u32,
+}
+
+impl PixelAccumulator {
+    const EMPTY: PixelAccumulator = PixelAccumulator {
+        color: Color::BLACK,
+        normal: Color::BLACK,
+        luminance_sum: 0.0,
+        luminance_sq_sum: 0.0,
+        hits: 0,
+        count: 0,
+    };
+
+    fn variance(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let scale = 1.0 / self.count as f32;
+        return (scale * self.luminance_sq_sum - (scale * self.luminance_sum).powi(2)).max(0.0);
+    }
+
+    fn relative_error(&self) -> f32 {
+        if self.count == 0 {
+            return INF_F32;
+        }
+        return relative_error(self.variance(), self.count, self.luminance_sum / self.count as f32);
+    }
+}
+
+// Distinct for every pixel and for each pass over it, told apart by how many samples it already has
+fn pixel_seed(i: u32, j: u32, count: u32) -> u64 {
+    return ((j as u64) << 32 | i as u64) ^ (count as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+}
+
+// Everything a pixel's samples are drawn with besides its running sums. Deterministic renders
+// seed the thread's generator from the pixel's coordinates, so they are part of it too.
+struct PixelContext<'a> {
+    i: u32,
+    j: u32,
+    block_info: &'a ImageBlockInfo,
+    camera: &'a Camera,
+    tracer: &'a Tracer,
+}
+
+impl PixelContext<'_> {
+    fn sample(&self, acc: &mut PixelAccumulator, mut deep: Option<&mut DeepAccumulator>, n_samples: u32) {
+        let block_info = self.block_info;
+        if block_info.deterministic {
+            seed_thread_rng(Option::from(pixel_seed(self.i, self.j, acc.count)));
+        }
+        let sampler = Sampler::new(n_samples, block_info.sampling);
+        for s in 0..n_samples {
+            let jitter = sampler.get_2d(SampleDimension::Pixel, s);
+            let (lens_u, lens_v) = sampler.get_2d(SampleDimension::Lens, s);
+            let lens_point = square_to_unit_disk(lens_u, lens_v);
+            // A path the lens blocks carries no light, so the sample counts as black
+            let ray = match self.camera.ray_through_pixel(self.i, self.j, block_info.image_width, block_info.image_height, jitter, lens_point) {
+                Some(ray) => ray,
+                None => continue,
+            };
+            let bounce = BounceSamples {
+                light: sampler.get_2d(SampleDimension::Light, s),
+                bsdf: sampler.get_2d(SampleDimension::Bsdf, s),
+            };
+            let (color, opt_hit) = self.tracer.trace_primary(ray, block_info.max_depth, self.camera, bounce);
+            acc.color += color;
+            acc.luminance_sum += color.luminance();
+            acc.luminance_sq_sum += color.luminance().powi(2);
+            if let Some((normal, distance)) = opt_hit {
+                acc.hits += 1;
+                let n = normal.map(|c| 0.5 * (c + 1.0));
+                acc.normal += Color { r: n.x, g: n.y, b: n.z };
+                if let Some(deep) = deep.as_mut() {
+                    deep.add(distance, color);
+                }
+            }
+        }
+        acc.count += n_samples;
+    }
+
+    fn sample_until_converged(&self, acc: &mut PixelAccumulator, mut deep: Option<&mut DeepAccumulator>, threshold: AdaptiveThreshold) {
+        let spp = self.block_info.spp;
+        let batch = threshold.min_samples.max(1);
+        while acc.count < spp && !self.tracer.cancel.is_cancelled() {
+            let n_samples = batch.min(spp - acc.count);
+            self.sample(acc, deep.as_deref_mut(), n_samples);
+            if acc.relative_error() < threshold.max_error {
+                break;
+            }
+        }
+    }
+}
+
+fn process_block(mut block_info: ImageBlockInfo, camera: &Camera, tracer: &Tracer) -> ImageBlockInfo {
+    let width = block_info.image_width;
+    let n_pixels = ((block_info.end_row - block_info.start_row) * width) as usize;
+    let mut accumulators = vec![PixelAccumulator::EMPTY; n_pixels];
+    let mut deep_accumulators = vec![DeepAccumulator::default(); if block_info.deep_slices > 0 { n_pixels } else { 0 }];
+
+    // Adaptive blocks spend a quarter of the budget evenly, then hand out the rest
+    // in proportion to each pixel's estimated error (variance of its mean)
+    let base_spp = if block_info.adaptive { (block_info.spp / 4).max(1) } else { block_info.spp };
+    for (k, acc) in accumulators.iter_mut().enumerate() {
+        if tracer.cancel.is_cancelled() {
+            break;
+        }
+        let (i, j) = (k as u32 % width, block_info.start_row + k as u32 / width);
+        let pixel = PixelContext { i, j, block_info: &block_info, camera, tracer };
+        match block_info.adaptive_threshold {
+            Some(threshold) => pixel.sample_until_converged(acc, deep_accumulators.get_mut(k), threshold),
+            None => pixel.sample(acc, deep_accumulators.get_mut(k), base_spp),
+        }
+    }
+
+    if block_info.adaptive_threshold.is_none() && block_info.adaptive && block_info.spp > base_spp && !tracer.cancel.is_cancelled() {
+        let budget = ((block_info.spp - base_spp) as usize * n_pixels) as f32;
+        let errors: Vec<f32> = accumulators.iter().map(|acc| acc.variance() / acc.count as f32).collect();
+        let total_error: f32 = errors.iter().sum();
+
+        for (k, acc) in accumulators.iter_mut().enumerate() {
+            if tracer.cancel.is_cancelled() {
+                break;
+            }
+            // Flooring keeps the block within its budget
+            let extra = if total_error > 0.0 {
+                (budget * errors[k] / total_error) as u32
+            } else {
+                block_info.spp - base_spp
+            };
+            let (i, j) = (k as u32 % width, block_info.start_row + k as u32 / width);
+            let pixel = PixelContext { i, j, block_info: &block_info, camera, tracer };
+            pixel.sample(acc, deep_accumulators.get_mut(k), extra);
+        }
+    }
+
+    for acc in accumulators.iter() {
+        // Pixels skipped by a cancelled render stay black
+        let scale = if acc.count > 0 { 1.0 / acc.count as f32 } else { 0.0 };
+        block_info.pixels.push(scale * acc.color);
+        block_info.variances.push(acc.variance());
+        block_info.sample_counts.push(acc.count);
+        block_info.coverage.push(scale * acc.hits as f32);
+        if block_info.normals {
+            block_info.normal_pixels.push(scale * acc.normal);
+        }
+    }
+    for (deep, acc) in deep_accumulators.iter().zip(accumulators.iter()) {
+        block_info.deep_pixels.push(deep.resolve(acc.count, block_info.deep_slices));
+    }
+    // Pixels reseed the thread's generator, which must not carry over into whatever the
+    // worker runs next
+    if block_info.deterministic {
+        seed_thread_rng(Option::None);
+    }
+
+    return block_info;
+}
+
+// How a render was run, including any configuration picked by calibration
+#[derive(Clone, Copy, Default)]
+pub struct RenderStats {
+    pub n_threads: u32,
+    pub tile_rows: u32,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "Rendered in {:.2}s with {} threads, {} rows per tile",
+                      self.elapsed.as_secs_f32(), self.n_threads, self.tile_rows);
+    }
+}
+
+// Renders rows start..end as tiles of tile_rows rows spread over n_threads workers, returned top to bottom
+fn render_rows(settings: &RenderSettings, camera: &Camera, tracer: &Arc<Tracer>, rows: (u32, u32), spp: u32, n_threads: u32, tile_rows: u32) -> Vec<ImageBlockInfo> {
+    let mut tiles: Vec<ImageBlockInfo> = Vec::new();
+    let mut start_row = rows.0;
+    while start_row < rows.1 {
+        let end_row = (start_row + tile_rows).min(rows.1);
+        tiles.push(ImageBlockInfo::new(settings, start_row, end_row, spp));
+        start_row = end_row;
+    }
+    // A pool per call so calibration can compare thread counts, idle workers steal tiles
+    let pool = ThreadPoolBuilder::new().num_threads(n_threads as usize).build().unwrap();
+    return pool.install(|| tiles.into_par_iter().map(|tile| process_block(tile, camera, tracer)).collect());
+}
+
+// Times a few thread counts and tile heights on a band through the middle of the
+// image at low sample counts and returns the fastest (n_threads, tile_rows)
+fn calibrate(settings: &RenderSettings, camera: &Camera, tracer: &Arc<Tracer>) -> (u32, u32) {
+    let band = (settings.image_height / 8).max(1);
+    let start = (settings.image_height - band) / 2;
+    let spp = (settings.samples_per_pixel / 16).max(1);
+
+    let available = thread::available_parallelism().map(|n| n.get() as u32).unwrap_or(settings.n_threads);
+    let mut thread_counts = vec![settings.n_threads.max(1), available];
+    thread_counts.dedup();
+
+    let mut best = (settings.n_threads.max(1), 1);
+    let mut best_time = Duration::MAX;
+    for &n_threads in thread_counts.iter() {
+        for &tile_rows in [1, 4, 16].iter() {
+            let timer = Instant::now();
+            render_rows(settings, camera, tracer, (start, start + band), spp, n_threads, tile_rows);
+            let elapsed = timer.elapsed();
+            if elapsed < best_time {
+                best_time = elapsed;
+                best = (n_threads, tile_rows);
+            }
+        }
+    }
+    return best;
+}
+
+pub fn render(settings: &RenderSettings, camera: &Camera, world: Arc<dyn Hittable>, lights: Arc<dyn Hittable>, environment: Arc<dyn Environment>) -> Framebuffer {
+    return render_cancellable(settings, camera, world, lights, environment, CancellationToken::new());
+}
+
+// Like render, but stops early once the token is cancelled and returns the partially filled framebuffer
+pub fn render_cancellable(settings: &RenderSettings, camera: &Camera, world: Arc<dyn Hittable>, lights: Arc<dyn Hittable>, environment: Arc<dyn Environment>, cancel: CancellationToken) -> Framebuffer {
+    let timer = Instant::now();
+    let epsilon = scene_epsilon(world.as_ref());
+    let has_lights = lights.bounding_box(0.0, 1.0).is_some();
+    let tracer = Arc::new(Tracer { world, lights, has_lights, environment, settings: *settings, epsilon, cancel });
+
+    // Without a tile height every thread gets one contiguous block, as before tiling
+    let (n_threads, tile_rows) = if settings.auto_tune {
+        calibrate(settings, camera, &tracer)
+    } else if settings.tile_rows == 0 {
+        (settings.n_threads, (settings.image_height / settings.n_threads).max(1))
+    } else {
+        (settings.n_threads, settings.tile_rows)
+    };
+    let final_blocks = render_rows(settings, camera, &tracer, (0, settings.image_height), settings.samples_per_pixel, n_threads, tile_rows);
+
+    let mut framebuffer = Framebuffer::new(settings.image_width, settings.image_height, settings.normals_preview || settings.edge_aa);
+    framebuffer.color_space = settings.color_space;
+    framebuffer.tone_map = settings.tone_map;
+    framebuffer.exposure = settings.exposure;
+    framebuffer.gamma = settings.gamma;
+    if settings.deep_slices > 0 {
+        framebuffer.deep = Option::from(DeepFramebuffer::new(settings.image_width, settings.image_height));
+    }
+
+    for block in final_blocks.iter() {
+        let offset = framebuffer.index(0, block.start_row);
+        framebuffer.pixels[offset..offset + block.pixels.len()].copy_from_slice(&block.pixels);
+        framebuffer.variance[offset..offset + block.variances.len()].copy_from_slice(&block.variances);
+        framebuffer.sample_counts[offset..offset + block.sample_counts.len()].copy_from_slice(&block.sample_counts);
+        framebuffer.coverage[offset..offset + block.coverage.len()].copy_from_slice(&block.coverage);
+        if let Some(normals) = framebuffer.normals.as_mut() {
+            normals[offset..offset + block.normal_pixels.len()].copy_from_slice(&block.normal_pixels);
+        }
+        if let Some(deep) = framebuffer.deep.as_mut() {
+            deep.pixels[offset..offset + block.deep_pixels.len()].clone_from_slice(&block.deep_pixels);
+        }
+    }
+
+    if let Some(threshold) = settings.firefly_threshold {
+        remove_fireflies(&mut framebuffer, threshold);
+    }
+    if settings.edge_aa {
+        smooth_edges(&mut framebuffer);
+        // Normals were only gathered for edge detection
+        if !settings.normals_preview {
+            framebuffer.normals = None;
+        }
+    }
+    framebuffer.alpha = settings.alpha;
+    framebuffer.stats = RenderStats { n_threads, tile_rows, elapsed: timer.elapsed() };
+    if settings.burn_in {
+        framebuffer.label = Option::from(format!("{}spp {:.1}s", settings.samples_per_pixel, framebuffer.stats.elapsed.as_secs_f32()));
+    }
+    return framebuffer;
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::output::diff::assert_images_close;
+    use crate::materials::dielectric::Dielectric;
+    use crate::materials::lambertian::Lambertian;
+    use crate::materials::light::DiffuseLight;
+    use crate::objects::hittable::FlipFace;
+    use crate::objects::rectangle::{XYRect, XZRect};
+    use crate::objects::sphere::Sphere;
+    use crate::world::environment::{GradientEnvironment, SolidEnvironment};
+    use crate::world::hittable_list::HittableList;
+
+    // Small, single-threaded and repeatable, so tests stay fast and can compare renders exactly
+    pub(crate) fn test_settings(width: u32, height: u32, spp: u32) -> RenderSettings {
+        return RenderSettings {
+            image_width: width,
+            image_height: height,
+            samples_per_pixel: spp,
+            max_depth: 8,
+            n_threads: 1,
+            tile_rows: 0,
+            auto_tune: false,
+            progressive: false,
+            normals_preview: false,
+            dither: false,
+            bit_depth: 8,
+            direct_only: false,
+            max_memory_bytes: 1 << 30,
+            render_layer_mask: u32::MAX,
+            firefly_threshold: None,
+            burn_in: false,
+            split_depth: 0,
+            russian_roulette: None,
+            rr_min_bounces: 3,
+            clamp_schedule: None,
+            edge_aa: false,
+            fog: None,
+            light_samples: 1,
+            nee_weight: 0.5,
+            sampling: SamplingMode::Stratified,
+            adaptive: false,
+            adaptive_threshold: None,
+            alpha: false,
+            deep_slices: 0,
+            convergence_map: false,
+            deterministic: true,
+            color_space: ColorSpace::Srgb,
+            tone_map: ToneMap::None,
+            exposure: 1.0,
+            gamma: Gamma::DEFAULT,
+        };
+    }
+
+    // Looks down -z at the origin from 3 units away, framing a unit sphere
+    pub(crate) fn test_camera(settings: &RenderSettings) -> Camera {
+        let aspect_ratio = settings.image_width as f32 / settings.image_height as f32;
+        return Camera::new(
+            Point { x: 0.0, y: 0.0, z: 3.0 },
+            Point::ORIGIN,
+            Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            40.0,
+            aspect_ratio,
+            0.0,
+            3.0,
+            0.0,
+            1.0,
+        );
+    }
+
+    // Square light over the origin shining down, also the lights list for next event estimation
+    pub(crate) fn test_light() -> Arc<dyn Hittable> {
+        return Arc::new(FlipFace {
+            object: Arc::new(XZRect {
+                x: (-1.0, 1.0),
+                z: (-1.0, 1.0),
+                k: 2.0,
+                material: Arc::new(DiffuseLight::new(Color { r: 4.0, g: 4.0, b: 4.0 })),
+            }),
+        });
+    }
+
+    // Renders a world holding the test light against a black background
+    pub(crate) fn render_world(settings: &RenderSettings, world: Arc<dyn Hittable>) -> Framebuffer {
+        let mut lights = HittableList::new();
+        lights.add(test_light());
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+        return render(settings, &test_camera(settings), world, Arc::new(lights), background);
+    }
+
+    // Renders objects under the test light
+    pub(crate) fn render_objects(settings: &RenderSettings, objects: Vec<Arc<dyn Hittable>>) -> Framebuffer {
+        let mut world = HittableList::new();
+        world.add(test_light());
+        for object in objects {
+            world.add(object);
+        }
+        return render_world(settings, Arc::new(world));
+    }
+
+    // A grey unit sphere lit from above
+    pub(crate) fn render_test_scene(settings: &RenderSettings) -> Framebuffer {
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere {
+            center: Point::ORIGIN,
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 })),
+        });
+        return render_objects(settings, vec![sphere]);
+    }
+
+    pub(crate) fn mean_luminance(framebuffer: &Framebuffer) -> f32 {
+        return framebuffer.pixels.iter().map(|c| c.luminance()).sum::<f32>() / framebuffer.pixels.len() as f32;
+    }
+
+    // Counts heap allocations made by the current thread, so tests running alongside don't
+    // add to each other's counts
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            return System.alloc(layout);
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    // Runs f, returning its result and the number of allocations it made
+    pub(crate) fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+        let before = ALLOCATIONS.with(|count| count.get());
+        let result = f();
+        return (result, ALLOCATIONS.with(|count| count.get()) - before);
+    }
+
+    #[test]
+    fn preview_is_a_quarter_of_the_full_render() {
+        let settings = test_settings(32, 24, 4);
+        let preview = render_test_scene(&settings.preview());
+        let full = render_test_scene(&settings);
+
+        assert_eq!((preview.width, preview.height), (8, 6));
+        assert_eq!(preview.pixels.len(), 48);
+        assert_eq!((full.width, full.height), (32, 24));
+        assert_eq!(full.pixels.len(), 32 * 24);
+        for framebuffer in [&preview, &full] {
+            assert!(framebuffer.pixels.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+            assert!(mean_luminance(framebuffer) > 0.0);
+        }
+    }
+
+    #[test]
+    fn preview_keeps_everything_but_the_size() {
+        let settings = test_settings(800, 600, 16);
+        let preview = settings.preview();
+        assert_eq!((preview.image_width, preview.image_height), (200, 150));
+        assert_eq!(preview.samples_per_pixel, 16);
+        assert_eq!(preview.max_depth, settings.max_depth);
+
+        // The shorter side stops at one pixel, and the other shrinks by as much
+        let tiny = test_settings(5, 3, 1).preview();
+        assert_eq!((tiny.image_width, tiny.image_height), (2, 1));
+        let wide = test_settings(400, 4, 1).preview();
+        assert_eq!((wide.image_width, wide.image_height), (100, 1));
+        let tall = test_settings(2, 30, 1).preview();
+        assert_eq!((tall.image_width, tall.image_height), (1, 15));
+        assert_eq!(render_test_scene(&tiny).pixels.len(), 2);
+    }
+
+    // The test sphere resting on a wide floor, so light bounces up onto its underside
+    fn sphere_on_floor() -> Vec<Arc<dyn Hittable>> {
+        return vec![
+            Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 })) }),
+            Arc::new(XZRect { x: (-10.0, 10.0), z: (-10.0, 10.0), k: -1.0, material: Arc::new(Lambertian::new(Color { r: 0.8, g: 0.8, b: 0.8 })) }),
+        ];
+    }
+
+    #[test]
+    fn direct_only_misses_indirect_light() {
+        let mut settings = test_settings(24, 24, 16);
+        let full = render_objects(&settings, sphere_on_floor());
+        settings.direct_only = true;
+        settings.light_samples = 4;
+        let direct = render_objects(&settings, sphere_on_floor());
+
+        assert!(mean_luminance(&direct) < mean_luminance(&full));
+        // Where no light arrives directly, e.g. the underside of the sphere, the full render
+        // still picks up what bounces off the floor
+        let unlit: Vec<usize> = (0..direct.pixels.len())
+            .filter(|&k| direct.coverage[k] > 0.0 && direct.pixels[k].luminance() == 0.0)
+            .collect();
+        assert!(unlit.len() > 10, "only {} unlit pixels", unlit.len());
+        let bounced = unlit.iter().map(|&k| full.pixels[k].luminance()).sum::<f32>() / unlit.len() as f32;
+        assert!(bounced > 0.005, "unlit pixels average {} in the full render", bounced);
+    }
+
+    #[test]
+    fn adaptive_sampling_favours_the_noisy_region() {
+        let settings = RenderSettings { adaptive: true, ..test_settings(24, 24, 16) };
+        let framebuffer = render_test_scene(&settings);
+        let (noisy, flat): (Vec<usize>, Vec<usize>) = (0..framebuffer.pixels.len()).partition(|&k| framebuffer.coverage[k] > 0.0);
+        let mean_count = |pixels: &[usize]| pixels.iter().map(|&k| framebuffer.sample_counts[k] as f32).sum::<f32>() / pixels.len() as f32;
+        assert!(!noisy.is_empty() && !flat.is_empty());
+
+        // The black background has no variance, so it keeps the even quarter of the budget
+        assert!(flat.iter().all(|&k| framebuffer.sample_counts[k] == 4));
+        assert!(mean_count(&noisy) > 4.0 * mean_count(&flat), "sphere {} vs background {}", mean_count(&noisy), mean_count(&flat));
+        let total: u32 = framebuffer.sample_counts.iter().sum();
+        assert!(total <= 16 * 24 * 24, "{} samples over the budget", total);
+        assert!(total > 15 * 24 * 24, "only {} of the budget spent", total);
+    }
+
+    #[test]
+    fn converged_pixels_stop_at_the_first_batch_and_edges_sample_on() {
+        // A glowing sphere against a grey sky, both flat, so only the silhouette is noisy
+        let threshold = AdaptiveThreshold { max_error: 0.02, min_samples: 8 };
+        let settings = RenderSettings { adaptive_threshold: Option::from(threshold), ..test_settings(24, 24, 128) };
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 0.6, material: Arc::new(DiffuseLight::new(Color::WHITE)) });
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color { r: 0.2, g: 0.2, b: 0.2 } });
+        let world: Arc<dyn Hittable> = Arc::new(HittableList { objects: vec![sphere.clone()] });
+        let framebuffer = render(&settings, &test_camera(&settings), world, sphere, background);
+
+        // Every sample of a pixel seeing only the sphere or only the sky is the same
+        let (edge, flat): (Vec<usize>, Vec<usize>) = (0..framebuffer.pixels.len())
+            .partition(|&k| framebuffer.coverage[k] > 0.0 && framebuffer.coverage[k] < 1.0);
+        assert!(flat.iter().all(|&k| framebuffer.sample_counts[k] == 8));
+        assert!(flat.len() > 8 * edge.len() && edge.len() > 20, "{} flat and {} edge pixels", flat.len(), edge.len());
+        let mean_edge = edge.iter().map(|&k| framebuffer.sample_counts[k] as f32).sum::<f32>() / edge.len() as f32;
+        assert!(mean_edge > 64.0, "edge pixels took {} samples on average", mean_edge);
+        assert!(framebuffer.sample_counts.iter().all(|&count| count <= 128));
+    }
+
+    #[test]
+    fn cancelling_returns_a_partial_render() {
+        let settings = test_settings(200, 200, 64);
+        let world: Arc<dyn Hittable> = Arc::new(HittableList { objects: sphere_on_floor() });
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+        let cancel = CancellationToken::new();
+        let canceller = {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(std::time::Duration::from_millis(50));
+                cancel.cancel();
+            })
+        };
+        let framebuffer = render_cancellable(&settings, &test_camera(&settings), world.clone(), test_light(), background.clone(), cancel.clone());
+        canceller.join().unwrap();
+
+        let traced = framebuffer.sample_counts.iter().filter(|&&count| count > 0).count();
+        assert!(traced > 0, "nothing was traced before the cancel");
+        assert!(traced < framebuffer.pixels.len(), "the render ran to completion");
+        // Untraced pixels are left black
+        assert!((0..framebuffer.pixels.len()).all(|k| framebuffer.sample_counts[k] > 0 || framebuffer.pixels[k] == Color::BLACK));
+
+        // An already cancelled token traces nothing at all
+        let early = render_cancellable(&settings, &test_camera(&settings), world, test_light(), background, cancel);
+        assert!(early.sample_counts.iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn auto_tuned_renders_match_the_untuned_image() {
+        let settings = test_settings(24, 32, 4);
+        let tuned = render_test_scene(&RenderSettings { auto_tune: true, ..settings });
+        assert!(tuned.stats.n_threads > 0);
+        assert!([1, 4, 16].contains(&tuned.stats.tile_rows), "{} rows per tile", tuned.stats.tile_rows);
+        // Deterministic pixels don't depend on how rows were split up
+        assert_images_close(&tuned.to_image(false), &render_test_scene(&settings).to_image(false), 0);
+    }
+
+    #[test]
+    fn deterministic_pixels_ignore_tiling_and_threads() {
+        // Best effort: this checks the order samples are added in, on this machine only
+        let settings = test_settings(24, 24, 4);
+        let single = render_objects(&settings, sphere_on_floor());
+        let split = render_objects(&RenderSettings { n_threads: 3, tile_rows: 1, ..settings }, sphere_on_floor());
+        assert_eq!(split.pixels, single.pixels);
+        assert_eq!(split.variance, single.variance);
+    }
+
+    #[test]
+    fn parallel_tiles_come_back_in_row_order_every_time() {
+        let settings = test_settings(12, 20, 2);
+        let mut objects = vec![test_light()];
+        objects.extend(sphere_on_floor());
+        let tracer = Arc::new(tracer_for(settings, objects));
+        let camera = test_camera(&settings);
+
+        // More workers than the two cores a CI machine may have, and tiles of uneven height
+        let first = render_rows(&settings, &camera, &tracer, (3, 20), 2, 8, 2);
+        let starts: Vec<u32> = first.iter().map(|tile| tile.start_row).collect();
+        assert_eq!(starts, [3, 5, 7, 9, 11, 13, 15, 17, 19]);
+        assert_eq!(first.last().unwrap().end_row, 20);
+
+        let again = render_rows(&settings, &camera, &tracer, (3, 20), 2, 8, 2);
+        let serial = render_rows(&settings, &camera, &tracer, (3, 20), 2, 1, 17);
+        let pixels = |tiles: &[ImageBlockInfo]| tiles.iter().flat_map(|tile| tile.pixels.clone()).collect::<Vec<Color>>();
+        assert_eq!(pixels(&again), pixels(&first));
+        assert_eq!(pixels(&serial), pixels(&first));
+    }
+
+    #[test]
+    fn one_render_fills_both_the_beauty_and_the_normals() {
+        let settings = RenderSettings { normals_preview: true, ..test_settings(16, 16, 4) };
+        let framebuffer = render_objects(&settings, sphere_on_floor());
+        let normals = framebuffer.normals.as_ref().expect("the normals preview should come with the render");
+        assert_eq!(normals.len(), framebuffer.pixels.len());
+
+        // The middle of the sphere faces the camera, mapped from [-1, 1] into [0, 1]
+        let center = framebuffer.index(8, 8);
+        assert!((normals[center].r - 0.5).abs() < 0.1 && (normals[center].g - 0.5).abs() < 0.1 && normals[center].b > 0.9, "{:?}", normals[center]);
+        assert!(mean_luminance(&framebuffer) > 0.0);
+        // Pixels seeing nothing have no normal
+        assert!((0..normals.len()).filter(|&k| framebuffer.coverage[k] == 0.0).all(|k| normals[k] == Color::BLACK));
+
+        // The beauty pass is the same as a render without the preview, which keeps no normals
+        let plain = render_objects(&test_settings(16, 16, 4), sphere_on_floor());
+        assert_eq!(plain.pixels, framebuffer.pixels);
+        assert!(plain.normals.is_none());
+    }
+
+    #[test]
+    fn tiles_are_copied_into_the_framebuffer_unchanged() {
+        let settings = RenderSettings { n_threads: 2, tile_rows: 3, normals_preview: true, ..test_settings(16, 12, 2) };
+        let framebuffer = render_objects(&settings, sphere_on_floor());
+        // The same world as render_objects builds, light first
+        let mut objects = vec![test_light()];
+        objects.extend(sphere_on_floor());
+        let tracer = tracer_for(settings, objects);
+        let block = process_block(ImageBlockInfo::new(&settings, 5, 8, 2), &test_camera(&settings), &tracer);
+        let rows = 5 * 16..8 * 16;
+        assert_eq!(block.pixels, framebuffer.pixels[rows.clone()]);
+        assert_eq!(block.variances, framebuffer.variance[rows.clone()]);
+        assert_eq!(block.sample_counts, framebuffer.sample_counts[rows.clone()]);
+        assert_eq!(block.coverage, framebuffer.coverage[rows.clone()]);
+        assert_eq!(block.normal_pixels, framebuffer.normals.as_ref().unwrap()[rows]);
+    }
+
+    #[test]
+    fn deterministic_blocks_leave_the_thread_unseeded() {
+        let settings = test_settings(4, 4, 2);
+        let tracer = tracer_for(settings, sphere_on_floor());
+        let camera = test_camera(&settings);
+        // Left seeded, both blocks would hand on the same generator state from their last pixel
+        let after_block = || {
+            process_block(ImageBlockInfo::new(&settings, 0, 4, 2), &camera, &tracer);
+            return random_f32();
+        };
+        assert_ne!(after_block(), after_block());
+    }
+
+    fn lit_floor() -> Vec<Arc<dyn Hittable>> {
+        return vec![Arc::new(XZRect { x: (-10.0, 10.0), z: (-10.0, 10.0), k: -1.0, material: Arc::new(Lambertian::new(Color { r: 0.8, g: 0.8, b: 0.8 })) })];
+    }
+
+    #[test]
+    fn without_lights_the_environment_is_sampled_in_their_place() {
+        // The floor only sees the sky, so it is the sky's 0.5 times its albedo of 0.8 everywhere
+        let sky: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color { r: 0.5, g: 0.5, b: 0.5 } });
+        let floor: Arc<dyn Hittable> = Arc::new(HittableList { objects: lit_floor() });
+        for light_samples in [1, 4] {
+            let settings = RenderSettings { light_samples, ..test_settings(16, 16, 32) };
+            let framebuffer = render(&settings, &test_camera(&settings), floor.clone(), Arc::new(HittableList::new()), sky.clone());
+            let on_floor: Vec<usize> = (0..framebuffer.pixels.len()).filter(|&k| framebuffer.coverage[k] == 1.0).collect();
+            assert!(on_floor.len() > 50, "only {} pixels on the floor", on_floor.len());
+            let mean = on_floor.iter().map(|&k| framebuffer.pixels[k].luminance()).sum::<f32>() / on_floor.len() as f32;
+            assert!((mean - 0.4).abs() < 0.01, "floor at {} with {} light samples", mean, light_samples);
+        }
+
+        // With a black sky there is nothing to aim at, and the light is only found by bounces
+        let settings = test_settings(24, 24, 64);
+        let black: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+        let unlisted = render(&settings, &test_camera(&settings), floor.clone(), Arc::new(HittableList::new()), black.clone());
+        assert!(unlisted.pixels.iter().all(|c| *c == Color::BLACK));
+        let lit_room = || Arc::new(HittableList { objects: [lit_floor(), vec![test_light()]].concat() });
+        let unlisted = render(&settings, &test_camera(&settings), lit_room(), Arc::new(HittableList::new()), black);
+        let listed = render_world(&settings, lit_room());
+        assert!(unlisted.pixels.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+        let (found, expected) = (mean_luminance(&unlisted), mean_luminance(&listed));
+        assert!((found - expected).abs() < 0.05 * expected, "mean luminance {} without lights, {} with", found, expected);
+    }
+
+    #[test]
+    fn more_light_samples_cut_direct_lighting_noise() {
+        // Two bounces leave only the light reaching the floor directly
+        let one = RenderSettings { max_depth: 2, ..test_settings(24, 24, 8) };
+        let four = RenderSettings { light_samples: 4, ..one };
+        let (noisy, smooth) = (render_objects(&one, lit_floor()), render_objects(&four, lit_floor()));
+        let mean_variance = |framebuffer: &Framebuffer| framebuffer.variance.iter().sum::<f32>() / framebuffer.variance.len() as f32;
+
+        assert!(mean_variance(&smooth) < 0.5 * mean_variance(&noisy), "variance {} with 4 samples, {} with 1", mean_variance(&smooth), mean_variance(&noisy));
+        let (a, b) = (mean_luminance(&noisy), mean_luminance(&smooth));
+        assert!((a - b).abs() < 0.05 * a, "mean luminance {} with 4 samples, {} with 1", b, a);
+    }
+
+    #[test]
+    fn nee_weight_changes_the_noise_but_not_the_brightness() {
+        // Two bounces only see light reaching the floor directly, which every weight finds,
+        // up to pure light sampling
+        let settings = RenderSettings { max_depth: 2, ..test_settings(24, 24, 32) };
+        let renders: Vec<(f32, Framebuffer)> = [0.0, 0.5, 1.0].iter()
+            .map(|&nee_weight| (nee_weight, render_objects(&RenderSettings { nee_weight, ..settings }, lit_floor())))
+            .collect();
+        let mean_variance = |framebuffer: &Framebuffer| framebuffer.variance.iter().sum::<f32>() / framebuffer.variance.len() as f32;
+        let reference = mean_luminance(&renders[1].1);
+        for (nee_weight, framebuffer) in renders.iter() {
+            let mean = mean_luminance(framebuffer);
+            assert!((mean - reference).abs() < 0.05 * reference, "mean luminance {} at weight {}, {} at 0.5", mean, nee_weight, reference);
+        }
+        // Material sampling alone rarely finds the small light
+        assert!(mean_variance(&renders[0].1) > 2.0 * mean_variance(&renders[1].1));
+        assert!(mean_variance(&renders[2].1) < mean_variance(&renders[1].1));
+    }
+
+    #[test]
+    fn light_samples_keep_global_illumination_unbiased() {
+        let one = test_settings(16, 16, 64);
+        let four = RenderSettings { light_samples: 4, ..one };
+        let (a, b) = (mean_luminance(&render_objects(&one, sphere_on_floor())), mean_luminance(&render_objects(&four, sphere_on_floor())));
+        assert!((a - b).abs() < 0.03 * a, "mean luminance {} with 4 samples, {} with 1", b, a);
+    }
+
+    // Floor, a plate shading half of it and a light above, all scaled by scale, for
+    // direct-only renders from the side
+    fn shadowed_floor(settings: &RenderSettings, scale: f32) -> Framebuffer {
+        let grey = Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 }));
+        let light: Arc<dyn Hittable> = Arc::new(FlipFace {
+            object: Arc::new(XZRect {
+                x: (-0.5 * scale, 0.5 * scale),
+                z: (-0.5 * scale, 0.5 * scale),
+                k: 2.0 * scale,
+                material: Arc::new(DiffuseLight::new(Color { r: 4.0, g: 4.0, b: 4.0 })),
+            }),
+        });
+        let mut world = HittableList::new();
+        world.add(light.clone());
+        world.add(Arc::new(XZRect { x: (-2.0 * scale, 2.0 * scale), z: (-2.0 * scale, 2.0 * scale), k: 0.0, material: grey.clone() }));
+        world.add(Arc::new(XZRect { x: (-2.0 * scale, 0.0), z: (-2.0 * scale, 2.0 * scale), k: scale, material: grey }));
+        let mut lights = HittableList::new();
+        lights.add(light);
+
+        let camera = Camera::new(
+            Point { x: 0.0, y: 1.5 * scale, z: 6.0 * scale },
+            Point::ORIGIN,
+            Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            50.0,
+            1.0,
+            0.0,
+            6.0 * scale,
+            0.0,
+            1.0,
+        );
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+        return render(settings, &camera, Arc::new(world), Arc::new(lights), background);
+    }
+
+    #[test]
+    fn shadows_hold_at_any_scene_scale() {
+        let settings = RenderSettings { direct_only: true, light_samples: 4, ..test_settings(32, 32, 4) };
+        // Pixels of a surface in shadow, and of a surface the light reaches, leaving out the
+        // light itself
+        let shadowed_and_lit = |framebuffer: &Framebuffer| -> (Vec<usize>, Vec<usize>) {
+            let covered = (0..framebuffer.pixels.len()).filter(|&k| framebuffer.coverage[k] > 0.0);
+            let (shadowed, lit): (Vec<usize>, Vec<usize>) = covered.partition(|&k| framebuffer.pixels[k].luminance() == 0.0);
+            return (shadowed, lit.into_iter().filter(|&k| framebuffer.pixels[k].luminance() < 3.0).collect());
+        };
+        let unit = shadowed_floor(&settings, 1.0);
+        let (shadowed, lit) = shadowed_and_lit(&unit);
+        assert!(shadowed.len() > 30, "only {} shadowed pixels", shadowed.len());
+        assert!(lit.len() > 100, "only {} lit pixels", lit.len());
+
+        for scale in [1e-3, 1e4] {
+            let scaled = shadowed_floor(&settings, scale);
+            // A leak lights the floor under the plate, while self-shadowing or a light that
+            // shadows itself darkens surfaces in the open
+            let (scaled_shadowed, scaled_lit) = shadowed_and_lit(&scaled);
+            let leaks = shadowed.iter().filter(|k| !scaled_shadowed.contains(k)).count();
+            let darkened = lit.iter().filter(|k| !scaled_lit.contains(k)).count();
+            assert!(leaks <= shadowed.len() / 20, "{} of {} shadowed pixels lit at scale {}", leaks, shadowed.len(), scale);
+            assert!(darkened <= lit.len() / 20, "{} of {} lit pixels dark at scale {}", darkened, lit.len(), scale);
+            let (a, b) = (mean_luminance(&unit), mean_luminance(&scaled));
+            assert!((a - b).abs() < 0.02 * a, "mean luminance {} at scale {}, {} at scale 1", b, scale, a);
+        }
+    }
+
+    // Tracer over objects lit by the test light, for calling its methods directly
+    fn tracer_for(settings: RenderSettings, objects: Vec<Arc<dyn Hittable>>) -> Tracer {
+        let world: Arc<dyn Hittable> = Arc::new(HittableList { objects });
+        let mut lights = HittableList::new();
+        lights.add(test_light());
+        return Tracer {
+            world: world.clone(),
+            lights: Arc::new(lights),
+            has_lights: true,
+            environment: Arc::new(SolidEnvironment { color: Color::BLACK }),
+            settings,
+            epsilon: scene_epsilon(world.as_ref()),
+            cancel: CancellationToken::new(),
+        };
+    }
+
+    #[test]
+    fn deeper_bounces_are_clamped_harder() {
+        let settings = RenderSettings { clamp_schedule: Option::from(falloff_clamp as ClampSchedule), ..test_settings(1, 1, 1) };
+        let tracer = tracer_for(settings, sphere_on_floor());
+        let bright = Color { r: 400.0, g: 200.0, b: 100.0 };
+
+        // Light reaching the first hit is never clamped
+        assert_eq!(tracer.clamp_incoming(bright, settings.max_depth), bright);
+        let peaks: Vec<f32> = (1..settings.max_depth).map(|bounce| {
+            let clamped = tracer.clamp_incoming(bright, settings.max_depth - bounce);
+            // Scaled down as a whole, so the hue is kept
+            assert!((clamped.g / clamped.r - 0.5).abs() < 1e-6 && (clamped.b / clamped.r - 0.25).abs() < 1e-6);
+            assert!((clamped.r - falloff_clamp(bounce)).abs() < 1e-5);
+            clamped.r
+        }).collect();
+        assert!(peaks.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", peaks);
+
+        // Anything already under the limit passes through
+        let dim = Color { r: 0.5, g: 0.25, b: 0.1 };
+        assert_eq!(tracer.clamp_incoming(dim, 1), dim);
+        let unclamped = tracer_for(test_settings(1, 1, 1), sphere_on_floor());
+        assert_eq!(unclamped.clamp_incoming(bright, 1), bright);
+    }
+
+    #[test]
+    fn fog_thickens_with_distance() {
+        let fog = Fog { color: Color { r: 0.0, g: 1.0, b: 0.0 }, density: 0.3 };
+        let grey = Color { r: 0.5, g: 0.5, b: 0.5 };
+        assert_eq!(fog.apply(grey, fog.color, 0.0), grey);
+        let (near, far) = (fog.apply(grey, fog.color, 1.0), fog.apply(grey, fog.color, 10.0));
+        assert!(far.r < near.r && far.g > near.g);
+        assert!((far.r - 0.5 * (-3.0f32).exp()).abs() < 1e-6);
+
+        // On the lit floor, rows further up the image see further away. Fog only adds green, so
+        // the share of red lost from a pixel is how much of it is fog.
+        let plain_settings = test_settings(24, 24, 4);
+        let settings = RenderSettings { fog: Option::from(fog), ..plain_settings };
+        let (plain, fogged) = (render_objects(&plain_settings, lit_floor()), render_objects(&settings, lit_floor()));
+        let amounts: Vec<f32> = (0..24).filter_map(|j| {
+            let k = j * 24 + 12;
+            if plain.pixels[k].r > 0.0 { Option::from(1.0 - fogged.pixels[k].r / plain.pixels[k].r) } else { Option::None }
+        }).collect();
+        assert!(amounts.len() > 5, "only {} rows reach the floor", amounts.len());
+        assert!(amounts.windows(2).all(|pair| pair[1] < pair[0] + 1e-4), "fog by row {:?}", amounts);
+        assert!(amounts[0] > amounts[amounts.len() - 1] + 0.2, "fog by row {:?}", amounts);
+        // The black sky above the floor is left as it was
+        assert_eq!(fogged.pixels[12], plain.pixels[12]);
+    }
+
+    #[test]
+    fn primary_rays_skip_what_the_camera_clips() {
+        let settings = test_settings(9, 9, 4);
+        // A black sheet a unit in front of the camera hides the lit test sphere
+        let mut world = HittableList::new();
+        world.add(test_light());
+        world.add(Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 })) }));
+        world.add(Arc::new(XYRect { x: (-5.0, 5.0), y: (-5.0, 5.0), k: 2.0, material: Arc::new(Lambertian::new(Color::BLACK)) }));
+        let world: Arc<dyn Hittable> = Arc::new(world);
+        let mut lights = HittableList::new();
+        lights.add(test_light());
+        let lights: Arc<dyn Hittable> = Arc::new(lights);
+        let brightness = |camera: &Camera| {
+            let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+            mean_luminance(&render(&settings, camera, world.clone(), lights.clone(), background))
+        };
+
+        assert_eq!(brightness(&test_camera(&settings)), 0.0);
+        let cutaway = test_camera(&settings).with_clipping(Option::from(1.5), Option::None);
+        assert!(brightness(&cutaway) > 0.01, "mean luminance {} with the sheet clipped", brightness(&cutaway));
+        // The sphere's nearest point is 2 away, so a far plane before it leaves only the background
+        let shallow = cutaway.with_clipping(Option::from(1.5), Option::from(1.9));
+        assert_eq!(brightness(&shallow), 0.0);
+    }
+
+    #[test]
+    fn splitting_at_glass_cuts_its_noise() {
+        // A glass ball against the sky, with a light sample target out of the way
+        let glass: Arc<dyn Hittable> = Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Dielectric { refractive_index: 1.5 }) });
+        let mut lights = HittableList::new();
+        lights.add(Arc::new(XZRect { x: (-1.0, 1.0), z: (-1.0, 1.0), k: 100.0, material: Arc::new(DiffuseLight::new(Color::WHITE)) }));
+        let lights: Arc<dyn Hittable> = Arc::new(lights);
+        let render_with = |settings: &RenderSettings| {
+            render(settings, &test_camera(settings), glass.clone(), lights.clone(), Arc::new(GradientEnvironment::SKY))
+        };
+        let single = RenderSettings { max_depth: 6, ..test_settings(24, 24, 4) };
+        let split = RenderSettings { split_depth: 3, ..single };
+        let (noisy, smooth) = (render_with(&single), render_with(&split));
+
+        let on_glass: Vec<usize> = (0..24 * 24).filter(|&k| noisy.coverage[k] == 1.0).collect();
+        assert!(on_glass.len() > 50, "only {} pixels on the glass", on_glass.len());
+        let mean_variance = |framebuffer: &Framebuffer| on_glass.iter().map(|&k| framebuffer.variance[k]).sum::<f32>() / on_glass.len() as f32;
+        assert!(mean_variance(&smooth) < 0.5 * mean_variance(&noisy), "variance {} split, {} picking one branch", mean_variance(&smooth), mean_variance(&noisy));
+
+        // Both estimate the same image, as a high sample count reference shows
+        let reference = render_with(&RenderSettings { samples_per_pixel: 256, ..single });
+        let mean = |framebuffer: &Framebuffer| on_glass.iter().map(|&k| framebuffer.pixels[k].luminance()).sum::<f32>() / on_glass.len() as f32;
+        assert!((mean(&smooth) - mean(&reference)).abs() < 0.03 * mean(&reference), "split {} against {}", mean(&smooth), mean(&reference));
+        assert!((mean(&noisy) - mean(&reference)).abs() < 0.03 * mean(&reference), "single {} against {}", mean(&noisy), mean(&reference));
+    }
+
+    // A red sphere on a red floor, where the max channel and luminance of the throughput differ most
+    fn red_room() -> Vec<Arc<dyn Hittable>> {
+        let red = Color { r: 0.9, g: 0.1, b: 0.1 };
+        return vec![
+            Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(red)) }),
+            Arc::new(XZRect { x: (-10.0, 10.0), z: (-10.0, 10.0), k: -1.0, material: Arc::new(Lambertian::new(red)) }),
+        ];
+    }
+
+    #[test]
+    fn roulette_heuristics_keep_the_brightness_but_end_paths_differently() {
+        let reference = RenderSettings { rr_min_bounces: 1, ..test_settings(24, 24, 128) };
+        let expected = mean_luminance(&render_objects(&reference, red_room()));
+        for heuristic in [RrHeuristic::MaxChannel, RrHeuristic::Luminance] {
+            let settings = RenderSettings { russian_roulette: Option::from(heuristic), ..reference };
+            let found = mean_luminance(&render_objects(&settings, red_room()));
+            assert!((found - expected).abs() < 0.06 * expected, "mean luminance {} with roulette, {} without", found, expected);
+        }
+
+        // Follow paths bouncing off the red surfaces until roulette ends them
+        seed_thread_rng(Option::from(264));
+        let mean_length = |heuristic: RrHeuristic| {
+            let settings = RenderSettings { russian_roulette: Option::from(heuristic), ..reference };
+            let tracer = tracer_for(settings, red_room());
+            let mut total = 0;
+            for _ in 0..2000 {
+                let mut throughput = Color::WHITE;
+                let mut depth = settings.max_depth;
+                while depth > 0 {
+                    match tracer.roulette(throughput, depth) {
+                        Some(weight) => throughput = weight * Color { r: 0.9, g: 0.1, b: 0.1 } * throughput,
+                        None => break,
+                    }
+                    depth -= 1;
+                }
+                total += settings.max_depth - depth;
+            }
+            total as f32 / 2000.0
+        };
+        let (max_channel, luminance) = (mean_length(RrHeuristic::MaxChannel), mean_length(RrHeuristic::Luminance));
+        // The red channel keeps max channel paths going where luminance sees a dim path
+        assert!(max_channel > luminance + 1.0, "mean path length {} by max channel, {} by luminance", max_channel, luminance);
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn roulette_waits_for_the_minimum_bounces_then_shortens_paths_without_darkening() {
+        let reference = test_settings(24, 24, 128);
+        let expected = mean_luminance(&render_objects(&reference, sphere_on_floor()));
+        let settings = RenderSettings { russian_roulette: Option::from(RrHeuristic::MaxChannel), rr_min_bounces: 3, ..reference };
+        let found = mean_luminance(&render_objects(&settings, sphere_on_floor()));
+        assert!((found - expected).abs() < 0.05 * expected, "mean luminance {} with roulette, {} without", found, expected);
+
+        // Even a path carrying nothing survives its first rr_min_bounces bounces
+        seed_thread_rng(Option::from(276));
+        let tracer = tracer_for(settings, sphere_on_floor());
+        for bounce in 0..3 {
+            assert!((0..1000).all(|_| tracer.roulette(Color::BLACK, settings.max_depth - bounce) == Option::from(1.0)));
+        }
+        assert!((0..1000).any(|_| tracer.roulette(Color::BLACK, settings.max_depth - 3).is_none()));
+
+        // Grey bounces, so paths past the minimum end well before max_depth
+        let mean_length = |tracer: &Tracer| {
+            let mut total = 0;
+            for _ in 0..2000 {
+                let mut throughput = Color::WHITE;
+                let mut depth = settings.max_depth;
+                while depth > 0 {
+                    match tracer.roulette(throughput, depth) {
+                        Some(weight) => throughput = weight * Color { r: 0.5, g: 0.5, b: 0.5 } * throughput,
+                        None => break,
+                    }
+                    depth -= 1;
+                }
+                total += settings.max_depth - depth;
+            }
+            total as f32 / 2000.0
+        };
+        let without = tracer_for(reference, sphere_on_floor());
+        assert_eq!(mean_length(&without), settings.max_depth as f32);
+        let with = mean_length(&tracer);
+        assert!(with >= 3.0 && with < settings.max_depth as f32 - 1.0, "mean path length {} with roulette", with);
+        seed_thread_rng(Option::None);
+    }
+
+    #[test]
+    fn a_diffuse_bounce_allocates_only_its_scatter_pdf() {
+        let settings = RenderSettings { max_depth: 2, ..test_settings(1, 1, 1) };
+        let tracer = tracer_for(settings, sphere_on_floor());
+        // Straight down onto the top of the sphere, which then bounces once more
+        let ray = Ray { origin: Point { x: 0.0, y: 1.5, z: 0.0 }, direction: Vector3 { x: 0.0, y: -1.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+        let hit_rec = tracer.world.hit(ray, tracer.epsilon, INF_F32).unwrap();
+        assert_eq!(hit_rec.intersection.y, 1.0);
+
+        let (_, allocations) = count_allocations(|| tracer.ray_color(ray, settings.max_depth, Color::WHITE));
+        // The boxed CosinePDF of each of the two Lambertian scatter records. The light PDF and
+        // the mixture used to be boxed too.
+        assert!(allocations <= 2, "{} allocations for two bounces", allocations);
+    }
+
+}
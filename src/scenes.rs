@@ -1,21 +1,108 @@
 use std::sync::Arc;
 
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng, thread_rng};
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::color::Color;
+use crate::geometry::transform::Mat4;
 use crate::geometry::vector::{Point, Vector3};
+use crate::materials::cache::MaterialCache;
 use crate::materials::dielectric::Dielectric;
 use crate::materials::lambertian::Lambertian;
 use crate::materials::light::DiffuseLight;
+use crate::materials::material::Material;
 use crate::materials::metal::Metal;
+use crate::materials::thin_film::ThinFilm;
 use crate::objects::boxes::AxisAlignedBox;
 use crate::objects::hittable::{FlipFace, Hittable};
-use crate::objects::instances::{RotateY, Translate};
+use crate::objects::instances::{RotateY, Transformed, Translate};
+use crate::objects::medium::{ChannelSampling, ChromaticMedium, NoiseVolume, SoftSphere};
 use crate::objects::rectangle::{XYRect, XZRect, YZRect};
-use crate::objects::sphere::{MovingSphere, Sphere};
+use crate::objects::plane::GroundPlane;
+use crate::objects::sphere::{DisplacedSphere, MovingSphere, Sphere};
+use crate::textures::checkered::{CheckeredTexture, SolidChecker};
 use crate::textures::image::ImageTexture;
-use crate::textures::perlin::{NoiseTexture, Perlin};
+use crate::textures::perlin::{NoiseMode, NoiseTexture, Perlin};
 use crate::textures::solid::SolidColor;
 use crate::utils::random_f32_range;
 use crate::world::bvh_node::BVHNode;
+use crate::world::environment::{Environment, GradientEnvironment, SolidEnvironment};
+use crate::world::hittable_list::HittableList;
+use crate::world::scene_file::CameraSpec;
+
+// A world together with what rendering it needs: the lights to aim shadow rays at, where the
+// camera goes and what rays that miss everything see
+pub struct Scene {
+    pub objects: Vec<Arc<dyn Hittable>>,
+    pub lights: HittableList,
+    pub view: CameraSpec,
+    pub environment: Arc<dyn Environment>,
+}
+
+// What --scene can pick, the Cornell box first as the default
+pub const SCENES: [&str; 11] = ["cornell", "rtweekend", "checkered", "perlin", "materials", "bubbles", "cloud", "displaced", "soft", "transformed", "tinted"];
+
+pub fn scene(name: &str) -> Option<Scene> {
+    let far_view = CameraSpec::looking(Point { x: 13.0, y: 2.0, z: 3.0 }, Point::ORIGIN, 20.0);
+    let scene = match name {
+        "cornell" => cornell_scene(),
+        "rtweekend" => sky_lit(final_scene_rtweekend(), CameraSpec { aperture: 0.1, focus_dist: 10.0, ..far_view }),
+        "checkered" => sky_lit(checkered_spheres(), far_view),
+        "perlin" => sky_lit(perlin_spheres(), far_view),
+        "materials" => sky_lit(material_spheres(), CameraSpec::looking(Point { x: 0.0, y: 1.0, z: 9.0 }, Point { x: 0.0, y: 1.0, z: 0.0 }, 40.0)),
+        "bubbles" => sky_lit(soap_bubbles(), CameraSpec::looking(Point { x: 0.0, y: 0.0, z: 3.0 }, Point::ORIGIN, 40.0)),
+        "cloud" => sky_lit(noise_cloud(), CameraSpec::looking(Point { x: 0.0, y: 1.75, z: 9.0 }, Point { x: 0.0, y: 1.75, z: 0.0 }, 30.0)),
+        "displaced" => sky_lit(bumpy_planet(), CameraSpec::looking(Point { x: 0.0, y: 2.0, z: 6.0 }, Point { x: 0.0, y: 1.2, z: 0.0 }, 35.0)),
+        "soft" => nebula_scene(),
+        "transformed" => sky_lit(transformed_shapes(), CameraSpec::looking(Point { x: 0.0, y: 2.0, z: 7.0 }, Point { x: 0.0, y: 1.0, z: 0.0 }, 40.0)),
+        "tinted" => sky_lit(tinted_glass(), CameraSpec::looking(Point { x: 0.0, y: 1.5, z: 5.0 }, Point { x: 0.0, y: 1.0, z: 0.0 }, 40.0)),
+        _ => return Option::None,
+    };
+    return Option::from(scene);
+}
+
+fn cornell_scene() -> Scene {
+    // The ceiling light, and the glass sphere so light is aimed through it for the caustic
+    let mut lights = HittableList::new();
+    lights.add(Arc::new(XZRect {
+        x: (213.0, 343.0),
+        z: (227.0, 332.0),
+        k: 554.0,
+        material: Arc::new(DiffuseLight::new(Color::WHITE)),
+    }));
+    lights.add(Arc::new(Sphere {
+        center: Point { x: 190.0, y: 90.0, z: 190.0 },
+        radius: 90.0,
+        material: Arc::new(DiffuseLight::new(Color::WHITE)),
+    }));
+    return Scene {
+        objects: cornell_box(),
+        lights,
+        view: CameraSpec {
+            focus_dist: 10.0,
+            ..CameraSpec::looking(Point { x: 278.0, y: 278.0, z: -800.0 }, Point { x: 278.0, y: 278.0, z: 0.0 }, 40.0)
+        },
+        environment: Arc::new(SolidEnvironment { color: Color::BLACK }),
+    };
+}
+
+// In black space, lit by its star
+fn nebula_scene() -> Scene {
+    let mut lights = HittableList::new();
+    lights.add(nebula_star());
+    return Scene {
+        objects: nebula(),
+        lights,
+        view: CameraSpec::looking(Point { x: 0.0, y: 0.0, z: 6.0 }, Point::ORIGIN, 40.0),
+        environment: Arc::new(SolidEnvironment { color: Color::BLACK }),
+    };
+}
+
+// Under the sky with no lights of their own
+fn sky_lit(objects: Vec<Arc<dyn Hittable>>, view: CameraSpec) -> Scene {
+    return Scene { objects, lights: HittableList::new(), view, environment: Arc::new(GradientEnvironment::SKY) };
+}
 
 pub fn cornell_box() -> Vec<Arc<dyn Hittable>> {
     let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
@@ -79,3 +166,459 @@ pub fn cornell_box() -> Vec<Arc<dyn Hittable>> {
 
     return world;
 }
+
+// The scenes below have no lights of their own and are lit by the environment,
+// as in the Ray Tracing in One Weekend books
+
+// Cover scene of the first book: a field of small random spheres around three large ones
+pub fn final_scene_rtweekend() -> Vec<Arc<dyn Hittable>> {
+    return final_scene_rtweekend_seeded(thread_rng().gen());
+}
+
+// Same seed, same scene
+pub fn final_scene_rtweekend_seeded(seed: u64) -> Vec<Arc<dyn Hittable>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    let mut materials = MaterialCache::new();
+
+    let checker = Arc::new(CheckeredTexture {
+        even: Arc::new(SolidColor { color: Color { r: 0.2, g: 0.3, b: 0.1 } }),
+        odd: Arc::new(SolidColor { color: Color { r: 0.9, g: 0.9, b: 0.9 } }),
+    });
+    world.push(Arc::new(Sphere {
+        center: Point { x: 0.0, y: -1000.0, z: 0.0 },
+        radius: 1000.0,
+        material: Arc::new(Lambertian { albedo: checker }),
+    }));
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Point { x: a as f32 + 0.9 * rng.gen::<f32>(), y: 0.2, z: b as f32 + 0.9 * rng.gen::<f32>() };
+            if (center - Point { x: 4.0, y: 0.2, z: 0.0 }).length() <= 0.9 {
+                continue;
+            }
+
+            let choose_material = rng.gen::<f32>();
+            if choose_material < 0.8 {
+                let albedo = Color::random(&mut rng) * Color::random(&mut rng);
+                world.push(Arc::new(MovingSphere {
+                    centre0: center,
+                    center1: center + Vector3 { x: 0.0, y: rng.gen_range(0.0..0.5), z: 0.0 },
+                    time0: 0.0,
+                    time1: 1.0,
+                    radius: 0.2,
+                    material: materials.lambertian(albedo),
+                }));
+            } else if choose_material < 0.95 {
+                let color = Color::random_range(&mut rng, 0.5, 1.0);
+                world.push(Arc::new(Sphere {
+                    center,
+                    radius: 0.2,
+                    material: materials.metal(color, rng.gen_range(0.0..0.5)),
+                }));
+            } else {
+                world.push(Arc::new(Sphere { center, radius: 0.2, material: materials.dielectric(1.5) }));
+            }
+        }
+    }
+
+    world.push(Arc::new(Sphere { center: Point { x: 0.0, y: 1.0, z: 0.0 }, radius: 1.0, material: materials.dielectric(1.5) }));
+    world.push(Arc::new(Sphere {
+        center: Point { x: -4.0, y: 1.0, z: 0.0 },
+        radius: 1.0,
+        material: materials.lambertian(Color { r: 0.4, g: 0.2, b: 0.1 }),
+    }));
+    world.push(Arc::new(Sphere {
+        center: Point { x: 4.0, y: 1.0, z: 0.0 },
+        radius: 1.0,
+        material: materials.metal(Color { r: 0.7, g: 0.6, b: 0.5 }, 0.0),
+    }));
+
+    return world;
+}
+
+pub fn checkered_spheres() -> Vec<Arc<dyn Hittable>> {
+    let checker: Arc<dyn Material> = Arc::new(Lambertian {
+        albedo: Arc::new(CheckeredTexture {
+            even: Arc::new(SolidColor { color: Color { r: 0.2, g: 0.3, b: 0.1 } }),
+            odd: Arc::new(SolidColor { color: Color { r: 0.9, g: 0.9, b: 0.9 } }),
+        })
+    });
+
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(Sphere { center: Point { x: 0.0, y: -10.0, z: 0.0 }, radius: 10.0, material: checker.clone() }));
+    world.push(Arc::new(Sphere { center: Point { x: 0.0, y: 10.0, z: 0.0 }, radius: 10.0, material: checker }));
+    return world;
+}
+
+pub fn perlin_spheres() -> Vec<Arc<dyn Hittable>> {
+    let noise: Arc<dyn Material> = Arc::new(Lambertian {
+        // Seeded, so every render of the scene shows the same marble
+        albedo: Arc::new(NoiseTexture { noise: Perlin::seeded(262), scale: 4.0, mode: NoiseMode::Marble })
+    });
+
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(Sphere { center: Point { x: 0.0, y: -1000.0, z: 0.0 }, radius: 1000.0, material: noise.clone() }));
+    world.push(Arc::new(Sphere { center: Point { x: 0.0, y: 2.0, z: 0.0 }, radius: 2.0, material: noise }));
+    return world;
+}
+
+pub fn single_sphere() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(Sphere {
+        center: Point::ORIGIN,
+        radius: 0.5,
+        material: Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 })),
+    }));
+    return world;
+}
+
+// One sphere of each basic material side by side on a checkered floor
+pub fn material_spheres() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(Sphere {
+        center: Point { x: -2.2, y: 1.0, z: 0.0 },
+        radius: 1.0,
+        material: Arc::new(Lambertian::new(Color { r: 0.7, g: 0.3, b: 0.3 })),
+    }));
+    world.push(Arc::new(Sphere {
+        center: Point { x: 0.0, y: 1.0, z: 0.0 },
+        radius: 1.0,
+        material: Arc::new(Metal { color: Color { r: 0.8, g: 0.8, b: 0.8 }, fuzz: 0.05 }),
+    }));
+    world.push(Arc::new(Sphere {
+        center: Point { x: 2.2, y: 1.0, z: 0.0 },
+        radius: 1.0,
+        material: Arc::new(Dielectric { refractive_index: 1.5 }),
+    }));
+    return with_ground(world, 0.0);
+}
+
+// Soap bubbles in a row, each film thicker than the last, which moves its colors along the
+// interference orders
+pub fn soap_bubbles() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    for (k, thickness) in [250.0, 400.0, 550.0].iter().enumerate() {
+        world.push(Arc::new(Sphere {
+            center: Point { x: 0.8 * (k as f32 - 1.0), y: 0.0, z: 0.0 },
+            radius: 0.35,
+            material: Arc::new(ThinFilm { thickness: *thickness, refractive_index: 1.33 }),
+        }));
+    }
+    return world;
+}
+
+// A bank of Perlin cloud over the floor, thick in places and wispy in others
+pub fn noise_cloud() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(cloud_bank()));
+    return with_ground(world, 0.0);
+}
+
+// Seeded, so every render of the scene shows the same cloud
+fn cloud_bank() -> NoiseVolume {
+    let bounds = AxisAlignedBoundingBox { minimum: Point { x: -3.0, y: 1.0, z: -1.5 }, maximum: Point { x: 3.0, y: 2.5, z: 1.5 } };
+    return NoiseVolume::new(bounds, Perlin::seeded(275), 1.0, 1.5, Color::WHITE);
+}
+
+// A planet with Perlin hills and valleys, sitting on the floor
+pub fn bumpy_planet() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(Arc::new(DisplacedSphere {
+        center: Point { x: 0.0, y: 1.2, z: 0.0 },
+        radius: 1.0,
+        amplitude: 0.2,
+        // Seeded, so every render of the scene shows the same terrain
+        height: Arc::new(NoiseTexture { noise: Perlin::seeded(230), scale: 4.0, mode: NoiseMode::Smooth }),
+        material: Arc::new(Lambertian::new(Color { r: 0.6, g: 0.5, b: 0.4 })),
+    }));
+    return with_ground(world, 0.0);
+}
+
+// A star inside overlapping gaussian clouds of colored dust, first in the list. The clouds are
+// dense at their centers and fade out towards their edges.
+pub fn nebula() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    world.push(nebula_star());
+    let clouds = [
+        (Point { x: -0.6, y: 0.2, z: 0.0 }, Color { r: 0.9, g: 0.4, b: 0.3 }),
+        (Point { x: 0.5, y: 0.4, z: -0.3 }, Color { r: 0.3, g: 0.5, b: 0.9 }),
+        (Point { x: 0.1, y: -0.5, z: 0.2 }, Color { r: 0.8, g: 0.8, b: 0.5 }),
+    ];
+    for (center, color) in clouds.iter() {
+        world.push(Arc::new(SoftSphere::new(*center, 1.2, 0.4, 3.0, *color)));
+    }
+    return world;
+}
+
+fn nebula_star() -> Arc<dyn Hittable> {
+    return Arc::new(Sphere { center: Point::ORIGIN, radius: 0.3, material: Arc::new(DiffuseLight::new(Color { r: 10.0, g: 10.0, b: 10.0 })) });
+}
+
+// A cube turned about a tilted axis and a sphere squashed into an ellipsoid, placed by Mat4
+// transforms rather than the one-axis instances
+pub fn transformed_shapes() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    let half = Vector3 { x: 0.5, y: 0.5, z: 0.5 };
+    let cube = Arc::new(AxisAlignedBox::new(Point::ORIGIN - half, Point::ORIGIN + half, Arc::new(Lambertian::new(Color { r: 0.7, g: 0.2, b: 0.2 }))));
+    let ball = Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Metal { color: Color { r: 0.8, g: 0.7, b: 0.4 }, fuzz: 0.1 }) });
+    let placed: [(Arc<dyn Hittable>, Mat4); 2] = [
+        (cube, Mat4::compose(&[
+            Mat4::rotation_axis(Vector3 { x: 1.0, y: 1.0, z: 0.0 }, 45.0),
+            Mat4::translation(Vector3 { x: -1.5, y: 1.0, z: 0.0 }),
+        ])),
+        (ball, Mat4::compose(&[
+            Mat4::scaling(Vector3 { x: 1.2, y: 0.6, z: 0.6 }),
+            Mat4::rotation_z(30.0),
+            Mat4::translation(Vector3 { x: 1.5, y: 1.0, z: 0.0 }),
+        ])),
+    ];
+    // Both transforms are invertible, so neither is left out
+    for (object, transform) in placed {
+        if let Some(transformed) = Transformed::new(object, transform) {
+            world.push(Arc::new(transformed));
+        }
+    }
+    return with_ground(world, 0.0);
+}
+
+// A ball of deep red glass on the ground, murky enough that light scatters around inside it.
+// Green and blue die out within a few bounces while red carries on, the case hero channel
+// sampling is for.
+pub fn tinted_glass() -> Vec<Arc<dyn Hittable>> {
+    let mut world: Vec<Arc<dyn Hittable>> = Vec::new();
+    let center = Point { x: 0.0, y: 1.0, z: 0.0 };
+    world.push(Arc::new(Sphere { center, radius: 1.0, material: Arc::new(Dielectric { refractive_index: 1.5 }) }));
+    world.push(Arc::new(ChromaticMedium {
+        boundary: Arc::new(Sphere { center, radius: 0.99, material: Arc::new(Dielectric { refractive_index: 1.5 }) }),
+        extinction: Color { r: 0.5, g: 4.0, b: 8.0 },
+        albedo: Color { r: 0.6, g: 0.6, b: 0.6 },
+        sampling: ChannelSampling::Hero,
+    }));
+    return with_ground(world, 0.0);
+}
+
+// Adds a flat checkered floor at height y to any scene
+pub fn with_ground(mut world: Vec<Arc<dyn Hittable>>, y: f32) -> Vec<Arc<dyn Hittable>> {
+    let checker = Arc::new(SolidChecker {
+        even: Arc::new(SolidColor { color: Color { r: 0.2, g: 0.3, b: 0.1 } }),
+        odd: Arc::new(SolidColor { color: Color { r: 0.9, g: 0.9, b: 0.9 } }),
+        size: 1.0,
+    });
+    world.push(Arc::new(GroundPlane::new(y, checker)));
+    return world;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::ray::{Ray, RayKind};
+    use crate::output::framebuffer::Framebuffer;
+    use crate::render::{render, RenderSettings};
+    use crate::render::tests::{mean_luminance, render_objects, test_settings};
+    use crate::utils::INF_F32;
+    use crate::world::camera::Camera;
+
+    // Largest channel difference relative to the brightest channel
+    fn chroma(color: Color) -> f32 {
+        let max = color.r.max(color.g).max(color.b);
+        let min = color.r.min(color.g).min(color.b);
+        return if max > 0.0 { (max - min) / max } else { 0.0 };
+    }
+
+    #[test]
+    fn every_listed_scene_can_be_picked_and_renders() {
+        let settings = test_settings(12, 12, 2);
+        for name in SCENES.iter() {
+            let scene = scene(name).unwrap();
+            let camera = scene.view.camera(1.0);
+            let framebuffer = render(&settings, &camera, Arc::new(HittableList { objects: scene.objects }), Arc::new(scene.lights), scene.environment);
+            assert!(framebuffer.pixels.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()), "{}", name);
+            assert!(framebuffer.coverage.iter().any(|&c| c > 0.0), "nothing in view of the {} camera", name);
+            assert!(mean_luminance(&framebuffer) > 0.01, "{} renders black", name);
+        }
+        assert!(scene("teapot").is_none());
+    }
+
+    #[test]
+    fn the_cornell_box_light_is_the_brightest_region() {
+        let settings = test_settings(64, 64, 8);
+        let camera = Camera::new(Point { x: 278.0, y: 278.0, z: -800.0 }, Point { x: 278.0, y: 278.0, z: 0.0 },
+                                 Vector3 { x: 0.0, y: 1.0, z: 0.0 }, 40.0, 1.0, 0.0, 10.0, 0.0, 1.0);
+        let objects = cornell_box();
+        // The ceiling light, after the two side walls
+        let light = objects[2].clone();
+        let world = HittableList { objects };
+        let mut lights = HittableList::new();
+        lights.add(light.clone());
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color::BLACK });
+        let framebuffer = render(&settings, &camera, Arc::new(world), Arc::new(lights), background);
+
+        // Pixels whose center looks straight at the light. Samples jitter over [0, 1), so the center is at 0.5
+        let on_light: Vec<bool> = (0..64 * 64).map(|k| {
+            let ray = camera.ray_through_pixel(k % 64, k / 64, 64, 64, (0.5, 0.5), Point::ORIGIN).unwrap();
+            light.hit(ray, 0.001, INF_F32).is_some()
+        }).collect();
+        let luminance = |k: usize| framebuffer.pixels[k].luminance();
+        let mean = |light: bool| {
+            let ks: Vec<usize> = (0..on_light.len()).filter(|&k| on_light[k] == light).collect();
+            ks.iter().map(|&k| luminance(k)).sum::<f32>() / ks.len() as f32
+        };
+        assert!(on_light.iter().any(|&l| l));
+        let brightest = |light: bool| (0..on_light.len()).filter(|&k| on_light[k] == light).map(luminance).fold(0.0, f32::max);
+        assert!(brightest(true) >= brightest(false), "light {} against the rest {}", brightest(true), brightest(false));
+        assert!(mean(true) > 5.0 * mean(false), "light {} against the rest {}", mean(true), mean(false));
+    }
+
+    // Renders a scene lit by a blue sky alone. The renderer wants something to aim light
+    // samples at, and a panel missing from the world sends them into the sky like any other bounce.
+    fn render_under_sky(settings: &RenderSettings, camera: &Camera, objects: Vec<Arc<dyn Hittable>>) -> Framebuffer {
+        let background: Arc<dyn Environment> = Arc::new(SolidEnvironment { color: Color { r: 0.5, g: 0.7, b: 1.0 } });
+        let mut lights = HittableList::new();
+        lights.add(Arc::new(XZRect { x: (-1.0, 1.0), z: (-1.0, 1.0), k: 100.0, material: Arc::new(DiffuseLight::new(Color::WHITE)) }));
+        return render(settings, camera, Arc::new(HittableList { objects }), Arc::new(lights), background);
+    }
+
+    #[test]
+    fn perlin_spheres_render_the_same_marble_every_time() {
+        let settings = test_settings(24, 24, 2);
+        let camera = Camera::new(Point { x: 13.0, y: 2.0, z: 3.0 }, Point::ORIGIN, Vector3 { x: 0.0, y: 1.0, z: 0.0 }, 20.0, 1.0, 0.0, 10.0, 0.0, 1.0);
+        let first = render_under_sky(&settings, &camera, perlin_spheres());
+        let second = render_under_sky(&settings, &camera, perlin_spheres());
+        assert_eq!(first.pixels, second.pixels);
+
+        // The texture itself is the same between builds and streaked rather than flat
+        let albedo = |objects: &[Arc<dyn Hittable>], z: f32| {
+            let ray = Ray { origin: Point { x: 10.0, y: 2.0, z }, direction: Vector3 { x: -1.0, y: 0.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+            let hit_rec = objects[1].hit(ray, 0.001, INF_F32).unwrap();
+            hit_rec.material.scatter(ray, &hit_rec).unwrap().attenuation.r
+        };
+        let (a, b) = (perlin_spheres(), perlin_spheres());
+        let streaks: Vec<f32> = (0..40).map(|k| albedo(&a, -1.0 + 0.05 * k as f32)).collect();
+        assert!((0..40).all(|k| albedo(&b, -1.0 + 0.05 * k as f32) == streaks[k]));
+        let (low, high) = streaks.iter().fold((1.0f32, 0.0f32), |(low, high), &v| (low.min(v), high.max(v)));
+        assert!(high - low > 0.5, "marble only spans {} to {}", low, high);
+    }
+
+    #[test]
+    fn material_spheres_render_every_material() {
+        let settings = test_settings(48, 24, 8);
+        let camera = Camera::new(Point { x: 0.0, y: 1.0, z: 9.0 }, Point { x: 0.0, y: 1.0, z: 0.0 },
+                                 Vector3 { x: 0.0, y: 1.0, z: 0.0 }, 40.0, 2.0, 0.0, 9.0, 0.0, 1.0);
+        let objects = material_spheres();
+        assert_eq!(objects.len(), 4);
+        let framebuffer = render_under_sky(&settings, &camera, objects.clone());
+
+        // Each sphere scatters the way its material does: diffusely, or along one specular ray
+        for (k, specular) in [(0, false), (1, true), (2, true)] {
+            let ray = camera.ray_through_pixel(16 + 8 * k as u32, 12, 48, 24, (0.5, 0.5), Point::ORIGIN).unwrap();
+            let hit_rec = objects[k].hit(ray, 0.001, INF_F32).expect("the pixel should look at the sphere");
+            let scatter = hit_rec.material.scatter(ray, &hit_rec).unwrap();
+            assert_eq!(scatter.specular_ray.is_some(), specular, "sphere {}", k);
+            assert_eq!(scatter.pdf_ptr.is_some(), !specular, "sphere {}", k);
+            let pixel = framebuffer.pixels[framebuffer.index(16 + 8 * k as u32, 12)];
+            assert!(pixel.luminance() > 0.01 && pixel.luminance().is_finite(), "sphere {} renders as {:?}", k, pixel);
+        }
+    }
+
+    #[test]
+    fn soap_bubbles_reflect_the_white_light_in_color() {
+        let framebuffer = render_objects(&test_settings(48, 48, 16), soap_bubbles());
+        let reflecting: Vec<Color> = (0..framebuffer.pixels.len())
+            .filter(|&k| framebuffer.coverage[k] > 0.0 && framebuffer.pixels[k].luminance() > 0.01)
+            .map(|k| framebuffer.pixels[k])
+            .collect();
+        assert!(reflecting.len() > 5, "only {} pixels reflect the light", reflecting.len());
+        assert!(reflecting.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+        let colored = reflecting.iter().filter(|&&c| chroma(c) > 0.2).count();
+        assert!(colored * 2 > reflecting.len(), "{} of {} reflections are colored", colored, reflecting.len());
+    }
+
+    #[test]
+    fn the_noise_cloud_is_patchy_and_shades_what_it_covers() {
+        let settings = test_settings(32, 16, 8);
+        let camera = Camera::new(Point { x: 0.0, y: 1.75, z: 9.0 }, Point { x: 0.0, y: 1.75, z: 0.0 },
+                                 Vector3 { x: 0.0, y: 1.0, z: 0.0 }, 30.0, 2.0, 0.0, 9.0, 0.0, 1.0);
+        let objects = noise_cloud();
+        let with_cloud = render_under_sky(&settings, &camera, objects.clone());
+        let without = render_under_sky(&settings, &camera, objects[1..].to_vec());
+        assert!(with_cloud.pixels.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+
+        // How much of the light behind it the cloud stops, along each pixel's center ray
+        let volume = cloud_bank();
+        let opacity: Vec<f32> = (0..32 * 16).map(|k| {
+            let ray = camera.ray_through_pixel(k % 32, k / 32, 32, 16, (0.5, 0.5), Point::ORIGIN).unwrap();
+            volume.opacity(ray, 0.001, INF_F32)
+        }).collect();
+        let covered: Vec<usize> = (0..opacity.len()).filter(|&k| opacity[k] > 0.0).collect();
+        assert!(covered.len() > 100, "the cloud only covers {} pixels", covered.len());
+        let (low, high) = covered.iter().fold((1.0f32, 0.0f32), |(low, high), &k| (low.min(opacity[k]), high.max(opacity[k])));
+        assert!(high - low > 0.3, "opacity only spans {} to {}", low, high);
+
+        // and the pixels behind it render differently from the bare floor and sky
+        let changed = covered.iter().filter(|&&k| (with_cloud.pixels[k].luminance() - without.pixels[k].luminance()).abs() > 1e-3).count();
+        assert!(changed * 2 > covered.len(), "only {} of {} covered pixels changed", changed, covered.len());
+    }
+
+    #[test]
+    fn the_bumpy_planet_rises_and_falls_around_its_sphere() {
+        let objects = bumpy_planet();
+        // Distance from the center to where rays from all around meet the surface
+        let center = Point { x: 0.0, y: 1.2, z: 0.0 };
+        let heights: Vec<f32> = (0..64).map(|k| {
+            let angle = k as f32 * 0.1;
+            let origin = center + Vector3 { x: 5.0 * angle.cos(), y: 0.0, z: 5.0 * angle.sin() };
+            let ray = Ray { origin, direction: center - origin, time: 0.0, kind: RayKind::Camera };
+            (objects[0].hit(ray, 0.001, INF_F32).unwrap().intersection - center).length()
+        }).collect();
+        assert!(heights.iter().all(|&h| (0.99..=1.21).contains(&h)), "{:?}", heights);
+        let (low, high) = heights.iter().fold((INF_F32, 0.0f32), |(low, high), &h| (low.min(h), high.max(h)));
+        assert!(high - low > 0.05, "heights only span {} to {}", low, high);
+    }
+
+    #[test]
+    fn the_nebula_glows_where_its_clouds_are_thickest() {
+        let settings = test_settings(32, 32, 16);
+        let scene = nebula_scene();
+        let camera = scene.view.camera(1.0);
+        let lit = render(&settings, &camera, Arc::new(HittableList { objects: scene.objects }), Arc::new(scene.lights), scene.environment);
+        assert!(lit.pixels.iter().all(|c| c.r.is_finite() && c.g.is_finite() && c.b.is_finite()));
+
+        // Looking through a cloud's center picks up more scattered starlight than through its
+        // edge. Each point is seen by the pixel whose center ray passes nearest to it.
+        let brightness = |point: Point| {
+            let miss = |k: &u32| {
+                let ray = camera.ray_through_pixel(k % 32, k / 32, 32, 32, (0.5, 0.5), Point::ORIGIN).unwrap();
+                let (to_point, direction) = (point - ray.origin, ray.direction.direction());
+                (to_point - to_point.dot(direction) * direction).length()
+            };
+            let k = (0..32 * 32).min_by(|a, b| miss(a).partial_cmp(&miss(b)).unwrap()).unwrap();
+            lit.pixels[lit.index(k % 32, k / 32)].luminance()
+        };
+        let center = Point { x: -0.6, y: 0.2, z: 0.0 };
+        let edge = center + Vector3 { x: -1.1, y: 0.0, z: 0.0 };
+        assert!(brightness(center) > 2.0 * brightness(edge), "center {} against edge {}", brightness(center), brightness(edge));
+    }
+
+    #[test]
+    fn the_transformed_ellipsoid_follows_its_scale_and_rotation() {
+        let objects = transformed_shapes();
+        assert_eq!(objects.len(), 3);
+        let ellipsoid = &objects[1];
+        let center = Point { x: 1.5, y: 1.0, z: 0.0 };
+        // The long axis is x turned 30 degrees towards y, and the short ones are across it
+        let angle = 30f32.to_radians();
+        let long_axis = Vector3 { x: angle.cos(), y: angle.sin(), z: 0.0 };
+        for (axis, radius) in [(long_axis, 1.2), (Vector3 { x: -angle.sin(), y: angle.cos(), z: 0.0 }, 0.6), (Vector3 { x: 0.0, y: 0.0, z: 1.0 }, 0.6)] {
+            let ray = Ray { origin: center + 5.0 * axis, direction: -axis, time: 0.0, kind: RayKind::Camera };
+            let hit_rec = ellipsoid.hit(ray, 0.001, INF_F32).unwrap();
+            assert!(((hit_rec.intersection - center).length() - radius).abs() < 1e-3, "{:?} at {:?}", axis, hit_rec.intersection);
+            assert!(hit_rec.normal.direction().dot(axis) > 0.999, "normal {:?} along {:?}", hit_rec.normal, axis);
+        }
+
+        // The cube turned about a diagonal reaches further out than its half width of 0.5
+        let bounds = objects[0].bounding_box(0.0, 1.0).unwrap();
+        assert!(bounds.maximum.x - bounds.minimum.x > 1.2, "{:?} to {:?}", bounds.minimum, bounds.maximum);
+        assert!(((bounds.maximum.y + bounds.minimum.y) / 2.0 - 1.0).abs() < 1e-4, "{:?} to {:?}", bounds.minimum, bounds.maximum);
+    }
+}
@@ -19,3 +19,22 @@ impl Texture for CheckeredTexture {
         };
     }
 }
+
+// Alternates between cubic cells of the given size. The sine product above is zero on the
+// axis planes, so a floor at y = 0 would come out in a single color; this has no such plane.
+pub struct SolidChecker {
+    pub even: Arc<dyn Texture>,
+    pub odd: Arc<dyn Texture>,
+    pub size: f32,
+}
+
+impl Texture for SolidChecker {
+    fn color(&self, u: f32, v: f32, point: Point) -> Color {
+        let cell = |c: f32| (c / self.size).floor() as i64;
+        return if (cell(point.x) + cell(point.y) + cell(point.z)).rem_euclid(2) == 0 {
+            self.even.color(u, v, point)
+        } else {
+            self.odd.color(u, v, point)
+        };
+    }
+}
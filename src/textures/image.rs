@@ -9,12 +9,25 @@ pub struct ImageTexture {
     pub width: u32,
     pub height: u32,
     pub bytes_per_scanline: u32,
+    // Color maps are stored gamma-encoded and get decoded on lookup, data maps such as normals
+    // or roughness hold linear values and are used as they are
+    pub is_srgb: bool,
 }
 
 impl ImageTexture {
     const BYTES_PER_PIXEL: u32 = 3;
 
+    // A color map, decoded from sRGB
     pub fn new(path: String) -> Self {
+        return Self::load(path, true);
+    }
+
+    // A data map, read as linear values
+    pub fn linear(path: String) -> Self {
+        return Self::load(path, false);
+    }
+
+    fn load(path: String, is_srgb: bool) -> Self {
         let img = image::open(path).unwrap().to_rgb8();
         let width = img.width();
         let height = img.height();
@@ -24,13 +37,24 @@ impl ImageTexture {
             width,
             height,
             bytes_per_scanline: width * Self::BYTES_PER_PIXEL,
+            is_srgb,
         };
     }
+
+    // Linear value of texel (i, j), counting rows down from the top of the file
+    pub fn texel(&self, i: u32, j: u32) -> Color {
+        let pixel = *self.img_data.get_pixel(i, j);
+        if self.is_srgb {
+            return Color::from_srgb8(pixel);
+        }
+        return Color { r: pixel[0] as f32 / 255.0, g: pixel[1] as f32 / 255.0, b: pixel[2] as f32 / 255.0 };
+    }
 }
 
 impl Texture for ImageTexture {
     fn color(&self, u: f32, v: f32, _point: Point) -> Color {
         let _u = u.clamp(0.0, 1.0);
+        // Out-of-range coordinates stick to the edge, and v is flipped since image rows run top to bottom
         let _v = 1.0 - v.clamp(0.0, 1.0);
 
         let mut i = (_u * self.width as f32) as i32;
@@ -43,13 +67,73 @@ impl Texture for ImageTexture {
             j = self.height as i32 - 1;
         }
 
-        const COLOR_SCALE: f32 = 1.0 / 255.0;
-        let pixel = self.img_data.get_pixel(i as u32, j as u32).clone();
+        return self.texel(i as u32, j as u32);
+    }
+}
 
-        return Color {
-            r: pixel[0] as f32 * COLOR_SCALE,
-            g: pixel[1] as f32 * COLOR_SCALE,
-            b: pixel[2] as f32 * COLOR_SCALE,
-        };
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use image::Rgb;
+
+    use super::*;
+
+    // A 3x2 image with a different color in every corner, saved where only this test looks
+    fn corner_image(name: &str) -> String {
+        let mut img = RgbImage::from_pixel(3, 2, Rgb([128, 128, 128]));
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        img.put_pixel(2, 0, Rgb([0, 255, 0]));
+        img.put_pixel(0, 1, Rgb([0, 0, 255]));
+        img.put_pixel(2, 1, Rgb([255, 255, 255]));
+        let path = env::temp_dir().join(format!("raytracer_{}_{}.png", name, process::id()));
+        img.save(&path).unwrap();
+        return path.to_string_lossy().into_owned();
+    }
+
+    #[test]
+    fn corners_map_to_the_corner_texels_with_v_up() {
+        let path = corner_image("texture_corners");
+        let texture = ImageTexture::new(path.clone());
+        fs::remove_file(path).unwrap();
+        let at = |u: f32, v: f32| texture.color(u, v, Point::ORIGIN);
+
+        assert_eq!(at(0.0, 1.0), Color { r: 1.0, g: 0.0, b: 0.0 });
+        assert_eq!(at(1.0, 1.0), Color { r: 0.0, g: 1.0, b: 0.0 });
+        assert_eq!(at(0.0, 0.0), Color { r: 0.0, g: 0.0, b: 1.0 });
+        assert_eq!(at(1.0, 0.0), Color::WHITE);
+        // Coordinates past the edges stick to them
+        assert_eq!(at(-0.5, 2.0), at(0.0, 1.0));
+        assert_eq!(at(3.0, -1.0), at(1.0, 0.0));
+        // The middle column is mid grey in sRGB, about a fifth in linear light
+        let grey = at(0.5, 0.75);
+        assert!((grey.r - 0.2158).abs() < 1e-3 && grey.r == grey.g && grey.g == grey.b, "{:?}", grey);
+    }
+
+    #[test]
+    fn data_maps_are_read_without_decoding() {
+        let path = corner_image("texture_linear");
+        let texture = ImageTexture::linear(path.clone());
+        fs::remove_file(path).unwrap();
+        assert_eq!(texture.color(0.5, 0.75, Point::ORIGIN), Color { r: 128.0 / 255.0, g: 128.0 / 255.0, b: 128.0 / 255.0 });
+        assert_eq!(texture.color(0.0, 1.0, Point::ORIGIN), Color { r: 1.0, g: 0.0, b: 0.0 });
+    }
+
+    #[test]
+    fn the_srgb_flag_decides_how_texels_decode() {
+        let path = corner_image("texture_flag");
+        let (color_map, data_map) = (ImageTexture::new(path.clone()), ImageTexture::linear(path.clone()));
+        fs::remove_file(path).unwrap();
+        assert!(color_map.is_srgb && !data_map.is_srgb);
+
+        // Mid grey 128 is about 0.216 once decoded, and stays about a half as data
+        let (decoded, raw) = (color_map.texel(1, 0), data_map.texel(1, 0));
+        assert!((decoded.r - 0.2158).abs() < 1e-3, "{:?}", decoded);
+        assert!((raw.r - 0.502).abs() < 1e-3, "{:?}", raw);
+        // Black and full channels are the same either way
+        assert_eq!(color_map.texel(2, 1), data_map.texel(2, 1));
+        assert_eq!(color_map.texel(0, 0), data_map.texel(0, 0));
     }
 }
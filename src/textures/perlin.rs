@@ -1,7 +1,10 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
 use crate::geometry::color::Color;
 use crate::geometry::vector::{Point, Vector3};
 use crate::textures::texture::Texture;
-use crate::utils::random_int;
+use crate::utils::with_rng;
 
 pub struct Perlin {
     ran_vec: Vec<Vector3>,
@@ -14,14 +17,23 @@ impl Perlin {
     const POINT_COUNT: usize = 256;
 
     pub fn new() -> Self {
+        return with_rng(|rng| Self::with_rng(rng));
+    }
+
+    // Same seed, same noise
+    pub fn seeded(seed: u64) -> Self {
+        return Self::with_rng(&mut StdRng::seed_from_u64(seed));
+    }
+
+    fn with_rng(rng: &mut (impl Rng + ?Sized)) -> Self {
         let mut ran_vec: Vec<Vector3> = Vec::with_capacity(Self::POINT_COUNT);
         for _i in 0..Self::POINT_COUNT {
-            ran_vec.push(Vector3::random_unit_vector());
+            ran_vec.push(Vector3::random_unit_vector_with(rng));
         }
 
-        let perm_x = Self::perlin_generate_perm();
-        let perm_y = Self::perlin_generate_perm();
-        let perm_z = Self::perlin_generate_perm();
+        let perm_x = Self::perlin_generate_perm(rng);
+        let perm_y = Self::perlin_generate_perm(rng);
+        let perm_z = Self::perlin_generate_perm(rng);
 
         return Self {
             ran_vec,
@@ -40,10 +52,7 @@ impl Perlin {
         let j = point.y.floor() as i32;
         let k = point.z.floor() as i32;
 
-        let def = Vector3 { x: 0.0, y: 0.0, z: 0.0 };
-        let def_z = vec![def, def];
-        let def_y = vec![def_z.clone(), def_z];
-        let mut def_x = vec![def_y.clone(), def_y];
+        let mut def_x = [[[Vector3::ORIGIN; 2]; 2]; 2];
 
         for di in 0..2 {
             for dj in 0..2 {
@@ -60,7 +69,7 @@ impl Perlin {
         return Self::perlin_interp(def_x, u, v, w);
     }
 
-    fn perlin_interp(c: Vec<Vec<Vec<Vector3>>>, u: f32, v: f32, w: f32) -> f32 {
+    fn perlin_interp(c: [[[Vector3; 2]; 2]; 2], u: f32, v: f32, w: f32) -> f32 {
         let uu = u.powi(2) * (3.0 - 2.0 * u);
         let vv = v.powi(2) * (3.0 - 2.0 * v);
         let ww = w.powi(2) * (3.0 - 2.0 * w);
@@ -100,30 +109,144 @@ impl Perlin {
         return accum.abs();
     }
 
-    fn perlin_generate_perm() -> Vec<usize> {
+    fn perlin_generate_perm(rng: &mut (impl Rng + ?Sized)) -> Vec<usize> {
         let mut p: Vec<usize> = Vec::with_capacity(Self::POINT_COUNT);
         for i in 0..Self::POINT_COUNT {
             p.push(i);
         }
-        Self::permute(&mut p);
+        Self::permute(&mut p, rng);
         return p;
     }
 
-    fn permute(points: &mut Vec<usize>) {
+    fn permute(points: &mut Vec<usize>, rng: &mut (impl Rng + ?Sized)) {
         for i in (0..points.len()).rev() {
-            let target = random_int(0, i as u32);
-            points.swap(i, target as usize);
+            let target = rng.gen_range(0..=i);
+            points.swap(i, target);
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub enum NoiseMode {
+    // Plain noise remapped from [-1, 1] to [0, 1]
+    Smooth,
+    // Octaves summed with halving amplitude, folded to positive
+    Turbulence,
+    // Stripes along z distorted by turbulence
+    Marble,
+}
+
 pub struct NoiseTexture {
     pub noise: Perlin,
     pub scale: f32,
+    pub mode: NoiseMode,
+}
+
+impl NoiseTexture {
+    const TURBULENCE_OCTAVES: u32 = 7;
 }
 
 impl Texture for NoiseTexture {
     fn color(&self, _u: f32, _v: f32, point: Point) -> Color {
-        return 0.5 * (1.0 + (self.scale * point.z + 10.0 * self.noise.turb(point, 7)).sin()) * Color::WHITE;
+        let value = match self.mode {
+            NoiseMode::Smooth => 0.5 * (1.0 + self.noise.noise(self.scale * point)),
+            NoiseMode::Turbulence => self.noise.turb(self.scale * point, Self::TURBULENCE_OCTAVES),
+            NoiseMode::Marble => 0.5 * (1.0 + (self.scale * point.z + 10.0 * self.noise.turb(point, Self::TURBULENCE_OCTAVES)).sin()),
+        };
+        return value * Color::WHITE;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::tests::count_allocations;
+
+    // The corner gradients as they were gathered into nested Vecs before noise used an array
+    fn noise_from_vecs(perlin: &Perlin, point: Point) -> f32 {
+        let (i, j, k) = (point.x.floor() as i32, point.y.floor() as i32, point.z.floor() as i32);
+        let mut corners = vec![vec![vec![Vector3::ORIGIN; 2]; 2]; 2];
+        for (di, plane) in corners.iter_mut().enumerate() {
+            for (dj, row) in plane.iter_mut().enumerate() {
+                for (dk, corner) in row.iter_mut().enumerate() {
+                    *corner = perlin.ran_vec[perlin.perm_x[((i + di as i32) & 255) as usize] ^
+                        perlin.perm_y[((j + dj as i32) & 255) as usize] ^
+                        perlin.perm_z[((k + dk as i32) & 255) as usize]];
+                }
+            }
+        }
+        let mut c = [[[Vector3::ORIGIN; 2]; 2]; 2];
+        for di in 0..2 {
+            for dj in 0..2 {
+                c[di][dj].copy_from_slice(&corners[di][dj]);
+            }
+        }
+        return Perlin::perlin_interp(c, point.x - point.x.floor(), point.y - point.y.floor(), point.z - point.z.floor());
+    }
+
+    #[test]
+    fn noise_is_unchanged_and_allocation_free() {
+        let perlin = Perlin::seeded(5);
+        let mut rng = StdRng::seed_from_u64(6);
+        for _ in 0..200 {
+            let point = Point { x: rng.gen_range(-50.0..50.0), y: rng.gen_range(-50.0..50.0), z: rng.gen_range(-50.0..50.0) };
+            let (value, allocations) = count_allocations(|| perlin.noise(point));
+            assert_eq!(allocations, 0);
+            assert_eq!(value, noise_from_vecs(&perlin, point));
+        }
+        let (_, allocations) = count_allocations(|| perlin.turb(Point { x: 0.3, y: 1.7, z: -2.2 }, 7));
+        assert_eq!(allocations, 0);
+    }
+
+    fn random_points(seed: u64, count: usize) -> Vec<Point> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        return (0..count).map(|_| Point { x: rng.gen_range(-20.0..20.0), y: rng.gen_range(-20.0..20.0), z: rng.gen_range(-20.0..20.0) }).collect();
+    }
+
+    #[test]
+    fn noise_stays_within_minus_one_and_one_and_varies() {
+        let perlin = Perlin::seeded(1);
+        let values: Vec<f32> = random_points(2, 5000).into_iter().map(|p| perlin.noise(p)).collect();
+        assert!(values.iter().all(|v| (-1.0..=1.0).contains(v)));
+        let (low, high) = values.iter().fold((0.0f32, 0.0f32), |(low, high), &v| (low.min(v), high.max(v)));
+        assert!(low < -0.3 && high > 0.3, "noise only spans {} to {}", low, high);
+        // Lattice points sit where every corner's gradient is dotted with a zero offset
+        assert_eq!(perlin.noise(Point { x: 3.0, y: -2.0, z: 7.0 }), 0.0);
+    }
+
+    #[test]
+    fn small_steps_change_the_noise_a_little() {
+        let perlin = Perlin::seeded(3);
+        let step = Vector3 { x: 1e-3, y: -1e-3, z: 1e-3 };
+        for p in random_points(4, 2000) {
+            let change = (perlin.noise(p + step) - perlin.noise(p)).abs();
+            assert!(change < 1e-2, "noise jumps by {} near {:?}", change, p);
+        }
+        // Including across lattice cell boundaries
+        let boundary = Point { x: 2.0, y: 0.5, z: -1.0 };
+        assert!((perlin.noise(boundary - step) - perlin.noise(boundary + step)).abs() < 1e-2);
+    }
+
+    #[test]
+    fn the_same_seed_repeats_the_noise() {
+        let points = random_points(5, 200);
+        let (a, b, other) = (Perlin::seeded(7), Perlin::seeded(7), Perlin::seeded(8));
+        assert!(points.iter().all(|&p| a.noise(p) == b.noise(p) && a.turb(p, 7) == b.turb(p, 7)));
+        assert!(points.iter().any(|&p| a.noise(p) != other.noise(p)));
+    }
+
+    #[test]
+    fn every_mode_gives_a_grey_in_range() {
+        for mode in [NoiseMode::Smooth, NoiseMode::Turbulence, NoiseMode::Marble] {
+            let texture = NoiseTexture { noise: Perlin::seeded(9), scale: 4.0, mode };
+            for p in random_points(10, 500) {
+                let color = texture.color(0.0, 0.0, p);
+                assert!(color.r == color.g && color.g == color.b);
+                assert!(color.r >= 0.0 && color.r <= 2.0 && color.r.is_finite(), "{:?}", color);
+                if !matches!(mode, NoiseMode::Turbulence) {
+                    assert!(color.r <= 1.0, "{:?}", color);
+                }
+            }
+        }
     }
 }
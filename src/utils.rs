@@ -1,4 +1,7 @@
-use rand::{Rng, thread_rng};
+use std::cell::RefCell;
+
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng, thread_rng};
 
 use crate::geometry::vector::{Point, Vector3};
 
@@ -6,13 +9,31 @@ use crate::geometry::vector::{Point, Vector3};
 pub const INF_F32: f32 = f32::MAX;
 pub const PI: f32 = std::f32::consts::PI;
 
+thread_local! {
+    // Replaces thread_rng on this thread while set, see seed_thread_rng
+    static SEEDED_RNG: RefCell<Option<StdRng>> = RefCell::new(Option::None);
+}
+
+// Makes the random functions below on this thread replay the sequence for seed, or go back
+// to the unseeded thread_rng for None
+pub fn seed_thread_rng(seed: Option<u64>) {
+    SEEDED_RNG.with(|cell| *cell.borrow_mut() = seed.map(StdRng::seed_from_u64));
+}
+
+pub fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    SEEDED_RNG.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(rng) => f(rng),
+        None => f(&mut thread_rng()),
+    })
+}
+
 // Functions
 pub fn degrees_to_radians(degrees: f32) -> f32 {
     degrees * PI / 180.0
 }
 
 pub fn random_f32() -> f32 {
-    thread_rng().gen()
+    with_rng(|rng| rng.gen())
 }
 
 pub fn random_f32_range(min: f32, max: f32) -> f32 {
@@ -35,13 +56,38 @@ pub fn random_in_unit_disk() -> Point {
     }
 }
 
+// Concentric mapping of the unit square onto the unit disk, keeps stratified samples stratified
+pub fn square_to_unit_disk(u: f32, v: f32) -> Point {
+    let a = 2.0 * u - 1.0;
+    let b = 2.0 * v - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Point::ORIGIN;
+    }
+
+    let (radius, theta) = if a.abs() > b.abs() {
+        (a, (PI / 4.0) * (b / a))
+    } else {
+        (b, PI / 2.0 - (PI / 4.0) * (a / b))
+    };
+    Point {
+        x: radius * theta.cos(),
+        y: radius * theta.sin(),
+        z: 0.0,
+    }
+}
+
 pub fn random_int(min: u32, max: u32) -> u32 {
-    thread_rng().gen_range(min..(max + 1))
+    with_rng(|rng| rng.gen_range(min..(max + 1)))
 }
 
 pub fn random_to_sphere(radius: f32, dist_sq: f32) -> Vector3 {
-    let r1 = random_f32();
-    let r2 = random_f32();
+    return random_to_sphere_from(radius, dist_sq, (random_f32(), random_f32()));
+}
+
+// Direction towards a sphere of radius at squared distance dist_sq, about +z, from a point
+// in [0, 1)^2
+pub fn random_to_sphere_from(radius: f32, dist_sq: f32, sample: (f32, f32)) -> Vector3 {
+    let (r1, r2) = sample;
     let z = 1.0 + r2 * ((1.0 - radius.powi(2) / dist_sq).sqrt() - 1.0);
 
     let phi = 2.0 * PI * r1;
@@ -0,0 +1,122 @@
+use std::mem::size_of;
+use std::sync::Arc;
+
+use crate::objects::hittable::Hittable;
+use crate::world::bvh_node::BVHNode;
+use crate::world::hittable_list::HittableList;
+use crate::world::uniform_grid::UniformGrid;
+
+// Acceleration structure that can be built over the objects of a scene
+pub trait Aggregate: Hittable {
+    fn build(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Arc<dyn Hittable> where Self: Sized;
+
+    // Heap bytes the structure is expected to allocate for `count` objects
+    fn estimated_bytes(count: usize) -> usize where Self: Sized;
+
+    // Builds the structure unless its estimated size is over max_bytes
+    fn build_with_budget(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> where Self: Sized {
+        let estimate = Self::estimated_bytes(objects.len());
+        if estimate > max_bytes {
+            return Err(format!("building over {} objects needs ~{} bytes, over the {} byte budget", objects.len(), estimate, max_bytes));
+        }
+        return Ok(Self::build(objects, time0, time1));
+    }
+}
+
+impl Aggregate for BVHNode {
+    fn build(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Arc<dyn Hittable> {
+        return Self::create_tree(objects, time0, time1);
+    }
+
+    // One reference-counted node per split, leaves are the objects themselves
+    fn estimated_bytes(count: usize) -> usize {
+        return count.saturating_sub(1) * (size_of::<Self>() + 2 * size_of::<usize>());
+    }
+}
+
+impl Aggregate for UniformGrid {
+    fn build(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Arc<dyn Hittable> {
+        return Arc::new(Self::new(objects, time0, time1));
+    }
+
+    // The cells, about CELLS_PER_OBJECT per object, and an index per object in each cell it
+    // overlaps, taken as one for typical small objects
+    fn estimated_bytes(count: usize) -> usize {
+        let cells = (Self::CELLS_PER_OBJECT * count as f32) as usize;
+        return cells * size_of::<Vec<usize>>() + count * (size_of::<Arc<dyn Hittable>>() + size_of::<usize>());
+    }
+}
+
+impl Aggregate for HittableList {
+    fn build(objects: &mut [Arc<dyn Hittable>], _time0: f32, _time1: f32) -> Arc<dyn Hittable> {
+        return Arc::new(Self { objects: objects.to_vec() });
+    }
+
+    fn estimated_bytes(count: usize) -> usize {
+        return count * size_of::<Arc<dyn Hittable>>();
+    }
+}
+
+// The aggregates a scene can be built into, so the renderer can be pointed at any of them
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AggregateKind {
+    Bvh,
+    Grid,
+    List,
+}
+
+impl AggregateKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "bvh" => Option::from(AggregateKind::Bvh),
+            "grid" => Option::from(AggregateKind::Grid),
+            "list" => Option::from(AggregateKind::List),
+            _ => Option::None,
+        };
+    }
+
+    pub fn build_with_budget(self, objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32, max_bytes: usize) -> Result<Arc<dyn Hittable>, String> {
+        return match self {
+            AggregateKind::Bvh => BVHNode::build_with_budget(objects, time0, time1, max_bytes),
+            AggregateKind::Grid => UniformGrid::build_with_budget(objects, time0, time1, max_bytes),
+            AggregateKind::List => HittableList::build_with_budget(objects, time0, time1, max_bytes),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+    use crate::output::diff::assert_images_close;
+    use crate::render::tests::{render_world, test_light, test_settings};
+    use crate::scenes::final_scene_rtweekend_seeded;
+    use crate::utils::seed_thread_rng;
+
+    fn render_with<A: Aggregate>() -> RgbImage {
+        seed_thread_rng(Option::from(1));
+        let mut objects = final_scene_rtweekend_seeded(7);
+        objects.push(test_light());
+        let world = A::build(&mut objects, 0.0, 1.0);
+        return render_world(&test_settings(16, 12, 2), world).to_image(false);
+    }
+
+    #[test]
+    fn every_aggregate_renders_the_same_image() {
+        let bvh = render_with::<BVHNode>();
+        assert_images_close(&bvh, &render_with::<UniformGrid>(), 0);
+        assert_images_close(&bvh, &render_with::<HittableList>(), 0);
+    }
+
+    #[test]
+    fn kinds_parse_and_build_within_budget() {
+        assert_eq!(AggregateKind::parse("grid"), Option::from(AggregateKind::Grid));
+        assert_eq!(AggregateKind::parse("octree"), Option::None);
+        for kind in [AggregateKind::Bvh, AggregateKind::Grid, AggregateKind::List] {
+            let mut objects = final_scene_rtweekend_seeded(7);
+            assert!(kind.build_with_budget(&mut objects, 0.0, 1.0, 1 << 30).is_ok());
+            assert!(kind.build_with_budget(&mut objects, 0.0, 1.0, 16).is_err());
+        }
+    }
+}
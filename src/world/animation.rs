@@ -0,0 +1,116 @@
+use std::ops::{Add, Mul};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Interp {
+    Linear,
+    // Catmull-Rom through the keys, the end keys are repeated for the outer tangents
+    Cubic,
+}
+
+// A value keyed over time. Anything that can be weighted and summed can be animated,
+// which covers f32, Vector3/Point and Color.
+#[derive(Clone)]
+pub struct Track<T> {
+    keys: Vec<(f32, T)>,
+    interp: Interp,
+}
+
+impl<T> Track<T> where T: Copy + Add<Output = T>, f32: Mul<T, Output = T> {
+    // None without keys or with a key at a NaN time, which has no place in the order
+    pub fn new(mut keys: Vec<(f32, T)>, interp: Interp) -> Option<Self> {
+        if keys.is_empty() || keys.iter().any(|key| key.0.is_nan()) {
+            return Option::None;
+        }
+        keys.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        return Option::from(Track { keys, interp });
+    }
+
+    pub fn constant(value: T) -> Self {
+        return Track { keys: vec![(0.0, value)], interp: Interp::Linear };
+    }
+
+    // Value at time t, held at the first/last key outside the keyed range
+    pub fn sample(&self, t: f32) -> T {
+        let last = self.keys.len() - 1;
+        if t <= self.keys[0].0 {
+            return self.keys[0].1;
+        }
+        if t >= self.keys[last].0 {
+            return self.keys[last].1;
+        }
+
+        // First key strictly after t, so the segment is [i - 1, i]
+        let i = self.keys.partition_point(|key| key.0 <= t);
+        let (t0, p1) = self.keys[i - 1];
+        let (t1, p2) = self.keys[i];
+        let s = segment_fraction(t, t0, t1);
+
+        return match self.interp {
+            Interp::Linear => {
+                let [w1, w2] = linear_weights(s);
+                w1 * p1 + w2 * p2
+            }
+            Interp::Cubic => {
+                let p0 = self.keys[i.saturating_sub(2)].1;
+                let p3 = self.keys[(i + 1).min(last)].1;
+                let [w0, w1, w2, w3] = catmull_rom_weights(s);
+                w0 * p0 + w1 * p1 + w2 * p2 + w3 * p3
+            }
+        };
+    }
+}
+
+// Scalar math is kept out of the generic impl, where the `f32: Mul<T>` bound
+// would otherwise shadow plain f32 arithmetic during inference
+fn segment_fraction(t: f32, t0: f32, t1: f32) -> f32 {
+    return (t - t0) / (t1 - t0);
+}
+
+fn linear_weights(s: f32) -> [f32; 2] {
+    return [1.0 - s, s];
+}
+
+fn catmull_rom_weights(s: f32) -> [f32; 4] {
+    let s2 = s * s;
+    let s3 = s2 * s;
+    return [
+        0.5 * (-s3 + 2.0 * s2 - s),
+        0.5 * (3.0 * s3 - 5.0 * s2 + 2.0),
+        0.5 * (-3.0 * s3 + 4.0 * s2 + s),
+        0.5 * (s3 - s2),
+    ];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::vector::Point;
+
+    #[test]
+    fn linear_track_interpolates_between_keys() {
+        let track = Track::new(vec![(1.0, 10.0), (0.0, 2.0)], Interp::Linear).unwrap();
+        assert_eq!(track.sample(0.25), 4.0);
+        assert_eq!(track.sample(-1.0), 2.0);
+        assert_eq!(track.sample(3.0), 10.0);
+
+        let path = Track::new(vec![(0.0, Point::ORIGIN), (1.0, Point { x: 4.0, y: -8.0, z: 2.0 })], Interp::Linear).unwrap();
+        assert_eq!(path.sample(0.25), Point { x: 1.0, y: -2.0, z: 0.5 });
+    }
+
+    #[test]
+    fn cubic_track_passes_through_its_keys() {
+        let track = Track::new(vec![(0.0, 0.0), (1.0, 1.0), (2.0, 4.0), (3.0, 9.0)], Interp::Cubic).unwrap();
+        for (t, value) in [(0.0, 0.0), (1.0, 1.0), (2.0, 4.0), (3.0, 9.0)] {
+            assert!((track.sample(t) - value).abs() < 1e-5);
+        }
+        // Catmull-Rom reproduces the parabola between the inner keys
+        assert!((track.sample(1.5) - 2.25).abs() < 1e-5);
+        assert_eq!(Track::constant(7.0).sample(0.6), 7.0);
+    }
+
+    #[test]
+    fn tracks_without_usable_keys_are_refused() {
+        assert!(Track::<f32>::new(Vec::new(), Interp::Linear).is_none());
+        assert!(Track::new(vec![(0.0, 1.0), (f32::NAN, 2.0)], Interp::Cubic).is_none());
+    }
+}
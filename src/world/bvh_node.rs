@@ -1,33 +1,57 @@
+use std::fmt::Write;
 use std::sync::Arc;
 
+use rand::RngCore;
+
 use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::ray::Ray;
-use crate::objects::hittable::{box_cmp_x, box_cmp_y, box_cmp_z, HitRecord, Hittable};
-use crate::utils::random_int;
+use crate::geometry::vector::{Point, Vector3};
+use crate::objects::hittable::{box_cmp_x, box_cmp_y, box_cmp_z, HitRecord, Hittable, sample_by_area};
 
 pub struct BVHNode {
     pub bound_box: AxisAlignedBoundingBox,
     pub left_node: Arc<dyn Hittable>,
     pub right_node: Arc<dyn Hittable>,
+    // Axis the children were split along, the left one holding the lower boxes
+    pub split_axis: usize,
+    // Nodes deeper than this below where a traversal starts are scanned linearly instead,
+    // at most MAX_TRAVERSAL_DEPTH
+    pub max_traversal_depth: usize,
 }
 
 impl BVHNode {
+    // Also the capacity of the traversal stack, a depth-first walk of a binary tree never
+    // holds more nodes than the tree is deep. Trees are built with this limit unless given a
+    // lower one.
+    pub const MAX_TRAVERSAL_DEPTH: usize = 64;
+    // Below this many primitives create_tree splits at the median instead of by surface area
+    const SAH_MIN_OBJECTS: usize = 8;
+    // Buckets sah_split sorts centres into, more finds slightly better splits but builds slower
+    const SAH_BINS: usize = 16;
+
     pub fn create_tree(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32) -> Arc<dyn Hittable> {
-        let axis = random_int(0, 2);
+        return Self::create_tree_with_depth(objects, time0, time1, Self::MAX_TRAVERSAL_DEPTH);
+    }
+
+    // Like create_tree, with traversals falling back to a linear scan below max_traversal_depth
+    // levels, clamped to 1..=MAX_TRAVERSAL_DEPTH
+    pub fn create_tree_with_depth(objects: &mut [Arc<dyn Hittable>], time0: f32, time1: f32, max_traversal_depth: usize) -> Arc<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects[0].clone();
+        }
+
+        let axis = Self::longest_axis(objects, time0, time1);
         let comparator = match axis {
             0 => box_cmp_x,
             1 => box_cmp_y,
             2 | _ => box_cmp_z,
         };
 
-        if objects.len() == 1 {
-            return objects[0].clone();
-        }
-
-        objects.sort_unstable_by(|a, b| comparator(a, b));
-        let mid_idx = objects.len() / 2;
-        let left_node = Self::create_tree(&mut objects[..mid_idx], time0, time1);
-        let right_node = Self::create_tree(&mut objects[mid_idx..], time0, time1);
+        // Stable so primitives with equal keys keep their order and the tree is the same every run
+        objects.sort_by(|a, b| comparator(a, b));
+        let mid_idx = Self::sah_split(objects, axis, time0, time1).unwrap_or(objects.len() / 2);
+        let left_node = Self::create_tree_with_depth(&mut objects[..mid_idx], time0, time1, max_traversal_depth);
+        let right_node = Self::create_tree_with_depth(&mut objects[mid_idx..], time0, time1, max_traversal_depth);
 
         let box_left = left_node.bounding_box(time0, time1).unwrap();
         let box_right = right_node.bounding_box(time0, time1).unwrap();
@@ -36,30 +60,584 @@ impl BVHNode {
             bound_box: AxisAlignedBoundingBox::surrounding_box(box_left, box_right),
             left_node,
             right_node,
+            split_axis: axis,
+            max_traversal_depth: max_traversal_depth.clamp(1, Self::MAX_TRAVERSAL_DEPTH),
         })
     }
+
+    // Surface area heuristic: buckets the objects by where their box centres fall along axis and
+    // picks the bucket boundary minimizing area(left) * count(left) + area(right) * count(right),
+    // roughly the cost of testing a ray against both halves. Reorders objects by bucket, keeping
+    // their order within one, and returns how many go left. None for small slices, where the
+    // median split does as well, and when no boundary separates the centres or a box is unbounded.
+    fn sah_split(objects: &mut [Arc<dyn Hittable>], axis: usize, time0: f32, time1: f32) -> Option<usize> {
+        if objects.len() < Self::SAH_MIN_OBJECTS {
+            return Option::None;
+        }
+
+        let centre = |obj: &Arc<dyn Hittable>| {
+            let b = obj.bounding_box(time0, time1).unwrap();
+            0.5 * (b.minimum[axis] + b.maximum[axis])
+        };
+        let (low, high) = objects.iter().fold((f32::INFINITY, f32::NEG_INFINITY), |(low, high), obj| {
+            (low.min(centre(obj)), high.max(centre(obj)))
+        });
+        if !(high - low > 0.0) || !(high - low).is_finite() {
+            return Option::None;
+        }
+        let bin_of = |obj: &Arc<dyn Hittable>| {
+            (((centre(obj) - low) / (high - low) * Self::SAH_BINS as f32) as usize).min(Self::SAH_BINS - 1)
+        };
+
+        let mut bins: Vec<(Option<AxisAlignedBoundingBox>, usize)> = vec![(Option::None, 0); Self::SAH_BINS];
+        for obj in objects.iter() {
+            let bin = &mut bins[bin_of(obj)];
+            let obj_box = obj.bounding_box(time0, time1).unwrap();
+            bin.0 = Option::from(match bin.0.take() {
+                Some(bin_box) => AxisAlignedBoundingBox::surrounding_box(bin_box, obj_box),
+                None => obj_box,
+            });
+            bin.1 += 1;
+        }
+
+        // Area and count of everything from bin k up, so each boundary is scored in one sweep
+        let mut right: Vec<(f32, usize)> = vec![(0.0, 0); Self::SAH_BINS + 1];
+        let mut right_box: Option<AxisAlignedBoundingBox> = Option::None;
+        for k in (0..Self::SAH_BINS).rev() {
+            right_box = Self::merge(right_box, bins[k].0.clone());
+            right[k] = (right_box.as_ref().map_or(0.0, |b| b.surface_area()), right[k + 1].1 + bins[k].1);
+        }
+
+        let mut best: Option<(usize, f32)> = Option::None;
+        let mut left_box: Option<AxisAlignedBoundingBox> = Option::None;
+        let mut left_count = 0;
+        for k in 1..Self::SAH_BINS {
+            left_box = Self::merge(left_box, bins[k - 1].0.clone());
+            left_count += bins[k - 1].1;
+            let (right_area, right_count) = right[k];
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+            let cost = left_box.as_ref().unwrap().surface_area() * left_count as f32 + right_area * right_count as f32;
+            if !cost.is_finite() {
+                return Option::None;
+            }
+            if best.map_or(true, |(_, best_cost)| cost < best_cost) {
+                best = Option::from((left_count, cost));
+            }
+        }
+
+        let (split, _) = best?;
+        // Stable, so the tree is still the same every run
+        objects.sort_by_cached_key(|obj| bin_of(obj));
+        return Option::from(split);
+    }
+
+    fn merge(a: Option<AxisAlignedBoundingBox>, b: Option<AxisAlignedBoundingBox>) -> Option<AxisAlignedBoundingBox> {
+        return match (a, b) {
+            (Some(a), Some(b)) => Option::from(AxisAlignedBoundingBox::surrounding_box(a, b)),
+            (a, None) => a,
+            (None, b) => b,
+        };
+    }
+
+    fn longest_axis(objects: &[Arc<dyn Hittable>], time0: f32, time1: f32) -> usize {
+        let mut bound_box = objects[0].bounding_box(time0, time1).unwrap();
+        for obj in objects[1..].iter() {
+            bound_box = AxisAlignedBoundingBox::surrounding_box(bound_box, obj.bounding_box(time0, time1).unwrap());
+        }
+
+        let extent = bound_box.maximum - bound_box.minimum;
+        let mut axis = 0;
+        for a in 1..3 {
+            if extent[a] > extent[axis] {
+                axis = a;
+            }
+        }
+        return axis;
+    }
+
+    // Recomputes every box bottom-up from the primitives' current boxes, keeping the splits.
+    // Much cheaper than create_tree when primitives have only moved, e.g. moving objects
+    // rendered over the next frame's shutter interval, though the tree gets looser the further
+    // they drift from where it was built. Nested nodes are updated in place, so the tree must
+    // be the only owner of them. A node shared with another tree can't be refitted without
+    // changing that tree too, and skipping it would leave boxes missing the primitives, so
+    // that is an error and the tree is left as it was.
+    pub fn refit(&mut self, time0: f32, time1: f32) -> Result<(), String> {
+        if !self.owns_nested_nodes() {
+            return Err("refit needs sole ownership of nested BVH nodes".to_string());
+        }
+        self.refit_owned(time0, time1);
+        return Ok(());
+    }
+
+    // Whether every BVH node below this one can be borrowed mutably, as Arc::get_mut would
+    fn owns_nested_nodes(&self) -> bool {
+        return [&self.left_node, &self.right_node].iter().all(|child| match child.as_bvh_node() {
+            Some(node) => Arc::strong_count(child) == 1 && Arc::weak_count(child) == 0 && node.owns_nested_nodes(),
+            None => true,
+        });
+    }
+
+    fn refit_owned(&mut self, time0: f32, time1: f32) {
+        for child in [&mut self.left_node, &mut self.right_node] {
+            if let Some(node) = Arc::get_mut(child).and_then(|obj| obj.as_bvh_node_mut()) {
+                node.refit_owned(time0, time1);
+            }
+        }
+
+        let box_left = self.left_node.bounding_box(time0, time1).unwrap();
+        let box_right = self.right_node.bounding_box(time0, time1).unwrap();
+        self.bound_box = AxisAlignedBoundingBox::surrounding_box(box_left, box_right);
+    }
+
+    // Fallback for subtrees past max_traversal_depth: tests every primitive under the node
+    // without box tests, keeping the pending nodes on the heap
+    fn hit_linear(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut pending: Vec<&Arc<dyn Hittable>> = vec![&self.left_node, &self.right_node];
+        let mut closest: Option<HitRecord> = Option::None;
+        while let Some(obj) = pending.pop() {
+            if let Some(inner) = obj.as_bvh_node() {
+                pending.push(&inner.right_node);
+                pending.push(&inner.left_node);
+                continue;
+            }
+            let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+            let opt_hit_rec = obj.hit(ray, t_min, t_limit);
+            if opt_hit_rec.is_some() {
+                closest = opt_hit_rec;
+            }
+        }
+        return closest;
+    }
+
+    // GraphViz description of a tree from create_tree: one node per split labelled with the
+    // primitives under it and its box volume, and one node per primitive. Takes the tree as a
+    // Hittable since that is what create_tree hands back. Render with `dot -Tsvg`.
+    pub fn to_dot(tree: &dyn Hittable) -> String {
+        let mut out = String::from("digraph bvh {\n    node [shape=box];\n");
+        let mut next_id = 0;
+        Self::write_dot(tree, &mut out, &mut next_id);
+        out.push_str("}\n");
+        return out;
+    }
+
+    // Writes the subtree under obj, returning its node id and primitive count
+    fn write_dot(obj: &dyn Hittable, out: &mut String, next_id: &mut usize) -> (usize, usize) {
+        let id = *next_id;
+        *next_id += 1;
+        let children = obj.children();
+        if children.is_empty() {
+            writeln!(out, "    n{} [label=\"{}\", shape=ellipse];", id, obj.type_name()).unwrap();
+            return (id, 1);
+        }
+
+        let mut count = 0;
+        let mut child_ids = Vec::new();
+        for child in children {
+            let (child_id, child_count) = Self::write_dot(child.as_ref(), out, next_id);
+            child_ids.push(child_id);
+            count += child_count;
+        }
+        let volume = obj.bounding_box(0.0, 0.0).map_or(0.0, |b| {
+            let extent = b.maximum - b.minimum;
+            extent.x * extent.y * extent.z
+        });
+        writeln!(out, "    n{} [label=\"{} prims\\nvolume {:.3}\"];", id, count, volume).unwrap();
+        for child_id in child_ids {
+            writeln!(out, "    n{} -> n{};", id, child_id).unwrap();
+        }
+        return (id, count);
+    }
 }
 
 impl Hittable for BVHNode {
+    // Walks the tree with a fixed-size stack instead of recursing, so a degenerate tree cannot
+    // overflow the call stack. Subtrees below max_traversal_depth are scanned linearly.
     fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if !self.bound_box.hit(ray, t_min, t_max) {
+        let max_depth = self.max_traversal_depth.min(Self::MAX_TRAVERSAL_DEPTH);
+        let mut stack: [(&BVHNode, usize); Self::MAX_TRAVERSAL_DEPTH] = [(self, 0); Self::MAX_TRAVERSAL_DEPTH];
+        let mut stack_len = 1;
+        let mut closest: Option<HitRecord> = Option::None;
+
+        while stack_len > 0 {
+            stack_len -= 1;
+            let (node, depth) = stack[stack_len];
+            // Narrow the search to the closest hit so far by its ray parameter, the distance
+            // to it only matches t for unit-length directions
+            let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+            // A ray starting inside the box always crosses it, so the slab test can be skipped
+            if !node.bound_box.contains(ray.origin) && !node.bound_box.hit(ray, t_min, t_limit) {
+                continue;
+            }
+
+            // Near child first, so its hit narrows t_limit and the far side's tests are more
+            // likely to cull. Leaves are tested in that order straight away, inner nodes are
+            // pushed far one first so the near one is popped first.
+            let children = if ray.direction[node.split_axis] < 0.0 {
+                [&node.right_node, &node.left_node]
+            } else {
+                [&node.left_node, &node.right_node]
+            };
+            let mut inner_nodes: [Option<&BVHNode>; 2] = [Option::None, Option::None];
+            for (k, child) in children.iter().enumerate() {
+                let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+                let opt_hit_rec = match child.as_bvh_node() {
+                    Some(inner) if depth + 1 < max_depth => {
+                        inner_nodes[k] = Option::from(inner);
+                        continue;
+                    }
+                    Some(inner) => inner.hit_linear(ray, t_min, t_limit),
+                    None => child.hit(ray, t_min, t_limit),
+                };
+                if opt_hit_rec.is_some() {
+                    closest = opt_hit_rec;
+                }
+            }
+            for inner in inner_nodes.iter().rev().flatten() {
+                stack[stack_len] = (inner, depth + 1);
+                stack_len += 1;
+            }
+        }
+        return closest;
+    }
+
+    fn as_bvh_node(&self) -> Option<&BVHNode> {
+        return Option::from(self);
+    }
+
+    fn as_bvh_node_mut(&mut self) -> Option<&mut BVHNode> {
+        return Option::from(self);
+    }
+
+    fn area(&self) -> f32 {
+        return self.left_node.area() + self.right_node.area();
+    }
+
+    // Walks down picking a side by area, which covers meshes. Areas are summed afresh on every
+    // call, so this is linear in the primitives under the node.
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return sample_by_area(&self.children(), rng);
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return Option::from(self.bound_box.clone());
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn Hittable>> {
+        return vec![&self.left_node, &self.right_node];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::RayKind;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::sphere::{MovingSphere, Sphere};
+    use crate::output::diff::assert_images_close;
+    use crate::render::tests::{render_world, test_light, test_settings};
+    use crate::utils::INF_F32;
+    use crate::world::hittable_list::HittableList;
+
+    // Spheres of different sizes and colors in a few stacks sharing the same centre, built
+    // fresh each time so nothing is shared between two calls
+    fn coincident_spheres() -> Vec<Arc<dyn Hittable>> {
+        let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+        for k in 0..24 {
+            let center = Point { x: (k % 3) as f32 - 1.0, y: 0.0, z: 0.0 };
+            let shade = k as f32 / 24.0;
+            objects.push(Arc::new(Sphere {
+                center,
+                radius: 0.2 + 0.02 * k as f32,
+                material: Arc::new(Lambertian::new(Color { r: shade, g: 0.5, b: 1.0 - shade })),
+            }));
+        }
+        return objects;
+    }
+
+    // Leaf boxes left to right, which tells the spheres apart by size
+    fn leaf_boxes(tree: &dyn Hittable, out: &mut Vec<(Point, Point)>) {
+        let children = tree.children();
+        if children.is_empty() {
+            let b = tree.bounding_box(0.0, 1.0).unwrap();
+            out.push((b.minimum, b.maximum));
+        }
+        for child in children {
+            leaf_boxes(child.as_ref(), out);
+        }
+    }
+
+    #[test]
+    fn ten_thousand_coincident_spheres_find_the_closest_hit_at_any_depth_limit() {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let objects: Vec<Arc<dyn Hittable>> = (0..10_000).map(|k| {
+            Arc::new(Sphere { center: Point::ORIGIN, radius: 0.5 + 1e-4 * k as f32, material: material.clone() }) as Arc<dyn Hittable>
+        }).collect();
+        let list = HittableList { objects: objects.clone() };
+        let rays = [
+            Ray { origin: Point { x: 0.0, y: 0.0, z: 5.0 }, direction: Vector3 { x: 0.0, y: 0.0, z: -1.0 }, time: 0.0, kind: RayKind::Camera },
+            Ray { origin: Point { x: 0.3, y: -0.2, z: 0.1 }, direction: Vector3 { x: 1.0, y: 1.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera },
+        ];
+        for max_depth in [BVHNode::MAX_TRAVERSAL_DEPTH, 4, 1] {
+            let tree = BVHNode::create_tree_with_depth(&mut objects.clone(), 0.0, 1.0, max_depth);
+            assert_eq!(tree.as_bvh_node().unwrap().max_traversal_depth, max_depth);
+            for ray in rays {
+                let expected = list.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t);
+                assert!(expected.is_some());
+                assert_eq!(tree.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t), expected, "depth limit {}", max_depth);
+            }
+        }
+        // Limits past the stack capacity are held to it
+        let tree = BVHNode::create_tree_with_depth(&mut objects.clone(), 0.0, 1.0, 1000);
+        assert_eq!(tree.as_bvh_node().unwrap().max_traversal_depth, BVHNode::MAX_TRAVERSAL_DEPTH);
+    }
+
+    #[test]
+    fn dot_output_declares_every_node_and_edge() {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = (0..5).map(|k| {
+            Arc::new(Sphere { center: Point { x: 3.0 * k as f32, y: 0.0, z: 0.0 }, radius: 1.0, material: material.clone() }) as Arc<dyn Hittable>
+        }).collect();
+        let dot = BVHNode::to_dot(BVHNode::create_tree(&mut objects, 0.0, 1.0).as_ref());
+        assert!(dot.starts_with("digraph bvh {") && dot.ends_with("}\n"));
+
+        let nodes: Vec<&str> = dot.lines().filter(|line| line.contains("[label=")).collect();
+        let edges = dot.lines().filter(|line| line.contains(" -> ")).count();
+        let leaves = nodes.iter().filter(|line| line.contains("shape=ellipse")).count();
+        assert_eq!(leaves, 5);
+        assert_eq!(nodes.len(), 9);
+        // A tree has one edge into every node but the root
+        assert_eq!(edges, nodes.len() - 1);
+        // The root box spans x from -1 to 13 and is 2 deep and high
+        assert!(nodes.contains(&"    n0 [label=\"5 prims\\nvolume 56.000\"];"), "{}", dot);
+    }
+
+    #[test]
+    fn equal_centroids_build_the_same_tree() {
+        let first = BVHNode::create_tree(&mut coincident_spheres(), 0.0, 1.0);
+        let second = BVHNode::create_tree(&mut coincident_spheres(), 0.0, 1.0);
+        assert_eq!(BVHNode::to_dot(first.as_ref()), BVHNode::to_dot(second.as_ref()));
+        let (mut first_leaves, mut second_leaves) = (Vec::new(), Vec::new());
+        leaf_boxes(first.as_ref(), &mut first_leaves);
+        leaf_boxes(second.as_ref(), &mut second_leaves);
+        assert_eq!(first_leaves.len(), 24);
+        assert_eq!(first_leaves, second_leaves);
+
+        let render = |tree: Arc<dyn Hittable>| {
+            let mut objects = vec![tree, test_light()];
+            return render_world(&test_settings(12, 12, 2), BVHNode::create_tree(&mut objects, 0.0, 1.0)).to_image(false);
+        };
+        assert_images_close(&render(first), &render(second), 0);
+    }
+
+    #[test]
+    fn rays_from_inside_the_boxes_still_find_interior_objects() {
+        // A camera inside a large enclosing sphere, with a few spheres around it, so the ray
+        // starts inside the root box and several node boxes below it
+        let mut rng = StdRng::seed_from_u64(5);
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = vec![Arc::new(Sphere { center: Point::ORIGIN, radius: 50.0, material: material.clone() })];
+        for _ in 0..40 {
+            let center = Point { x: rng.gen_range(-10.0..10.0), y: rng.gen_range(-10.0..10.0), z: rng.gen_range(-10.0..10.0) };
+            objects.push(Arc::new(Sphere { center, radius: rng.gen_range(0.5..2.0), material: material.clone() }));
+        }
+        let list = HittableList { objects: objects.clone() };
+        let tree = BVHNode::create_tree(&mut objects, 0.0, 1.0);
+        let camera = Point { x: 0.5, y: -0.25, z: 0.1 };
+        assert!(tree.bounding_box(0.0, 1.0).unwrap().contains(camera));
+
+        let mut interior_hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray { origin: camera, direction: Vector3::random_unit_vector_with(&mut rng), time: 0.0, kind: RayKind::Camera };
+            let expected = list.hit(ray, 0.001, INF_F32).unwrap().t;
+            assert_eq!(tree.hit(ray, 0.001, INF_F32).unwrap().t, expected);
+            interior_hits += (expected < 49.0) as usize;
+        }
+        assert!(interior_hits > 100, "only {} rays reached an interior sphere", interior_hits);
+    }
+
+    // 200 spheres all sliding by the same offset over the unit shutter interval
+    fn sliding_spheres(rng: &mut StdRng, offset: Point) -> Vec<Arc<dyn Hittable>> {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        return (0..200).map(|_| {
+            let centre0 = Point { x: rng.gen_range(-10.0..10.0), y: rng.gen_range(-10.0..10.0), z: rng.gen_range(-10.0..10.0) };
+            Arc::new(MovingSphere { centre0, center1: centre0 + offset, time0: 0.0, time1: 1.0, radius: rng.gen_range(0.2..1.0), material: material.clone() }) as Arc<dyn Hittable>
+        }).collect();
+    }
+
+    #[test]
+    fn refitted_trees_find_the_same_hits_as_fresh_ones() {
+        let mut rng = StdRng::seed_from_u64(269);
+        let offset = Point { x: 6.0, y: -3.0, z: 2.0 };
+        let mut objects = sliding_spheres(&mut rng, offset);
+        // Built around where the spheres start, then refitted to where they end up
+        let mut stale = BVHNode::create_tree(&mut objects.clone(), 0.0, 0.0);
+        let mut refitted = BVHNode::create_tree(&mut objects.clone(), 0.0, 0.0);
+        Arc::get_mut(&mut refitted).unwrap().as_bvh_node_mut().unwrap().refit(1.0, 1.0).unwrap();
+        let fresh = BVHNode::create_tree(&mut objects, 1.0, 1.0);
+
+        let (fresh_box, refitted_box) = (fresh.bounding_box(1.0, 1.0).unwrap(), refitted.as_bvh_node().unwrap().bound_box.clone());
+        assert!((fresh_box.minimum - refitted_box.minimum).length() < 1e-5 && (fresh_box.maximum - refitted_box.maximum).length() < 1e-5);
+        let mut misses_by_stale = 0;
+        for _ in 0..5000 {
+            let origin = offset + Point { x: rng.gen_range(-15.0..15.0), y: rng.gen_range(-15.0..15.0), z: rng.gen_range(-15.0..15.0) };
+            let ray = Ray { origin, direction: Vector3::random_unit_vector_with(&mut rng), time: 1.0, kind: RayKind::Camera };
+            let expected = fresh.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t);
+            assert_eq!(refitted.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t), expected);
+            misses_by_stale += (stale.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t) != expected) as usize;
+        }
+        // Left alone, the tree still bounds where the spheres were
+        assert!(misses_by_stale > 100, "the stale tree got only {} rays wrong", misses_by_stale);
+
+        // A subtree shared with another tree is refused, leaving every box as it was
+        let stale_node = Arc::get_mut(&mut stale).unwrap().as_bvh_node_mut().unwrap();
+        let shared = stale_node.left_node.clone();
+        let before = stale_node.bound_box.clone();
+        assert!(stale_node.refit(1.0, 1.0).is_err());
+        assert_eq!(stale_node.bound_box.minimum, before.minimum);
+        assert_eq!(stale_node.bound_box.maximum, before.maximum);
+        drop(shared);
+        assert!(stale_node.refit(1.0, 1.0).is_ok());
+    }
+
+    // The tree create_tree built before the surface area split, halving every slice at the median
+    fn median_tree(objects: &mut [Arc<dyn Hittable>]) -> Arc<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects[0].clone();
+        }
+        let axis = BVHNode::longest_axis(objects, 0.0, 1.0);
+        objects.sort_by([box_cmp_x, box_cmp_y, box_cmp_z][axis]);
+        let mid_idx = objects.len() / 2;
+        let left_node = median_tree(&mut objects[..mid_idx]);
+        let right_node = median_tree(&mut objects[mid_idx..]);
+        let bound_box = AxisAlignedBoundingBox::surrounding_box(left_node.bounding_box(0.0, 1.0).unwrap(), right_node.bounding_box(0.0, 1.0).unwrap());
+        return Arc::new(BVHNode { bound_box, left_node, right_node, split_axis: axis, max_traversal_depth: BVHNode::MAX_TRAVERSAL_DEPTH });
+    }
+
+    // Closest hit found the way BVHNode::hit walks the tree, counting the node boxes tested
+    fn hit_counting_boxes(obj: &Arc<dyn Hittable>, ray: Ray, t_min: f32, t_max: f32, box_tests: &mut usize) -> Option<HitRecord> {
+        let node = match obj.as_bvh_node() {
+            Some(node) => node,
+            None => return obj.hit(ray, t_min, t_max),
+        };
+        *box_tests += 1;
+        if !node.bound_box.contains(ray.origin) && !node.bound_box.hit(ray, t_min, t_max) {
             return Option::None;
         }
+        let (near, far) = if ray.direction[node.split_axis] < 0.0 {
+            (&node.right_node, &node.left_node)
+        } else {
+            (&node.left_node, &node.right_node)
+        };
+        let near_hit = hit_counting_boxes(near, ray, t_min, t_max, box_tests);
+        let t_limit = near_hit.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+        return hit_counting_boxes(far, ray, t_min, t_limit, box_tests).or(near_hit);
+    }
 
-        let mut _t_max = t_max;
-        let hit_left = self.left_node.hit(ray, t_min, _t_max);
-        if hit_left.is_some() {
-            _t_max = (ray.origin - hit_left.as_ref().unwrap().intersection).length();
+    #[test]
+    fn surface_area_splits_test_fewer_boxes_on_clustered_scenes() {
+        // Five tight clusters of small spheres and a sparse scatter of larger ones, where
+        // halving at the median cuts through clusters and leaves boxes spanning the gaps
+        let mut rng = StdRng::seed_from_u64(274);
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+        for _ in 0..5 {
+            let cluster = Point { x: rng.gen_range(-20.0..20.0), y: rng.gen_range(-20.0..20.0), z: rng.gen_range(-20.0..20.0) };
+            for _ in 0..200 {
+                let offset = Point { x: rng.gen_range(-1.0..1.0), y: rng.gen_range(-1.0..1.0), z: rng.gen_range(-1.0..1.0) };
+                objects.push(Arc::new(Sphere { center: cluster + offset, radius: rng.gen_range(0.02..0.1), material: material.clone() }));
+            }
+        }
+        for _ in 0..50 {
+            let center = Point { x: rng.gen_range(-25.0..25.0), y: rng.gen_range(-25.0..25.0), z: rng.gen_range(-25.0..25.0) };
+            objects.push(Arc::new(Sphere { center, radius: rng.gen_range(0.3..1.0), material: material.clone() }));
         }
+        let sah = BVHNode::create_tree(&mut objects.clone(), 0.0, 1.0);
+        let median = median_tree(&mut objects);
 
-        let hit_right = self.right_node.hit(ray, t_min, _t_max);
-        if hit_right.is_some() {
-            return hit_right;
+        let (mut sah_tests, mut median_tests) = (0, 0);
+        for _ in 0..20_000 {
+            let origin = Point { x: rng.gen_range(-30.0..30.0), y: rng.gen_range(-30.0..30.0), z: rng.gen_range(-30.0..30.0) };
+            let ray = Ray { origin, direction: Vector3::random_unit_vector_with(&mut rng), time: 0.0, kind: RayKind::Camera };
+            let expected = median.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t);
+            assert_eq!(hit_counting_boxes(&median, ray, 0.001, INF_F32, &mut median_tests).map(|hit_rec| hit_rec.t), expected);
+            assert_eq!(hit_counting_boxes(&sah, ray, 0.001, INF_F32, &mut sah_tests).map(|hit_rec| hit_rec.t), expected);
+            assert_eq!(sah.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t), expected);
         }
-        return hit_left;
+        assert!((sah_tests as f32) < 0.8 * median_tests as f32, "{} box tests against {} for the median split", sah_tests, median_tests);
     }
 
-    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
-        return Option::from(self.bound_box.clone());
+    #[test]
+    fn finds_the_same_nearest_hits_as_a_recursive_walk() {
+        let mut rng = StdRng::seed_from_u64(275);
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = (0..500).map(|_| {
+            let center = Point { x: rng.gen_range(-10.0..10.0), y: rng.gen_range(-10.0..10.0), z: rng.gen_range(-10.0..10.0) };
+            Arc::new(Sphere { center, radius: rng.gen_range(0.1..1.5), material: material.clone() }) as Arc<dyn Hittable>
+        }).collect();
+        let tree = BVHNode::create_tree(&mut objects, 0.0, 1.0);
+        let mut hits = 0;
+        for _ in 0..20_000 {
+            let origin = Point { x: rng.gen_range(-15.0..15.0), y: rng.gen_range(-15.0..15.0), z: rng.gen_range(-15.0..15.0) };
+            let ray = Ray { origin, direction: Vector3::random_unit_vector_with(&mut rng), time: 0.0, kind: RayKind::Camera };
+            let expected = hit_counting_boxes(&tree, ray, 0.001, INF_F32, &mut 0);
+            let found = tree.hit(ray, 0.001, INF_F32);
+            assert_eq!(found.as_ref().map(|hit_rec| (hit_rec.t, hit_rec.intersection, hit_rec.normal)), expected.as_ref().map(|hit_rec| (hit_rec.t, hit_rec.intersection, hit_rec.normal)));
+            hits += found.is_some() as usize;
+        }
+        assert!(hits > 5000, "only {} rays hit anything", hits);
+    }
+
+    // Notes its id each time a ray is tested against the wrapped object
+    struct Probe {
+        id: usize,
+        object: Arc<dyn Hittable>,
+        visits: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Hittable for Probe {
+        fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+            self.visits.lock().unwrap().push(self.id);
+            return self.object.hit(ray, t_min, t_max);
+        }
+
+        fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+            return self.object.bounding_box(t0, t1);
+        }
+    }
+
+    #[test]
+    fn leaves_are_tested_near_side_first() {
+        // Unit spheres in a row along x, and rays along the row through the corners of their
+        // boxes, so every leaf is reached and none is hit
+        let visits = Arc::new(Mutex::new(Vec::new()));
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = (0..8).map(|k| {
+            let sphere = Arc::new(Sphere { center: Point { x: 3.0 * k as f32, y: 0.0, z: 0.0 }, radius: 1.0, material: material.clone() });
+            Arc::new(Probe { id: k, object: sphere, visits: visits.clone() }) as Arc<dyn Hittable>
+        }).collect();
+        let tree = BVHNode::create_tree(&mut objects, 0.0, 1.0);
+        let visit_order = |origin: Point, x: f32| {
+            visits.lock().unwrap().clear();
+            let ray = Ray { origin, direction: Vector3 { x, y: 0.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+            assert!(tree.hit(ray, 0.001, INF_F32).is_none());
+            return visits.lock().unwrap().clone();
+        };
+        assert_eq!(visit_order(Point { x: -5.0, y: 0.9, z: 0.9 }, 1.0), (0..8).collect::<Vec<usize>>());
+        assert_eq!(visit_order(Point { x: 30.0, y: 0.9, z: 0.9 }, -1.0), (0..8).rev().collect::<Vec<usize>>());
+
+        // A hit on the near side narrows the search, so past its sibling no leaf is tested
+        visits.lock().unwrap().clear();
+        let ray = Ray { origin: Point { x: -5.0, y: 0.0, z: 0.0 }, direction: Vector3 { x: 1.0, y: 0.0, z: 0.0 }, time: 0.0, kind: RayKind::Camera };
+        assert!((tree.hit(ray, 0.001, INF_F32).unwrap().t - 4.0).abs() < 1e-5);
+        assert_eq!(*visits.lock().unwrap(), vec![0, 1]);
     }
 }
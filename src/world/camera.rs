@@ -1,5 +1,7 @@
-use crate::geometry::ray::Ray;
-use crate::geometry::vector::{Point, Vector3};
+use crate::geometry::ray::{Ray, RayKind};
+use crate::geometry::vector::{lerp, Point, Vector3};
+use crate::world::animation::Track;
+use crate::world::lens::ThickLens;
 use crate::utils::{degrees_to_radians, random_f32_range, random_in_unit_disk};
 
 #[derive(Clone)]
@@ -15,46 +17,218 @@ impl Screen {
     }
 }
 
+// Camera placement at one instant of the shutter interval
 #[derive(Clone)]
-pub struct Camera {
+struct CameraFrame {
     position: Point,
     horizontal_orientation: Vector3,
     vertical_orientation: Vector3,
-    aperture: f32,
     viewport: Screen,
+}
+
+impl CameraFrame {
+    fn lerp(&self, other: &Self, s: f32) -> Self {
+        let mix = |a: Vector3, b: Vector3| lerp(a, b, s);
+        Self {
+            position: mix(self.position, other.position),
+            horizontal_orientation: mix(self.horizontal_orientation, other.horizontal_orientation),
+            vertical_orientation: mix(self.vertical_orientation, other.vertical_orientation),
+            viewport: Screen {
+                width: mix(self.viewport.width, other.viewport.width),
+                height: mix(self.viewport.height, other.viewport.height),
+                upper_left_corner: mix(self.viewport.upper_left_corner, other.viewport.upper_left_corner),
+            },
+        }
+    }
+
+    // The frame turned by angle radians about the view direction, counter-clockwise as seen from behind
+    fn rolled(&self, angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        let horizontal_orientation = cos * self.horizontal_orientation + sin * self.vertical_orientation;
+        let vertical_orientation = cos * self.vertical_orientation - sin * self.horizontal_orientation;
+
+        let width = self.viewport.width.length() * horizontal_orientation;
+        let height = self.viewport.height.length() * vertical_orientation;
+        let center = self.viewport.upper_left_corner + self.viewport.width / 2.0 - self.viewport.height / 2.0;
+        Self {
+            position: self.position,
+            horizontal_orientation,
+            vertical_orientation,
+            viewport: Screen {
+                width,
+                height,
+                upper_left_corner: center - width / 2.0 + height / 2.0,
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Camera {
+    start: CameraFrame,
+    end: CameraFrame,
+    // Keyed look_from/look_at, overriding start/end when set
+    path: Option<(Track<Point>, Track<Point>)>,
+    // Traced glass lens replacing the thin-lens aperture/focus_dist model when set
+    lens: Option<ThickLens>,
+    // Radians about the view direction, applied on top of v_up
+    roll: f32,
+    // Distances along primary rays outside which hits are ignored
+    near: Option<f32>,
+    far: Option<f32>,
+    v_up: Vector3,
+    v_fov: f32,
+    aspect_ratio: f32,
+    aperture: f32,
+    focus_dist: f32,
     time0: f32,
     time1: f32,
 }
 
 impl Camera {
     pub fn new(look_from: Point, look_at: Point, v_up: Vector3, v_fov: f32, aspect_ratio: f32, aperture: f32, focus_dist: f32, time0: f32, time1: f32) -> Self {
-        let normal_in = (look_from - look_at).direction();
-        let horizontal_orientation = v_up.cross(normal_in).direction();
-        let vertical_orientation = normal_in.cross(horizontal_orientation);
-
-        let viewport = Self::setup_viewport(look_from, normal_in, horizontal_orientation, vertical_orientation, v_fov, aspect_ratio, focus_dist);
+        let start = Self::setup_frame(look_from, look_at, v_up, v_fov, aspect_ratio, focus_dist);
 
         Camera {
-            position: look_from,
-            horizontal_orientation,
-            vertical_orientation,
+            end: start.clone(),
+            start,
+            path: Option::None,
+            lens: Option::None,
+            roll: 0.0,
+            near: Option::None,
+            far: Option::None,
+            v_up,
+            v_fov,
+            aspect_ratio,
             aperture,
-            viewport,
+            focus_dist,
             time0,
             time1,
         }
     }
 
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        let rd = self.aperture * random_in_unit_disk();
-        let offset = rd.x * self.horizontal_orientation + rd.y * self.vertical_orientation;
+    // Moves the camera to look_from/look_at by time1, rays in between use an interpolated frame
+    pub fn with_motion(mut self, look_from: Point, look_at: Point) -> Self {
+        self.end = Self::setup_frame(look_from, look_at, self.v_up, self.v_fov, self.aspect_ratio, self.focus_dist);
+        self
+    }
+
+    // Follows keyframed look_from/look_at tracks, sampled at each ray's time
+    pub fn with_path(mut self, look_from: Track<Point>, look_at: Track<Point>) -> Self {
+        self.path = Option::from((look_from, look_at));
+        self
+    }
+
+    // Tilts the camera for a dutch angle, positive values turn it counter-clockwise
+    pub fn with_roll(mut self, roll_degrees: f32) -> Self {
+        self.roll = degrees_to_radians(roll_degrees);
+        self
+    }
+
+    // Clips primary rays to [near, far] from the lens, for cutaways and dropping foreground
+    // occluders. Either side can be left open with None.
+    pub fn with_clipping(mut self, near: Option<f32>, far: Option<f32>) -> Self {
+        self.near = near;
+        self.far = far;
+        self
+    }
+
+    // Range of t a primary ray's first hit must fall in, camera rays have unit directions so
+    // this is also the distance. Open sides are returned as min/max.
+    pub fn clip_range(&self, min: f32, max: f32) -> (f32, f32) {
+        (self.near.map_or(min, |near| near.max(min)), self.far.map_or(max, |far| far.min(max)))
+    }
+
+    // Images through a thick glass lens of the given focal length and f-number, focused at
+    // focus_dist. None when no such lens can focus there, e.g. focus_dist within the focal length.
+    pub fn with_thick_lens(mut self, focal_length: f32, f_number: f32) -> Option<Self> {
+        self.lens = Option::from(ThickLens::new(focal_length, f_number, self.focus_dist)?);
+        Option::from(self)
+    }
+
+    // Angle between neighbouring pixel centres of an image image_height pixels tall, at the
+    // center of the view
+    pub fn pixel_angle(&self, image_height: u32) -> f32 {
+        2.0 * (degrees_to_radians(self.v_fov) / 2.0).tan() / (image_height.max(2) - 1) as f32
+    }
+
+    pub fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        self.get_ray_with_lens(u, v, random_in_unit_disk())
+    }
+
+    // Ray through pixel (x, y) of a width by height image, with y counting rows down from the
+    // top like the framebuffer. Pixels sit on a grid spanning the screen edge to edge, so the
+    // first and last columns map to u = 0 and u = 1, and jitter in [0, 1) spreads samples
+    // towards the next grid point.
+    pub fn ray_through_pixel(&self, x: u32, y: u32, width: u32, height: u32, jitter: (f32, f32), lens_point: Point) -> Option<Ray> {
+        let u = (x as f32 + jitter.0) / (width.max(2) - 1) as f32;
+        let v = (y as f32 + jitter.1) / (height.max(2) - 1) as f32;
+        self.get_ray_with_lens(u, v, lens_point)
+    }
+
+    // Ray through screen position (u, v) leaving the lens at lens_point on the unit disk.
+    // None when a thick lens blocks that path, so the sample adds no light, as vignetting does.
+    pub fn get_ray_with_lens(&self, u: f32, v: f32, lens_point: Point) -> Option<Ray> {
+        let time = random_f32_range(self.time0, self.time1);
+        let frame = self.frame_at(time);
 
-        let ray_origin = self.position + offset;
-        let ray_direction = (self.viewport.pixel_position(u, v) - ray_origin).direction();
-        Ray {
+        if let Some(lens) = &self.lens {
+            return self.thick_lens_ray(lens, &frame, u, v, lens_point, time);
+        }
+
+        let rd = self.aperture * lens_point;
+        let offset = rd.x * frame.horizontal_orientation + rd.y * frame.vertical_orientation;
+
+        let ray_origin = frame.position + offset;
+        let ray_direction = (frame.viewport.pixel_position(u, v) - ray_origin).direction();
+        Option::from(Ray {
             origin: ray_origin,
             direction: ray_direction,
-            time: random_f32_range(self.time0, self.time1),
+            time,
+            kind: RayKind::Camera,
+        })
+    }
+
+    // The sensor is sized so that rays through the lens center cover roughly v_fov. The lens
+    // inverts the image, so the sensor point is mirrored through the axis.
+    fn thick_lens_ray(&self, lens: &ThickLens, frame: &CameraFrame, u: f32, v: f32, lens_point: Point, time: f32) -> Option<Ray> {
+        let sensor_height = 2.0 * lens.sensor_distance * (degrees_to_radians(self.v_fov) / 2.0).tan();
+        let sensor_width = sensor_height * self.aspect_ratio;
+        let (p, d) = lens.ray_from_sensor((0.5 - u) * sensor_width, (v - 0.5) * sensor_height, lens_point)?;
+
+        let forward = -frame.horizontal_orientation.cross(frame.vertical_orientation);
+        let to_world = |a: Vector3| a.x * frame.horizontal_orientation + a.y * frame.vertical_orientation + a.z * forward;
+        return Option::from(Ray {
+            origin: frame.position + to_world(p),
+            direction: to_world(d).direction(),
+            time,
+            kind: RayKind::Camera,
+        });
+    }
+
+    fn frame_at(&self, time: f32) -> CameraFrame {
+        let frame = if let Some((look_from, look_at)) = &self.path {
+            Self::setup_frame(look_from.sample(time), look_at.sample(time), self.v_up, self.v_fov, self.aspect_ratio, self.focus_dist)
+        } else {
+            let duration = self.time1 - self.time0;
+            let s = if duration > 0.0 { (time - self.time0) / duration } else { 0.0 };
+            self.start.lerp(&self.end, s)
+        };
+        if self.roll == 0.0 { frame } else { frame.rolled(self.roll) }
+    }
+
+    fn setup_frame(look_from: Point, look_at: Point, v_up: Vector3, v_fov: f32, aspect_ratio: f32, focus_dist: f32) -> CameraFrame {
+        let normal_in = (look_from - look_at).direction();
+        let horizontal_orientation = v_up.cross(normal_in).direction();
+        let vertical_orientation = normal_in.cross(horizontal_orientation);
+
+        let viewport = Self::setup_viewport(look_from, normal_in, horizontal_orientation, vertical_orientation, v_fov, aspect_ratio, focus_dist);
+
+        CameraFrame {
+            position: look_from,
+            horizontal_orientation,
+            vertical_orientation,
+            viewport,
         }
     }
 
@@ -74,4 +248,105 @@ impl Camera {
             upper_left_corner,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::animation::Interp;
+
+    const UP: Vector3 = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+
+    fn camera_at(look_from: Point, look_at: Point) -> Camera {
+        return Camera::new(look_from, look_at, UP, 40.0, 1.0, 0.0, 1.0, 0.0, 1.0);
+    }
+
+    fn assert_close(a: Vector3, b: Vector3) {
+        assert!((a - b).length() < 1e-4, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn moving_camera_interpolates_between_keyframes() {
+        let from0 = Point { x: 0.0, y: 0.0, z: 5.0 };
+        let from1 = Point { x: 2.0, y: 1.0, z: 5.0 };
+        let camera = camera_at(from0, Point::ORIGIN).with_motion(from1, Point::ORIGIN);
+        assert_close(camera.frame_at(0.0).position, from0);
+        assert_close(camera.frame_at(1.0).position, from1);
+        assert_close(camera.frame_at(0.5).position, lerp(from0, from1, 0.5));
+
+        // A pinhole camera's rays all leave from where it is at their time
+        for _ in 0..16 {
+            let ray = camera.get_ray(0.3, 0.7).unwrap();
+            assert_close(ray.origin, lerp(from0, from1, ray.time));
+        }
+    }
+
+    #[test]
+    fn thick_lens_rays_meet_at_the_focus_distance() {
+        let mut camera = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN);
+        camera.focus_dist = 3.0;
+        let too_close = Camera { focus_dist: 0.04, ..camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN) };
+        assert!(too_close.with_thick_lens(0.05, 16.0).is_none());
+        let camera = camera.with_thick_lens(0.05, 16.0).unwrap();
+        let lens_points = [Point::ORIGIN, Point { x: 0.9, y: 0.0, z: 0.0 }, Point { x: 0.0, y: -0.9, z: 0.0 }, Point { x: -0.6, y: 0.6, z: 0.0 }];
+        let rays: Vec<Ray> = lens_points.iter().map(|&p| camera.get_ray_with_lens(0.5, 0.5, p).unwrap()).collect();
+        let at_depth = |ray: &Ray, depth: f32| ray.at_distance((depth - (5.0 - ray.origin.z)) / -ray.direction.z);
+        let spread = |depth: f32| rays.iter().map(|r| (at_depth(r, depth) - at_depth(&rays[0], depth)).length()).fold(0.0, f32::max);
+        // Sweep along the view for where the rays come closest together
+        let best = (20..120).map(|k| 0.05 * k as f32).fold(1.0, |best, depth| if spread(depth) < spread(best) { depth } else { best });
+        assert!((best - 3.0).abs() < 0.3, "rays meet {} in front of the camera", best);
+        assert!(spread(best) < 0.1 * spread(1.5).min(spread(6.0)));
+    }
+
+    #[test]
+    fn blocked_thick_lens_paths_give_no_ray() {
+        let camera = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN).with_thick_lens(0.05, 4.0).unwrap();
+        // Every lens point passes at the center of the view, only some at a far corner
+        let lens_points: Vec<Point> = (0..16).map(|k| {
+            let phi = k as f32 * std::f32::consts::PI / 8.0;
+            Point { x: 0.8 * phi.cos(), y: 0.8 * phi.sin(), z: 0.0 }
+        }).collect();
+        assert!(lens_points.iter().all(|&p| camera.get_ray_with_lens(0.5, 0.5, p).is_some()));
+        let corner: Vec<Option<Ray>> = lens_points.iter().map(|&p| camera.get_ray_with_lens(-3.0, -3.0, p)).collect();
+        assert!(corner.iter().any(|r| r.is_none()));
+    }
+
+    #[test]
+    fn a_quarter_roll_swaps_the_image_axes() {
+        let camera = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN);
+        let rolled = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN).with_roll(90.0);
+        let direction = |camera: &Camera, u: f32, v: f32| camera.get_ray_with_lens(u, v, Point::ORIGIN).unwrap().direction.direction();
+        // Turned counter-clockwise, the right edge looks where the top did and the top where the left did
+        assert_close(direction(&rolled, 1.0, 0.5), direction(&camera, 0.5, 0.0));
+        assert_close(direction(&rolled, 0.5, 0.0), direction(&camera, 0.0, 0.5));
+        assert_close(direction(&rolled, 0.5, 0.5), direction(&camera, 0.5, 0.5));
+        assert!(direction(&rolled, 1.0, 0.5).y > 0.3);
+    }
+
+    #[test]
+    fn the_center_pixel_looks_straight_ahead() {
+        let camera = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN);
+        let direction = |x: u32, y: u32, jitter: (f32, f32)| camera.ray_through_pixel(x, y, 9, 9, jitter, Point::ORIGIN).unwrap().direction.direction();
+        let ahead = Vector3 { x: 0.0, y: 0.0, z: -1.0 };
+        assert_close(direction(4, 4, (0.0, 0.0)), ahead);
+        assert_close(direction(4, 4, (0.0, 0.0)), camera.get_ray_with_lens(0.5, 0.5, Point::ORIGIN).unwrap().direction.direction());
+
+        // Rows count down from the top and columns to the right, corner to corner across the view
+        assert!(direction(4, 0, (0.0, 0.0)).y > 0.3 && direction(4, 8, (0.0, 0.0)).y < -0.3);
+        assert!(direction(0, 4, (0.0, 0.0)).x < -0.3 && direction(8, 4, (0.0, 0.0)).x > 0.3);
+        assert_close(direction(0, 0, (0.0, 0.0)), camera.get_ray_with_lens(0.0, 0.0, Point::ORIGIN).unwrap().direction.direction());
+        assert_close(direction(8, 8, (0.0, 0.0)), camera.get_ray_with_lens(1.0, 1.0, Point::ORIGIN).unwrap().direction.direction());
+        // Jitter moves a whole pixel's step at most
+        assert_close(direction(3, 3, (1.0, 1.0)), direction(4, 4, (0.0, 0.0)));
+    }
+
+    #[test]
+    fn keyed_path_is_sampled_at_each_time() {
+        let from = Track::new(vec![(0.0, Point { x: 0.0, y: 0.0, z: 5.0 }), (1.0, Point { x: 4.0, y: 0.0, z: 5.0 })], Interp::Linear).unwrap();
+        let at = Track::constant(Point::ORIGIN);
+        let camera = camera_at(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN).with_path(from, at);
+        assert_close(camera.frame_at(0.0).position, Point { x: 0.0, y: 0.0, z: 5.0 });
+        assert_close(camera.frame_at(0.5).position, Point { x: 2.0, y: 0.0, z: 5.0 });
+        assert_close(camera.frame_at(1.0).position, Point { x: 4.0, y: 0.0, z: 5.0 });
+    }
+}
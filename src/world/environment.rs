@@ -0,0 +1,480 @@
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::geometry::color::Color;
+use crate::geometry::pdf::PDF;
+use crate::geometry::vector::Vector3;
+use crate::textures::image::ImageTexture;
+use crate::textures::texture::Texture;
+use crate::utils::{PI, random_f32};
+
+// Radiance arriving from infinitely far away, looked up by ray direction on a miss
+pub trait Environment: Send + Sync {
+    fn color(&self, direction: Vector3) -> Color;
+    // Whether bounces should sample directions towards the environment like they do the lights
+    fn is_sampled(&self) -> bool {
+        return false;
+    }
+    // Density over directions of random, only meaningful when is_sampled
+    fn pdf_value(&self, direction: Vector3) -> f32 {
+        return 0.0;
+    }
+    fn random(&self) -> Vector3 {
+        return Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+    }
+}
+
+pub struct EnvironmentPDF<'a> {
+    pub environment: &'a dyn Environment,
+}
+
+impl PDF for EnvironmentPDF<'_> {
+    fn value(&self, direction: Vector3) -> f32 {
+        return self.environment.pdf_value(direction);
+    }
+
+    fn generate(&self) -> Vector3 {
+        return self.environment.random();
+    }
+}
+
+pub struct SolidEnvironment {
+    pub color: Color,
+}
+
+// A uniform sky is sampled uniformly over the sphere, a black one not at all
+impl Environment for SolidEnvironment {
+    fn color(&self, _direction: Vector3) -> Color {
+        return self.color;
+    }
+
+    fn is_sampled(&self) -> bool {
+        return self.color.luminance() > 0.0;
+    }
+
+    fn pdf_value(&self, _direction: Vector3) -> f32 {
+        return 1.0 / (4.0 * PI);
+    }
+
+    fn random(&self) -> Vector3 {
+        return Vector3::random_unit_vector();
+    }
+}
+
+// Vertical blend from bottom straight down to top straight up, the classic sky by default
+pub struct GradientEnvironment {
+    pub bottom: Color,
+    pub top: Color,
+}
+
+impl GradientEnvironment {
+    pub const SKY: GradientEnvironment = GradientEnvironment {
+        bottom: Color::WHITE,
+        top: Color { r: 0.5, g: 0.7, b: 1.0 },
+    };
+}
+
+// Smooth enough that uniform sampling does fine, like SolidEnvironment
+impl Environment for GradientEnvironment {
+    fn color(&self, direction: Vector3) -> Color {
+        let t = 0.5 * (direction.direction().y + 1.0);
+        return (1.0 - t) * self.bottom + t * self.top;
+    }
+
+    fn is_sampled(&self) -> bool {
+        return self.bottom.luminance() > 0.0 || self.top.luminance() > 0.0;
+    }
+
+    fn pdf_value(&self, _direction: Vector3) -> f32 {
+        return 1.0 / (4.0 * PI);
+    }
+
+    fn random(&self) -> Vector3 {
+        return Vector3::random_unit_vector();
+    }
+}
+
+// Latitude-longitude panorama wrapped around the scene, -z at the centre column and +y along
+// the top row. ImageTexture decodes to 8-bit sRGB, so .hdr files load but clip at 1.
+pub struct EquirectEnvironment {
+    pub texture: ImageTexture,
+}
+
+impl EquirectEnvironment {
+    pub fn new(path: String) -> Self {
+        return EquirectEnvironment { texture: ImageTexture::new(path) };
+    }
+
+    // Texture (u, v) seen looking along direction from inside, u grows to the right when looking
+    // down -z with +y up, unlike Sphere's, which reads correctly from outside
+    pub fn direction_uv(direction: Vector3) -> (f32, f32) {
+        let d = direction.direction();
+        let u = 0.5 + d.x.atan2(-d.z) / (2.0 * PI);
+        let v = 0.5 + d.y.clamp(-1.0, 1.0).asin() / PI;
+        return (u, v);
+    }
+}
+
+impl Environment for EquirectEnvironment {
+    fn color(&self, direction: Vector3) -> Color {
+        let (u, v) = Self::direction_uv(direction);
+        return self.texture.color(u, v, direction);
+    }
+}
+
+// Six square images on the faces of a cube around the scene, in the order
+// +x, -x, +y, -y, +z, -z with the usual OpenGL cube map orientation
+pub struct CubeMapEnvironment {
+    pub faces: [ImageTexture; 6],
+    // Running sum of texel luminance times solid angle over all faces in order, for sampling
+    // directions in proportion to the light arriving from them
+    cdf: Vec<f32>,
+    // Index of the first texel of each face in cdf, and the total at the end
+    face_offsets: [usize; 7],
+}
+
+impl CubeMapEnvironment {
+    pub fn new(paths: [String; 6]) -> Self {
+        let faces = paths.map(ImageTexture::new);
+        let mut cdf: Vec<f32> = Vec::new();
+        let mut face_offsets = [0; 7];
+        let mut total = 0.0;
+        for (face, texture) in faces.iter().enumerate() {
+            face_offsets[face] = cdf.len();
+            for j in 0..texture.height {
+                for i in 0..texture.width {
+                    let color = texture.texel(i, j);
+                    total += color.luminance() * Self::texel_solid_angle(texture, i, j);
+                    cdf.push(total);
+                }
+            }
+        }
+        face_offsets[6] = cdf.len();
+        return CubeMapEnvironment { faces, cdf, face_offsets };
+    }
+
+    // Texel centre in face coordinates, both in [-1, 1] like sc/ma and tc/ma in face_uv
+    fn texel_center(texture: &ImageTexture, i: f32, j: f32) -> (f32, f32) {
+        return (2.0 * i / texture.width as f32 - 1.0, 2.0 * j / texture.height as f32 - 1.0);
+    }
+
+    // Area of the texel on a face at unit distance, foreshortened and divided by distance squared
+    fn texel_solid_angle(texture: &ImageTexture, i: u32, j: u32) -> f32 {
+        let (s, t) = Self::texel_center(texture, i as f32 + 0.5, j as f32 + 0.5);
+        let area = 4.0 / (texture.width * texture.height) as f32;
+        return area / (1.0 + s * s + t * t).powf(1.5);
+    }
+
+    // Inverse of face_uv, taking the face coordinates before they are mapped to (u, v)
+    fn face_direction(face: usize, s: f32, t: f32) -> Vector3 {
+        return match face {
+            0 => Vector3 { x: 1.0, y: -t, z: -s },
+            1 => Vector3 { x: -1.0, y: -t, z: s },
+            2 => Vector3 { x: s, y: 1.0, z: t },
+            3 => Vector3 { x: s, y: -1.0, z: -t },
+            4 => Vector3 { x: s, y: -t, z: 1.0 },
+            _ => Vector3 { x: -s, y: -t, z: -1.0 },
+        };
+    }
+
+    fn total(&self) -> f32 {
+        return self.cdf.last().cloned().unwrap_or(0.0);
+    }
+
+    // Face index and (u, v) in that face for a direction, selected by its major axis
+    pub fn face_uv(direction: Vector3) -> (usize, f32, f32) {
+        let (ax, ay, az) = (direction.x.abs(), direction.y.abs(), direction.z.abs());
+        let (face, sc, tc, ma) = if ax >= ay && ax >= az {
+            if direction.x > 0.0 { (0, -direction.z, -direction.y, ax) } else { (1, direction.z, -direction.y, ax) }
+        } else if ay >= az {
+            if direction.y > 0.0 { (2, direction.x, direction.z, ay) } else { (3, direction.x, -direction.z, ay) }
+        } else if direction.z > 0.0 {
+            (4, direction.x, -direction.y, az)
+        } else {
+            (5, -direction.x, -direction.y, az)
+        };
+
+        let u = 0.5 * (sc / ma + 1.0);
+        // Cube map t runs down the image, texture v runs up
+        let v = 1.0 - 0.5 * (tc / ma + 1.0);
+        return (face, u, v);
+    }
+}
+
+impl Environment for CubeMapEnvironment {
+    fn color(&self, direction: Vector3) -> Color {
+        let (face, u, v) = Self::face_uv(direction);
+        return self.faces[face].color(u, v, direction);
+    }
+
+    fn is_sampled(&self) -> bool {
+        return self.total() > 0.0;
+    }
+
+    fn pdf_value(&self, direction: Vector3) -> f32 {
+        let (face, u, v) = Self::face_uv(direction);
+        let texture = &self.faces[face];
+        let i = ((u * texture.width as f32) as u32).min(texture.width - 1);
+        let j = (((1.0 - v) * texture.height as f32) as u32).min(texture.height - 1);
+        let k = self.face_offsets[face] + (j * texture.width + i) as usize;
+        let weight = self.cdf[k] - if k > 0 { self.cdf[k - 1] } else { 0.0 };
+        return weight / self.total() / Self::texel_solid_angle(texture, i, j);
+    }
+
+    fn random(&self) -> Vector3 {
+        let target = random_f32() * self.total();
+        let k = self.cdf.partition_point(|&c| c <= target).min(self.cdf.len() - 1);
+        let face = self.face_offsets.partition_point(|&offset| offset <= k) - 1;
+        let texture = &self.faces[face];
+        let texel = (k - self.face_offsets[face]) as u32;
+        let (i, j) = (texel % texture.width, texel / texture.width);
+        let (s, t) = Self::texel_center(texture, i as f32 + random_f32(), j as f32 + random_f32());
+        return Self::face_direction(face, s, t).direction();
+    }
+}
+
+// The backgrounds --environment can put behind any scene
+#[derive(Clone, PartialEq, Debug)]
+pub enum EnvironmentKind {
+    Black,
+    Sky,
+    // Path of a latitude-longitude image
+    Equirect(String),
+    // Paths of the six faces, in CubeMapEnvironment's order
+    CubeMap([String; 6]),
+}
+
+impl EnvironmentKind {
+    // black, sky, equirect:PATH or cubemap: then six comma-separated paths
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name.split_once(':') {
+            None if name == "black" => Option::from(EnvironmentKind::Black),
+            None if name == "sky" => Option::from(EnvironmentKind::Sky),
+            Some(("equirect", path)) if !path.is_empty() => Option::from(EnvironmentKind::Equirect(path.to_string())),
+            Some(("cubemap", paths)) => {
+                let paths: Vec<String> = paths.split(',').map(String::from).collect();
+                if paths.iter().any(|path| path.is_empty()) {
+                    return Option::None;
+                }
+                <[String; 6]>::try_from(paths).ok().map(EnvironmentKind::CubeMap)
+            }
+            _ => Option::None,
+        };
+    }
+
+    // Fails when an image is missing, rather than panicking in the middle of loading it
+    pub fn build(&self) -> Result<Arc<dyn Environment>, String> {
+        return match self {
+            EnvironmentKind::Black => Ok(Arc::new(SolidEnvironment { color: Color::BLACK })),
+            EnvironmentKind::Sky => Ok(Arc::new(GradientEnvironment::SKY)),
+            EnvironmentKind::Equirect(path) => {
+                Self::check_image(path)?;
+                Ok(Arc::new(EquirectEnvironment::new(path.clone())))
+            }
+            EnvironmentKind::CubeMap(paths) => {
+                for path in paths.iter() {
+                    Self::check_image(path)?;
+                }
+                Ok(Arc::new(CubeMapEnvironment::new(paths.clone())))
+            }
+        };
+    }
+
+    fn check_image(path: &str) -> Result<(), String> {
+        if !Path::new(path).is_file() {
+            return Err(format!("no environment image at {}", path));
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use image::{Rgb, RgbImage};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::geometry::vector::Point;
+    use crate::materials::lambertian::Lambertian;
+    use crate::materials::light::DiffuseLight;
+    use crate::objects::hittable::Hittable;
+    use crate::objects::rectangle::XZRect;
+    use crate::objects::sphere::Sphere;
+    use crate::output::framebuffer::Framebuffer;
+    use crate::render::tests::{test_camera, test_settings};
+    use crate::render::{render, RenderSettings};
+    use crate::world::hittable_list::HittableList;
+
+    // A 3x3 image of border with center in the middle, saved to a temporary file
+    fn write_face(name: &str, center: [u8; 3], border: [u8; 3]) -> String {
+        let mut image = RgbImage::from_pixel(3, 3, Rgb(border));
+        image.put_pixel(1, 1, Rgb(center));
+        let path = env::temp_dir().join(format!("raytracer_{}_{}.png", name, process::id()));
+        image.save(&path).unwrap();
+        return path.to_str().unwrap().to_string();
+    }
+
+    const CENTERS: [[u8; 3]; 6] = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 0], [0, 255, 255], [255, 0, 255]];
+
+    fn cube_map(name: &str) -> CubeMapEnvironment {
+        let paths = [0, 1, 2, 3, 4, 5].map(|face| write_face(&format!("{}_face{}", name, face), CENTERS[face], [10, 10, 10]));
+        return CubeMapEnvironment::new(paths);
+    }
+
+    #[test]
+    fn each_axis_sees_the_center_of_its_face() {
+        let environment = cube_map("axes");
+        let axes = [
+            Vector3 { x: 1.0, y: 0.0, z: 0.0 },
+            Vector3 { x: -1.0, y: 0.0, z: 0.0 },
+            Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: -1.0, z: 0.0 },
+            Vector3 { x: 0.0, y: 0.0, z: 1.0 },
+            Vector3 { x: 0.0, y: 0.0, z: -1.0 },
+        ];
+        for (face, axis) in axes.iter().enumerate() {
+            assert_eq!(CubeMapEnvironment::face_uv(*axis), (face, 0.5, 0.5));
+            assert_eq!(environment.color(*axis), Color::from_srgb8(Rgb(CENTERS[face])));
+            // Off towards an edge the border shows instead
+            let off_center = (*axis + 0.9 * axes[(face + 2) % 6]).direction();
+            assert_eq!(CubeMapEnvironment::face_uv(off_center).0, face);
+            assert_eq!(environment.color(off_center), Color::from_srgb8(Rgb([10, 10, 10])));
+        }
+    }
+
+    #[test]
+    fn sampled_directions_follow_the_bright_texels() {
+        let environment = cube_map("sampling");
+        for _ in 0..200 {
+            let direction = environment.random();
+            let (face, u, v) = CubeMapEnvironment::face_uv(direction);
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            assert!(face < 6 && (0.0..=1.0).contains(&u) && (0.0..=1.0).contains(&v));
+            assert!(environment.pdf_value(direction) > 0.0);
+        }
+        // The bright centers carry nearly all the light, so they are where most samples go
+        let on_centers = (0..1000).filter(|_| {
+            let (_, u, v) = CubeMapEnvironment::face_uv(environment.random());
+            (u - 0.5).abs() < 1.0 / 6.0 && (v - 0.5).abs() < 1.0 / 6.0
+        }).count();
+        assert!(on_centers > 900, "{} of 1000 samples on the centers", on_centers);
+    }
+
+    #[test]
+    fn equirect_directions_land_on_their_pixels() {
+        // Every pixel of an 8x4 panorama a different color
+        let mut image = RgbImage::new(8, 4);
+        for (i, j, pixel) in image.enumerate_pixels_mut() {
+            *pixel = Rgb([30 * i as u8, 60 * j as u8, 200]);
+        }
+        let path = env::temp_dir().join(format!("raytracer_equirect_{}.png", process::id()));
+        image.save(&path).unwrap();
+        let environment = EquirectEnvironment::new(path.to_str().unwrap().to_string());
+        fs::remove_file(&path).unwrap();
+
+        // Straight ahead down -z is the centre of the image, straight up the top row
+        assert_eq!(EquirectEnvironment::direction_uv(Vector3 { x: 0.0, y: 0.0, z: -1.0 }), (0.5, 0.5));
+        assert_eq!(EquirectEnvironment::direction_uv(Vector3 { x: 0.0, y: 1.0, z: 0.0 }).1, 1.0);
+        // Looking right from -z, +x is a quarter of the way round
+        assert!((EquirectEnvironment::direction_uv(Vector3 { x: 1.0, y: 0.0, z: 0.0 }).0 - 0.75).abs() < 1e-6);
+
+        // The direction through the middle of each pixel sees that pixel
+        for j in 0..4 {
+            for i in 0..8 {
+                let longitude = ((i as f32 + 0.5) / 8.0 - 0.5) * 2.0 * PI;
+                let latitude = (0.5 - (j as f32 + 0.5) / 4.0) * PI;
+                let direction = Vector3 {
+                    x: longitude.sin() * latitude.cos(),
+                    y: latitude.sin(),
+                    z: -longitude.cos() * latitude.cos(),
+                };
+                assert_eq!(environment.color(direction), Color::from_srgb8(*image.get_pixel(i, j)), "pixel ({}, {})", i, j);
+            }
+        }
+    }
+
+    #[test]
+    fn the_default_sky_matches_the_old_inline_gradient() {
+        let mut rng = StdRng::seed_from_u64(268);
+        for _ in 0..100 {
+            let direction = 3.0 * Vector3::random_unit_vector_with(&mut rng);
+            let t = 0.5 * (direction.direction().y + 1.0);
+            let expected = (1.0 - t) * Color { r: 1.0, g: 1.0, b: 1.0 } + t * Color { r: 0.5, g: 0.7, b: 1.0 };
+            assert_eq!(GradientEnvironment::SKY.color(direction), expected);
+        }
+        assert_eq!(GradientEnvironment::SKY.color(Vector3 { x: 0.0, y: -2.0, z: 0.0 }), Color::WHITE);
+        assert_eq!(GradientEnvironment::SKY.color(Vector3 { x: 0.0, y: 2.0, z: 0.0 }), Color { r: 0.5, g: 0.7, b: 1.0 });
+    }
+
+    #[test]
+    fn a_bright_spot_lights_the_side_facing_it_with_less_noise_when_sampled() {
+        // A single white texel in the middle of the +x face, everything else black
+        let faces = [0, 1, 2, 3, 4, 5].map(|face| {
+            let center = if face == 0 { [255, 255, 255] } else { [0, 0, 0] };
+            write_face(&format!("spot_face{}", face), center, [0, 0, 0])
+        });
+        let environment: Arc<dyn Environment> = Arc::new(CubeMapEnvironment::new(faces));
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere {
+            center: Point::ORIGIN,
+            radius: 1.0,
+            material: Arc::new(Lambertian::new(Color { r: 0.8, g: 0.8, b: 0.8 })),
+        });
+        // The panel is missing from the world, so light samples aimed at it go nowhere
+        let mut lights = HittableList::new();
+        lights.add(Arc::new(XZRect { x: (-1.0, 1.0), z: (-1.0, 1.0), k: 100.0, material: Arc::new(DiffuseLight::new(Color::WHITE)) }));
+        let lights: Arc<dyn Hittable> = Arc::new(lights);
+        let sampled = RenderSettings { max_depth: 2, ..test_settings(24, 24, 16) };
+        let bsdf_only = RenderSettings { nee_weight: 0.0, ..sampled };
+        let render_with = |settings: &RenderSettings| render(settings, &test_camera(settings), sphere.clone(), lights.clone(), environment.clone());
+        let (smooth, noisy) = (render_with(&sampled), render_with(&bsdf_only));
+
+        // The camera looks down -z, so +x is the right half of the image
+        let half = |framebuffer: &Framebuffer, right: bool| {
+            let pixels = (0..24 * 24).filter(|k| (k % 24 >= 12) == right);
+            pixels.map(|k| framebuffer.pixels[k].luminance()).sum::<f32>() / (12.0 * 24.0)
+        };
+        assert!(half(&smooth, true) > 10.0 * half(&smooth, false), "right {} against left {}", half(&smooth, true), half(&smooth, false));
+        assert!(half(&noisy, true) > 10.0 * half(&noisy, false), "right {} against left {}", half(&noisy, true), half(&noisy, false));
+
+        let mean_variance = |framebuffer: &Framebuffer| framebuffer.variance.iter().sum::<f32>() / framebuffer.variance.len() as f32;
+        assert!(mean_variance(&smooth) < 0.5 * mean_variance(&noisy), "variance {} sampled, {} from the material alone", mean_variance(&smooth), mean_variance(&noisy));
+        let (a, b) = (half(&smooth, true), half(&noisy, true));
+        assert!((a - b).abs() < 0.15 * b, "lit side {} sampled, {} from the material alone", a, b);
+    }
+
+    #[test]
+    fn environment_names_build_their_environments() {
+        assert_eq!(EnvironmentKind::parse("black"), Option::from(EnvironmentKind::Black));
+        assert_eq!(EnvironmentKind::parse("sky"), Option::from(EnvironmentKind::Sky));
+        assert_eq!(EnvironmentKind::parse("equirect:studio.png"), Option::from(EnvironmentKind::Equirect("studio.png".to_string())));
+        let faces = ["px.png", "nx.png", "py.png", "ny.png", "pz.png", "nz.png"].map(String::from);
+        assert_eq!(EnvironmentKind::parse("cubemap:px.png,nx.png,py.png,ny.png,pz.png,nz.png"), Option::from(EnvironmentKind::CubeMap(faces)));
+        for name in ["", "sunset", "equirect", "equirect:", "sky:blue", "cubemap:a,b,c,d,e", "cubemap:a,b,c,d,e,f,g", "cubemap:a,b,,d,e,f"] {
+            assert_eq!(EnvironmentKind::parse(name), Option::None, "for {:?}", name);
+        }
+
+        let up = Vector3 { x: 0.0, y: 1.0, z: 0.0 };
+        assert_eq!(EnvironmentKind::Black.build().unwrap().color(up), Color::BLACK);
+        assert_eq!(EnvironmentKind::Sky.build().unwrap().color(up), GradientEnvironment::SKY.color(up));
+        // The image's center looks down -z
+        let path = write_face("kind_equirect", [200, 100, 50], [10, 10, 10]);
+        let equirect = EnvironmentKind::Equirect(path).build().unwrap();
+        assert_eq!(equirect.color(Vector3 { x: 0.0, y: 0.0, z: -1.0 }), Color::from_srgb8(Rgb([200, 100, 50])));
+        let missing = EnvironmentKind::Equirect("no/such/panorama.png".to_string()).build();
+        assert_eq!(missing.err().as_deref(), Option::from("no environment image at no/such/panorama.png"));
+
+        // Each axis sees its own face, as with CubeMapEnvironment::new
+        let paths = [0, 1, 2, 3, 4, 5].map(|face| write_face(&format!("kind_face{}", face), CENTERS[face], [10, 10, 10]));
+        let mut with_missing = paths.clone();
+        let cube = EnvironmentKind::CubeMap(paths).build().unwrap();
+        assert_eq!(cube.color(Vector3 { x: 0.0, y: -1.0, z: 0.0 }), Color::from_srgb8(Rgb(CENTERS[3])));
+        with_missing[4] = "no/such/face.png".to_string();
+        assert_eq!(EnvironmentKind::CubeMap(with_missing).build().err().as_deref(), Option::from("no environment image at no/such/face.png"));
+    }
+}
@@ -1,8 +1,11 @@
 use std::sync::Arc;
 
+use rand::RngCore;
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
 use crate::geometry::ray::Ray;
 use crate::geometry::vector::{Point, Vector3};
-use crate::objects::hittable::{HitRecord, Hittable};
+use crate::objects::hittable::{HitRecord, Hittable, sample_by_area};
 use crate::utils::random_int;
 
 pub struct HittableList {
@@ -37,19 +40,59 @@ impl Hittable for HittableList {
         return hit_rec;
     }
 
-    fn pdf_value(&self, o: Point, v: Vector3) -> f32 {
+    fn area(&self) -> f32 {
+        return self.objects.iter().map(|obj| obj.area()).sum();
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return sample_by_area(&self.children(), rng);
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn Hittable>> {
+        return self.objects.iter().collect();
+    }
+
+    fn bounding_box(&self, t0: f32, t1: f32) -> Option<AxisAlignedBoundingBox> {
+        let mut bound_box: Option<AxisAlignedBoundingBox> = Option::None;
+        for obj in self.objects.iter() {
+            let obj_box = obj.bounding_box(t0, t1)?;
+            bound_box = match bound_box {
+                None => Option::from(obj_box),
+                Some(b) => Option::from(AxisAlignedBoundingBox::surrounding_box(b, obj_box)),
+            };
+        }
+        return bound_box;
+    }
+
+    fn pdf_value(&self, o: Point, v: Vector3, epsilon: f32) -> f32 {
         let weight = 1.0 / self.objects.len() as f32;
         let mut sum = 0.0;
 
         for obj in self.objects.iter() {
-            sum += weight * obj.pdf_value(o, v);
+            sum += weight * obj.pdf_value(o, v, epsilon);
         }
 
         return sum;
     }
 
+    // An empty list has nothing to aim at and, like objects without a sampler, returns a fixed
+    // direction its pdf_value gives 0
     fn random(&self, o: Vector3) -> Vector3 {
+        if self.objects.is_empty() {
+            return Vector3 { x: 1.0, y: 0.0, z: 0.0 };
+        }
         let int_size = self.objects.len() as i32;
         return self.objects[random_int(0, (int_size - 1) as u32) as usize].random(o);
     }
+
+    // The first coordinate picks the object and is stretched back over [0, 1) for it
+    fn random_from(&self, o: Vector3, sample: (f32, f32)) -> Vector3 {
+        let n = self.objects.len();
+        if n == 0 {
+            return self.random(o);
+        }
+        let scaled = sample.0 * n as f32;
+        let k = (scaled as usize).min(n - 1);
+        return self.objects[k].random_from(o, (scaled - k as f32, sample.1));
+    }
 }
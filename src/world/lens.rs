@@ -0,0 +1,157 @@
+use crate::geometry::vector::{Point, Vector3};
+use crate::materials::material::refract;
+
+// A symmetric biconvex glass singlet with real thickness, traced surface by surface
+// instead of the thin-lens shortcut. Positions are in lens space: the rear vertex
+// is at the origin, the front vertex at z = thickness and the scene lies towards +z.
+#[derive(Clone)]
+pub struct ThickLens {
+    pub refractive_index: f32,
+    pub thickness: f32,
+    pub surface_radius: f32,
+    pub aperture_radius: f32,
+    // Distance from the rear vertex back to the sensor plane at z = -sensor_distance
+    pub sensor_distance: f32,
+}
+
+impl ThickLens {
+    const REFRACTIVE_INDEX: f32 = 1.5;
+
+    // Lens with the given focal length and f-number, with the sensor placed so that
+    // points focus_dist in front of the lens are imaged sharply
+    pub fn new(focal_length: f32, f_number: f32, focus_dist: f32) -> Option<Self> {
+        let n = Self::REFRACTIVE_INDEX;
+        let thickness = 0.1 * focal_length;
+        // Lensmaker's equation with R1 = -R2 = R, solved for R
+        let k = focal_length * (n - 1.0);
+        let disc = k.powi(2) - k * (n - 1.0) * thickness / n;
+        if disc < 0.0 {
+            return Option::None;
+        }
+        let surface_radius = k + disc.sqrt();
+
+        let mut lens = ThickLens {
+            refractive_index: n,
+            thickness,
+            surface_radius,
+            aperture_radius: (0.5 * focal_length / f_number).min(0.9 * surface_radius),
+            sensor_distance: 0.0,
+        };
+
+        // Follow a near-axis ray from the in-focus point through the glass to where it meets the axis
+        let object = Point { x: 0.0, y: 0.0, z: thickness + focus_dist };
+        let target = Point { x: 0.0, y: 0.01 * lens.aperture_radius, z: thickness };
+        let (p, d) = lens.trace(object, (target - object).direction(), true)?;
+        if d.y >= 0.0 || d.z >= 0.0 {
+            return Option::None;
+        }
+        lens.sensor_distance = -(p.z - p.y / d.y * d.z);
+        return Option::from(lens);
+    }
+
+    // Sphere surfaces: the front one bulges towards the scene, the rear one towards the sensor
+    fn front_center(&self) -> Point {
+        return Point { x: 0.0, y: 0.0, z: self.thickness - self.surface_radius };
+    }
+
+    fn rear_center(&self) -> Point {
+        return Point { x: 0.0, y: 0.0, z: self.surface_radius };
+    }
+
+    // Refracts a ray through both surfaces, towards the sensor if inwards is set and
+    // out into the scene otherwise. None if it misses the aperture or reflects internally.
+    pub fn trace(&self, origin: Point, direction: Vector3, inwards: bool) -> Option<(Point, Vector3)> {
+        let (first, second) = if inwards { (self.front_center(), self.rear_center()) } else { (self.rear_center(), self.front_center()) };
+        let (p, d) = self.refract_at(origin, direction, first, 1.0 / self.refractive_index, true)?;
+        return self.refract_at(p, d, second, self.refractive_index, false);
+    }
+
+    fn refract_at(&self, origin: Point, direction: Vector3, center: Point, eta: f32, entering: bool) -> Option<(Point, Vector3)> {
+        let oc = origin - center;
+        let b = oc.dot(direction);
+        let c = oc.length_squared() - self.surface_radius.powi(2);
+        let disc = b * b - c;
+        if disc < 0.0 {
+            return Option::None;
+        }
+        // Entering the glass hits the near side of the sphere, leaving it the far side
+        let t = if entering { -b - disc.sqrt() } else { -b + disc.sqrt() };
+        if t <= 0.0 {
+            return Option::None;
+        }
+        let p = origin + t * direction;
+        if p.x.powi(2) + p.y.powi(2) > self.aperture_radius.powi(2) {
+            return Option::None;
+        }
+
+        let mut normal = (p - center).direction();
+        if normal.dot(direction) > 0.0 {
+            normal = -normal;
+        }
+        let cos_i = (-direction.dot(normal)).min(1.0);
+        if eta * (1.0 - cos_i.powi(2)).max(0.0).sqrt() > 1.0 {
+            return Option::None;
+        }
+        return Option::from((p, refract(direction, normal, eta)));
+    }
+
+    // Ray leaving the front of the lens for a sensor point, aimed through lens_point on the unit
+    // disk scaled to the aperture. None when the glass or the aperture blocks that path.
+    pub fn ray_from_sensor(&self, sensor_x: f32, sensor_y: f32, lens_point: Point) -> Option<(Point, Vector3)> {
+        let sensor = Point { x: sensor_x, y: sensor_y, z: -self.sensor_distance };
+        let aim = self.aperture_radius * lens_point;
+        let target = Point { x: aim.x, y: aim.y, z: 0.0 };
+        return self.trace(sensor, (target - sensor).direction(), false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Largest distance from their mean of where rays from object, entering the front of the
+    // lens across the whole aperture, cross the sensor plane
+    fn spot_radius(lens: &ThickLens, object: Point) -> f32 {
+        let mut hits: Vec<Point> = Vec::new();
+        for k in 0..49 {
+            let aim = Point { x: (k % 7) as f32 / 3.0 - 1.0, y: (k / 7) as f32 / 3.0 - 1.0, z: 0.0 };
+            if aim.length_squared() > 1.0 {
+                continue;
+            }
+            let target = Point { x: 0.95 * lens.aperture_radius * aim.x, y: 0.95 * lens.aperture_radius * aim.y, z: lens.thickness };
+            if let Some((p, d)) = lens.trace(object, (target - object).direction(), true) {
+                hits.push(p + (-lens.sensor_distance - p.z) / d.z * d);
+            }
+        }
+        assert!(hits.len() > 20);
+        let mean = hits.iter().fold(Point::ORIGIN, |sum, &p| sum + p) / hits.len() as f32;
+        return hits.iter().map(|&p| (p - mean).length()).fold(0.0, f32::max);
+    }
+
+    #[test]
+    fn points_at_the_focus_distance_image_sharply() {
+        let (focal_length, focus_dist) = (0.05, 2.0);
+        let lens = ThickLens::new(focal_length, 16.0, focus_dist).unwrap();
+        // Behind the lens and a little past the focal length, as a real camera focused at 2m
+        assert!(lens.sensor_distance > 0.0 && lens.sensor_distance < 1.2 * focal_length);
+
+        // Either side of the focus distance the rays no longer meet in a point on the sensor
+        let in_focus = spot_radius(&lens, Point { x: 0.0, y: 0.0, z: lens.thickness + focus_dist });
+        let off_axis = spot_radius(&lens, Point { x: 0.1, y: 0.05, z: lens.thickness + focus_dist });
+        let too_near = spot_radius(&lens, Point { x: 0.0, y: 0.0, z: lens.thickness + 0.5 * focus_dist });
+        let too_far = spot_radius(&lens, Point { x: 0.0, y: 0.0, z: lens.thickness + 4.0 * focus_dist });
+        let blur = too_near.min(too_far);
+        assert!(in_focus < 0.1 * blur, "in focus spot {} against {}", in_focus, blur);
+        assert!(off_axis < 0.5 * blur, "off-axis spot {} against {}", off_axis, blur);
+    }
+
+    #[test]
+    fn blocked_paths_give_no_ray() {
+        let lens = ThickLens::new(0.05, 4.0, 2.0).unwrap();
+        assert!(lens.ray_from_sensor(0.0, 0.0, Point { x: 0.5, y: -0.5, z: 0.0 }).is_some());
+        // Far off axis the aperture edge is cut off, not swapped for the path through its center
+        let (x, center, edge) = (2.0 * lens.sensor_distance, Point::ORIGIN, Point { x: 1.0, y: 0.0, z: 0.0 });
+        assert!(lens.ray_from_sensor(x, 0.0, center).is_some());
+        assert!(lens.ray_from_sensor(x, 0.0, edge).is_none());
+    }
+}
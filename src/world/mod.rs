@@ -1,3 +1,11 @@
 pub mod camera;
 pub mod bvh_node;
 pub mod hittable_list;
+pub mod aggregate;
+pub mod uniform_grid;
+pub mod sampler;
+pub mod summary;
+pub mod animation;
+pub mod environment;
+pub mod lens;
+pub mod scene_file;
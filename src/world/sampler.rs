@@ -0,0 +1,181 @@
+use crate::utils::{random_f32, random_int};
+
+// Named sample streams. Each one is stratified on its own grid with its own
+// shuffled cell order, so e.g. a sample's lens position is not tied to its pixel offset.
+// Light and Bsdf place the first bounce's light and material directions.
+#[derive(Clone, Copy)]
+pub enum SampleDimension {
+    Pixel,
+    Lens,
+    Light,
+    Bsdf,
+}
+
+impl SampleDimension {
+    const COUNT: usize = 4;
+}
+
+// Samples for the first bounce of a camera path, Light for the direction towards the lights
+// and Bsdf for the one drawn from the material
+#[derive(Clone, Copy)]
+pub struct BounceSamples {
+    pub light: (f32, f32),
+    pub bsdf: (f32, f32),
+}
+
+// How a pixel's samples are placed. Stratified puts one jittered sample in each cell of the
+// largest square grid that fits the sample count, which antialiases edges with less noise.
+// Random draws every sample independently, mostly for comparison.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SamplingMode {
+    Stratified,
+    Random,
+}
+
+impl SamplingMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        return match name {
+            "stratified" => Option::from(SamplingMode::Stratified),
+            "random" => Option::from(SamplingMode::Random),
+            _ => Option::None,
+        };
+    }
+}
+
+pub struct Sampler {
+    strata: u32,
+    permutations: Vec<Vec<u32>>,
+}
+
+impl Sampler {
+    pub fn new(spp: u32, mode: SamplingMode) -> Self {
+        let strata = match mode {
+            SamplingMode::Stratified => (spp as f32).sqrt() as u32,
+            SamplingMode::Random => 0,
+        };
+        let cells = strata * strata;
+
+        let mut permutations: Vec<Vec<u32>> = Vec::with_capacity(SampleDimension::COUNT);
+        for _ in 0..SampleDimension::COUNT {
+            let mut permutation: Vec<u32> = (0..cells).collect();
+            for i in (1..permutation.len()).rev() {
+                let target = random_int(0, i as u32);
+                permutation.swap(i, target as usize);
+            }
+            permutations.push(permutation);
+        }
+
+        return Self { strata, permutations };
+    }
+
+    // Jittered point in [0, 1)^2, samples beyond the last full grid cell are uniformly random
+    pub fn get_2d(&self, dimension: SampleDimension, sample_index: u32) -> (f32, f32) {
+        let permutation = &self.permutations[dimension as usize];
+        if sample_index as usize >= permutation.len() {
+            return (random_f32(), random_f32());
+        }
+
+        let cell = permutation[sample_index as usize];
+        let cell_size = 1.0 / self.strata as f32;
+        return (((cell % self.strata) as f32 + random_f32()) * cell_size,
+                ((cell / self.strata) as f32 + random_f32()) * cell_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{seed_thread_rng, square_to_unit_disk};
+
+    const DIMENSIONS: [SampleDimension; 4] = [SampleDimension::Pixel, SampleDimension::Lens, SampleDimension::Light, SampleDimension::Bsdf];
+
+    fn cell(sample: (f32, f32), strata: u32) -> u32 {
+        return (sample.1 * strata as f32) as u32 * strata + (sample.0 * strata as f32) as u32;
+    }
+
+    #[test]
+    fn every_dimension_fills_its_grid() {
+        seed_thread_rng(Option::from(5));
+        let sampler = Sampler::new(64, SamplingMode::Stratified);
+        for &dimension in DIMENSIONS.iter() {
+            let mut seen = [false; 64];
+            for s in 0..64 {
+                let c = cell(sampler.get_2d(dimension, s), 8) as usize;
+                assert!(!seen[c]);
+                seen[c] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn dimensions_are_shuffled_independently() {
+        seed_thread_rng(Option::from(6));
+        let sampler = Sampler::new(64, SamplingMode::Stratified);
+        for (a, &first) in DIMENSIONS.iter().enumerate() {
+            for &second in DIMENSIONS[a + 1..].iter() {
+                let shared = (0..64).filter(|&s| cell(sampler.get_2d(first, s), 8) == cell(sampler.get_2d(second, s), 8)).count();
+                assert!(shared < 8, "{} of 64 samples share a cell", shared);
+            }
+        }
+
+        // Taking only the samples in the left half of the pixel still spreads the lens evenly
+        let mut quadrants = [0; 4];
+        for s in 0..64 {
+            if sampler.get_2d(SampleDimension::Pixel, s).0 < 0.5 {
+                let (u, v) = sampler.get_2d(SampleDimension::Lens, s);
+                let p = square_to_unit_disk(u, v);
+                quadrants[(p.x > 0.0) as usize + 2 * (p.y > 0.0) as usize] += 1;
+            }
+        }
+        assert!(quadrants.iter().all(|&n| n >= 3 && n <= 13), "lens quadrants {:?}", quadrants);
+    }
+
+    #[test]
+    fn random_mode_is_not_stratified() {
+        seed_thread_rng(Option::from(7));
+        let sampler = Sampler::new(64, SamplingMode::Random);
+        let mut seen = [false; 64];
+        for s in 0..64 {
+            seen[cell(sampler.get_2d(SampleDimension::Pixel, s), 8) as usize] = true;
+        }
+        assert!(seen.iter().any(|&covered| !covered));
+    }
+
+    #[test]
+    fn counts_between_squares_stratify_the_largest_grid_that_fits() {
+        seed_thread_rng(Option::from(8));
+        let sampler = Sampler::new(20, SamplingMode::Stratified);
+        let mut seen = [false; 16];
+        for s in 0..16 {
+            seen[cell(sampler.get_2d(SampleDimension::Pixel, s), 4) as usize] = true;
+        }
+        assert!(seen.iter().all(|&covered| covered));
+        // and the four left over still land in the pixel
+        for s in 16..20 {
+            let (u, v) = sampler.get_2d(SampleDimension::Pixel, s);
+            assert!((0.0..1.0).contains(&u) && (0.0..1.0).contains(&v));
+        }
+    }
+
+    #[test]
+    fn stratified_samples_antialias_a_checker_edge_with_less_noise() {
+        seed_thread_rng(Option::from(278));
+        // Unit squares turned a little, so their edges cross pixels at a slant, and a pixel
+        // the size of one square at some offset across them
+        let (sin, cos) = 0.3f32.sin_cos();
+        let white = |x: f32, y: f32| (((cos * x - sin * y).floor() + (sin * x + cos * y).floor()) as i32).rem_euclid(2) == 0;
+        let mut squared_errors = [0.0; 2];
+        for _ in 0..1000 {
+            let offset = (10.0 * random_f32(), 10.0 * random_f32());
+            let covered = |(u, v): (f32, f32)| white(offset.0 + u, offset.1 + v) as u32 as f32;
+            let exact = (0..64 * 64).map(|k| covered(((k % 64) as f32 / 64.0, (k / 64) as f32 / 64.0))).sum::<f32>() / 4096.0;
+            for (k, mode) in [SamplingMode::Stratified, SamplingMode::Random].iter().enumerate() {
+                let sampler = Sampler::new(16, *mode);
+                let estimate = (0..16).map(|s| covered(sampler.get_2d(SampleDimension::Pixel, s))).sum::<f32>() / 16.0;
+                squared_errors[k] += (estimate - exact).powi(2);
+            }
+        }
+        let [stratified, random] = squared_errors;
+        assert!(stratified < 0.5 * random, "stratified error {} against {} for random", stratified / 1000.0, random / 1000.0);
+    }
+}
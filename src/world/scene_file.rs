@@ -0,0 +1,170 @@
+use std::fs;
+
+use crate::geometry::vector::{Point, Vector3};
+use crate::world::camera::Camera;
+
+// Everything Camera::new takes, read from the camera block of a scene file:
+//
+//     camera {
+//         look_from 278 278 -800
+//         look_at 278 278 0
+//         v_fov 40
+//     }
+//
+// look_from and look_at are required. The rest default to v_up 0 1 0, v_fov 40, aperture 0,
+// focus_dist the distance between look_from and look_at, time0 0 and time1 1. Without an
+// aspect the image's aspect ratio is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CameraSpec {
+    pub look_from: Point,
+    pub look_at: Point,
+    pub v_up: Vector3,
+    pub v_fov: f32,
+    pub aspect_ratio: Option<f32>,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    pub time0: f32,
+    pub time1: f32,
+}
+
+impl CameraSpec {
+    // A pinhole camera with the defaults above
+    pub fn looking(look_from: Point, look_at: Point, v_fov: f32) -> Self {
+        return CameraSpec {
+            look_from,
+            look_at,
+            v_up: Vector3 { x: 0.0, y: 1.0, z: 0.0 },
+            v_fov,
+            aspect_ratio: Option::None,
+            aperture: 0.0,
+            focus_dist: (look_at - look_from).length(),
+            time0: 0.0,
+            time1: 1.0,
+        };
+    }
+
+    pub fn camera(&self, image_aspect_ratio: f32) -> Camera {
+        let aspect_ratio = self.aspect_ratio.unwrap_or(image_aspect_ratio);
+        return Camera::new(self.look_from, self.look_at, self.v_up, self.v_fov, aspect_ratio, self.aperture, self.focus_dist, self.time0, self.time1);
+    }
+}
+
+pub fn load_camera(path: &str) -> Result<CameraSpec, String> {
+    let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    return parse_camera(&source).map_err(|e| format!("{}: {}", path, e));
+}
+
+// Reads the first camera block in source. Settings are a name and its numbers, separated by
+// any whitespace, and # starts a comment running to the end of the line. Anything outside the
+// block is left for other readers.
+pub fn parse_camera(source: &str) -> Result<CameraSpec, String> {
+    let text: String = source.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .collect::<Vec<&str>>()
+        .join("\n")
+        .replace('{', " { ")
+        .replace('}', " } ");
+    let mut tokens = text.split_whitespace();
+    while tokens.next().ok_or("no camera block")? != "camera" {}
+    if tokens.next() != Option::from("{") {
+        return Err("camera is not followed by {".to_string());
+    }
+
+    let mut settings: Vec<(&str, Vec<f32>)> = Vec::new();
+    loop {
+        let name = tokens.next().ok_or("camera block is not closed")?;
+        if name == "}" {
+            break;
+        }
+        let count = match name {
+            "look_from" | "look_at" | "v_up" => 3,
+            "v_fov" | "aspect" | "aperture" | "focus_dist" | "time0" | "time1" => 1,
+            _ => return Err(format!("unknown camera setting {}", name)),
+        };
+        let values = (0..count)
+            .map(|_| tokens.next().and_then(|token| token.parse::<f32>().ok()).filter(|x| x.is_finite()))
+            .collect::<Option<Vec<f32>>>()
+            .ok_or(format!("{} expects {} number{}", name, count, if count == 1 { "" } else { "s" }))?;
+        settings.push((name, values));
+    }
+
+    let get = |name: &str| settings.iter().rev().find(|(n, _)| *n == name).map(|(_, values)| values.clone());
+    let vector = |name: &str| get(name).map(|v| Vector3 { x: v[0], y: v[1], z: v[2] });
+    let look_from = vector("look_from").ok_or("camera block has no look_from")?;
+    let look_at = vector("look_at").ok_or("camera block has no look_at")?;
+    let mut spec = CameraSpec::looking(look_from, look_at, get("v_fov").map_or(40.0, |v| v[0]));
+    if let Some(v_up) = vector("v_up") {
+        spec.v_up = v_up;
+    }
+    spec.aspect_ratio = get("aspect").map(|v| v[0]);
+    if let Some(v) = get("aperture") {
+        spec.aperture = v[0];
+    }
+    if let Some(v) = get("focus_dist") {
+        spec.focus_dist = v[0];
+    }
+    if let Some(v) = get("time0") {
+        spec.time0 = v[0];
+    }
+    if let Some(v) = get("time1") {
+        spec.time1 = v[0];
+    }
+    return Ok(spec);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_camera_block_builds_the_same_camera_as_camera_new() {
+        let source = "\
+# The Cornell box view
+camera {
+    look_from 278 278 -800
+    look_at 278 278 0   # the back wall
+    v_up 0 1 0
+    v_fov 40
+    aspect 1.5
+    aperture 0.2
+    focus_dist 10
+    time0 0.25
+    time1 0.75
+}
+";
+        let spec = parse_camera(source).unwrap();
+        let parsed = spec.camera(1.0);
+        let direct = Camera::new(Point { x: 278.0, y: 278.0, z: -800.0 }, Point { x: 278.0, y: 278.0, z: 0.0 },
+                                 Vector3 { x: 0.0, y: 1.0, z: 0.0 }, 40.0, 1.5, 0.2, 10.0, 0.25, 0.75);
+        let lens_point = Point { x: 0.3, y: -0.4, z: 0.0 };
+        for (u, v) in [(0.5, 0.5), (0.0, 1.0), (0.9, 0.2)] {
+            let (a, b) = (parsed.get_ray_with_lens(u, v, lens_point).unwrap(), direct.get_ray_with_lens(u, v, lens_point).unwrap());
+            assert_eq!((a.origin, a.direction), (b.origin, b.direction));
+        }
+        // Shutter times come from the block too
+        let time = parsed.get_ray(0.5, 0.5).unwrap().time;
+        assert!((0.25..=0.75).contains(&time), "{}", time);
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let spec = parse_camera("light { k 554 }\ncamera{look_from 0 0 5 look_at 0 0 0}").unwrap();
+        assert_eq!(spec, CameraSpec::looking(Point { x: 0.0, y: 0.0, z: 5.0 }, Point::ORIGIN, 40.0));
+        assert_eq!((spec.focus_dist, spec.aperture, spec.aspect_ratio), (5.0, 0.0, Option::None));
+    }
+
+    #[test]
+    fn bad_camera_blocks_are_reported() {
+        for (source, message) in [
+            ("", "no camera block"),
+            ("camera look_from 0 0 0", "camera is not followed by {"),
+            ("camera { look_from 0 0 5 look_at 0 0 0", "camera block is not closed"),
+            ("camera { look_from 0 0 5 look_at 0 0 0 zoom 2 }", "unknown camera setting zoom"),
+            ("camera { look_from 0 0 look_at 0 0 0 }", "look_from expects 3 numbers"),
+            ("camera { look_from 0 0 5 look_at 0 0 0 v_fov wide }", "v_fov expects 1 number"),
+            ("camera { look_at 0 0 0 }", "camera block has no look_from"),
+        ] {
+            assert_eq!(parse_camera(source).err().as_deref(), Option::from(message), "for {:?}", source);
+        }
+    }
+}
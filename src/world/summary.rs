@@ -0,0 +1,142 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
+use crate::objects::hittable::Hittable;
+use crate::utils::PI;
+
+pub struct SceneSummary {
+    pub object_counts: BTreeMap<&'static str, usize>,
+    pub bounds: Option<AxisAlignedBoundingBox>,
+    pub memory_bytes: usize,
+    pub material_count: usize,
+    pub emissive_count: usize,
+    pub emitted_power: f32,
+}
+
+// Overview of the top-level objects of a scene. The memory figure only counts
+// the objects themselves, not what they point to, so it is a lower bound.
+pub fn scene_summary(objects: &[Arc<dyn Hittable>], time0: f32, time1: f32) -> SceneSummary {
+    let mut object_counts: BTreeMap<&'static str, usize> = BTreeMap::new();
+    let mut bounds: Option<AxisAlignedBoundingBox> = Option::None;
+    let mut memory_bytes = 0;
+    // Materials are shared between objects, so count them by Arc identity
+    let mut materials: HashSet<usize> = HashSet::new();
+    let mut emissive_count = 0;
+
+    for obj in objects.iter() {
+        *object_counts.entry(obj.type_name()).or_insert(0) += 1;
+        memory_bytes += std::mem::size_of_val(&**obj);
+        if let Some(material) = obj.material() {
+            materials.insert(Arc::as_ptr(material) as *const () as usize);
+            if material.is_emissive() {
+                emissive_count += 1;
+            }
+        }
+
+        if let Some(obj_box) = obj.bounding_box(time0, time1) {
+            bounds = match bounds {
+                None => Option::from(obj_box),
+                Some(b) => Option::from(AxisAlignedBoundingBox::surrounding_box(b, obj_box)),
+            };
+        }
+    }
+
+    let emitted_power = total_emitted_power(objects);
+    return SceneSummary { object_counts, bounds, memory_bytes, material_count: materials.len(), emissive_count, emitted_power };
+}
+
+// Radiant flux of the scene's diffuse emitters, radiance times area times pi, by luminance.
+// A single number for comparing light setups, objects without a known area count as dark.
+pub fn total_emitted_power(objects: &[Arc<dyn Hittable>]) -> f32 {
+    return objects.iter().map(|obj| emitted_power(obj.as_ref())).sum();
+}
+
+// Aggregates without a material of their own are walked through their children
+fn emitted_power(obj: &dyn Hittable) -> f32 {
+    return match obj.material() {
+        Some(material) if material.is_emissive() => PI * obj.area() * material.radiance().luminance(),
+        Some(_) => 0.0,
+        None => obj.children().iter().map(|child| emitted_power(child.as_ref())).sum(),
+    };
+}
+
+impl fmt::Display for SceneSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: usize = self.object_counts.values().sum();
+        writeln!(f, "Scene: {} objects ({} emissive, power {:.1}), {} materials, ~{} bytes", total, self.emissive_count, self.emitted_power, self.material_count, self.memory_bytes)?;
+        for (name, count) in self.object_counts.iter() {
+            writeln!(f, "  {}: {}", name, count)?;
+        }
+        if let Some(b) = &self.bounds {
+            write!(f, "  bounds: ({}, {}, {}) to ({}, {}, {})",
+                   b.minimum.x, b.minimum.y, b.minimum.z, b.maximum.x, b.maximum.y, b.maximum.z)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::vector::Point;
+    use crate::materials::lambertian::Lambertian;
+    use crate::materials::light::DiffuseLight;
+    use crate::objects::hittable::FlipFace;
+    use crate::objects::instances::Translate;
+    use crate::objects::rectangle::XZRect;
+    use crate::objects::sphere::Sphere;
+    use crate::world::hittable_list::HittableList;
+
+    #[test]
+    fn counts_and_bounds_of_a_known_scene() {
+        let grey = Arc::new(Lambertian::new(Color { r: 0.5, g: 0.5, b: 0.5 }));
+        let objects: Vec<Arc<dyn Hittable>> = vec![
+            Arc::new(Sphere { center: Point { x: -2.0, y: 0.0, z: 0.0 }, radius: 1.0, material: grey.clone() }),
+            Arc::new(Sphere { center: Point { x: 2.0, y: 0.0, z: 0.0 }, radius: 0.5, material: grey }),
+            Arc::new(XZRect { x: (-1.0, 1.0), z: (-2.0, 2.0), k: 3.0, material: Arc::new(DiffuseLight::new(Color { r: 2.0, g: 2.0, b: 2.0 })) }),
+        ];
+        let summary = scene_summary(&objects, 0.0, 1.0);
+
+        assert_eq!(summary.object_counts.get("Sphere"), Option::from(&2));
+        assert_eq!(summary.object_counts.get("XZRect"), Option::from(&1));
+        assert_eq!(summary.material_count, 2);
+        assert_eq!(summary.emissive_count, 1);
+        assert!((summary.emitted_power - PI * 8.0 * 2.0).abs() < 1e-3);
+        assert!(summary.memory_bytes >= 2 * std::mem::size_of::<Sphere>() + std::mem::size_of::<XZRect>());
+
+        let bounds = summary.bounds.clone().unwrap();
+        assert_eq!(bounds.minimum, Point { x: -3.0, y: -1.0, z: -2.0 });
+        assert_eq!(bounds.maximum.x, 2.5);
+        assert!((bounds.maximum.y - 3.0).abs() < 1e-3);
+        assert_eq!(bounds.maximum.z, 2.0);
+
+        let text = summary.to_string();
+        assert!(text.starts_with("Scene: 3 objects (1 emissive"));
+        assert!(text.contains("  Sphere: 2\n"));
+    }
+
+    #[test]
+    fn a_quad_light_emits_pi_times_its_area_and_radiance() {
+        // 3 by 2 with radiance 5, so 30 pi
+        let quad = || Arc::new(XZRect { x: (0.0, 3.0), z: (-1.0, 1.0), k: 4.0, material: Arc::new(DiffuseLight::new(Color { r: 5.0, g: 5.0, b: 5.0 })) });
+        let expected = PI * 6.0 * 5.0;
+        assert!((total_emitted_power(&[quad()]) - expected).abs() < 1e-3);
+
+        // Radiance counts by luminance, and emitting from both faces doubles it
+        let green = Arc::new(XZRect { x: (0.0, 3.0), z: (-1.0, 1.0), k: 4.0, material: Arc::new(DiffuseLight::new(Color { r: 0.0, g: 5.0, b: 0.0 })) });
+        assert!((total_emitted_power(&[green]) - expected * Color { r: 0.0, g: 1.0, b: 0.0 }.luminance()).abs() < 1e-3);
+        let two_sided = Arc::new(XZRect { x: (0.0, 3.0), z: (-1.0, 1.0), k: 4.0, material: Arc::new(DiffuseLight::new_two_sided(Color { r: 5.0, g: 5.0, b: 5.0 })) });
+        assert!((total_emitted_power(&[two_sided]) - 2.0 * expected).abs() < 1e-3);
+
+        // Lights inside lists and wrappers are found, everything else adds nothing
+        let mut nested = HittableList::new();
+        nested.add(Arc::new(FlipFace { object: quad() }));
+        nested.add(Arc::new(Translate { object: quad(), offset: Point { x: 5.0, y: 0.0, z: 0.0 } }));
+        nested.add(Arc::new(Sphere { center: Point::ORIGIN, radius: 1.0, material: Arc::new(Lambertian::new(Color::WHITE)) }));
+        assert!((total_emitted_power(&[Arc::new(nested)]) - 2.0 * expected).abs() < 1e-3);
+        assert_eq!(total_emitted_power(&[]), 0.0);
+    }
+}
@@ -0,0 +1,287 @@
+use std::sync::Arc;
+
+use rand::RngCore;
+
+use crate::geometry::bounding_volume::AxisAlignedBoundingBox;
+use crate::geometry::ray::Ray;
+use crate::geometry::vector::{Point, Vector3};
+use crate::objects::hittable::{HitRecord, Hittable, sample_by_area};
+use crate::utils::INF_F32;
+
+// Regular grid of cells over the scene box, each listing the objects whose box overlaps it.
+// Rays step through the cells they cross front to back, so the walk ends at the first cell
+// holding a hit. Suits many similar-sized objects spread evenly, where the BVH gains little.
+// Objects without a finite bounding box are kept aside and tested against every ray.
+pub struct UniformGrid {
+    // Box of the cells, around the objects in them
+    bound_box: Option<AxisAlignedBoundingBox>,
+    // Box of everything, None if any object has none
+    scene_box: Option<AxisAlignedBoundingBox>,
+    resolution: [usize; 3],
+    // Indices into objects, x fastest
+    cells: Vec<Vec<usize>>,
+    objects: Vec<Arc<dyn Hittable>>,
+    unbounded: Vec<Arc<dyn Hittable>>,
+}
+
+impl UniformGrid {
+    // Cells aimed for per object when picking the resolution
+    pub const CELLS_PER_OBJECT: f32 = 2.0;
+    pub const MAX_RESOLUTION: usize = 128;
+    // Flat scenes get this much thickness so their single layer of cells has a volume
+    const MIN_EXTENT: f32 = 1e-3;
+
+    pub fn new(objects: &[Arc<dyn Hittable>], time0: f32, time1: f32) -> Self {
+        let mut bounded: Vec<(Arc<dyn Hittable>, AxisAlignedBoundingBox)> = Vec::new();
+        let mut unbounded: Vec<Arc<dyn Hittable>> = Vec::new();
+        let mut scene_box: Option<AxisAlignedBoundingBox> = Option::None;
+        let mut has_box = true;
+        for obj in objects.iter() {
+            let opt_box = obj.bounding_box(time0, time1);
+            has_box &= opt_box.is_some();
+            match opt_box {
+                Some(b) if (0..3).all(|a| (b.maximum[a] - b.minimum[a]).is_finite()) => {
+                    scene_box = Option::from(Self::merge(scene_box, b.clone()));
+                    bounded.push((obj.clone(), b));
+                }
+                Some(b) => {
+                    scene_box = Option::from(Self::merge(scene_box, b));
+                    unbounded.push(obj.clone());
+                }
+                None => unbounded.push(obj.clone()),
+            }
+        }
+
+        let mut grid = UniformGrid {
+            bound_box: Option::None,
+            scene_box: if has_box { scene_box } else { Option::None },
+            resolution: [1, 1, 1],
+            cells: vec![Vec::new()],
+            objects: bounded.iter().map(|(obj, _)| obj.clone()).collect(),
+            unbounded,
+        };
+        if bounded.is_empty() {
+            return grid;
+        }
+
+        let mut bound_box = bounded[0].1.clone();
+        for (_, b) in bounded[1..].iter() {
+            bound_box = AxisAlignedBoundingBox::surrounding_box(bound_box, b.clone());
+        }
+        for a in 0..3 {
+            let pad = 0.5 * (Self::MIN_EXTENT - (bound_box.maximum[a] - bound_box.minimum[a])).max(0.0);
+            bound_box.minimum[a] -= pad;
+            bound_box.maximum[a] += pad;
+        }
+
+        // Roughly cubic cells, as many as CELLS_PER_OBJECT asks for along the longest side
+        let extent = bound_box.maximum - bound_box.minimum;
+        let longest = extent.x.max(extent.y).max(extent.z);
+        let per_axis = (Self::CELLS_PER_OBJECT * bounded.len() as f32).cbrt();
+        for a in 0..3 {
+            grid.resolution[a] = ((extent[a] / longest * per_axis).round() as usize).clamp(1, Self::MAX_RESOLUTION);
+        }
+        grid.cells = vec![Vec::new(); grid.resolution.iter().product()];
+        grid.bound_box = Option::from(bound_box);
+
+        for (index, (_, b)) in bounded.iter().enumerate() {
+            let low = grid.cell_of(b.minimum);
+            let high = grid.cell_of(b.maximum);
+            for z in low[2]..=high[2] {
+                for y in low[1]..=high[1] {
+                    for x in low[0]..=high[0] {
+                        let cell = grid.cell_index([x, y, z]);
+                        grid.cells[cell].push(index);
+                    }
+                }
+            }
+        }
+        return grid;
+    }
+
+    fn merge(a: Option<AxisAlignedBoundingBox>, b: AxisAlignedBoundingBox) -> AxisAlignedBoundingBox {
+        return match a {
+            Some(a) => AxisAlignedBoundingBox::surrounding_box(a, b),
+            None => b,
+        };
+    }
+
+    // Cell holding point, clamped to the grid
+    fn cell_of(&self, point: Point) -> [usize; 3] {
+        let bound_box = self.bound_box.as_ref().unwrap();
+        let mut cell = [0; 3];
+        for a in 0..3 {
+            let extent = bound_box.maximum[a] - bound_box.minimum[a];
+            let offset = (point[a] - bound_box.minimum[a]) / extent * self.resolution[a] as f32;
+            cell[a] = (offset.max(0.0) as usize).min(self.resolution[a] - 1);
+        }
+        return cell;
+    }
+
+    fn cell_index(&self, cell: [usize; 3]) -> usize {
+        return (cell[2] * self.resolution[1] + cell[1]) * self.resolution[0] + cell[0];
+    }
+
+    // Walks the cells the ray crosses front to back (Amanatides and Woo), testing each
+    // cell's objects against the closest hit so far
+    fn hit_cells(&self, ray: Ray, t_min: f32, t_max: f32, mut closest: Option<HitRecord>) -> Option<HitRecord> {
+        let bound_box = match &self.bound_box {
+            Some(b) => b,
+            None => return closest,
+        };
+        let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+        let (t_enter, _) = match bound_box.hit_interval(ray, t_min, t_limit) {
+            Some(interval) => interval,
+            None => return closest,
+        };
+
+        let mut cell = self.cell_of(ray.at_distance(t_enter));
+        let mut step = [0isize; 3];
+        let mut t_next = [INF_F32; 3];
+        let mut t_delta = [INF_F32; 3];
+        for a in 0..3 {
+            let size = (bound_box.maximum[a] - bound_box.minimum[a]) / self.resolution[a] as f32;
+            let direction = ray.direction[a];
+            if direction > 0.0 {
+                step[a] = 1;
+                t_next[a] = (bound_box.minimum[a] + (cell[a] + 1) as f32 * size - ray.origin[a]) / direction;
+                t_delta[a] = size / direction;
+            } else if direction < 0.0 {
+                step[a] = -1;
+                t_next[a] = (bound_box.minimum[a] + cell[a] as f32 * size - ray.origin[a]) / direction;
+                t_delta[a] = -size / direction;
+            }
+        }
+
+        loop {
+            for &index in self.cells[self.cell_index(cell)].iter() {
+                let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+                let opt_hit_rec = self.objects[index].hit(ray, t_min, t_limit);
+                if opt_hit_rec.is_some() {
+                    closest = opt_hit_rec;
+                }
+            }
+
+            // Objects in later cells can only be hit beyond where the ray leaves this one
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] { 0 } else { 2 }
+            } else if t_next[1] < t_next[2] { 1 } else { 2 };
+            let t_exit = t_next[axis];
+            let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+            if t_limit <= t_exit || t_exit == INF_F32 {
+                return closest;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.resolution[axis] as isize {
+                return closest;
+            }
+            cell[axis] = next as usize;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+}
+
+impl Hittable for UniformGrid {
+    fn hit(&self, ray: Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut closest: Option<HitRecord> = Option::None;
+        for obj in self.unbounded.iter() {
+            let t_limit = closest.as_ref().map_or(t_max, |hit_rec| hit_rec.t);
+            let opt_hit_rec = obj.hit(ray, t_min, t_limit);
+            if opt_hit_rec.is_some() {
+                closest = opt_hit_rec;
+            }
+        }
+        return self.hit_cells(ray, t_min, t_max, closest);
+    }
+
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<AxisAlignedBoundingBox> {
+        return self.scene_box.clone();
+    }
+
+    fn area(&self) -> f32 {
+        return self.children().iter().map(|obj| obj.area()).sum();
+    }
+
+    fn sample_surface(&self, rng: &mut dyn RngCore) -> Option<(Point, Vector3, f32)> {
+        return sample_by_area(&self.children(), rng);
+    }
+
+    fn children(&self) -> Vec<&Arc<dyn Hittable>> {
+        return self.objects.iter().chain(self.unbounded.iter()).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::geometry::color::Color;
+    use crate::geometry::ray::RayKind;
+    use crate::materials::lambertian::Lambertian;
+    use crate::objects::plane::GroundPlane;
+    use crate::objects::sphere::Sphere;
+    use crate::textures::solid::SolidColor;
+    use crate::world::hittable_list::HittableList;
+
+    fn spheres(rng: &mut StdRng, count: usize, flat: bool) -> Vec<Arc<dyn Hittable>> {
+        let material = Arc::new(Lambertian::new(Color::WHITE));
+        let mut objects: Vec<Arc<dyn Hittable>> = Vec::new();
+        for _ in 0..count {
+            let y = if flat { 0.0 } else { rng.gen_range(-5.0..5.0) };
+            objects.push(Arc::new(Sphere {
+                center: Point { x: rng.gen_range(-5.0..5.0), y, z: rng.gen_range(-5.0..5.0) },
+                radius: rng.gen_range(0.05..0.8),
+                material: material.clone(),
+            }));
+        }
+        return objects;
+    }
+
+    // Every ray must find the same closest hit as testing each object in turn
+    fn assert_matches_list(objects: &[Arc<dyn Hittable>], rng: &mut StdRng) {
+        let grid = UniformGrid::new(objects, 0.0, 1.0);
+        let list = HittableList { objects: objects.to_vec() };
+        for _ in 0..2000 {
+            let ray = Ray {
+                origin: Point { x: rng.gen_range(-8.0..8.0), y: rng.gen_range(-8.0..8.0), z: rng.gen_range(-8.0..8.0) },
+                direction: Vector3::random_unit_vector_with(rng),
+                time: 0.0,
+                kind: RayKind::Camera,
+            };
+            let expected = list.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t);
+            let found = grid.hit(ray, 0.001, INF_F32).map(|hit_rec| hit_rec.t);
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn finds_the_same_closest_hits_as_a_list() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let objects = spheres(&mut rng, 300, false);
+        assert_matches_list(&objects, &mut rng);
+    }
+
+    #[test]
+    fn handles_flat_and_unbounded_scenes() {
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut objects = spheres(&mut rng, 200, true);
+        let grid = UniformGrid::new(&objects, 0.0, 1.0);
+        assert!(grid.resolution[1] < grid.resolution[0]);
+        assert_matches_list(&objects, &mut rng);
+
+        objects.push(Arc::new(GroundPlane::new(-1.0, Arc::new(SolidColor { color: Color::WHITE }))));
+        let grid = UniformGrid::new(&objects, 0.0, 1.0);
+        assert_eq!(grid.unbounded.len(), 1);
+        assert_eq!(grid.bounding_box(0.0, 1.0).unwrap().maximum.x, INF_F32);
+        assert_matches_list(&objects, &mut rng);
+    }
+
+    #[test]
+    fn an_empty_grid_hits_nothing() {
+        let grid = UniformGrid::new(&[], 0.0, 1.0);
+        let ray = Ray { origin: Point::ORIGIN, direction: Vector3 { x: 0.0, y: 0.0, z: 1.0 }, time: 0.0, kind: RayKind::Camera };
+        assert!(grid.hit(ray, 0.001, INF_F32).is_none());
+    }
+}